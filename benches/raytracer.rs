@@ -0,0 +1,107 @@
+
+use std::path::PathBuf;
+
+use cgmath::{Point3, Vector3};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use raytracer::{Acceleration, CityParameters, Float, KDTreeOptions, Mesh, MeshData, PACKET_SIZE, Plane, Ray, Renderer, RoomParameters, Sphere, generate_city, generate_room};
+
+/// Rays pointing straight down onto a square of `2 * extent` around the origin, `count` along each side
+///
+/// The meshes and primitives below are centered on the origin with Y up, so most of these rays hit them.
+fn rays_from_above(extent: Float, count: usize) -> Vec<Ray> {
+    let step = 2.0 * extent / count as Float;
+    (0..count * count)
+        .map(|i| {
+            let x = -extent + ((i % count) as Float + 0.5) * step;
+            let z = -extent + ((i / count) as Float + 0.5) * step;
+            Ray::new(Point3::new(x, 5.0, z), Vector3::new(0.0, -1.0, 0.0))
+        })
+        .collect()
+}
+
+/// Camera rays through the center of every pixel of a scene, in world space
+fn camera_rays(renderer: &Renderer) -> Vec<Ray> {
+    let camera = &renderer.scene().camera;
+    let (width, height) = camera.resolution;
+    (0..width * height)
+        .map(|i| {
+            let ray = Ray::from_screen_coordinates((i % width) as Float, (i / width) as Float, width, height, camera.fov);
+            ray.transform(&camera.transformation_matrix)
+        })
+        .collect()
+}
+
+fn mesh_traversal(c: &mut Criterion) {
+    let rays = rays_from_above(1.5, 64);
+    let mut group = c.benchmark_group("mesh_traversal");
+    group.throughput(Throughput::Elements(rays.len() as u64));
+
+    for (name, acceleration) in [("kd_tree", Acceleration::KDTree), ("qbvh", Acceleration::Qbvh)] {
+        let mesh = Mesh::new(PathBuf::from("torus"), MeshData::torus(1.0, 0.4, 256, 128), false, acceleration, KDTreeOptions::default());
+        group.bench_with_input(BenchmarkId::new(name, "single"), &mesh, |b, mesh| {
+            b.iter(|| rays.iter().filter_map(|ray| mesh.intersect(ray)).count())
+        });
+        group.bench_with_input(BenchmarkId::new(name, "packet"), &mesh, |b, mesh| {
+            b.iter(|| rays.chunks(PACKET_SIZE).map(|packet| mesh.intersect_packet(packet).iter().flatten().count()).sum::<usize>())
+        });
+    }
+
+    group.finish();
+}
+
+fn primitive_intersection(c: &mut Criterion) {
+    let rays = rays_from_above(1.5, 64);
+    let mut group = c.benchmark_group("primitive_intersection");
+    group.throughput(Throughput::Elements(rays.len() as u64));
+
+    let sphere = Sphere::default();
+    group.bench_function("sphere", |b| {
+        b.iter(|| rays.iter().filter_map(|ray| sphere.intersect(ray)).count())
+    });
+
+    let plane = Plane::default();
+    group.bench_function("plane", |b| {
+        b.iter(|| rays.iter().filter_map(|ray| plane.intersect(ray)).count())
+    });
+
+    group.finish();
+}
+
+fn scene_tracing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("trace_batch");
+
+    for (name, scene) in [
+        ("room", generate_room(&RoomParameters { resolution: (160, 120), ..Default::default() })),
+        ("city", generate_city(&CityParameters { resolution: (160, 120), ..Default::default() })),
+    ] {
+        let renderer = Renderer::new(scene);
+        let rays = camera_rays(&renderer);
+        group.throughput(Throughput::Elements(rays.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| renderer.scene().trace_batch(&rays))
+        });
+    }
+
+    group.finish();
+}
+
+fn frame_render(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_render");
+    group.sample_size(10);
+
+    for (name, scene) in [
+        ("room", generate_room(&RoomParameters { resolution: (80, 60), ..Default::default() })),
+        ("city", generate_city(&CityParameters { resolution: (80, 60), ..Default::default() })),
+    ] {
+        let renderer = Renderer::new(scene);
+        group.bench_function(name, |b| {
+            b.iter(|| renderer.render())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, mesh_traversal, primitive_intersection, scene_tracing, frame_render);
+criterion_main!(benches);
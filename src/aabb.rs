@@ -1,24 +1,26 @@
 
-use cgmath::{Point3, Vector3};
+use cgmath::{Point3, Vector3, Matrix4, Transform};
 
 use crate::ray::Ray;
-use crate::math_util::Axis;
+use crate::math_util::{Axis, Float};
 
-#[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
+/// Axis-aligned bounding box
+#[derive(Clone, Debug)]
 pub struct AABB {
-    pub min: Point3<f32>,
-    pub max: Point3<f32>,
+    pub min: Point3<Float>,
+    pub max: Point3<Float>,
 }
 
 impl AABB {
     pub fn empty() -> AABB {
         AABB {
-            min: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
-            max: Point3::new(-f32::INFINITY, -f32::INFINITY, -f32::INFINITY),
+            min: Point3::new(Float::INFINITY, Float::INFINITY, Float::INFINITY),
+            max: Point3::new(-Float::INFINITY, -Float::INFINITY, -Float::INFINITY),
         }
     }
 
-    pub fn new(p1: &Point3<f32>, p2: &Point3<f32>) -> AABB {
+    pub fn new(p1: &Point3<Float>, p2: &Point3<Float>) -> AABB {
         AABB {
             min: Point3::new(
                 p1.x.min(p2.x),
@@ -33,7 +35,7 @@ impl AABB {
         }
     }
 
-    pub fn from_triangle(p1: &Vector3<f32>, p2: &Vector3<f32>, p3: &Vector3<f32>) -> AABB {
+    pub fn from_triangle(p1: &Vector3<Float>, p2: &Vector3<Float>, p3: &Vector3<Float>) -> AABB {
         AABB {
             min: Point3::new(
                 p1.x.min(p2.x).min(p3.x),
@@ -48,6 +50,50 @@ impl AABB {
         }
     }
 
+    /// Calculate the bounding box of the part of a triangle that lies inside this bounding box
+    ///
+    /// Returns `None` if the triangle doesn't intersect this bounding box at all
+    pub fn clip_triangle(&self, p1: &Vector3<Float>, p2: &Vector3<Float>, p3: &Vector3<Float>) -> Option<AABB> {
+        // Sutherland-Hodgman polygon clipping against all six planes of the box
+        let mut polygon = vec![*p1, *p2, *p3];
+        let mut clipped = Vec::with_capacity(9);
+
+        for &axis in &[Axis::X, Axis::Y, Axis::Z] {
+            for &(bound, keep_below) in &[(self.min[axis], false), (self.max[axis], true)] {
+                let inside = |p: &Vector3<Float>| if keep_below { p[axis] <= bound } else { p[axis] >= bound };
+
+                clipped.clear();
+                for i in 0..polygon.len() {
+                    let current = polygon[i];
+                    let next = polygon[(i + 1) % polygon.len()];
+
+                    if inside(&current) {
+                        clipped.push(current);
+                    }
+                    if inside(&current) != inside(&next) {
+                        // The edge crosses the plane, add the intersection point
+                        let t = (bound - current[axis]) / (next[axis] - current[axis]);
+                        let mut intersection = current + (next - current) * t;
+                        // Avoid rounding errors moving the point out of the box
+                        intersection[axis] = bound;
+                        clipped.push(intersection);
+                    }
+                }
+
+                std::mem::swap(&mut polygon, &mut clipped);
+                if polygon.is_empty() {
+                    return None;
+                }
+            }
+        }
+
+        let mut bounding_box = AABB::empty();
+        for p in &polygon {
+            bounding_box = bounding_box.union(&AABB::from_triangle(p, p, p));
+        }
+        Some(bounding_box)
+    }
+
     pub fn union(&self, other: &AABB) -> AABB {
         AABB {
             min: Point3::new(
@@ -63,6 +109,26 @@ impl AABB {
         }
     }
 
+    /// Whether the box contains no points at all, like the one returned by `empty()`
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    /// Bounding box of this box after transforming it, which may be larger than necessary for rotations
+    pub fn transform(&self, transformation: &Matrix4<Float>) -> AABB {
+        let mut bounding_box = AABB::empty();
+        for i in 0..8 {
+            let corner = Point3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            let corner = transformation.transform_point(corner);
+            bounding_box = bounding_box.union(&AABB::new(&corner, &corner));
+        }
+        bounding_box
+    }
+
     pub fn maximum_extent(&self) -> Axis {
         let extent = self.max - self.min;
 
@@ -75,8 +141,8 @@ impl AABB {
         }
     }
 
-    pub fn intersects_p(&self, ray: &Ray) -> Option<(f32, f32)> {
-        let dirfrac: Vector3<f32> = 1.0 / ray.direction;
+    pub fn intersects_p(&self, ray: &Ray) -> Option<(Float, Float)> {
+        let dirfrac: Vector3<Float> = 1.0 / ray.direction;
 
         let t1 = (self.min.x - ray.origin.x) * dirfrac.x;
         let t2 = (self.max.x - ray.origin.x) * dirfrac.x;
@@ -85,18 +151,17 @@ impl AABB {
         let t5 = (self.min.z - ray.origin.z) * dirfrac.z;
         let t6 = (self.max.z - ray.origin.z) * dirfrac.z;
 
-        let tmin = f32::max(f32::max(f32::min(t1, t2), f32::min(t3, t4)), f32::min(t5, t6));
-        let tmax = f32::min(f32::min(f32::max(t1, t2), f32::max(t3, t4)), f32::max(t5, t6));
+        let tmin = Float::max(Float::max(Float::min(t1, t2), Float::min(t3, t4)), Float::min(t5, t6));
+        let tmax = Float::min(Float::min(Float::max(t1, t2), Float::max(t3, t4)), Float::max(t5, t6));
 
-        if tmax < 0.0 {
-            None
-        } else if tmin > tmax {
+        if tmax < 0.0 || tmin > tmax {
             None
         } else {
             Some((tmin, tmax))
         }
     }
 
+    #[allow(dead_code)]
     pub fn intersects(&self, ray: &Ray) -> bool {
         self.intersects_p(ray).is_some()
     }
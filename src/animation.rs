@@ -0,0 +1,146 @@
+use std::convert::TryFrom;
+
+use serde::{Serialize, Deserialize};
+
+use crate::math_util::Float;
+use crate::error::RaytracerError;
+
+/// Types that can be blended linearly between two values
+pub trait Interpolate: Sized {
+    /// Blend between `self` (at `t` = 0) and `other` (at `t` = 1)
+    fn interpolate(&self, other: &Self, t: Float) -> Self;
+
+    /// Evaluate a uniform Catmull-Rom spline through `p0`..`p3` between `p1` (at `t` = 0) and `p2` (at `t` = 1)
+    fn catmull_rom(p0: &Self, p1: &Self, p2: &Self, p3: &Self, t: Float) -> Self {
+        // Barry and Goldman's pyramidal formulation only requires linear interpolation (and extrapolation)
+        let a1 = p0.interpolate(p1, t + 1.0);
+        let a2 = p1.interpolate(p2, t);
+        let a3 = p2.interpolate(p3, t - 1.0);
+        let b1 = a1.interpolate(&a2, (t + 1.0) * 0.5);
+        let b2 = a2.interpolate(&a3, t * 0.5);
+        b1.interpolate(&b2, t)
+    }
+}
+
+impl Interpolate for Float {
+    fn interpolate(&self, other: &Float, t: Float) -> Float {
+        self + (other - self) * t
+    }
+}
+
+/// A value at a specific point in time
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Keyframe<T> {
+    /// Time in seconds
+    pub time: Float,
+    pub value: T,
+}
+
+/// How values between two keyframes are calculated
+#[derive(Copy, Clone, Default, Serialize, Deserialize)]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    /// Smooth interpolation that also takes the neighbouring keyframes into account
+    CatmullRom,
+}
+
+/// A track is either deserialized from a plain list of keyframes or from an object that also specifies the interpolation
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum DeserializableTrack<T> {
+    Keyframes(Vec<Keyframe<T>>),
+    WithInterpolation {
+        interpolation: Interpolation,
+        keyframes: Vec<Keyframe<T>>,
+    },
+}
+
+impl<T> From<Track<T>> for DeserializableTrack<T> {
+    fn from(track: Track<T>) -> DeserializableTrack<T> {
+        DeserializableTrack::WithInterpolation {
+            interpolation: track.interpolation,
+            keyframes: track.keyframes,
+        }
+    }
+}
+
+impl<T> TryFrom<DeserializableTrack<T>> for Track<T> {
+    type Error = RaytracerError;
+
+    fn try_from(d: DeserializableTrack<T>) -> Result<Track<T>, RaytracerError> {
+        let (keyframes, interpolation) = match d {
+            DeserializableTrack::Keyframes(keyframes) => (keyframes, Interpolation::Linear),
+            DeserializableTrack::WithInterpolation { interpolation, keyframes } => (keyframes, interpolation),
+        };
+        if let Some(keyframe) = keyframes.iter().find(|keyframe| !keyframe.time.is_finite()) {
+            return Err(RaytracerError::InvalidScene(format!("Keyframe time {} is not a finite number", keyframe.time)));
+        }
+        Ok(Track::with_interpolation(keyframes, interpolation))
+    }
+}
+
+/// A sequence of keyframes that describes how a value changes over time
+///
+/// The keyframes don't need to be sorted when deserializing
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "DeserializableTrack<T>")]
+#[serde(into = "DeserializableTrack<T>")]
+#[serde(bound(serialize = "T: Clone + Serialize", deserialize = "T: Deserialize<'de>"))]
+pub struct Track<T> {
+    /// Keyframes sorted by time
+    keyframes: Vec<Keyframe<T>>,
+    interpolation: Interpolation,
+}
+
+impl<T> Track<T> {
+    /// Keyframes with a NaN time are dropped, as they have no place in the sequence
+    pub fn with_interpolation(mut keyframes: Vec<Keyframe<T>>, interpolation: Interpolation) -> Track<T> {
+        keyframes.retain(|keyframe| !keyframe.time.is_nan());
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Track {
+            keyframes,
+            interpolation,
+        }
+    }
+}
+
+impl<T: Interpolate + Clone> Track<T> {
+    pub fn new(keyframes: Vec<Keyframe<T>>) -> Track<T> {
+        Track::with_interpolation(keyframes, Interpolation::Linear)
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe<T>] {
+        &self.keyframes
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    /// Calculate the value at time `time` by interpolating between the surrounding keyframes
+    ///
+    /// Before the first and after the last keyframe the value is held constant. Returns `None` if there are no keyframes.
+    pub fn sample(&self, time: Float) -> Option<T> {
+        let next_index = self.keyframes.iter().position(|keyframe| keyframe.time > time);
+
+        match next_index {
+            None => self.keyframes.last().map(|keyframe| keyframe.value.clone()),
+            Some(0) => Some(self.keyframes[0].value.clone()),
+            Some(next_index) => {
+                let previous = &self.keyframes[next_index - 1];
+                let next = &self.keyframes[next_index];
+                let t = (time - previous.time) / (next.time - previous.time);
+                match self.interpolation {
+                    Interpolation::Linear => Some(previous.value.interpolate(&next.value, t)),
+                    Interpolation::CatmullRom => {
+                        // The first and last keyframes are duplicated to get the outer control points
+                        let before = &self.keyframes[next_index.saturating_sub(2)];
+                        let after = &self.keyframes[(next_index + 1).min(self.keyframes.len() - 1)];
+                        Some(T::catmull_rom(&before.value, &previous.value, &next.value, &after.value, t))
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,27 +1,326 @@
-
-use std::path::Path;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::error::Error;
+use std::sync::{Arc, Mutex, RwLock};
 
-use once_cell::sync::OnceCell;
+use once_cell::sync::Lazy;
 
+use crate::error::RaytracerError;
 use crate::image::RgbImage;
 use crate::mesh::MeshData;
+use crate::compact_mesh::CompactMeshData;
+use crate::volume::DensityGrid;
+use crate::obj_parser::ObjParser;
+use crate::ply_parser::PlyParser;
 
+/// Source of the images, meshes and density grids that scenes reference
+///
+/// Unlike the rest of the public API, loaders return boxed errors instead of `RaytracerError`: implementations wrap
+/// whatever their storage fails with (file systems, HTTP clients, archives), and the `load_*()` functions of this
+/// module turn those into `RaytracerError::Asset` with the path attached.
 pub trait AssetLoader: Send + Sync {
-    fn load_image(&self, path: &Path) -> Result<RgbImage, Box<dyn Error>>;
+    fn load_image(&self, path: &Path) -> Result<RgbImage, Box<dyn Error + Send + Sync>>;
+
+    fn load_obj(&self, path: &Path) -> Result<MeshData, Box<dyn Error + Send + Sync>>;
 
-    fn load_obj(&self, path: &Path) -> Result<MeshData, Box<dyn Error>>;
+    /// Load a mesh to be kept in compact form, by default by converting the result of `load_obj()`
+    fn load_compact_mesh(&self, path: &Path) -> Result<CompactMeshData, Box<dyn Error + Send + Sync>> {
+        Ok(CompactMeshData::new(&self.load_obj(path)?)?)
+    }
+
+    /// Load the density grid of a `Volume`; loaders that don't support volumes can keep this default, which fails
+    fn load_density_grid(&self, path: &Path) -> Result<DensityGrid, Box<dyn Error + Send + Sync>> {
+        Err(format!("Density grids aren't supported by this loader ({})", path.display()).into())
+    }
 }
 
-static INSTANCE: OnceCell<Box<dyn AssetLoader>> = OnceCell::new();
+/// Loader that reads assets from the file system
+///
+/// Supports OBJ, PLY and preprocessed `.rtmesh` meshes (see `CompactMeshData::save()`), NRRD and raw density grids
+/// (see `DensityGrid`) and binary PPM (P6) images. With the `std-loader` feature, PNG, JPEG and TGA images are
+/// supported as well. Applications that need other formats or don't have a file system (e.g. in the browser) have to
+/// provide their own loader.
+pub struct FileSystemLoader;
+
+impl AssetLoader for FileSystemLoader {
+    #[cfg(feature = "std-loader")]
+    fn load_image(&self, path: &Path) -> Result<RgbImage, Box<dyn Error + Send + Sync>> {
+        // Images with alpha channel or 16 bits per channel are converted
+        let img = image::open(path)?.into_rgb8();
+        let (width, height) = img.dimensions();
+        Ok(RgbImage::from_raw(width as usize, height as usize, img.into_raw()))
+    }
+
+    #[cfg(not(feature = "std-loader"))]
+    fn load_image(&self, path: &Path) -> Result<RgbImage, Box<dyn Error + Send + Sync>> {
+        parse_ppm(&std::fs::read(path)?)
+    }
+
+    fn load_obj(&self, path: &Path) -> Result<MeshData, Box<dyn Error + Send + Sync>> {
+        if has_extension(path, "ply") {
+            return Ok(PlyParser::parse_file(path)?);
+        }
+        if has_extension(path, "rtmesh") {
+            return Ok(CompactMeshData::load(path)?.to_mesh_data());
+        }
+
+        // Streaming keeps large files from having to fit into memory twice
+        Ok(ObjParser::parse_file(path)?)
+    }
+
+    fn load_compact_mesh(&self, path: &Path) -> Result<CompactMeshData, Box<dyn Error + Send + Sync>> {
+        if has_extension(path, "rtmesh") {
+            return Ok(CompactMeshData::map(path)?);
+        }
+        Ok(CompactMeshData::new(&self.load_obj(path)?)?)
+    }
+
+    fn load_density_grid(&self, path: &Path) -> Result<DensityGrid, Box<dyn Error + Send + Sync>> {
+        let bytes = std::fs::read(path)?;
+        if has_extension(path, "nrrd") {
+            return Ok(DensityGrid::parse_nrrd(&bytes)?);
+        }
+        let file_name = path.file_name().map(|file_name| file_name.to_string_lossy()).unwrap_or_default();
+        Ok(DensityGrid::parse_raw(&bytes, &file_name)?)
+    }
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().is_some_and(|path_extension| path_extension.eq_ignore_ascii_case(extension))
+}
+
+/// Decode a binary PPM image with a maximum value of 255
+#[cfg(not(feature = "std-loader"))]
+fn parse_ppm(bytes: &[u8]) -> Result<RgbImage, Box<dyn Error + Send + Sync>> {
+    if !bytes.starts_with(b"P6") {
+        return Err("Unsupported image format, only binary PPM (P6) is supported without the \"std-loader\" feature".into());
+    }
+
+    // The header consists of four whitespace separated fields, comments start with '#' and run until the line end
+    let mut fields = Vec::with_capacity(4);
+    let mut index = 0;
+    while fields.len() < 4 {
+        match bytes.get(index) {
+            Some(b'#') => {
+                while bytes.get(index).is_some_and(|&byte| byte != b'\n') {
+                    index += 1;
+                }
+            }
+            Some(byte) if byte.is_ascii_whitespace() => index += 1,
+            Some(_) => {
+                let start = index;
+                while bytes.get(index).is_some_and(|byte| !byte.is_ascii_whitespace()) {
+                    index += 1;
+                }
+                fields.push(String::from_utf8_lossy(&bytes[start..index]).into_owned());
+            }
+            None => return Err("Incomplete PPM header".into()),
+        }
+    }
+    // Exactly one whitespace character separates the header from the pixel data
+    index += 1;
+
+    let width: usize = fields[1].parse()?;
+    let height: usize = fields[2].parse()?;
+    if fields[3] != "255" {
+        return Err(format!("Unsupported PPM maximum value {}, only 255 is supported", fields[3]).into());
+    }
 
+    let length = width.checked_mul(height).and_then(|pixels| pixels.checked_mul(3))
+        .ok_or_else(|| format!("PPM size {}x{} is too large", width, height))?;
+    let data = bytes.get(index..).unwrap_or(&[]);
+    if data.len() < length {
+        return Err("PPM pixel data is truncated".into());
+    }
+    Ok(RgbImage::from_raw(width, height, data[..length].to_vec()))
+}
+
+/// Loader used outside of `with_loader()` calls
+static INSTANCE: Lazy<RwLock<Arc<dyn AssetLoader>>> = Lazy::new(|| RwLock::new(Arc::new(FileSystemLoader)));
+
+/// Images loaded through `load_image_cached()` from the default loader, by path
+static IMAGE_CACHE: Lazy<Mutex<HashMap<PathBuf, Arc<RgbImage>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A loader installed with `with_loader()`, together with the images it loaded
+struct Scope {
+    loader: Arc<dyn AssetLoader>,
+    image_cache: HashMap<PathBuf, Arc<RgbImage>>,
+}
+
+thread_local! {
+    /// Loaders installed with `with_loader()` on this thread, the innermost one is last
+    static SCOPES: RefCell<Vec<Scope>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Replace the default loader, which is a `FileSystemLoader` initially
+///
+/// This affects all threads and clears the image cache. To use a different loader for a single scene only, use
+/// `with_loader()` instead.
 pub fn set_instance(instance: Box<dyn AssetLoader>) {
-    INSTANCE.set(instance)
-        .ok()
-        .expect("Instance already set");
+    *INSTANCE.write().unwrap() = Arc::from(instance);
+    clear_image_cache();
+}
+
+/// The default loader, which is used outside of `with_loader()` calls
+pub fn get_instance() -> Arc<dyn AssetLoader> {
+    INSTANCE.read().unwrap().clone()
+}
+
+/// Run `f` with `loader` loading all assets on this thread instead of the default loader
+///
+/// This is how a loader is passed to `Deserialize` implementations like those of `Scene`, `Mesh` and `Texture`,
+/// which can't take any arguments. Calls can be nested, the innermost loader is used. Images are only shared between
+/// textures loaded within the same call.
+pub fn with_loader<R>(loader: Arc<dyn AssetLoader>, f: impl FnOnce() -> R) -> R {
+    /// Removes the scope again even if `f` panics
+    struct ScopeGuard;
+
+    impl Drop for ScopeGuard {
+        fn drop(&mut self) {
+            SCOPES.with(|scopes| scopes.borrow_mut().pop());
+        }
+    }
+
+    SCOPES.with(|scopes| scopes.borrow_mut().push(Scope {
+        loader,
+        image_cache: HashMap::new(),
+    }));
+    let _guard = ScopeGuard;
+    f()
 }
 
-pub fn get_instance() -> &'static Box<dyn AssetLoader> {
-    INSTANCE.get()
-        .expect("Instance not set")
+/// The loader installed by the innermost `with_loader()` call on this thread
+fn scoped_loader() -> Option<Arc<dyn AssetLoader>> {
+    SCOPES.with(|scopes| scopes.borrow().last().map(|scope| scope.loader.clone()))
+}
+
+/// Load an image through the current loader
+pub fn load_image(path: &Path) -> Result<RgbImage, RaytracerError> {
+    let loader = scoped_loader().unwrap_or_else(get_instance);
+    loader.load_image(path).map_err(|err| RaytracerError::asset(path, err))
+}
+
+/// Load an OBJ file through the current loader
+pub fn load_obj(path: &Path) -> Result<MeshData, RaytracerError> {
+    let loader = scoped_loader().unwrap_or_else(get_instance);
+    loader.load_obj(path).map_err(|err| RaytracerError::asset(path, err))
+}
+
+/// Load a mesh to be kept in compact form through the current loader
+pub fn load_compact_mesh(path: &Path) -> Result<CompactMeshData, RaytracerError> {
+    let loader = scoped_loader().unwrap_or_else(get_instance);
+    loader.load_compact_mesh(path).map_err(|err| RaytracerError::asset(path, err))
+}
+
+/// Load a density grid through the current loader
+pub fn load_density_grid(path: &Path) -> Result<DensityGrid, RaytracerError> {
+    let loader = scoped_loader().unwrap_or_else(get_instance);
+    loader.load_density_grid(path).map_err(|err| RaytracerError::asset(path, err))
+}
+
+/// Load an image through the current loader, or share the copy that was loaded before from the same path
+///
+/// Textures use this, so materials referencing the same file don't each keep their own copy in memory.
+pub fn load_image_cached(path: &Path) -> Result<Arc<RgbImage>, RaytracerError> {
+    let scoped_img = SCOPES.with(|scopes| {
+        scopes.borrow().last().map(|scope| scope.image_cache.get(path).cloned())
+    });
+    match scoped_img {
+        Some(Some(img)) => return Ok(img),
+        Some(None) => {
+            let img = Arc::new(load_image(path)?);
+            SCOPES.with(|scopes| {
+                if let Some(scope) = scopes.borrow_mut().last_mut() {
+                    scope.image_cache.insert(path.to_path_buf(), img.clone());
+                }
+            });
+            return Ok(img);
+        }
+        None => {}
+    }
+
+    if let Some(img) = IMAGE_CACHE.lock().unwrap().get(path) {
+        return Ok(img.clone());
+    }
+
+    // Don't hold the lock while loading, other threads may want to load different images in the meantime
+    let img = Arc::new(get_instance().load_image(path).map_err(|err| RaytracerError::asset(path, err))?);
+    let mut cache = IMAGE_CACHE.lock().unwrap();
+    Ok(cache.entry(path.to_path_buf()).or_insert(img).clone())
+}
+
+/// Drop all images cached for the default loader
+///
+/// Images that are still used by textures stay alive until those are dropped, but are loaded again the next time a
+/// scene references them. Long-running processes should call this after unloading scenes or when image files change.
+pub fn clear_image_cache() {
+    IMAGE_CACHE.lock().unwrap().clear();
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AssetKind {
+    Image,
+    Obj,
+    DensityGrid,
+}
+
+/// An asset that was requested from a `DeferredLoader`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssetRequest {
+    pub path: PathBuf,
+    pub kind: AssetKind,
+}
+
+/// Loader that records which assets are requested and hands out placeholders instead of loading them
+///
+/// Deserializing a scene with this loader (see `with_loader()`) succeeds without any asset being available, e.g.
+/// while they are still being downloaded. Once the requested assets have arrived, `Scene::resolve_assets()` replaces
+/// the placeholders. Until then, textures are plain white and meshes and volumes are empty.
+#[derive(Default)]
+pub struct DeferredLoader {
+    requests: Mutex<Vec<AssetRequest>>,
+}
+
+impl DeferredLoader {
+    pub fn new() -> DeferredLoader {
+        DeferredLoader::default()
+    }
+
+    /// All distinct assets requested so far, in the order of their first request
+    pub fn requests(&self) -> Vec<AssetRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn record(&self, path: &Path, kind: AssetKind) {
+        let request = AssetRequest { path: path.to_path_buf(), kind };
+        let mut requests = self.requests.lock().unwrap();
+        if !requests.contains(&request) {
+            requests.push(request);
+        }
+    }
+}
+
+impl AssetLoader for DeferredLoader {
+    fn load_image(&self, path: &Path) -> Result<RgbImage, Box<dyn Error + Send + Sync>> {
+        self.record(path, AssetKind::Image);
+        Ok(RgbImage::from_raw(1, 1, vec![255; 3]))
+    }
+
+    fn load_obj(&self, path: &Path) -> Result<MeshData, Box<dyn Error + Send + Sync>> {
+        self.record(path, AssetKind::Obj);
+        Ok(MeshData {
+            vertex_positions: Vec::new(),
+            vertex_normals: Vec::new(),
+            vertex_tex_coords: Vec::new(),
+            vertex_colors: Vec::new(),
+            extra_tex_coords: Vec::new(),
+            triangles: Vec::new(),
+        })
+    }
+
+    fn load_density_grid(&self, path: &Path) -> Result<DensityGrid, Box<dyn Error + Send + Sync>> {
+        self.record(path, AssetKind::DensityGrid);
+        Ok(DensityGrid::new([1, 1, 1], vec![0.0]))
+    }
 }
@@ -0,0 +1,121 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use cgmath::{Point3, Vector3, InnerSpace};
+use serde::{Serialize, Deserialize};
+
+use crate::animation::{Keyframe, Track, Interpolation};
+use crate::scene::CameraPose;
+use crate::math_util::Float;
+
+#[derive(Debug)]
+pub enum CameraPathParseError {
+    InvalidColumnCount(usize, usize),
+    InvalidFloat(usize),
+    CoincidentTarget(usize),
+}
+
+impl Display for CameraPathParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CameraPathParseError::InvalidColumnCount(line_number, count) => write!(f, "Expected 8 columns but found {} in line {}", count, line_number),
+            CameraPathParseError::InvalidFloat(line_number) => write!(f, "Invalid float in line {}", line_number),
+            CameraPathParseError::CoincidentTarget(line_number) => write!(f, "Camera position and target are identical in line {}", line_number),
+        }
+    }
+}
+
+impl Error for CameraPathParseError {}
+
+/// A single keyframe of a camera path
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CameraPathKeyframe {
+    /// Time in seconds
+    pub time: Float,
+    pub position: Point3<Float>,
+    /// The point the camera looks at
+    pub target: Point3<Float>,
+    pub fov: Float,
+}
+
+/// A camera motion path, e.g. exported from another application
+///
+/// Serializes/deserializes to/from a list of keyframes
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CameraPath {
+    pub keyframes: Vec<CameraPathKeyframe>,
+}
+
+impl CameraPath {
+    /// Parse a camera path from comma separated values
+    ///
+    /// Each line contains `time, position x, position y, position z, target x, target y, target z, fov`.
+    /// Empty lines, lines starting with `#` and a header line (first line whose first column isn't a number) are ignored.
+    pub fn parse_csv(csv_str: &str) -> Result<CameraPath, CameraPathParseError> {
+        let mut keyframes = Vec::new();
+        let mut is_first_line = true;
+
+        for (i, line) in csv_str.lines().enumerate() {
+            let line_number = i + 1;
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let columns: Vec<_> = line.split(',').map(str::trim).collect();
+
+            let is_header = is_first_line && columns[0].parse::<Float>().is_err();
+            is_first_line = false;
+            if is_header {
+                continue;
+            }
+
+            if columns.len() != 8 {
+                return Err(CameraPathParseError::InvalidColumnCount(line_number, columns.len()));
+            }
+
+            let values = columns.iter()
+                .map(|column| column.parse::<Float>().ok().filter(|value| value.is_finite()))
+                .collect::<Option<Vec<_>>>()
+                .ok_or(CameraPathParseError::InvalidFloat(line_number))?;
+
+            let keyframe = CameraPathKeyframe {
+                time: values[0],
+                position: Point3::new(values[1], values[2], values[3]),
+                target: Point3::new(values[4], values[5], values[6]),
+                fov: values[7],
+            };
+
+            if keyframe.position == keyframe.target {
+                return Err(CameraPathParseError::CoincidentTarget(line_number));
+            }
+
+            keyframes.push(keyframe);
+        }
+
+        Ok(CameraPath {
+            keyframes,
+        })
+    }
+
+    /// Convert to a smoothly interpolated track that can be assigned to `Camera::animation`
+    pub fn to_track(&self, up: Vector3<Float>) -> Track<CameraPose> {
+        let keyframes = self.keyframes.iter()
+            .map(|keyframe| Keyframe {
+                time: keyframe.time,
+                value: CameraPose {
+                    fov: keyframe.fov,
+                    position: keyframe.position,
+                    direction: (keyframe.target - keyframe.position).normalize(),
+                    up,
+                    roll: 0.0,
+                },
+            })
+            .collect();
+
+        Track::with_interpolation(keyframes, Interpolation::CatmullRom)
+    }
+}
@@ -2,13 +2,43 @@
 use std::ops::{Add, AddAssign, Mul, Div};
 
 use serde::{Serialize, Deserialize};
+use once_cell::sync::Lazy;
+
+use crate::math_util::{float, Float};
+
+/// Linear value of each 8-bit sRGB value, as decoding textures is done for every texel lookup
+static SRGB_TO_LINEAR: Lazy<[Float; 256]> = Lazy::new(|| {
+    let mut table = [0.0; 256];
+    for (value, linear) in table.iter_mut().enumerate() {
+        *linear = srgb_to_linear(value as Float / 255.0);
+    }
+    table
+});
+
+/// Convert an sRGB encoded component in [0, 1] to linear light
+pub fn srgb_to_linear(value: Float) -> Float {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        float::powf((value + 0.055) / 1.055, 2.4)
+    }
+}
+
+/// Convert a linear component in [0, 1] to sRGB encoding
+pub fn linear_to_srgb(value: Float) -> Float {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * float::powf(value, 1.0 / 2.4) - 0.055
+    }
+}
 
 /// Represents RGB colors
-#[derive(Copy, Clone, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Color {
-    pub r: f32,
-    pub g: f32,
-    pub b: f32,
+    pub r: Float,
+    pub g: Float,
+    pub b: Float,
 }
 
 impl Add for Color {
@@ -43,10 +73,10 @@ impl Mul for Color {
     }
 }
 
-impl Mul<f32> for Color {
+impl Mul<Float> for Color {
     type Output = Color;
 
-    fn mul(self, rhs: f32) -> Color {
+    fn mul(self, rhs: Float) -> Color {
         Color {
             r: self.r * rhs,
             g: self.g * rhs,
@@ -55,7 +85,7 @@ impl Mul<f32> for Color {
     }
 }
 
-impl Mul<Color> for f32 {
+impl Mul<Color> for Float {
     type Output = Color;
 
     fn mul(self, rhs: Color) -> Color {
@@ -63,10 +93,10 @@ impl Mul<Color> for f32 {
     }
 }
 
-impl Div<f32> for Color {
+impl Div<Float> for Color {
     type Output = Color;
 
-    fn div(self, rhs: f32) -> Color {
+    fn div(self, rhs: Float) -> Color {
         Color {
             r: self.r / rhs,
             g: self.g / rhs,
@@ -77,15 +107,24 @@ impl Div<f32> for Color {
 
 impl Color {
     /// Construct a new Color struct
-    pub fn new(r: f32, g: f32, b: f32) -> Color {
+    pub fn new(r: Float, g: Float, b: Float) -> Color {
         Color { r, g, b }
     }
 
     pub fn from_u8(rgb: &(u8, u8, u8)) -> Color {
         Color {
-            r: rgb.0 as f32 / 255.0,
-            g: rgb.1 as f32 / 255.0,
-            b: rgb.2 as f32 / 255.0,
+            r: rgb.0 as Float / 255.0,
+            g: rgb.1 as Float / 255.0,
+            b: rgb.2 as Float / 255.0,
+        }
+    }
+
+    /// Decode 8-bit sRGB values, as stored in most image files, to a linear color
+    pub fn from_srgb_u8(rgb: &(u8, u8, u8)) -> Color {
+        Color {
+            r: SRGB_TO_LINEAR[rgb.0 as usize],
+            g: SRGB_TO_LINEAR[rgb.1 as usize],
+            b: SRGB_TO_LINEAR[rgb.2 as usize],
         }
     }
 
@@ -94,20 +133,33 @@ impl Color {
         Color::new(0.0, 0.0, 0.0)
     }
 
+    /// Construct a Color struct with all components set to 1.0
+    pub fn white() -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
+
     pub fn clamp(&self) -> Color {
         Color {
-            r: self.r.min(1.0).max(0.0),
-            g: self.g.min(1.0).max(0.0),
-            b: self.b.min(1.0).max(0.0),
+            r: self.r.clamp(0.0, 1.0),
+            g: self.g.clamp(0.0, 1.0),
+            b: self.b.clamp(0.0, 1.0),
         }
     }
 
-    /// Convert to tuple of 8-bit RGB values
-    pub fn to_u8(&self) -> (u8, u8, u8) {
-        (
-            (self.r * 255.0) as u8,
-            (self.g * 255.0) as u8,
-            (self.b * 255.0) as u8,
-        )
+    /// Relative luminance (Rec. 709 coefficients)
+    pub fn luminance(&self) -> Float {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    /// Convert to tuple of linear 8-bit RGB values, clamping all color components
+    pub fn to_u8(self) -> (u8, u8, u8) {
+        let encode = |value: Float| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        (encode(self.r), encode(self.g), encode(self.b))
+    }
+
+    /// Convert to tuple of 8-bit sRGB values, clamping all color components
+    pub fn to_srgb_u8(self) -> (u8, u8, u8) {
+        let encode = |value: Float| (linear_to_srgb(value.clamp(0.0, 1.0)) * 255.0).round() as u8;
+        (encode(self.r), encode(self.g), encode(self.b))
     }
 }
@@ -0,0 +1,579 @@
+use std::array;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+#[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+use std::sync::Arc;
+
+use cgmath::{Vector2, Vector3};
+
+use crate::color::Color;
+use crate::mesh::{MeshData, IndexedTriangle, CornerIndices, TriangleCorners, TriangleHit, intersect_triangle};
+use crate::ray::{Hit, Ray, MAX_UV_CHANNELS};
+use crate::math_util::Float;
+
+/// Start of every preprocessed mesh file, the last byte is the version of the format
+const MAGIC: &[u8; 8] = b"RTMESH\0\x01";
+
+/// Index of the corners of triangles that don't have an attribute
+const NO_INDEX: u32 = u32::MAX;
+
+#[derive(Debug)]
+pub enum CompactMeshError {
+    /// The data doesn't start with the header of a preprocessed mesh of a supported version
+    InvalidHeader,
+    /// The data is shorter or longer than the header says
+    InvalidSize,
+    /// An index of the named attribute is out of bounds
+    IndexOutOfBounds(String),
+    /// The mesh has more vertices than 32 bit indices can address
+    TooLarge,
+    /// Reading or writing the file failed
+    Io(io::Error),
+}
+
+impl Display for CompactMeshError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CompactMeshError::InvalidHeader => write!(f, "Not a preprocessed mesh or unsupported version"),
+            CompactMeshError::InvalidSize => write!(f, "Size doesn't match the header"),
+            CompactMeshError::IndexOutOfBounds(name) => write!(f, "Vertex {} index out of bounds", name),
+            CompactMeshError::TooLarge => write!(f, "Too many vertices for 32 bit indices"),
+            CompactMeshError::Io(err) => write!(f, "Unable to access file: {}", err),
+        }
+    }
+}
+
+impl Error for CompactMeshError {}
+
+/// A `CompactMeshError` together with the file it occurred in
+#[derive(Debug)]
+pub struct CompactMeshFileError {
+    pub path: PathBuf,
+    pub error: CompactMeshError,
+}
+
+impl Display for CompactMeshFileError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.error)
+    }
+}
+
+impl Error for CompactMeshFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Values of 4 bytes that are stored in little endian byte order
+trait Element: Copy {
+    fn from_le_bytes(bytes: [u8; 4]) -> Self;
+    fn to_le_bytes(self) -> [u8; 4];
+}
+
+impl Element for f32 {
+    fn from_le_bytes(bytes: [u8; 4]) -> f32 {
+        f32::from_le_bytes(bytes)
+    }
+
+    fn to_le_bytes(self) -> [u8; 4] {
+        f32::to_le_bytes(self)
+    }
+}
+
+impl Element for u32 {
+    fn from_le_bytes(bytes: [u8; 4]) -> u32 {
+        u32::from_le_bytes(bytes)
+    }
+
+    fn to_le_bytes(self) -> [u8; 4] {
+        u32::to_le_bytes(self)
+    }
+}
+
+/// A read-only file mapped into memory
+#[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+struct MappedFile {
+    ptr: *const u8,
+    len: usize,
+}
+
+// The mapping is read-only and only accessed through shared references
+#[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+unsafe impl Send for MappedFile {}
+#[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+unsafe impl Sync for MappedFile {}
+
+#[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+impl MappedFile {
+    fn open(path: &Path) -> io::Result<MappedFile> {
+        use std::os::unix::io::AsRawFd;
+
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        // Empty files can't be mapped, and they aren't valid meshes anyway
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File is empty"));
+        }
+
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(MappedFile { ptr: ptr as *const u8, len })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+#[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Array of 4 byte values, either in memory or in a mapped file
+#[derive(Clone)]
+enum Buffer<T> {
+    Owned(Vec<T>),
+    /// `len` values starting `offset` bytes into the file, which is a multiple of 4
+    #[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+    Mapped { file: Arc<MappedFile>, offset: usize, len: usize },
+}
+
+impl<T: Element> Deref for Buffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Buffer::Owned(values) => values,
+            // Mappings start at a page boundary, so the values are aligned; the byte order was checked at compile time
+            #[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+            Buffer::Mapped { file, offset, len } => unsafe {
+                std::slice::from_raw_parts(file.ptr.add(*offset) as *const T, *len)
+            },
+        }
+    }
+}
+
+impl<T: Element> Buffer<T> {
+    /// Bytes of memory owned by the buffer
+    fn memory_usage(&self) -> usize {
+        match self {
+            Buffer::Owned(values) => values.capacity() * mem::size_of::<T>(),
+            #[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+            Buffer::Mapped { .. } => 0,
+        }
+    }
+}
+
+/// Where the arrays of a preprocessed mesh are taken from
+enum Source<'a> {
+    /// Copy the values
+    Bytes(&'a [u8]),
+    /// Refer to the values in the mapped file
+    #[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+    Mapped(&'a Arc<MappedFile>),
+}
+
+impl Source<'_> {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Source::Bytes(bytes) => bytes,
+            #[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+            Source::Mapped(file) => file.bytes(),
+        }
+    }
+
+    /// Take the next `len` values after `offset` and advance it
+    fn buffer<T: Element>(&self, offset: &mut usize, len: usize) -> Result<Buffer<T>, CompactMeshError> {
+        let start = *offset;
+        let end = len.checked_mul(4).and_then(|size| start.checked_add(size))
+            .filter(|&end| end <= self.bytes().len())
+            .ok_or(CompactMeshError::InvalidSize)?;
+        *offset = end;
+
+        Ok(match self {
+            Source::Bytes(bytes) => Buffer::Owned(bytes[start..end].chunks_exact(4)
+                .map(|chunk| T::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect()),
+            #[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+            Source::Mapped(file) => Buffer::Mapped { file: Arc::clone(file), offset: start, len },
+        })
+    }
+}
+
+/// Values of one vertex attribute with `N` components, stored as structure of arrays, and three indices per triangle
+#[derive(Clone)]
+struct Attribute<const N: usize> {
+    values: [Buffer<f32>; N],
+    /// Empty if no triangle has the attribute, `NO_INDEX` for the corners of triangles that don't have it
+    indices: Buffer<u32>,
+}
+
+impl<const N: usize> Attribute<N> {
+    /// Convert values and per-triangle indices of `MeshData`
+    #[allow(clippy::unnecessary_cast)]
+    fn new(values: impl ExactSizeIterator<Item=[Float; N]>, triangles: &[IndexedTriangle], indices: impl Fn(&IndexedTriangle) -> Option<CornerIndices>) -> Result<Attribute<N>, CompactMeshError> {
+        if values.len() >= NO_INDEX as usize {
+            return Err(CompactMeshError::TooLarge);
+        }
+
+        let mut components: [Vec<f32>; N] = array::from_fn(|_| Vec::with_capacity(values.len()));
+        for value in values {
+            for (component, &x) in components.iter_mut().zip(&value) {
+                component.push(x as f32);
+            }
+        }
+
+        let triangle_indices = if triangles.iter().any(|triangle| indices(triangle).is_some()) {
+            triangles.iter()
+                .flat_map(|triangle| match indices(triangle) {
+                    Some(indices) => indices.to_array().map(|index| index as u32),
+                    None => [NO_INDEX; 3],
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Ok(Attribute {
+            values: components.map(Buffer::Owned),
+            indices: Buffer::Owned(triangle_indices),
+        })
+    }
+
+    fn value_count(&self) -> usize {
+        self.values[0].len()
+    }
+
+    fn has_indices(&self) -> bool {
+        !self.indices.is_empty()
+    }
+
+    fn value(&self, index: usize) -> [Float; N] {
+        array::from_fn(|component| self.values[component][index] as Float)
+    }
+
+    fn values(&self) -> impl Iterator<Item=[Float; N]> + '_ {
+        (0..self.value_count()).map(move |index| self.value(index))
+    }
+
+    fn triangle_indices(&self, triangle_index: usize) -> Option<(usize, usize, usize)> {
+        let indices = self.indices.get(triangle_index * 3..triangle_index * 3 + 3)?;
+        if indices[0] == NO_INDEX {
+            None
+        } else {
+            Some((indices[0] as usize, indices[1] as usize, indices[2] as usize))
+        }
+    }
+
+    fn corners(&self, triangle_index: usize) -> Option<[[Float; N]; 3]> {
+        self.triangle_indices(triangle_index)
+            .map(|(i0, i1, i2)| [self.value(i0), self.value(i1), self.value(i2)])
+    }
+
+    /// Check that every triangle either has valid indices for all corners or `NO_INDEX` for all of them
+    fn validate(&self, name: &str, triangle_count: usize, optional: bool) -> Result<(), CompactMeshError> {
+        let error = || CompactMeshError::IndexOutOfBounds(name.to_string());
+        if self.indices.is_empty() {
+            return if optional || triangle_count == 0 { Ok(()) } else { Err(error()) };
+        }
+        if self.indices.len() != triangle_count * 3 {
+            return Err(CompactMeshError::InvalidSize);
+        }
+
+        let value_count = self.value_count();
+        for corners in self.indices.chunks_exact(3) {
+            let missing = optional && corners.iter().all(|&index| index == NO_INDEX);
+            if !missing && corners.iter().any(|&index| index as usize >= value_count) {
+                return Err(error());
+            }
+        }
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.values.iter().map(Buffer::memory_usage).sum::<usize>() + self.indices.memory_usage()
+    }
+
+    fn read_header(bytes: &[u8], offset: &mut usize) -> Result<(usize, bool), CompactMeshError> {
+        let value_count = read_u32(bytes, offset)? as usize;
+        let has_indices = match read_u32(bytes, offset)? {
+            0 => false,
+            1 => true,
+            _ => return Err(CompactMeshError::InvalidHeader),
+        };
+        Ok((value_count, has_indices))
+    }
+
+    fn read(source: &Source, offset: &mut usize, (value_count, has_indices): (usize, bool), triangle_count: usize) -> Result<Attribute<N>, CompactMeshError> {
+        let mut values = Vec::with_capacity(N);
+        for _ in 0..N {
+            values.push(source.buffer(offset, value_count)?);
+        }
+        let indices = source.buffer(offset, if has_indices { triangle_count * 3 } else { 0 })?;
+
+        Ok(Attribute {
+            values: values.try_into().unwrap_or_else(|_| unreachable!()),
+            indices,
+        })
+    }
+
+    fn write_header(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&(self.value_count() as u32).to_le_bytes())?;
+        writer.write_all(&(self.has_indices() as u32).to_le_bytes())
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        for component in &self.values {
+            write_values(writer, component)?;
+        }
+        write_values(writer, &self.indices)
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: &mut usize) -> Result<u32, CompactMeshError> {
+    let value = bytes.get(*offset..*offset + 4).ok_or(CompactMeshError::InvalidHeader)?;
+    *offset += 4;
+    Ok(u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+}
+
+fn write_values<T: Element>(writer: &mut impl Write, values: &[T]) -> io::Result<()> {
+    for &value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Read-only mesh data in compact arrays, for meshes too large to keep as `MeshData`
+///
+/// Vertex attributes are stored as single precision structure of arrays and triangles as 32 bit indices, which takes
+/// a fraction of the memory of `MeshData` with its tuples and optional index triples. Meshes in this form can be
+/// saved to a preprocessed binary file, which loads without parsing and can be memory-mapped with `map()`.
+#[derive(Clone)]
+pub struct CompactMeshData {
+    positions: Attribute<3>,
+    normals: Attribute<3>,
+    tex_coords: Attribute<2>,
+    colors: Attribute<3>,
+    /// UV channels after the first, see `MeshData::extra_tex_coords`
+    extra_tex_coords: Vec<Attribute<2>>,
+}
+
+impl CompactMeshData {
+    pub fn new(data: &MeshData) -> Result<CompactMeshData, CompactMeshError> {
+        let to_array3 = |&(x, y, z): &(Float, Float, Float)| [x, y, z];
+        let to_array2 = |&(u, v): &(Float, Float)| [u, v];
+        let triangles = &data.triangles;
+
+        Ok(CompactMeshData {
+            positions: Attribute::new(data.vertex_positions.iter().map(to_array3), triangles, |triangle| Some(triangle.position_indices))?,
+            normals: Attribute::new(data.vertex_normals.iter().map(to_array3), triangles, |triangle| triangle.normal_indices)?,
+            tex_coords: Attribute::new(data.vertex_tex_coords.iter().map(to_array2), triangles, |triangle| triangle.tex_coords_indices)?,
+            colors: Attribute::new(data.vertex_colors.iter().map(to_array3), triangles, |triangle| triangle.color_indices)?,
+            extra_tex_coords: data.extra_tex_coords.iter().enumerate()
+                .map(|(channel, tex_coords)| Attribute::new(tex_coords.iter().map(to_array2), triangles, |triangle| triangle.extra_tex_coords_indices[channel]))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Convert back into editable mesh data, e.g. to subdivide a preprocessed mesh
+    pub fn to_mesh_data(&self) -> MeshData {
+        let from_array3 = |[x, y, z]: [Float; 3]| (x, y, z);
+        let from_array2 = |[u, v]: [Float; 2]| (u, v);
+
+        let triangles = (0..self.triangle_count())
+            .map(|triangle_index| IndexedTriangle {
+                position_indices: self.positions.triangle_indices(triangle_index).unwrap().into(),
+                normal_indices: self.normals.triangle_indices(triangle_index).map(CornerIndices::from),
+                tex_coords_indices: self.tex_coords.triangle_indices(triangle_index).map(CornerIndices::from),
+                color_indices: self.colors.triangle_indices(triangle_index).map(CornerIndices::from),
+                extra_tex_coords_indices: array::from_fn(|channel| self.extra_tex_coords.get(channel)
+                    .and_then(|tex_coords| tex_coords.triangle_indices(triangle_index))
+                    .map(CornerIndices::from)),
+            })
+            .collect();
+
+        MeshData {
+            vertex_positions: self.positions.values().map(from_array3).collect(),
+            vertex_normals: self.normals.values().map(from_array3).collect(),
+            vertex_tex_coords: self.tex_coords.values().map(from_array2).collect(),
+            vertex_colors: self.colors.values().map(from_array3).collect(),
+            extra_tex_coords: self.extra_tex_coords.iter().map(|tex_coords| tex_coords.values().map(from_array2).collect()).collect(),
+            triangles,
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.positions.indices.len() / 3
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.positions.value_count()
+    }
+
+    /// Positions of the corners of a triangle
+    pub fn triangle_positions(&self, triangle_index: usize) -> [Vector3<Float>; 3] {
+        let [p0, p1, p2] = self.positions.corners(triangle_index).unwrap();
+        [p0.into(), p1.into(), p2.into()]
+    }
+
+    /// Whether all vertex positions are finite numbers
+    pub fn has_finite_positions(&self) -> bool {
+        self.positions.values.iter().all(|component| component.iter().all(|x| x.is_finite()))
+    }
+
+    /// Test a ray against a single triangle
+    pub fn intersect_triangle(&self, ray: &Ray, triangle_index: usize) -> Option<TriangleHit> {
+        let [v0, v1, v2] = self.triangle_positions(triangle_index);
+        intersect_triangle(ray, &v0, &v1, &v2)
+    }
+
+    /// Calculate coordinates, normal, texture coordinates and vertex color of a hit point on a triangle
+    pub fn create_hit(&self, ray: &Ray, triangle_index: usize, triangle_hit: &TriangleHit) -> Hit {
+        let to_vector3 = |corners: [[Float; 3]; 3]| corners.map(Vector3::from);
+        let to_vector2 = |corners: [[Float; 2]; 3]| corners.map(Vector2::from);
+
+        let mut tex_coords = [None; MAX_UV_CHANNELS];
+        tex_coords[0] = self.tex_coords.corners(triangle_index).map(to_vector2);
+        for (channel_tex_coords, attribute) in tex_coords[1..].iter_mut().zip(&self.extra_tex_coords) {
+            *channel_tex_coords = attribute.corners(triangle_index).map(to_vector2);
+        }
+
+        TriangleCorners {
+            positions: self.triangle_positions(triangle_index),
+            normals: self.normals.corners(triangle_index).map(to_vector3),
+            tex_coords,
+            colors: self.colors.corners(triangle_index).map(|corners| corners.map(|[r, g, b]| Color::new(r, g, b))),
+        }.create_hit(ray, triangle_hit)
+    }
+
+    /// Bytes of memory used by the arrays, not counting memory-mapped files
+    pub fn memory_usage(&self) -> usize {
+        self.positions.memory_usage() + self.normals.memory_usage() + self.tex_coords.memory_usage() + self.colors.memory_usage()
+            + self.extra_tex_coords.iter().map(Attribute::memory_usage).sum::<usize>()
+    }
+
+    /// Write the mesh in the preprocessed binary format
+    ///
+    /// The format is a header with the number of values of each attribute followed by the arrays of their components
+    /// and indices, all as 32 bit little endian values, so that it can be mapped into memory as is.
+    pub fn write(&self, writer: impl Write) -> io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(self.triangle_count() as u32).to_le_bytes())?;
+        writer.write_all(&(self.extra_tex_coords.len() as u32).to_le_bytes())?;
+
+        self.positions.write_header(&mut writer)?;
+        self.normals.write_header(&mut writer)?;
+        self.tex_coords.write_header(&mut writer)?;
+        self.colors.write_header(&mut writer)?;
+        for tex_coords in &self.extra_tex_coords {
+            tex_coords.write_header(&mut writer)?;
+        }
+
+        self.positions.write(&mut writer)?;
+        self.normals.write(&mut writer)?;
+        self.tex_coords.write(&mut writer)?;
+        self.colors.write(&mut writer)?;
+        for tex_coords in &self.extra_tex_coords {
+            tex_coords.write(&mut writer)?;
+        }
+        writer.flush()
+    }
+
+    /// Write the mesh to a preprocessed mesh file, conventionally with the extension `.rtmesh`
+    pub fn save(&self, path: &Path) -> Result<(), CompactMeshFileError> {
+        let to_file_error = |err| CompactMeshFileError { path: path.to_path_buf(), error: CompactMeshError::Io(err) };
+        let file = File::create(path).map_err(to_file_error)?;
+        self.write(file).map_err(to_file_error)
+    }
+
+    /// Read a mesh in the preprocessed binary format, see `write()`
+    pub fn parse(bytes: &[u8]) -> Result<CompactMeshData, CompactMeshError> {
+        CompactMeshData::read(&Source::Bytes(bytes))
+    }
+
+    /// Read a preprocessed mesh file into memory
+    pub fn load(path: &Path) -> Result<CompactMeshData, CompactMeshFileError> {
+        let to_file_error = |error| CompactMeshFileError { path: path.to_path_buf(), error };
+        let bytes = std::fs::read(path).map_err(|err| to_file_error(CompactMeshError::Io(err)))?;
+        CompactMeshData::parse(&bytes).map_err(to_file_error)
+    }
+
+    /// Map a preprocessed mesh file into memory instead of reading it
+    ///
+    /// The operating system only reads the parts of the file that are accessed and can drop them again under memory
+    /// pressure. Positions are read while building the acceleration structure, the other attributes only where rays
+    /// hit the mesh. The file must not be changed while it is mapped. Without the `mmap` feature, on platforms other
+    /// than Unix or on big endian machines, this is the same as `load()`.
+    pub fn map(path: &Path) -> Result<CompactMeshData, CompactMeshFileError> {
+        #[cfg(all(feature = "mmap", unix, target_endian = "little"))]
+        {
+            let to_file_error = |error| CompactMeshFileError { path: path.to_path_buf(), error };
+            let file = Arc::new(MappedFile::open(path).map_err(|err| to_file_error(CompactMeshError::Io(err)))?);
+            CompactMeshData::read(&Source::Mapped(&file)).map_err(to_file_error)
+        }
+
+        #[cfg(not(all(feature = "mmap", unix, target_endian = "little")))]
+        CompactMeshData::load(path)
+    }
+
+    fn read(source: &Source) -> Result<CompactMeshData, CompactMeshError> {
+        let bytes = source.bytes();
+        if !bytes.starts_with(MAGIC) {
+            return Err(CompactMeshError::InvalidHeader);
+        }
+
+        let mut offset = MAGIC.len();
+        let triangle_count = read_u32(bytes, &mut offset)? as usize;
+        let extra_channel_count = read_u32(bytes, &mut offset)? as usize;
+        if extra_channel_count >= MAX_UV_CHANNELS {
+            return Err(CompactMeshError::InvalidHeader);
+        }
+
+        let position_header = Attribute::<3>::read_header(bytes, &mut offset)?;
+        let normal_header = Attribute::<3>::read_header(bytes, &mut offset)?;
+        let tex_coords_header = Attribute::<2>::read_header(bytes, &mut offset)?;
+        let color_header = Attribute::<3>::read_header(bytes, &mut offset)?;
+        let extra_tex_coords_headers = (0..extra_channel_count)
+            .map(|_| Attribute::<2>::read_header(bytes, &mut offset))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let data = CompactMeshData {
+            positions: Attribute::read(source, &mut offset, position_header, triangle_count)?,
+            normals: Attribute::read(source, &mut offset, normal_header, triangle_count)?,
+            tex_coords: Attribute::read(source, &mut offset, tex_coords_header, triangle_count)?,
+            colors: Attribute::read(source, &mut offset, color_header, triangle_count)?,
+            extra_tex_coords: extra_tex_coords_headers.into_iter()
+                .map(|header| Attribute::read(source, &mut offset, header, triangle_count))
+                .collect::<Result<_, _>>()?,
+        };
+        if offset != bytes.len() {
+            return Err(CompactMeshError::InvalidSize);
+        }
+
+        data.positions.validate("position", triangle_count, false)?;
+        data.normals.validate("normal", triangle_count, true)?;
+        data.tex_coords.validate("texture coordinates", triangle_count, true)?;
+        data.colors.validate("color", triangle_count, true)?;
+        for tex_coords in &data.extra_tex_coords {
+            tex_coords.validate("texture coordinates", triangle_count, true)?;
+        }
+
+        Ok(data)
+    }
+}
@@ -0,0 +1,158 @@
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::sync::{Arc, RwLock};
+
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+
+use crate::aabb::AABB;
+use crate::math_util::Float;
+use crate::ray::{Ray, Hit, Interval};
+use crate::error::RaytracerError;
+
+/// A primitive implemented outside of this crate
+///
+/// Register a constructor with `register_shape()` to use it in scene files, or wrap an instance in a `RegisteredShape`
+/// to add it to a scene in code. Like the built-in shapes, custom shapes are intersected in object space; the
+/// object's transformation is applied around them.
+pub trait CustomShape: Send + Sync {
+    /// Closest hit in front of the ray origin, with `distance` measured along the ray
+    fn intersect(&self, ray: &Ray) -> Option<Hit>;
+
+    /// All sections of the ray inside the shape, in the order along the ray
+    ///
+    /// Only needed for capping the shape where a clipping plane cuts it; the default treats the shape as having no
+    /// inside.
+    fn intersect_interval(&self, _ray: &Ray) -> Vec<Interval> {
+        Vec::new()
+    }
+
+    /// Object space bounding box, `None` if the shape is unbounded
+    fn bounding_box(&self) -> Option<AABB>;
+}
+
+/// Value of a parameter of a custom shape in the scene file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ShapeParameter {
+    Bool(bool),
+    Number(Float),
+    String(String),
+    List(Vec<ShapeParameter>),
+    Map(BTreeMap<String, ShapeParameter>),
+}
+
+impl ShapeParameter {
+    pub fn as_number(&self) -> Option<Float> {
+        match self {
+            ShapeParameter::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            ShapeParameter::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ShapeParameter::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[ShapeParameter]> {
+        match self {
+            ShapeParameter::List(list) => Some(list),
+            _ => None,
+        }
+    }
+}
+
+/// Named parameters of a custom shape, passed to its constructor
+pub type ShapeParameters = BTreeMap<String, ShapeParameter>;
+
+/// Creates a custom shape from its parameters, or explains what is wrong with them
+type ShapeConstructor = dyn Fn(&ShapeParameters) -> Result<Box<dyn CustomShape>, String> + Send + Sync;
+
+/// Constructors of custom shapes by type name
+static REGISTRY: Lazy<RwLock<HashMap<String, Arc<ShapeConstructor>>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Make a custom shape available to scene files under `type_name`, replacing any shape registered under that name
+///
+/// Scene files refer to it as `{"Custom": {"type": "<type_name>", "parameters": {...}}}`, and `constructor` is called
+/// with the parameters when the scene is loaded. Register all shapes before loading scenes that use them.
+pub fn register_shape(type_name: &str, constructor: impl Fn(&ShapeParameters) -> Result<Box<dyn CustomShape>, String> + Send + Sync + 'static) {
+    REGISTRY.write().unwrap().insert(type_name.to_string(), Arc::new(constructor));
+}
+
+/// A custom shape together with the type name and parameters it was created from, which are written back when the
+/// scene is serialized
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "DeserializableRegisteredShape")]
+#[serde(into = "DeserializableRegisteredShape")]
+pub struct RegisteredShape {
+    pub type_name: String,
+    pub parameters: ShapeParameters,
+    shape: Arc<dyn CustomShape>,
+}
+
+impl RegisteredShape {
+    /// Create a shape with the constructor registered under `type_name`
+    pub fn new(type_name: &str, parameters: ShapeParameters) -> Result<RegisteredShape, RaytracerError> {
+        let constructor = REGISTRY.read().unwrap().get(type_name).cloned()
+            .ok_or_else(|| RaytracerError::InvalidScene(format!("No custom shape is registered as \"{}\"", type_name)))?;
+        let shape = constructor(&parameters)
+            .map_err(|err| RaytracerError::InvalidScene(format!("Invalid parameters for custom shape \"{}\": {}", type_name, err)))?;
+        Ok(RegisteredShape {
+            type_name: type_name.to_string(),
+            parameters,
+            shape: Arc::from(shape),
+        })
+    }
+
+    /// Wrap a shape created in code
+    ///
+    /// A scene containing it can only be loaded again if a constructor is registered under `type_name` that creates
+    /// the same shape from `parameters`.
+    pub fn from_shape(type_name: &str, parameters: ShapeParameters, shape: Box<dyn CustomShape>) -> RegisteredShape {
+        RegisteredShape {
+            type_name: type_name.to_string(),
+            parameters,
+            shape: Arc::from(shape),
+        }
+    }
+
+    pub fn shape(&self) -> &dyn CustomShape {
+        &*self.shape
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeserializableRegisteredShape {
+    #[serde(rename = "type")]
+    type_name: String,
+    #[serde(default)]
+    parameters: ShapeParameters,
+}
+
+impl TryFrom<DeserializableRegisteredShape> for RegisteredShape {
+    type Error = RaytracerError;
+
+    fn try_from(d: DeserializableRegisteredShape) -> Result<RegisteredShape, RaytracerError> {
+        RegisteredShape::new(&d.type_name, d.parameters)
+    }
+}
+
+impl From<RegisteredShape> for DeserializableRegisteredShape {
+    fn from(s: RegisteredShape) -> DeserializableRegisteredShape {
+        DeserializableRegisteredShape {
+            type_name: s.type_name,
+            parameters: s.parameters,
+        }
+    }
+}
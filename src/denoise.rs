@@ -0,0 +1,178 @@
+
+use cgmath::{Vector3, InnerSpace, Zero};
+
+use crate::color::Color;
+use crate::hdr_image::HdrImage;
+use crate::math_util::{float, Float};
+
+/// Per-pixel information about the surfaces seen by the camera, used as guides by `Denoiser`
+///
+/// Rendered alongside the image by `Renderer::render_rect_with_aovs()`. Each value is averaged over the samples of a
+/// pixel that hit an object; pixels in which nothing was hit have a zero normal, infinite depth and black albedo.
+#[derive(Clone)]
+pub struct Aovs {
+    width: usize,
+    height: usize,
+    normals: Vec<Vector3<Float>>,
+    depths: Vec<Float>,
+    albedos: Vec<Color>,
+}
+
+impl Aovs {
+    pub fn new(w: usize, h: usize) -> Aovs {
+        Aovs {
+            width: w,
+            height: h,
+            normals: vec![Vector3::zero(); w * h],
+            depths: vec![Float::INFINITY; w * h],
+            albedos: vec![Color::black(); w * h],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn pixel_index(&self, x: usize, y: usize) -> usize {
+        y * self.width + x
+    }
+
+    /// Set the world space normal, the distance from the camera and the material color of a pixel
+    pub fn put_pixel(&mut self, x: usize, y: usize, normal: Vector3<Float>, depth: Float, albedo: Color) {
+        let index = self.pixel_index(x, y);
+        self.normals[index] = normal;
+        self.depths[index] = depth;
+        self.albedos[index] = albedo;
+    }
+
+    pub fn normal(&self, x: usize, y: usize) -> Vector3<Float> {
+        self.normals[self.pixel_index(x, y)]
+    }
+
+    pub fn depth(&self, x: usize, y: usize) -> Float {
+        self.depths[self.pixel_index(x, y)]
+    }
+
+    pub fn albedo(&self, x: usize, y: usize) -> Color {
+        self.albedos[self.pixel_index(x, y)]
+    }
+}
+
+/// Edge-avoiding À-Trous wavelet filter (Dammertz et al., 2010)
+///
+/// Smooths noise from soft shadows, glossy reflections, depth of field etc. by repeatedly applying a 5x5 B-spline
+/// kernel with growing gaps between the taps. Taps are weighted down where the color or any of the `Aovs` differs
+/// from the center pixel, so edges of objects, shading discontinuities and textures stay sharp. Each `sigma_*` is the
+/// difference at which the weight has dropped to 1/e; larger values smooth more.
+#[derive(Clone, Debug)]
+pub struct Denoiser {
+    /// Number of filter passes; the kernel covers 4 * 2^iterations pixels in each direction
+    pub iterations: usize,
+    /// Allowed difference in color, halved after each pass so that the filter gets more selective
+    pub sigma_color: Float,
+    /// Allowed difference between normals, as 1 minus the cosine of the angle between them
+    pub sigma_normal: Float,
+    /// Allowed difference in depth, relative to the depth of the center pixel
+    pub sigma_depth: Float,
+    /// Allowed difference in albedo
+    pub sigma_albedo: Float,
+}
+
+impl Default for Denoiser {
+    fn default() -> Denoiser {
+        Denoiser {
+            iterations: 5,
+            sigma_color: 0.5,
+            sigma_normal: 0.1,
+            sigma_depth: 0.05,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+
+impl Denoiser {
+    /// Filter `img` using the guides in `aovs`, which must have the same size
+    pub fn denoise(&self, img: &HdrImage, aovs: &Aovs) -> HdrImage {
+        assert!(img.width() == aovs.width() && img.height() == aovs.height(), "Image and AOVs differ in size");
+
+        const KERNEL: [Float; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+        let w = img.width() as isize;
+        let h = img.height() as isize;
+        let mut current = img.clone();
+        let mut sigma_color = self.sigma_color;
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let mut filtered = HdrImage::new(img.width(), img.height());
+
+            for y in 0..img.height() {
+                for x in 0..img.width() {
+                    let color = current.get_pixel(x, y);
+                    let normal = aovs.normal(x, y);
+                    let depth = aovs.depth(x, y);
+                    let albedo = aovs.albedo(x, y);
+
+                    let mut sum = Color::black();
+                    let mut weight_sum = 0.0;
+                    for (j, &kernel_y) in KERNEL.iter().enumerate() {
+                        let tap_y = y as isize + (j as isize - 2) * step;
+                        if tap_y < 0 || tap_y >= h {
+                            continue;
+                        }
+                        for (i, &kernel_x) in KERNEL.iter().enumerate() {
+                            let tap_x = x as isize + (i as isize - 2) * step;
+                            if tap_x < 0 || tap_x >= w {
+                                continue;
+                            }
+                            let (tap_x, tap_y) = (tap_x as usize, tap_y as usize);
+
+                            let tap_color = current.get_pixel(tap_x, tap_y);
+                            let exponent = color_distance_squared(color, tap_color) / float::powi(sigma_color, 2)
+                                + normal_distance(normal, aovs.normal(tap_x, tap_y)) / self.sigma_normal
+                                + depth_distance_squared(depth, aovs.depth(tap_x, tap_y)) / float::powi(self.sigma_depth, 2)
+                                + color_distance_squared(albedo, aovs.albedo(tap_x, tap_y)) / float::powi(self.sigma_albedo, 2);
+                            let weight = kernel_x * kernel_y * float::exp(-exponent);
+
+                            sum += tap_color * weight;
+                            weight_sum += weight;
+                        }
+                    }
+
+                    // The center tap always has a weight of 9/64, only zero sigmas can leave no valid weights
+                    let denoised = if weight_sum > 0.0 { sum / weight_sum } else { color };
+                    filtered.put_pixel(x, y, denoised);
+                }
+            }
+
+            current = filtered;
+            sigma_color *= 0.5;
+        }
+        current
+    }
+}
+
+fn color_distance_squared(a: Color, b: Color) -> Float {
+    float::powi(a.r - b.r, 2) + float::powi(a.g - b.g, 2) + float::powi(a.b - b.b, 2)
+}
+
+/// 1 minus the cosine of the angle between two normals; pixels without a normal are separated by their depth instead
+fn normal_distance(a: Vector3<Float>, b: Vector3<Float>) -> Float {
+    if a.is_zero() || b.is_zero() {
+        0.0
+    } else {
+        (1.0 - a.dot(b)).max(0.0)
+    }
+}
+
+/// Squared difference of two depths relative to the first one; infinite if only one of them is (nothing was hit)
+fn depth_distance_squared(a: Float, b: Float) -> Float {
+    match (a.is_finite(), b.is_finite()) {
+        (true, true) => float::powi((a - b) / a.max(Float::EPSILON), 2),
+        (false, false) => 0.0,
+        _ => Float::INFINITY,
+    }
+}
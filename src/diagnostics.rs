@@ -0,0 +1,94 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::math_util::Float;
+
+/// How severe a problem found by `Scene::validate()` is
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The scene can be rendered but probably doesn't look as intended
+    Warning,
+    /// Rendering the scene would panic or produce garbage
+    Error,
+}
+
+/// The part of the scene a diagnostic refers to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    Camera,
+    /// Index into `Scene::objects`
+    Object(usize),
+    /// Index into `Scene::lights`
+    Light(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiagnosticKind {
+    MaterialIndexOutOfRange { index: usize, material_count: usize },
+    NonInvertibleTransformation,
+    /// Camera direction or up vector is zero or they are parallel
+    DegenerateCameraVectors,
+    InvalidFieldOfView(Float),
+    EmptyResolution,
+    /// A position, direction or transformation contains NaN or infinity
+    NonFiniteValue,
+    EmptyMesh,
+    NonPositiveLightIntensity(Float),
+}
+
+/// A single problem found by `Scene::validate()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub location: Location,
+    pub kind: DiagnosticKind,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, location: Location, kind: DiagnosticKind) -> Diagnostic {
+        Diagnostic { severity, location, kind }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Location::Camera => write!(f, "camera"),
+            Location::Object(index) => write!(f, "object {}", index),
+            Location::Light(index) => write!(f, "light {}", index),
+        }
+    }
+}
+
+impl Display for DiagnosticKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DiagnosticKind::MaterialIndexOutOfRange { index, material_count } => write!(f, "Material index {} is out of range, the scene has {} materials", index, material_count),
+            DiagnosticKind::NonInvertibleTransformation => write!(f, "Transformation is not invertible"),
+            DiagnosticKind::DegenerateCameraVectors => write!(f, "Camera direction and up vector must be non-zero and not parallel"),
+            DiagnosticKind::InvalidFieldOfView(fov) => write!(f, "Field of view of {} degrees is outside of the range (0, 180)", fov),
+            DiagnosticKind::EmptyResolution => write!(f, "Resolution has zero pixels"),
+            DiagnosticKind::NonFiniteValue => write!(f, "Contains NaN or infinite values"),
+            DiagnosticKind::EmptyMesh => write!(f, "Mesh has no triangles"),
+            DiagnosticKind::NonPositiveLightIntensity(intensity) => write!(f, "Light intensity {} is not positive", intensity),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} in {}: {}", self.severity, self.location, self.kind)
+    }
+}
@@ -0,0 +1,182 @@
+
+use serde::{Serialize, Deserialize};
+use cgmath::{Vector2, Vector3};
+use rand::Rng;
+
+use crate::color::Color;
+use crate::material::Texture;
+use crate::math_util::{float, Float, consts};
+use crate::error::RaytracerError;
+
+fn default_intensity() -> Float {
+    1.0
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeserializableEnvironmentMap {
+    pub texture: Texture,
+    #[serde(default = "default_intensity")]
+    pub intensity: Float,
+    #[serde(default)]
+    pub light_samples: usize,
+}
+
+impl From<EnvironmentMap> for DeserializableEnvironmentMap {
+    fn from(e: EnvironmentMap) -> DeserializableEnvironmentMap {
+        DeserializableEnvironmentMap {
+            texture: e.texture,
+            intensity: e.intensity,
+            light_samples: e.light_samples,
+        }
+    }
+}
+
+impl From<DeserializableEnvironmentMap> for EnvironmentMap {
+    fn from(d: DeserializableEnvironmentMap) -> EnvironmentMap {
+        let distribution = Distribution2D::new(&d.texture);
+        EnvironmentMap {
+            texture: d.texture,
+            intensity: d.intensity,
+            light_samples: d.light_samples,
+            distribution,
+        }
+    }
+}
+
+/// An equirectangular image that surrounds the scene at infinite distance, e.g. a photographed sky
+///
+/// The image is laid out like the texture of a `Sphere`: U follows the longitude and V runs from straight up (0) to
+/// straight down (1).
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "DeserializableEnvironmentMap")]
+#[serde(into = "DeserializableEnvironmentMap")]
+pub struct EnvironmentMap {
+    pub texture: Texture,
+    /// Factor applied to all pixels, as 8-bit images can't hold the brightness of a real sky
+    pub intensity: Float,
+    /// Number of directions sampled per diffuse hit to light the scene with the image; 0 disables image-based
+    /// lighting, the map is then only seen in the background and in reflections
+    pub light_samples: usize,
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    /// Radiance arriving from `direction`
+    pub fn radiance(&self, direction: &Vector3<Float>) -> Color {
+        self.texture.sample_bilinear(&direction_to_tex_coords(direction)) * self.intensity
+    }
+
+    /// Choose a direction with a probability proportional to the radiance arriving from it
+    ///
+    /// Returns the direction, the radiance arriving from it and the probability density with respect to solid angle.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Option<(Vector3<Float>, Color, Float)> {
+        let (x, y, pixel_probability) = self.distribution.sample(rng.gen(), rng.gen())?;
+
+        // Pick a uniformly distributed point within the pixel
+        let w = self.texture.img.width() as Float;
+        let h = self.texture.img.height() as Float;
+        let tex_coords = Vector2::new((x as Float + rng.gen::<Float>()) / w, (y as Float + rng.gen::<Float>()) / h);
+        let (direction, sin_theta) = tex_coords_to_direction(&tex_coords);
+        if sin_theta <= 0.0 {
+            return None;
+        }
+
+        // The solid angle covered by a pixel shrinks towards the poles
+        let pdf = pixel_probability * w * h / (2.0 * consts::PI * consts::PI * sin_theta);
+        let radiance = self.texture.texel(x, y) * self.intensity;
+        Some((direction, radiance, pdf))
+    }
+
+    /// Load the image again through the current asset loader and rebuild the sampling distribution
+    pub fn reload(&mut self) -> Result<(), RaytracerError> {
+        self.texture.reload()?;
+        self.distribution = Distribution2D::new(&self.texture);
+        Ok(())
+    }
+}
+
+/// Piecewise constant distribution over the pixels of an equirectangular image, proportional to their luminance
+/// weighted by the solid angle they cover
+#[derive(Clone)]
+struct Distribution2D {
+    width: usize,
+    /// Cumulative distribution of the rows, normalized to end at 1
+    row_cdf: Vec<Float>,
+    /// Cumulative distribution of the pixels within each row, normalized to end at 1 per row
+    column_cdfs: Vec<Float>,
+    /// Probability of choosing each pixel
+    pixel_probabilities: Vec<Float>,
+}
+
+impl Distribution2D {
+    fn new(texture: &Texture) -> Distribution2D {
+        let width = texture.img.width();
+        let height = texture.img.height();
+
+        let mut weights = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let sin_theta = float::sin((y as Float + 0.5) / height as Float * consts::PI);
+            for x in 0..width {
+                weights.push(texture.texel(x, y).luminance() * sin_theta);
+            }
+        }
+        let total: Float = weights.iter().sum();
+
+        let mut row_cdf = Vec::with_capacity(height);
+        let mut column_cdfs = Vec::with_capacity(width * height);
+        let mut row_sum = 0.0;
+        for row in weights.chunks(width.max(1)) {
+            let row_weight: Float = row.iter().sum();
+            let mut column_sum = 0.0;
+            for &weight in row {
+                column_sum += weight;
+                column_cdfs.push(if row_weight > 0.0 { column_sum / row_weight } else { 0.0 });
+            }
+            row_sum += row_weight;
+            row_cdf.push(if total > 0.0 { row_sum / total } else { 0.0 });
+        }
+
+        let pixel_probabilities = weights.iter()
+            .map(|&weight| if total > 0.0 { weight / total } else { 0.0 })
+            .collect();
+
+        Distribution2D {
+            width,
+            row_cdf,
+            column_cdfs,
+            pixel_probabilities,
+        }
+    }
+
+    /// Map two uniformly distributed numbers in [0, 1) to a pixel and its probability, `None` for black images
+    fn sample(&self, u1: Float, u2: Float) -> Option<(usize, usize, Float)> {
+        // Rounding errors may leave the last entry of a CDF slightly below 1
+        let y = self.row_cdf.partition_point(|&cdf| cdf <= u1).min(self.row_cdf.len().saturating_sub(1));
+        let row = self.column_cdfs.get(y * self.width..(y + 1) * self.width)?;
+        let x = row.partition_point(|&cdf| cdf <= u2).min(self.width.saturating_sub(1));
+
+        let probability = *self.pixel_probabilities.get(y * self.width + x)?;
+        if probability > 0.0 {
+            Some((x, y, probability))
+        } else {
+            None
+        }
+    }
+}
+
+/// Texture coordinates of the pixel seen in `direction`, using the same layout as spheres
+fn direction_to_tex_coords(direction: &Vector3<Float>) -> Vector2<Float> {
+    let tex_x = (1.0 + float::atan2(direction.z, direction.x) / consts::PI) * 0.5;
+    // acos() returns NaN for values that exceed 1 due to rounding errors
+    let tex_y = float::acos(direction.y.clamp(-1.0, 1.0)) / consts::PI;
+    Vector2::new(tex_x, tex_y)
+}
+
+/// Direction seen at the given texture coordinates, together with the sine of its polar angle
+fn tex_coords_to_direction(tex_coords: &Vector2<Float>) -> (Vector3<Float>, Float) {
+    let phi = 2.0 * consts::PI * tex_coords.x - consts::PI;
+    let theta = consts::PI * tex_coords.y;
+    let sin_theta = float::sin(theta);
+    let direction = Vector3::new(sin_theta * float::cos(phi), float::cos(theta), sin_theta * float::sin(phi));
+    (direction, sin_theta)
+}
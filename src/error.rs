@@ -0,0 +1,114 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::obj_parser::ObjFileError;
+use crate::ply_parser::PlyFileError;
+use crate::compact_mesh::CompactMeshFileError;
+use crate::diagnostics::Diagnostic;
+
+/// Error returned by the public functions that load assets, build scenes or configure rendering
+#[derive(Debug)]
+pub enum RaytracerError {
+    /// The asset loader failed to load the file, e.g. because it doesn't exist or has an unsupported format
+    Asset { path: PathBuf, error: Box<dyn Error + Send + Sync> },
+    /// An OBJ file is malformed
+    Obj(ObjFileError),
+    /// A PLY file is malformed
+    Ply(PlyFileError),
+    /// A preprocessed mesh file is malformed, or a mesh can't be stored in compact form
+    CompactMesh(CompactMeshFileError),
+    /// The scene description is inconsistent, e.g. references a material that doesn't exist
+    InvalidScene(String),
+    /// `Scene::validate()` found errors, which are listed here without the warnings
+    Validation(Vec<Diagnostic>),
+    /// The camera or the render settings can't be used, e.g. a camera whose direction is parallel to its up vector
+    InvalidConfiguration(String),
+    /// Writing an output file or communicating with other machines failed
+    Io(io::Error),
+    /// An image couldn't be encoded, e.g. as OpenEXR or PNG
+    Encode(Box<dyn Error + Send + Sync>),
+    /// A render doesn't match its golden image, or the golden image can't be read or written, see `check_golden()`
+    Golden(String),
+}
+
+impl RaytracerError {
+    /// Wrap an error of an asset loader, keeping the errors of the built-in parsers as their own variants
+    pub fn asset(path: &Path, error: Box<dyn Error + Send + Sync>) -> RaytracerError {
+        let error = match error.downcast::<ObjFileError>() {
+            Ok(error) => return RaytracerError::Obj(*error),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<PlyFileError>() {
+            Ok(error) => return RaytracerError::Ply(*error),
+            Err(error) => error,
+        };
+        match error.downcast::<CompactMeshFileError>() {
+            Ok(error) => RaytracerError::CompactMesh(*error),
+            Err(error) => RaytracerError::Asset { path: path.to_path_buf(), error },
+        }
+    }
+}
+
+impl Display for RaytracerError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            RaytracerError::Asset { path, error } => write!(f, "Unable to load {}: {}", path.display(), error),
+            RaytracerError::Obj(err) => write!(f, "{}", err),
+            RaytracerError::Ply(err) => write!(f, "{}", err),
+            RaytracerError::CompactMesh(err) => write!(f, "{}", err),
+            RaytracerError::InvalidScene(msg) => write!(f, "{}", msg),
+            RaytracerError::Validation(diagnostics) => {
+                write!(f, "Scene has {} errors", diagnostics.len())?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n  {}", diagnostic)?;
+                }
+                Ok(())
+            }
+            RaytracerError::InvalidConfiguration(msg) => write!(f, "{}", msg),
+            RaytracerError::Io(err) => write!(f, "{}", err),
+            RaytracerError::Encode(err) => write!(f, "Unable to encode image: {}", err),
+            RaytracerError::Golden(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for RaytracerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RaytracerError::Asset { error, .. } => Some(&**error),
+            RaytracerError::Obj(err) => Some(err),
+            RaytracerError::Ply(err) => Some(err),
+            RaytracerError::CompactMesh(err) => Some(err),
+            RaytracerError::Io(err) => Some(err),
+            RaytracerError::Encode(err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ObjFileError> for RaytracerError {
+    fn from(err: ObjFileError) -> RaytracerError {
+        RaytracerError::Obj(err)
+    }
+}
+
+impl From<PlyFileError> for RaytracerError {
+    fn from(err: PlyFileError) -> RaytracerError {
+        RaytracerError::Ply(err)
+    }
+}
+
+impl From<io::Error> for RaytracerError {
+    fn from(err: io::Error) -> RaytracerError {
+        RaytracerError::Io(err)
+    }
+}
+
+impl From<CompactMeshFileError> for RaytracerError {
+    fn from(err: CompactMeshFileError) -> RaytracerError {
+        RaytracerError::CompactMesh(err)
+    }
+}
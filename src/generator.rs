@@ -0,0 +1,486 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use cgmath::{Point3, Vector3, InnerSpace};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::color::Color;
+use crate::image::RgbImage;
+use crate::lights::{Light, DirectionalLight, PointLight, Falloff};
+use crate::material::{Material, Coloration, Texture, Parameter, ShadingModel};
+use crate::ray::MAX_UV_CHANNELS;
+use crate::mesh::{Mesh, MeshData, IndexedTriangle, CornerIndices, Acceleration, KDTreeOptions};
+use crate::primitives::{Plane, Sphere};
+use crate::scene::{Scene, Camera, Object, Shape, Transformation, Background};
+use crate::math_util::Float;
+
+/// Parameters for `generate_room()`
+#[derive(Clone, Debug)]
+pub struct RoomParameters {
+    pub seed: u64,
+    pub resolution: (usize, usize),
+    /// Floor dimensions (X and Z) and ceiling height
+    pub size: Vector3<Float>,
+    pub furniture_count: usize,
+    pub light_count: usize,
+}
+
+impl Default for RoomParameters {
+    fn default() -> RoomParameters {
+        RoomParameters {
+            seed: 0,
+            resolution: (640, 480),
+            size: Vector3::new(8.0, 3.0, 10.0),
+            furniture_count: 12,
+            light_count: 3,
+        }
+    }
+}
+
+/// Parameters for `generate_city()`
+#[derive(Clone, Debug)]
+pub struct CityParameters {
+    pub seed: u64,
+    pub resolution: (usize, usize),
+    /// Number of blocks along X and Z
+    pub blocks: (usize, usize),
+    pub max_floors: usize,
+    /// Probability of a tree at each spot along the sidewalks
+    pub tree_density: Float,
+}
+
+impl Default for CityParameters {
+    fn default() -> CityParameters {
+        CityParameters {
+            seed: 0,
+            resolution: (640, 480),
+            blocks: (4, 4),
+            max_floors: 12,
+            tree_density: 0.3,
+        }
+    }
+}
+
+/// Generate a furnished room with tables, chairs, shelves and decorative spheres
+///
+/// The same parameters always result in the same scene. Textures and meshes are generated in memory, so the scene
+/// can be rendered but not serialized and loaded again.
+pub fn generate_room(parameters: &RoomParameters) -> Scene {
+    let mut rng = StdRng::seed_from_u64(parameters.seed);
+    let size = parameters.size;
+    let (half_x, height, half_z) = (size.x * 0.5, size.y, size.z * 0.5);
+
+    let camera = Camera::new(
+        parameters.resolution,
+        70.0,
+        Point3::new(-half_x + 0.5, 1.6, half_z - 0.5),
+        Vector3::new(half_x * 0.6, -1.0, -half_z * 0.6).normalize(),
+        Vector3::unit_y(),
+    ).expect("Camera looks diagonally downwards");
+    let mut builder = SceneBuilder::new(Scene {
+        aa_samples: 4,
+        ambient_light_color: Color::new(0.12, 0.12, 0.12),
+        ..Scene::new(camera)
+    });
+
+    let floor_texture = checker_texture("floor", random_color(&mut rng, 0.3, 0.5), random_color(&mut rng, 0.3, 0.3), 8);
+    let floor = builder.add_material(diffuse(Coloration::Texture(floor_texture)), 0.5);
+    let wall = builder.add_material(diffuse(Coloration::Color(random_color(&mut rng, 0.2, 0.85))), 1.0);
+    let ceiling = builder.add_material(diffuse(Coloration::Color(Color::new(0.9, 0.9, 0.9))), 1.0);
+
+    // Planes face upwards, so rotate them to face the inside of the room
+    builder.add_plane(floor, Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.0));
+    builder.add_plane(ceiling, Vector3::new(0.0, height, 0.0), Vector3::new(180.0, 0.0, 0.0));
+    builder.add_plane(wall, Vector3::new(-half_x, 0.0, 0.0), Vector3::new(0.0, 0.0, -90.0));
+    builder.add_plane(wall, Vector3::new(half_x, 0.0, 0.0), Vector3::new(0.0, 0.0, 90.0));
+    builder.add_plane(wall, Vector3::new(0.0, 0.0, -half_z), Vector3::new(90.0, 0.0, 0.0));
+    builder.add_plane(wall, Vector3::new(0.0, 0.0, half_z), Vector3::new(-90.0, 0.0, 0.0));
+
+    let wood = builder.add_material(Material {
+        shading_model: ShadingModel::MetallicRoughness {
+            metallic: Parameter::Value(0.0),
+            roughness: Parameter::Value(0.6),
+        },
+        ..diffuse(Coloration::Color(random_color(&mut rng, 0.5, 0.45)))
+    }, 1.0);
+    let fabric = builder.add_material(diffuse(Coloration::Color(random_color(&mut rng, 0.7, 0.6))), 1.0);
+
+    // Place furniture in distinct cells of a grid so that pieces don't overlap
+    let cell_size = 1.6;
+    let cells_x = ((size.x - 1.0) / cell_size).floor().max(1.0) as usize;
+    let cells_z = ((size.z - 1.0) / cell_size).floor().max(1.0) as usize;
+    let mut cells: Vec<(usize, usize)> = (0..cells_x).flat_map(|x| (0..cells_z).map(move |z| (x, z))).collect();
+    for i in (1..cells.len()).rev() {
+        cells.swap(i, rng.gen_range(0, i + 1));
+    }
+
+    for &(cell_x, cell_z) in cells.iter().take(parameters.furniture_count) {
+        let center_x = -half_x + 0.5 + (cell_x as Float + 0.5) * cell_size;
+        let center_z = -half_z + 0.5 + (cell_z as Float + 0.5) * cell_size;
+
+        match rng.gen_range(0, 4) {
+            0 => {
+                // Table with legs and possibly a vase on top
+                let (w, d) = (rng.gen_range(0.4, 0.65), rng.gen_range(0.3, 0.55));
+                let top = 0.75;
+                builder.add_box(wood, Point3::new(center_x - w, top - 0.05, center_z - d), Point3::new(center_x + w, top, center_z + d));
+                for &(sx, sz) in &[(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+                    let leg_x = center_x + sx * (w - 0.05);
+                    let leg_z = center_z + sz * (d - 0.05);
+                    builder.add_box(wood, Point3::new(leg_x - 0.03, 0.0, leg_z - 0.03), Point3::new(leg_x + 0.03, top - 0.05, leg_z + 0.03));
+                }
+                if rng.gen::<Float>() < 0.6 {
+                    let radius = rng.gen_range(0.08, 0.15);
+                    let vase = builder.add_material(random_material(&mut rng), 1.0);
+                    builder.add_sphere(vase, Point3::new(center_x, top + radius, center_z), radius);
+                }
+            }
+            1 => {
+                // Chair with a backrest
+                let seat = 0.45;
+                builder.add_box(fabric, Point3::new(center_x - 0.22, seat - 0.05, center_z - 0.22), Point3::new(center_x + 0.22, seat, center_z + 0.22));
+                builder.add_box(fabric, Point3::new(center_x - 0.22, seat, center_z + 0.18), Point3::new(center_x + 0.22, seat + 0.5, center_z + 0.22));
+                for &(sx, sz) in &[(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+                    let leg_x = center_x + sx * 0.19;
+                    let leg_z = center_z + sz * 0.19;
+                    builder.add_box(wood, Point3::new(leg_x - 0.025, 0.0, leg_z - 0.025), Point3::new(leg_x + 0.025, seat - 0.05, leg_z + 0.025));
+                }
+            }
+            2 => {
+                // Open shelf with a few boards
+                let shelf_height = rng.gen_range(1.2, (height - 0.3).max(1.3));
+                let boards = rng.gen_range(3, 6);
+                builder.add_box(wood, Point3::new(center_x - 0.5, 0.0, center_z - 0.2), Point3::new(center_x - 0.47, shelf_height, center_z + 0.2));
+                builder.add_box(wood, Point3::new(center_x + 0.47, 0.0, center_z - 0.2), Point3::new(center_x + 0.5, shelf_height, center_z + 0.2));
+                for board in 0..boards {
+                    let y = board as Float * (shelf_height - 0.03) / (boards - 1) as Float;
+                    builder.add_box(wood, Point3::new(center_x - 0.47, y, center_z - 0.2), Point3::new(center_x + 0.47, y + 0.03, center_z + 0.2));
+                }
+            }
+            _ => {
+                // Decorative sphere on the floor
+                let radius = rng.gen_range(0.2, 0.45);
+                let material = builder.add_material(random_material(&mut rng), 1.0);
+                builder.add_sphere(material, Point3::new(center_x, radius, center_z), radius);
+            }
+        }
+    }
+
+    for _ in 0..parameters.light_count {
+        builder.scene.lights.push(Light::Point(PointLight {
+            point: Point3::new(rng.gen_range(-half_x * 0.8, half_x * 0.8), height - 0.2, rng.gen_range(-half_z * 0.8, half_z * 0.8)),
+            color: random_color(&mut rng, 0.15, 1.0),
+            intensity: 100.0,
+            falloff: Falloff::InverseSquare,
+            range: None,
+            radius: 0.0,
+            shadow_samples: 1,
+            projection: None,
+            name: None,
+            group: None,
+        }));
+    }
+
+    builder.finish()
+}
+
+/// Generate a grid of city blocks with buildings of varying height, sidewalks and trees
+///
+/// The same parameters always result in the same scene. Textures and meshes are generated in memory, so the scene
+/// can be rendered but not serialized and loaded again.
+pub fn generate_city(parameters: &CityParameters) -> Scene {
+    const BLOCK_SIZE: Float = 20.0;
+    const STREET_WIDTH: Float = 8.0;
+    const SIDEWALK_WIDTH: Float = 2.0;
+    const FLOOR_HEIGHT: Float = 3.0;
+
+    let mut rng = StdRng::seed_from_u64(parameters.seed);
+    let pitch = BLOCK_SIZE + STREET_WIDTH;
+    let extent_x = parameters.blocks.0 as Float * pitch;
+    let extent_z = parameters.blocks.1 as Float * pitch;
+
+    let camera = Camera::new(
+        parameters.resolution,
+        50.0,
+        Point3::new(-0.3 * extent_x, 0.5 * (extent_x + extent_z), 1.3 * extent_z),
+        Vector3::new(0.8 * extent_x, -0.5 * (extent_x + extent_z), -0.8 * extent_z).normalize(),
+        Vector3::unit_y(),
+    ).expect("Camera looks diagonally downwards");
+    let mut builder = SceneBuilder::new(Scene {
+        aa_samples: 4,
+        background: Background::Gradient {
+            top: Color::new(0.35, 0.55, 0.9),
+            bottom: Color::new(0.8, 0.85, 0.9),
+        },
+        ambient_light_color: Color::new(0.25, 0.27, 0.3),
+        ..Scene::new(camera)
+    });
+    builder.scene.lights.push(Light::Directional(DirectionalLight {
+        direction: Vector3::new(-0.4, -1.0, -0.3).normalize(),
+        color: Color::new(1.0, 0.95, 0.85),
+        intensity: 2.5,
+        name: None,
+        group: None,
+    }));
+
+    let asphalt = builder.add_material(diffuse(Coloration::Color(Color::new(0.3, 0.3, 0.32))), 1.0);
+    let concrete = builder.add_material(diffuse(Coloration::Color(Color::new(0.6, 0.6, 0.58))), 1.0);
+    let bark = builder.add_material(diffuse(Coloration::Color(Color::new(0.3, 0.2, 0.12))), 1.0);
+    let leaves = builder.add_material(diffuse(Coloration::Color(Color::new(0.2, 0.45, 0.15))), 1.0);
+
+    builder.add_plane(asphalt, Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.0));
+
+    for block_x in 0..parameters.blocks.0 {
+        for block_z in 0..parameters.blocks.1 {
+            let x0 = block_x as Float * pitch;
+            let z0 = block_z as Float * pitch;
+
+            builder.add_box(concrete, Point3::new(x0, 0.0, z0), Point3::new(x0 + BLOCK_SIZE, 0.15, z0 + BLOCK_SIZE));
+
+            // Split the block into two or four lots
+            let lot_size = (BLOCK_SIZE - 2.0 * SIDEWALK_WIDTH) * 0.5;
+            let lots: &[(Float, Float, Float, Float)] = if rng.gen::<bool>() {
+                &[(0.0, 0.0, 1.0, 2.0), (1.0, 0.0, 1.0, 2.0)]
+            } else {
+                &[(0.0, 0.0, 1.0, 1.0), (1.0, 0.0, 1.0, 1.0), (0.0, 1.0, 1.0, 1.0), (1.0, 1.0, 1.0, 1.0)]
+            };
+
+            for &(lot_x, lot_z, lots_wide, lots_deep) in lots {
+                let floors = rng.gen_range(1, parameters.max_floors.max(1) + 1);
+                let inset = rng.gen_range(0.3, 1.0);
+                let min = Point3::new(
+                    x0 + SIDEWALK_WIDTH + lot_x * lot_size + inset,
+                    0.15,
+                    z0 + SIDEWALK_WIDTH + lot_z * lot_size + inset,
+                );
+                let max = Point3::new(
+                    min.x + lots_wide * lot_size - 2.0 * inset,
+                    0.15 + floors as Float * FLOOR_HEIGHT,
+                    min.z + lots_deep * lot_size - 2.0 * inset,
+                );
+
+                let facade = windows_texture("facade", random_color(&mut rng, 0.25, 0.7), Color::new(0.25, 0.3, 0.4));
+                let material = builder.add_material(Material {
+                    reflectivity: 0.1,
+                    ..diffuse(Coloration::Texture(facade))
+                }, 1.0 / FLOOR_HEIGHT);
+                builder.add_box(material, min, max);
+                // Roof structure
+                if floors > 3 && rng.gen::<Float>() < 0.5 {
+                    let center = Point3::new((min.x + max.x) * 0.5, max.y, (min.z + max.z) * 0.5);
+                    builder.add_box(concrete, Point3::new(center.x - 1.5, max.y, center.z - 1.5), Point3::new(center.x + 1.5, max.y + 2.0, center.z + 1.5));
+                }
+            }
+
+            // Trees along the edges of the block
+            let spots = (BLOCK_SIZE / 4.0) as usize;
+            for spot in 0..spots {
+                let t = (spot as Float + 0.5) * BLOCK_SIZE / spots as Float;
+                let positions = [
+                    (x0 + t, z0 + SIDEWALK_WIDTH * 0.5),
+                    (x0 + t, z0 + BLOCK_SIZE - SIDEWALK_WIDTH * 0.5),
+                    (x0 + SIDEWALK_WIDTH * 0.5, z0 + t),
+                    (x0 + BLOCK_SIZE - SIDEWALK_WIDTH * 0.5, z0 + t),
+                ];
+                for &(x, z) in &positions {
+                    if rng.gen::<Float>() < parameters.tree_density {
+                        let trunk_height = rng.gen_range(1.5, 2.5);
+                        let crown_radius = rng.gen_range(0.8, 1.2);
+                        builder.add_box(bark, Point3::new(x - 0.12, 0.15, z - 0.12), Point3::new(x + 0.12, 0.15 + trunk_height, z + 0.12));
+                        builder.add_sphere(leaves, Point3::new(x, 0.15 + trunk_height + crown_radius * 0.8, z), crown_radius);
+                    }
+                }
+            }
+        }
+    }
+
+    builder.finish()
+}
+
+/// Minimum and maximum corner of an axis-aligned box
+type BoxBounds = (Point3<Float>, Point3<Float>);
+
+/// All boxes with the same material
+struct BoxGroup {
+    boxes: Vec<BoxBounds>,
+    /// Texture repetitions per unit
+    uv_scale: Float,
+}
+
+/// Collects objects while generating a scene; boxes are merged into one mesh per material
+struct SceneBuilder {
+    scene: Scene,
+    /// Indexed by material
+    box_groups: Vec<BoxGroup>,
+}
+
+impl SceneBuilder {
+    fn new(scene: Scene) -> SceneBuilder {
+        SceneBuilder {
+            scene,
+            box_groups: Vec::new(),
+        }
+    }
+
+    fn add_material(&mut self, material: Material, uv_scale: Float) -> usize {
+        self.scene.materials.push(material);
+        self.box_groups.push(BoxGroup { boxes: Vec::new(), uv_scale });
+        self.scene.materials.len() - 1
+    }
+
+    fn add_plane(&mut self, material_index: usize, translation: Vector3<Float>, rotation: Vector3<Float>) {
+        let transformation = Transformation::new(translation, rotation, 1.0);
+        self.scene.add_object(Object::new(Shape::Plane(Plane::default()), material_index, transformation));
+    }
+
+    fn add_sphere(&mut self, material_index: usize, center: Point3<Float>, radius: Float) {
+        let transformation = Transformation::new(Vector3::new(center.x, center.y, center.z), Vector3::new(0.0, 0.0, 0.0), radius);
+        self.scene.add_object(Object::new(Shape::Sphere(Sphere::default()), material_index, transformation));
+    }
+
+    fn add_box(&mut self, material_index: usize, min: Point3<Float>, max: Point3<Float>) {
+        self.box_groups[material_index].boxes.push((min, max));
+    }
+
+    fn finish(mut self) -> Scene {
+        for (material_index, group) in self.box_groups.into_iter().enumerate() {
+            if group.boxes.is_empty() {
+                continue;
+            }
+            let path = PathBuf::from(format!("generated/boxes_{}.obj", material_index));
+            let mesh = Mesh::new(path, box_mesh(&group.boxes, group.uv_scale), false, Acceleration::default(), KDTreeOptions::default());
+            let transformation = Transformation::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.0), 1.0);
+            self.scene.add_object(Object::new(Shape::Mesh(mesh), material_index, transformation));
+        }
+        self.scene
+    }
+}
+
+/// Build a mesh from axis-aligned boxes with flat normals and world space texture coordinates
+fn box_mesh(boxes: &[BoxBounds], uv_scale: Float) -> MeshData {
+    struct Face {
+        normal: (Float, Float, Float),
+        /// Axes spanned by the face
+        u_axis: usize,
+        v_axis: usize,
+        normal_axis: usize,
+    }
+    const FACES: [Face; 6] = [
+        Face { normal: (1.0, 0.0, 0.0), u_axis: 2, v_axis: 1, normal_axis: 0 },
+        Face { normal: (-1.0, 0.0, 0.0), u_axis: 2, v_axis: 1, normal_axis: 0 },
+        Face { normal: (0.0, 1.0, 0.0), u_axis: 0, v_axis: 2, normal_axis: 1 },
+        Face { normal: (0.0, -1.0, 0.0), u_axis: 0, v_axis: 2, normal_axis: 1 },
+        Face { normal: (0.0, 0.0, 1.0), u_axis: 0, v_axis: 1, normal_axis: 2 },
+        Face { normal: (0.0, 0.0, -1.0), u_axis: 0, v_axis: 1, normal_axis: 2 },
+    ];
+
+    let mut data = MeshData {
+        vertex_positions: Vec::with_capacity(boxes.len() * 24),
+        vertex_normals: FACES.iter().map(|face| face.normal).collect(),
+        vertex_tex_coords: Vec::with_capacity(boxes.len() * 24),
+        vertex_colors: Vec::new(),
+        extra_tex_coords: Vec::new(),
+        triangles: Vec::with_capacity(boxes.len() * 12),
+    };
+
+    for (min, max) in boxes {
+        for (face_index, &Face { normal, u_axis, v_axis, normal_axis }) in FACES.iter().enumerate() {
+            let first_vertex = data.vertex_positions.len();
+            let normal_sign = normal.0 + normal.1 + normal.2;
+            let fixed = if normal_sign > 0.0 { max[normal_axis] } else { min[normal_axis] };
+
+            for &(u_max, v_max) in &[(false, false), (true, false), (true, true), (false, true)] {
+                let mut position = [0.0; 3];
+                position[normal_axis] = fixed;
+                position[u_axis] = if u_max { max[u_axis] } else { min[u_axis] };
+                position[v_axis] = if v_max { max[v_axis] } else { min[v_axis] };
+                data.vertex_positions.push((position[0], position[1], position[2]));
+                data.vertex_tex_coords.push((position[u_axis] * uv_scale, position[v_axis] * uv_scale));
+            }
+
+            let quad = [(0, 1, 2), (0, 2, 3)];
+            for &(a, b, c) in &quad {
+                let indices = CornerIndices::new(first_vertex + a, first_vertex + b, first_vertex + c);
+                data.triangles.push(IndexedTriangle {
+                    position_indices: indices,
+                    normal_indices: Some(CornerIndices::new(face_index, face_index, face_index)),
+                    tex_coords_indices: Some(indices),
+                    color_indices: None,
+                    extra_tex_coords_indices: [None; MAX_UV_CHANNELS - 1],
+                });
+            }
+        }
+    }
+
+    data
+}
+
+fn diffuse(color: Coloration) -> Material {
+    Material::new(color, 1.0, 0.0, 0.0, 1.5)
+}
+
+/// A random diffuse, metallic, mirror or glass material
+fn random_material<R: Rng>(rng: &mut R) -> Material {
+    let color = random_color(rng, 0.6, 0.8);
+    match rng.gen_range(0, 4) {
+        0 => diffuse(Coloration::Color(color)),
+        1 => Material {
+            shading_model: ShadingModel::MetallicRoughness {
+                metallic: Parameter::Value(1.0),
+                roughness: Parameter::Value(rng.gen_range(0.2, 0.6)),
+            },
+            ..diffuse(Coloration::Color(color))
+        },
+        2 => Material::new(Coloration::Color(color), 1.0, 0.7, 0.0, 1.5),
+        _ => Material::new(Coloration::Color(Color::white()), 1.0, 0.0, 0.9, 1.5),
+    }
+}
+
+/// A random color with the given saturation and value (brightness)
+fn random_color<R: Rng>(rng: &mut R, saturation: Float, value: Float) -> Color {
+    let hue = rng.gen::<Float>() * 6.0;
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    Color::new(r + m, g + m, b + m)
+}
+
+fn generated_texture(name: &str, img: RgbImage) -> Texture {
+    Texture::new(PathBuf::from(format!("generated/{}.png", name)), Arc::new(img))
+}
+
+/// Checkerboard with `squares` x `squares` fields
+fn checker_texture(name: &str, a: Color, b: Color, squares: usize) -> Texture {
+    const FIELD_SIZE: usize = 8;
+    let size = squares * FIELD_SIZE;
+    let mut img = RgbImage::new(size, size);
+    for y in 0..size {
+        for x in 0..size {
+            let color = if (x / FIELD_SIZE + y / FIELD_SIZE).is_multiple_of(2) { a } else { b };
+            img.put_pixel(x, y, &color.to_srgb_u8());
+        }
+    }
+    generated_texture(name, img)
+}
+
+/// A single window in a wall, tiled once per floor
+fn windows_texture(name: &str, wall: Color, window: Color) -> Texture {
+    const SIZE: usize = 32;
+    let mut img = RgbImage::new(SIZE, SIZE);
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let is_window = (6..26).contains(&x) && (8..24).contains(&y);
+            img.put_pixel(x, y, &if is_window { window } else { wall }.to_srgb_u8());
+        }
+    }
+    generated_texture(name, img)
+}
@@ -0,0 +1,256 @@
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use image::ImageError;
+
+use crate::image::RgbImage;
+use crate::generator::{generate_room, generate_city, RoomParameters, CityParameters};
+use crate::renderer::Renderer;
+use crate::scene::Scene;
+use crate::validation::ReferenceScene;
+use crate::math_util::Float;
+use crate::error::RaytracerError;
+
+/// Set this environment variable to write the current renders as the new golden images instead of comparing them
+pub const UPDATE_GOLDEN_VARIABLE: &str = "RAYTRACER_UPDATE_GOLDEN";
+
+/// Side length of the windows the structural similarity is computed over
+const SSIM_WINDOW: usize = 8;
+
+/// Built-in scenes for pinning the behavior of the renderer with golden images
+///
+/// All of them are built in code and render the same image on every platform, since this module enables the
+/// `deterministic` feature.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GoldenScene {
+    /// See `ReferenceScene::Furnace`
+    Furnace,
+    /// See `ReferenceScene::LambertSphere`
+    LambertSphere,
+    /// See `ReferenceScene::MirrorBox`
+    MirrorBox,
+    /// A small `generate_room()` scene, covering textures, meshes, point lights and soft shadows
+    Room,
+    /// A small `generate_city()` scene, covering large meshes and the sky
+    City,
+}
+
+impl GoldenScene {
+    pub fn all() -> [GoldenScene; 5] {
+        [GoldenScene::Furnace, GoldenScene::LambertSphere, GoldenScene::MirrorBox, GoldenScene::Room, GoldenScene::City]
+    }
+
+    /// File name of the golden image without extension
+    pub fn name(&self) -> &'static str {
+        match self {
+            GoldenScene::Furnace => ReferenceScene::Furnace.name(),
+            GoldenScene::LambertSphere => ReferenceScene::LambertSphere.name(),
+            GoldenScene::MirrorBox => ReferenceScene::MirrorBox.name(),
+            GoldenScene::Room => "room",
+            GoldenScene::City => "city",
+        }
+    }
+
+    /// Build the scene to render, the generated ones with a fixed seed
+    pub fn scene(&self) -> Scene {
+        match self {
+            GoldenScene::Furnace => ReferenceScene::Furnace.scene(),
+            GoldenScene::LambertSphere => ReferenceScene::LambertSphere.scene(),
+            GoldenScene::MirrorBox => ReferenceScene::MirrorBox.scene(),
+            GoldenScene::Room => generate_room(&RoomParameters {
+                seed: 1,
+                resolution: (96, 72),
+                ..Default::default()
+            }),
+            GoldenScene::City => generate_city(&CityParameters {
+                seed: 1,
+                resolution: (96, 72),
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn render(&self) -> RgbImage {
+        Renderer::new(self.scene()).render()
+    }
+
+    /// Render the scene and compare it against its golden image in `directory`, see `check_golden()`
+    pub fn check(&self, directory: &Path, min_ssim: Float) -> Result<GoldenComparison, RaytracerError> {
+        check_golden(self.name(), &self.render(), directory, min_ssim)
+    }
+}
+
+/// Result of comparing a render against its golden image
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GoldenComparison {
+    /// Mean structural similarity of the luminance, 1 for identical images
+    pub ssim: Float,
+    /// Largest difference of any pixel and channel
+    pub max_difference: u8,
+    /// Number of pixels that differ at all
+    pub differing_pixels: usize,
+}
+
+impl GoldenComparison {
+    /// Compare two images of the same size
+    pub fn new(expected: &RgbImage, actual: &RgbImage) -> GoldenComparison {
+        assert!(expected.width() == actual.width() && expected.height() == actual.height(), "Compared images must have the same size");
+
+        let mut max_difference = 0;
+        let mut differing_pixels = 0;
+        for (expected, actual) in expected.data().chunks_exact(3).zip(actual.data().chunks_exact(3)) {
+            let difference = channel_difference(expected, actual);
+            max_difference = max_difference.max(difference);
+            if difference > 0 {
+                differing_pixels += 1;
+            }
+        }
+
+        GoldenComparison {
+            ssim: ssim(expected, actual),
+            max_difference,
+            differing_pixels,
+        }
+    }
+
+    /// Whether the images are similar enough to count as the same, e.g. with a `min_ssim` of 0.99
+    ///
+    /// Identical images always are; the structural similarity tolerates the noise of slightly different samples while
+    /// still catching shifted edges and changed shading.
+    pub fn is_within(&self, min_ssim: Float) -> bool {
+        self.differing_pixels == 0 || self.ssim >= min_ssim
+    }
+}
+
+/// Compare a render against `<directory>/<name>.png`
+///
+/// If the environment variable named by `UPDATE_GOLDEN_VARIABLE` is set, the render is written as the new golden image
+/// instead. A missing golden image is an error otherwise, so that a forgotten file doesn't let a test pass. If the
+/// render isn't within `min_ssim` of the golden image, it is written to `<name>.actual.png` next to it, together with a
+/// `<name>.diff.png` from `diff_image()`, and the error names both files.
+pub fn check_golden(name: &str, image: &RgbImage, directory: &Path, min_ssim: Float) -> Result<GoldenComparison, RaytracerError> {
+    let golden_path = directory.join(format!("{}.png", name));
+
+    if env::var_os(UPDATE_GOLDEN_VARIABLE).is_some() {
+        fs::create_dir_all(directory).map_err(RaytracerError::from)
+            .and_then(|_| save_png(image, &golden_path))
+            .map_err(|err| RaytracerError::Golden(format!("Failed to write golden image {}: {}", golden_path.display(), err)))?;
+        return Ok(GoldenComparison::new(image, image));
+    }
+
+    if !golden_path.exists() {
+        return Err(RaytracerError::Golden(format!("Golden image {} doesn't exist, set {} to create it", golden_path.display(), UPDATE_GOLDEN_VARIABLE)));
+    }
+    let golden = load_png(&golden_path)
+        .map_err(|err| RaytracerError::Golden(format!("Failed to load golden image {}: {}", golden_path.display(), err)))?;
+    if golden.width() != image.width() || golden.height() != image.height() {
+        return Err(RaytracerError::Golden(format!("Golden image {} is {}x{}, but the render is {}x{}", golden_path.display(), golden.width(), golden.height(), image.width(), image.height())));
+    }
+
+    let comparison = GoldenComparison::new(&golden, image);
+    if comparison.is_within(min_ssim) {
+        return Ok(comparison);
+    }
+
+    let actual_path = directory.join(format!("{}.actual.png", name));
+    let diff_path = directory.join(format!("{}.diff.png", name));
+    let written = save_png(image, &actual_path).and_then(|_| save_png(&diff_image(&golden, image), &diff_path));
+    let mut message = format!("Render differs from golden image {}: SSIM is {:.4} (at least {} required), {} pixels differ by up to {}",
+                              golden_path.display(), comparison.ssim, min_ssim, comparison.differing_pixels, comparison.max_difference);
+    match written {
+        Ok(()) => message += &format!(", see {} and {}", actual_path.display(), diff_path.display()),
+        Err(err) => message += &format!(", and writing the render failed: {}", err),
+    }
+    Err(RaytracerError::Golden(message))
+}
+
+/// Visualize the differences between two images of the same size
+///
+/// Shows a dimmed grayscale copy of `expected` with the pixels that differ in red, brighter the larger the difference,
+/// so that even differences of a single step stand out.
+pub fn diff_image(expected: &RgbImage, actual: &RgbImage) -> RgbImage {
+    assert!(expected.width() == actual.width() && expected.height() == actual.height(), "Compared images must have the same size");
+
+    let data = expected.data().chunks_exact(3).zip(actual.data().chunks_exact(3))
+        .flat_map(|(expected, actual)| {
+            let gray = (luma(expected) * 0.25) as u8;
+            match channel_difference(expected, actual) {
+                0 => [gray, gray, gray],
+                difference => [(128 + difference as usize / 2).max(gray as usize).min(255) as u8, gray / 2, gray / 2],
+            }
+        })
+        .collect();
+    RgbImage::from_raw(expected.width(), expected.height(), data)
+}
+
+/// Mean structural similarity (SSIM) of the luminance of two images of the same size
+///
+/// Computed over windows of 8x8 pixels that overlap by half, or a single window for smaller images.
+pub fn ssim(a: &RgbImage, b: &RgbImage) -> Float {
+    assert!(a.width() == b.width() && a.height() == b.height(), "Compared images must have the same size");
+
+    const C1: Float = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: Float = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = (a.width(), a.height());
+    let luma_a: Vec<Float> = a.data().chunks_exact(3).map(luma).collect();
+    let luma_b: Vec<Float> = b.data().chunks_exact(3).map(luma).collect();
+
+    let window_starts = |size: usize| {
+        let window = SSIM_WINDOW.min(size);
+        (0..=size - window).step_by((window / 2).max(1)).map(move |start| (start, window))
+    };
+
+    let mut ssim_sum = 0.0;
+    let mut window_count = 0;
+    for (y0, window_height) in window_starts(height) {
+        for (x0, window_width) in window_starts(width) {
+            let pixels = || (y0..y0 + window_height).flat_map(move |y| (x0..x0 + window_width).map(move |x| y * width + x));
+            let n = (window_width * window_height) as Float;
+
+            let mean_a = pixels().map(|i| luma_a[i]).sum::<Float>() / n;
+            let mean_b = pixels().map(|i| luma_b[i]).sum::<Float>() / n;
+            let (mut variance_a, mut variance_b, mut covariance) = (0.0, 0.0, 0.0);
+            for i in pixels() {
+                let (da, db) = (luma_a[i] - mean_a, luma_b[i] - mean_b);
+                variance_a += da * da;
+                variance_b += db * db;
+                covariance += da * db;
+            }
+            let (variance_a, variance_b, covariance) = (variance_a / n, variance_b / n, covariance / n);
+
+            ssim_sum += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2));
+            window_count += 1;
+        }
+    }
+
+    if window_count > 0 { ssim_sum / window_count as Float } else { 1.0 }
+}
+
+/// Luminance of an sRGB pixel in the range of the channels, as used by JPEG
+fn luma(pixel: &[u8]) -> Float {
+    0.299 * pixel[0] as Float + 0.587 * pixel[1] as Float + 0.114 * pixel[2] as Float
+}
+
+fn channel_difference(a: &[u8], b: &[u8]) -> u8 {
+    a.iter().zip(b).map(|(a, b)| a.abs_diff(*b)).max().unwrap_or(0)
+}
+
+/// Load a PNG image, dropping any alpha channel
+pub fn load_png(path: &Path) -> Result<RgbImage, RaytracerError> {
+    let img = image::open(path).map_err(|err| RaytracerError::asset(path, Box::new(err)))?.into_rgb8();
+    let (width, height) = img.dimensions();
+    Ok(RgbImage::from_raw(width as usize, height as usize, img.into_raw()))
+}
+
+/// Write an image as PNG
+pub fn save_png(image: &RgbImage, path: &Path) -> Result<(), RaytracerError> {
+    image::save_buffer_with_format(path, image.data(), image.width() as u32, image.height() as u32, image::ColorType::Rgb8, image::ImageFormat::Png)
+        .map_err(|err| match err {
+            ImageError::IoError(err) => RaytracerError::Io(err),
+            err => RaytracerError::Encode(Box::new(err)),
+        })
+}
@@ -0,0 +1,218 @@
+use crate::color::{Color, linear_to_srgb};
+use crate::image::{RgbImage, RgbaImage};
+use crate::math_util::Float;
+#[cfg(feature = "exr")]
+use crate::error::RaytracerError;
+
+/// An image with floating point color values, e.g. the accumulation buffer of a render
+#[derive(Clone)]
+pub struct HdrImage {
+    width: usize,
+    height: usize,
+    data: Vec<Color>,
+}
+
+impl HdrImage {
+    pub fn new(w: usize, h: usize) -> HdrImage {
+        HdrImage {
+            width: w,
+            height: h,
+            data: vec![Color::black(); w * h],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn data(&self) -> &Vec<Color> {
+        &self.data
+    }
+
+    fn pixel_index(&self, x: usize, y: usize) -> usize {
+        y * self.width + x
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: Color) {
+        let index = self.pixel_index(x, y);
+        self.data[index] = color;
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> Color {
+        self.data[self.pixel_index(x, y)]
+    }
+
+    /// Copy the pixels `x..(x + w)` × `y..(y + h)` into a new image
+    pub fn crop(&self, x: usize, y: usize, w: usize, h: usize) -> HdrImage {
+        let mut img = HdrImage::new(w, h);
+        for y_local in 0..h {
+            for x_local in 0..w {
+                img.put_pixel(x_local, y_local, self.get_pixel(x + x_local, y + y_local));
+            }
+        }
+        img
+    }
+
+    /// Convert to an 8-bit sRGB image, clamping all color components
+    pub fn to_rgb_image(&self) -> RgbImage {
+        self.quantize(Color::to_srgb_u8)
+    }
+
+    /// Convert to an 8-bit image without sRGB encoding, for data like normals that is read back rather than viewed
+    pub fn to_linear_rgb_image(&self) -> RgbImage {
+        self.quantize(Color::to_u8)
+    }
+
+    fn quantize(&self, encode: fn(Color) -> (u8, u8, u8)) -> RgbImage {
+        let mut img = RgbImage::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                img.put_pixel(x, y, &encode(self.get_pixel(x, y)));
+            }
+        }
+        img
+    }
+
+    /// Convert to an 8-bit sRGB image with the alpha values `alpha` (one per pixel, in [0, 1])
+    ///
+    /// The colors of this image are taken to be premultiplied with alpha, as a render with a transparent background
+    /// yields them, and are divided by it before quantizing.
+    pub fn to_rgba_image(&self, alpha: &[Float]) -> RgbaImage {
+        self.quantize_rgba(alpha, Color::to_srgb_u8)
+    }
+
+    /// Like `to_rgba_image()`, but without sRGB encoding like `to_linear_rgb_image()`
+    pub fn to_linear_rgba_image(&self, alpha: &[Float]) -> RgbaImage {
+        self.quantize_rgba(alpha, Color::to_u8)
+    }
+
+    fn quantize_rgba(&self, alpha: &[Float], encode: fn(Color) -> (u8, u8, u8)) -> RgbaImage {
+        let mut img = RgbaImage::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let pixel_alpha = alpha[self.pixel_index(x, y)].clamp(0.0, 1.0);
+                let color = if pixel_alpha > 0.0 { self.get_pixel(x, y) / pixel_alpha } else { Color::black() };
+                let (r, g, b) = encode(color);
+                img.put_pixel(x, y, &(r, g, b, (pixel_alpha * 255.0).round() as u8));
+            }
+        }
+        img
+    }
+
+    /// Encode as binary PPM with 16 bits per channel, sRGB encoded and clamped like `to_rgb_image()`
+    pub fn to_ppm16(&self) -> Vec<u8> {
+        let mut bytes = format!("P6\n{} {}\n65535\n", self.width, self.height).into_bytes();
+        bytes.reserve(self.data.len() * 6);
+        let encode = |value: Float| (linear_to_srgb(value.clamp(0.0, 1.0)) * 65535.0).round() as u16;
+        for color in &self.data {
+            for &value in &[color.r, color.g, color.b] {
+                // PPM stores multi-byte values big endian
+                bytes.extend_from_slice(&encode(value).to_be_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Encode as Portable Float Map with 32-bit linear values, which keeps colors above 1
+    // `Float` is only wider than `f32` with the `f64` feature
+    #[allow(clippy::unnecessary_cast)]
+    pub fn to_pfm(&self) -> Vec<u8> {
+        // A negative scale marks the data as little endian
+        let mut bytes = format!("PF\n{} {}\n-1.0\n", self.width, self.height).into_bytes();
+        bytes.reserve(self.data.len() * 12);
+        // Rows are stored from bottom to top
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                let color = self.get_pixel(x, y);
+                for &value in &[color.r, color.g, color.b] {
+                    bytes.extend_from_slice(&(value as f32).to_le_bytes());
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Encode as OpenEXR with 32-bit linear values
+    #[cfg(feature = "exr")]
+    #[allow(clippy::unnecessary_cast)]
+    pub fn to_exr(&self) -> Result<Vec<u8>, RaytracerError> {
+        use exr::prelude::{Image, SpecificChannels, Vec2, WritableImage};
+
+        let channels = SpecificChannels::rgb(|Vec2(x, y): Vec2<usize>| {
+            let color = self.get_pixel(x, y);
+            (color.r as f32, color.g as f32, color.b as f32)
+        });
+        let image = Image::from_channels((self.width, self.height), channels);
+
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        image.write().to_buffered(&mut buffer).map_err(|err| RaytracerError::Encode(Box::new(err)))?;
+        Ok(buffer.into_inner())
+    }
+
+    /// Create an image with half the width and height by averaging 2x2 blocks of pixels
+    ///
+    /// Odd widths and heights are rounded up, the last row/column is then averaged with itself. An empty image stays
+    /// empty.
+    pub fn downsample(&self) -> HdrImage {
+        if self.width == 0 || self.height == 0 {
+            return HdrImage::new(self.width.div_ceil(2), self.height.div_ceil(2));
+        }
+
+        let w = self.width.div_ceil(2).max(1);
+        let h = self.height.div_ceil(2).max(1);
+
+        let mut img = HdrImage::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let x0 = (x * 2).min(self.width - 1);
+                let x1 = (x * 2 + 1).min(self.width - 1);
+                let y0 = (y * 2).min(self.height - 1);
+                let y1 = (y * 2 + 1).min(self.height - 1);
+
+                let sum = self.get_pixel(x0, y0) + self.get_pixel(x1, y0) + self.get_pixel(x0, y1) + self.get_pixel(x1, y1);
+                img.put_pixel(x, y, sum * 0.25);
+            }
+        }
+        img
+    }
+
+    /// Scale the image to `w` × `h` pixels with bilinear interpolation, e.g. to show a preview at full size
+    pub fn resize(&self, w: usize, h: usize) -> HdrImage {
+        let mut img = HdrImage::new(w, h);
+        if self.width == 0 || self.height == 0 {
+            return img;
+        }
+
+        // Position of the center of a target pixel in source pixel coordinates
+        let source_position = |target: usize, target_size: usize, source_size: usize| {
+            let position = ((target as Float + 0.5) * source_size as Float / target_size as Float - 0.5).max(0.0);
+            let index = (position as usize).min(source_size - 1);
+            (index, (index + 1).min(source_size - 1), position - index as Float)
+        };
+
+        for y in 0..h {
+            let (y0, y1, ty) = source_position(y, h, self.height);
+            for x in 0..w {
+                let (x0, x1, tx) = source_position(x, w, self.width);
+                let top = self.get_pixel(x0, y0) * (1.0 - tx) + self.get_pixel(x1, y0) * tx;
+                let bottom = self.get_pixel(x0, y1) * (1.0 - tx) + self.get_pixel(x1, y1) * tx;
+                img.put_pixel(x, y, top * (1.0 - ty) + bottom * ty);
+            }
+        }
+        img
+    }
+
+    /// Create `levels` successively downsampled images (1/2, 1/4, 1/8, ... scale)
+    pub fn pyramid(&self, levels: usize) -> Vec<HdrImage> {
+        let mut pyramid: Vec<HdrImage> = Vec::with_capacity(levels);
+        for _ in 0..levels {
+            let next = pyramid.last().unwrap_or(self).downsample();
+            pyramid.push(next);
+        }
+        pyramid
+    }
+}
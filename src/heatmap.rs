@@ -0,0 +1,198 @@
+
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use crate::image::RgbImage;
+use crate::stats::RayType;
+use crate::math_util::{float, Float};
+
+/// Work done to render a single pixel
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PixelCost {
+    /// Ray-triangle intersection tests of all rays of the pixel
+    pub triangle_tests: usize,
+    /// Acceleration structure nodes visited by all rays of the pixel
+    pub node_traversals: usize,
+    /// Shadow, reflection, refraction and occlusion rays
+    pub secondary_rays: usize,
+    /// Time spent tracing and shading all rays of the pixel
+    pub time: Duration,
+}
+
+/// Quantity shown by a heatmap
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CostMetric {
+    TriangleTests,
+    NodeTraversals,
+    SecondaryRays,
+    /// In microseconds
+    Time,
+}
+
+impl PixelCost {
+    pub fn value(&self, metric: CostMetric) -> Float {
+        match metric {
+            CostMetric::TriangleTests => self.triangle_tests as Float,
+            CostMetric::NodeTraversals => self.node_traversals as Float,
+            CostMetric::SecondaryRays => self.secondary_rays as Float,
+            CostMetric::Time => self.time.as_secs_f64() as Float * 1e6,
+        }
+    }
+}
+
+/// The `PixelCost` of every pixel of a frame, returned by `Renderer::render_costs()`
+#[derive(Clone)]
+pub struct CostBuffer {
+    width: usize,
+    height: usize,
+    costs: Vec<PixelCost>,
+}
+
+impl CostBuffer {
+    pub fn new(w: usize, h: usize) -> CostBuffer {
+        CostBuffer {
+            width: w,
+            height: h,
+            costs: vec![PixelCost::default(); w * h],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, cost: PixelCost) {
+        self.costs[y * self.width + x] = cost;
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> PixelCost {
+        self.costs[y * self.width + x]
+    }
+
+    /// Sum of the costs of all pixels
+    pub fn total(&self) -> PixelCost {
+        self.costs.iter().fold(PixelCost::default(), |total, cost| PixelCost {
+            triangle_tests: total.triangle_tests + cost.triangle_tests,
+            node_traversals: total.node_traversals + cost.node_traversals,
+            secondary_rays: total.secondary_rays + cost.secondary_rays,
+            time: total.time + cost.time,
+        })
+    }
+}
+
+/// Gradient that values are mapped to, from low to high
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ColorMap {
+    /// Black to white
+    Grayscale,
+    /// Black over red and yellow to white
+    #[default]
+    Heat,
+    /// Dark purple over blue and green to yellow, perceptually uniform and readable with color vision deficiencies
+    Viridis,
+}
+
+impl ColorMap {
+    /// Color at `t` between 0 and 1
+    pub fn color(&self, t: Float) -> (u8, u8, u8) {
+        const GRAYSCALE: [(u8, u8, u8); 2] = [(0, 0, 0), (255, 255, 255)];
+        const HEAT: [(u8, u8, u8); 5] = [(0, 0, 0), (128, 0, 0), (255, 64, 0), (255, 200, 0), (255, 255, 255)];
+        const VIRIDIS: [(u8, u8, u8); 5] = [(68, 1, 84), (59, 82, 139), (33, 145, 140), (94, 201, 98), (253, 231, 37)];
+
+        let stops: &[(u8, u8, u8)] = match self {
+            ColorMap::Grayscale => &GRAYSCALE,
+            ColorMap::Heat => &HEAT,
+            ColorMap::Viridis => &VIRIDIS,
+        };
+
+        let position = t.clamp(0.0, 1.0) * (stops.len() - 1) as Float;
+        let index = (position as usize).min(stops.len() - 2);
+        let fraction = position - index as Float;
+        let lerp = |a: u8, b: u8| (a as Float + (b as Float - a as Float) * fraction).round() as u8;
+        let (a, b) = (stops[index], stops[index + 1]);
+        (lerp(a.0, b.0), lerp(a.1, b.1), lerp(a.2, b.2))
+    }
+}
+
+/// Turns one metric of a `CostBuffer` into a false-color image
+#[derive(Clone, Debug, Default)]
+pub struct Heatmap {
+    pub color_map: ColorMap,
+    /// Value mapped to the top of the color map, `None` for the largest value in the image
+    ///
+    /// A fixed value makes heatmaps of different frames or scenes comparable.
+    pub max: Option<Float>,
+    /// Map the logarithm of 1 + the value, so that differences between cheap pixels stay visible next to expensive
+    /// ones
+    pub logarithmic: bool,
+}
+
+impl Heatmap {
+    pub fn render(&self, costs: &CostBuffer, metric: CostMetric) -> RgbImage {
+        let scale = |value: Float| if self.logarithmic { float::ln(1.0 + value) } else { value };
+
+        let max = self.max.unwrap_or_else(|| costs.costs.iter().map(|cost| cost.value(metric)).fold(0.0, Float::max));
+        let max = scale(max);
+
+        let mut img = RgbImage::new(costs.width(), costs.height());
+        for y in 0..costs.height() {
+            for x in 0..costs.width() {
+                let value = scale(costs.get_pixel(x, y).value(metric));
+                let t = if max > 0.0 { value / max } else { 0.0 };
+                img.put_pixel(x, y, &self.color_map.color(t));
+            }
+        }
+        img
+    }
+}
+
+/// A heatmap of each `CostMetric`, returned by `Renderer::render_heatmaps()`
+pub struct Heatmaps {
+    pub triangle_tests: RgbImage,
+    pub node_traversals: RgbImage,
+    pub secondary_rays: RgbImage,
+    pub time: RgbImage,
+}
+
+thread_local! {
+    /// Cost of the pixel being measured by `Renderer::render_costs()` on this thread
+    static RECORDING: RefCell<Option<PixelCost>> = const { RefCell::new(None) };
+}
+
+/// Measure the cost of everything traced on this thread while `f` runs
+pub(crate) fn record(f: impl FnOnce()) -> PixelCost {
+    /// Stops the recording again even if `f` panics
+    struct RecordingGuard;
+
+    impl Drop for RecordingGuard {
+        fn drop(&mut self) {
+            RECORDING.with(|recording| recording.borrow_mut().take());
+        }
+    }
+
+    RECORDING.with(|recording| *recording.borrow_mut() = Some(PixelCost::default()));
+    let guard = RecordingGuard;
+    let start = Instant::now();
+    f();
+    let time = start.elapsed();
+    let cost = RECORDING.with(|recording| recording.borrow_mut().take()).unwrap_or_default();
+    drop(guard);
+    PixelCost { time, ..cost }
+}
+
+/// Add a traced ray to the cost being recorded, if any
+pub(crate) fn record_ray(ray_type: RayType, triangle_tests: usize, node_traversals: usize) {
+    RECORDING.with(|recording| {
+        if let Some(cost) = recording.borrow_mut().as_mut() {
+            cost.triangle_tests += triangle_tests;
+            cost.node_traversals += node_traversals;
+            if ray_type != RayType::Primary {
+                cost.secondary_rays += 1;
+            }
+        }
+    });
+}
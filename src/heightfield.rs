@@ -0,0 +1,400 @@
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize, Deserializer};
+use cgmath::{Point3, Vector2, Vector3, InnerSpace};
+
+use crate::ray::{Ray, RayDebugData, Hit, Interval};
+use crate::aabb::AABB;
+use crate::color::Color;
+use crate::image::RgbImage;
+use crate::mesh::{intersect_triangle, TriangleHit};
+use crate::asset_loader::{self, AssetLoader};
+use crate::math_util::Float;
+use crate::error::RaytracerError;
+
+fn default_height() -> Float {
+    1.0
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeserializableHeightfield {
+    path: PathBuf,
+    #[serde(default = "default_height")]
+    height: Float,
+}
+
+impl From<Heightfield> for DeserializableHeightfield {
+    fn from(heightfield: Heightfield) -> DeserializableHeightfield {
+        DeserializableHeightfield {
+            path: heightfield.path,
+            height: heightfield.height,
+        }
+    }
+}
+
+/// Terrain whose elevation is read from a grayscale image
+///
+/// The image is stretched over the square from (-1, -1) to (1, 1) in the XZ plane, with its top row at Z = -1. Each
+/// pixel is a grid vertex that is raised by its brightness times `height`, black pixels stay at Y = 0. Every grid
+/// cell is split into two triangles, which are found by walking the cells below the ray instead of building an
+/// acceleration structure, so even huge terrains need little more memory than the image itself.
+///
+/// Texture coordinates map the whole image onto the terrain, so a color texture of the same layout lines up with it.
+#[derive(Clone, Serialize)]
+#[serde(into = "DeserializableHeightfield")]
+pub struct Heightfield {
+    path: PathBuf,
+    /// Elevation of white pixels
+    height: Float,
+    /// Normalized elevation of each grid vertex, row by row; shared between clones
+    elevations: Arc<Vec<Float>>,
+    columns: usize,
+    rows: usize,
+}
+
+impl<'de> Deserialize<'de> for Heightfield {
+    fn deserialize<D>(deserializer: D) -> Result<Heightfield, D::Error>
+        where
+            D: Deserializer<'de>
+    {
+        let d = DeserializableHeightfield::deserialize(deserializer)?;
+        Self::load(d.path.clone(), d.height).map_err(|err| {
+            serde::de::Error::custom(format!("Unable to open heightfield image \"{}\": {}", d.path.display(), err))
+        })
+    }
+}
+
+impl Heightfield {
+    /// Build the terrain from an image that was loaded from `path`
+    ///
+    /// Images smaller than 2x2 pixels don't contain a single grid cell and result in a terrain that is never hit.
+    pub fn new(path: PathBuf, img: &RgbImage, height: Float) -> Heightfield {
+        let mut elevations = Vec::with_capacity(img.width() * img.height());
+        for y in 0..img.height() {
+            for x in 0..img.width() {
+                // Elevations are data, not colors, so they are not sRGB decoded
+                elevations.push(Color::from_u8(&img.get_pixel(x, y)).luminance());
+            }
+        }
+
+        Heightfield {
+            path,
+            height,
+            elevations: Arc::new(elevations),
+            columns: img.width(),
+            rows: img.height(),
+        }
+    }
+
+    pub fn load(path: PathBuf, height: Float) -> Result<Heightfield, RaytracerError> {
+        let img = asset_loader::load_image(&path)?;
+        Ok(Heightfield::new(path, &img, height))
+    }
+
+    /// Like `load()`, but load the image through `loader` instead of the current asset loader
+    pub fn load_with(path: PathBuf, height: Float, loader: &dyn AssetLoader) -> Result<Heightfield, RaytracerError> {
+        let img = loader.load_image(&path).map_err(|err| RaytracerError::asset(&path, err))?;
+        Ok(Heightfield::new(path, &img, height))
+    }
+
+    /// Load the image again from the same path through the current asset loader
+    pub fn reload(&mut self) -> Result<(), RaytracerError> {
+        *self = Heightfield::load(self.path.clone(), self.height)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn height(&self) -> Float {
+        self.height
+    }
+
+    /// Number of grid cells in X and Z direction
+    fn cell_counts(&self) -> (usize, usize) {
+        (self.columns.saturating_sub(1), self.rows.saturating_sub(1))
+    }
+
+    pub fn bounding_box(&self) -> AABB {
+        AABB::new(&Point3::new(-1.0, 0.0, -1.0), &Point3::new(1.0, self.height.max(0.0), 1.0))
+    }
+
+    /// Position of a grid vertex
+    fn vertex(&self, column: usize, row: usize) -> Vector3<Float> {
+        let (cells_x, cells_z) = self.cell_counts();
+        Vector3::new(
+            -1.0 + 2.0 * column as Float / cells_x as Float,
+            self.elevations[row * self.columns + column] * self.height,
+            -1.0 + 2.0 * row as Float / cells_z as Float,
+        )
+    }
+
+    /// Smooth normal at a grid vertex from the central differences of the neighbouring elevations
+    fn vertex_normal(&self, column: usize, row: usize) -> Vector3<Float> {
+        let left = self.vertex(column.saturating_sub(1), row);
+        let right = self.vertex((column + 1).min(self.columns - 1), row);
+        let back = self.vertex(column, row.saturating_sub(1));
+        let front = self.vertex(column, (row + 1).min(self.rows - 1));
+        (front - back).cross(right - left).normalize()
+    }
+
+    /// Grid coordinates of a point in the XZ plane, in cells
+    fn grid_coordinates(&self, x: Float, z: Float) -> (Float, Float) {
+        let (cells_x, cells_z) = self.cell_counts();
+        ((x + 1.0) * 0.5 * cells_x as Float, (z + 1.0) * 0.5 * cells_z as Float)
+    }
+
+    /// Elevation of the surface above a point in the XZ plane, which has to lie within the terrain
+    fn surface_height(&self, x: Float, z: Float) -> Float {
+        let (cells_x, cells_z) = self.cell_counts();
+        let (grid_x, grid_z) = self.grid_coordinates(x, z);
+        let column = (grid_x.floor().max(0.0) as usize).min(cells_x - 1);
+        let row = (grid_z.floor().max(0.0) as usize).min(cells_z - 1);
+        let fx = grid_x - column as Float;
+        let fz = grid_z - row as Float;
+
+        let h00 = self.vertex(column, row).y;
+        let h10 = self.vertex(column + 1, row).y;
+        let h01 = self.vertex(column, row + 1).y;
+        let h11 = self.vertex(column + 1, row + 1).y;
+        // Interpolate within the triangle the point lies in, the diagonal runs from (0, 0) to (1, 1)
+        if fx >= fz {
+            h00 + (h10 - h00) * fx + (h11 - h10) * fz
+        } else {
+            h00 + (h11 - h01) * fx + (h01 - h00) * fz
+        }
+    }
+
+    /// Visit the grid cells below the ray in the order the ray passes over them, from `t_start` to `t_end`, until
+    /// `visit` returns `false`
+    fn traverse(&self, ray: &Ray, t_start: Float, t_end: Float, mut visit: impl FnMut(usize, usize) -> bool) {
+        let (cells_x, cells_z) = self.cell_counts();
+        let (origin_x, origin_z) = self.grid_coordinates(ray.origin.x, ray.origin.z);
+        // Change of the grid coordinates per unit of distance along the ray
+        let direction_x = ray.direction.x * 0.5 * cells_x as Float;
+        let direction_z = ray.direction.z * 0.5 * cells_z as Float;
+
+        // 2D-DDA (Amanatides & Woo)
+        let start_x = origin_x + direction_x * t_start;
+        let start_z = origin_z + direction_z * t_start;
+        let mut column = (start_x.floor().max(0.0) as usize).min(cells_x - 1) as isize;
+        let mut row = (start_z.floor().max(0.0) as usize).min(cells_z - 1) as isize;
+
+        let setup = |cell: isize, origin: Float, direction: Float| {
+            if direction > 0.0 {
+                (1, (cell as Float + 1.0 - origin) / direction, 1.0 / direction)
+            } else if direction < 0.0 {
+                (-1, (cell as Float - origin) / direction, -1.0 / direction)
+            } else {
+                (0, Float::INFINITY, Float::INFINITY)
+            }
+        };
+        let (step_x, mut t_next_x, t_delta_x) = setup(column, origin_x, direction_x);
+        let (step_z, mut t_next_z, t_delta_z) = setup(row, origin_z, direction_z);
+
+        let mut lookups = 0;
+        loop {
+            lookups += 1;
+            if !visit(column as usize, row as usize) {
+                break;
+            }
+
+            if t_next_x < t_next_z {
+                if t_next_x > t_end {
+                    break;
+                }
+                column += step_x;
+                t_next_x += t_delta_x;
+            } else {
+                if t_next_z > t_end {
+                    break;
+                }
+                row += step_z;
+                t_next_z += t_delta_z;
+            }
+            if column < 0 || column >= cells_x as isize || row < 0 || row >= cells_z as isize {
+                break;
+            }
+        }
+
+        RayDebugData::record(RayDebugData {
+            kd_tree_lookups: 0,
+            node_traversals: lookups,
+            triangle_tests: 2 * lookups,
+        });
+    }
+
+    /// The two triangles of a grid cell as grid vertex indices, both wound counterclockwise when seen from above
+    fn cell_triangles(column: usize, row: usize) -> [[(usize, usize); 3]; 2] {
+        [
+            [(column, row), (column + 1, row + 1), (column + 1, row)],
+            [(column, row), (column, row + 1), (column + 1, row + 1)],
+        ]
+    }
+
+    /// Intersect the two triangles of a grid cell, returning the closer hit
+    fn intersect_cell(&self, ray: &Ray, column: usize, row: usize) -> Option<Hit> {
+        Heightfield::cell_triangles(column, row).iter()
+            .filter_map(|triangle| {
+                let [p0, p1, p2] = triangle.map(|(c, r)| self.vertex(c, r));
+                Some((intersect_triangle(ray, &p0, &p1, &p2)?, triangle))
+            })
+            .min_by(|(a, _), (b, _)| a.distance.partial_cmp(&b.distance).unwrap())
+            .map(|(triangle_hit, triangle)| self.create_hit(ray, triangle, &triangle_hit))
+    }
+
+    /// Calculate coordinates, normal and texture coordinates of a hit point on a triangle of the grid
+    fn create_hit(&self, ray: &Ray, triangle: &[(usize, usize); 3], triangle_hit: &TriangleHit) -> Hit {
+        let [p0, p1, p2] = triangle.map(|(c, r)| self.vertex(c, r));
+        let [n0, n1, n2] = triangle.map(|(c, r)| self.vertex_normal(c, r));
+        let (u, v) = (triangle_hit.u, triangle_hit.v);
+        let point = ray.origin + ray.direction * triangle_hit.distance;
+
+        let geometric_normal = (p1 - p0).cross(p2 - p0).normalize();
+        let normal = (n0 * (1.0 - u - v) + n1 * u + n2 * v).normalize();
+
+        let (grid_x, grid_z) = self.grid_coordinates(point.x, point.z);
+        let (cells_x, cells_z) = self.cell_counts();
+        let tex_coords = Vector2::new(grid_x / cells_x as Float, grid_z / cells_z as Float);
+
+        // U and V follow X and Z across the whole terrain, which spans two units; the triangles are never vertical
+        let dpdu = Vector3::new(2.0, -2.0 * geometric_normal.x / geometric_normal.y, 0.0);
+        let dpdv = Vector3::new(0.0, -2.0 * geometric_normal.z / geometric_normal.y, 2.0);
+
+        Hit::new(point, triangle_hit.distance, normal, tex_coords, dpdu, dpdv).with_triangle_edges(&p0, &p1, &p2)
+    }
+
+    pub fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (cells_x, cells_z) = self.cell_counts();
+        if cells_x == 0 || cells_z == 0 {
+            return None;
+        }
+        let (t_min, t_max) = self.bounding_box().intersects_p(ray)?;
+        let t_start = t_min.max(0.0);
+
+        let mut closest_hit = None;
+        self.traverse(ray, t_start, t_max, |column, row| {
+            // Skip cells whose triangles lie entirely above or below the ray
+            let corners = [(column, row), (column + 1, row), (column, row + 1), (column + 1, row + 1)];
+            let (low, high) = corners.iter().fold((Float::INFINITY, -Float::INFINITY), |(low, high), &(c, r)| {
+                let elevation = self.vertex(c, r).y;
+                (low.min(elevation), high.max(elevation))
+            });
+            let (cell_enter, cell_exit) = self.cell_distances(ray, column, row, t_start, t_max);
+            let ray_low = (ray.origin.y + ray.direction.y * cell_enter).min(ray.origin.y + ray.direction.y * cell_exit);
+            let ray_high = (ray.origin.y + ray.direction.y * cell_enter).max(ray.origin.y + ray.direction.y * cell_exit);
+            if ray_low > high || ray_high < low {
+                return true;
+            }
+
+            // The cells don't overlap, so the first cell with a hit contains the closest one
+            closest_hit = self.intersect_cell(ray, column, row);
+            closest_hit.is_none()
+        });
+        closest_hit
+    }
+
+    /// Distances along the ray at which it passes over a grid cell, limited to `t_start` and `t_end`
+    fn cell_distances(&self, ray: &Ray, column: usize, row: usize, t_start: Float, t_end: Float) -> (Float, Float) {
+        let min = self.vertex(column, row);
+        let max = self.vertex(column + 1, row + 1);
+        let slab = |origin: Float, direction: Float, min: Float, max: Float| {
+            if direction == 0.0 {
+                (-Float::INFINITY, Float::INFINITY)
+            } else {
+                let t1 = (min - origin) / direction;
+                let t2 = (max - origin) / direction;
+                (t1.min(t2), t1.max(t2))
+            }
+        };
+        let (x_enter, x_exit) = slab(ray.origin.x, ray.direction.x, min.x, max.x);
+        let (z_enter, z_exit) = slab(ray.origin.z, ray.direction.z, min.z, max.z);
+        (x_enter.max(z_enter).max(t_start), x_exit.min(z_exit).min(t_end))
+    }
+
+    /// Sections of the ray inside the solid between the surface and the base at Y = 0
+    ///
+    /// Like `Mesh::intersect_interval()`, this walks every cell below the ray, so it is meant for CSG, volumes and
+    /// tools rather than for tracing every ray.
+    pub fn intersect_interval(&self, ray: &Ray) -> Vec<Interval> {
+        let (cells_x, cells_z) = self.cell_counts();
+        if cells_x == 0 || cells_z == 0 {
+            return Vec::new();
+        }
+        let bounding_box = self.bounding_box();
+        let (t_min, t_max) = match bounding_box.intersects_p(ray) {
+            Some(distances) => distances,
+            None => return Vec::new(),
+        };
+        let t_start = t_min.max(0.0);
+
+        let mut crossings = Vec::new();
+        self.traverse(ray, t_start, t_max, |column, row| {
+            let mut cell_crossings: Vec<_> = Heightfield::cell_triangles(column, row).iter()
+                .filter_map(|triangle| {
+                    let [p0, p1, p2] = triangle.map(|(c, r)| self.vertex(c, r));
+                    let triangle_hit = intersect_triangle(ray, &p0, &p1, &p2)?;
+                    let is_entry = (p1 - p0).cross(p2 - p0).dot(ray.direction) < 0.0;
+                    Some((self.create_hit(ray, triangle, &triangle_hit), is_entry))
+                })
+                .collect();
+            cell_crossings.sort_by(|(a, _), (b, _)| a.cmp(b));
+            crossings.extend(cell_crossings);
+            true
+        });
+
+        let is_below_surface = |t: Float| {
+            let point = ray.origin + ray.direction * t;
+            point.y <= self.surface_height(point.x, point.z)
+        };
+
+        let mut intervals = Vec::new();
+        // Entry of the interval the ray is currently in, `Some(None)` if the ray started inside
+        let mut current_entry = if is_below_surface(t_start) {
+            Some(if t_min > 0.0 { Some(self.box_hit(ray, t_min)) } else { None })
+        } else {
+            None
+        };
+        for (hit, is_entry) in crossings {
+            match (&current_entry, is_entry) {
+                (None, true) => current_entry = Some(Some(hit)),
+                (Some(_), false) => intervals.push(Interval {
+                    entry: current_entry.take().unwrap(),
+                    exit: Some(hit),
+                }),
+                _ => {}
+            }
+        }
+        if let Some(entry) = current_entry {
+            intervals.push(Interval { entry, exit: Some(self.box_hit(ray, t_max)) });
+        }
+
+        intervals
+    }
+
+    /// Hit on the side walls or the base of the solid below the terrain
+    fn box_hit(&self, ray: &Ray, distance: Float) -> Hit {
+        let point = ray.origin + ray.direction * distance;
+        let (grid_x, grid_z) = self.grid_coordinates(point.x, point.z);
+        let (cells_x, cells_z) = self.cell_counts();
+        let tex_coords = Vector2::new(grid_x / cells_x as Float, grid_z / cells_z as Float);
+
+        // The face closest to the point is the one that was hit
+        let faces = [
+            ((point.x + 1.0).abs(), -Vector3::unit_x()),
+            ((point.x - 1.0).abs(), Vector3::unit_x()),
+            ((point.z + 1.0).abs(), -Vector3::unit_z()),
+            ((point.z - 1.0).abs(), Vector3::unit_z()),
+            (point.y.abs(), -Vector3::unit_y()),
+        ];
+        let normal = faces.iter()
+            .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap())
+            .map(|&(_, normal)| normal)
+            .unwrap();
+
+        Hit::new(point, distance, normal, tex_coords, Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 2.0))
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use crate::ray::Ray;
+use crate::math_util::Float;
+use crate::stats::RayType;
+
+/// Quantized ray origin, direction and maximum distance together with the exact time and the ray type, used as the hash
+/// grid key
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    origin: (i32, i32, i32),
+    direction: (i32, i32, i32),
+    max_distance: i32,
+    /// Bits of the time, animated objects are only at the same place at the same time
+    time: Option<u64>,
+    /// Objects and materials can be visible to some ray types only
+    ray_type: RayType,
+}
+
+/// Caches which object recent rays hit in a hash grid over ray origin and direction, see `Scene::trace_cached()`
+///
+/// Rays whose origins and directions fall into the same grid cells share one cache entry, so this is only useful
+/// for workloads that repeatedly query a static scene with (nearly) identical rays, e.g. baking or sensor simulations.
+/// Only the index of the hit object is cached, which is intersected with each ray to get its own hit; if that misses,
+/// the ray is traced through the whole scene. The hit is therefore always exact, but near the silhouette of an object
+/// a ray may get a farther hit than the closest one, and rays next to ones that missed everything are assumed to miss
+/// as well. The cache empties itself whenever the scene changes in a way that affects which surfaces rays hit, i.e. when
+/// objects are edited, materials change their visibility or opacity or clipping planes are edited.
+pub struct HitCache {
+    /// Size of a grid cell for ray origins, in world units
+    origin_resolution: Float,
+    /// Size of a grid cell for the components of (normalized) ray directions
+    direction_resolution: Float,
+    /// Maximum number of entries; the cache is cleared entirely when it is full
+    capacity: usize,
+    /// Index of the hit object, `None` for rays that missed
+    entries: HashMap<CacheKey, Option<usize>>,
+    /// Fingerprint of the scene state that the entries were traced in, see `Scene::trace_cached()`
+    scene_state: Option<u64>,
+    hits: usize,
+    misses: usize,
+}
+
+impl HitCache {
+    pub fn new(origin_resolution: Float, direction_resolution: Float, capacity: usize) -> HitCache {
+        HitCache {
+            origin_resolution,
+            direction_resolution,
+            capacity,
+            entries: HashMap::new(),
+            scene_state: None,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    #[allow(clippy::useless_conversion)]
+    fn key(&self, ray: &Ray, ray_type: RayType) -> CacheKey {
+        let quantize = |value: Float, resolution: Float| (value / resolution).floor() as i32;
+
+        CacheKey {
+            origin: (
+                quantize(ray.origin.x, self.origin_resolution),
+                quantize(ray.origin.y, self.origin_resolution),
+                quantize(ray.origin.z, self.origin_resolution),
+            ),
+            direction: (
+                quantize(ray.direction.x, self.direction_resolution),
+                quantize(ray.direction.y, self.direction_resolution),
+                quantize(ray.direction.z, self.direction_resolution),
+            ),
+            // Saturates for the infinite distance of most rays
+            max_distance: quantize(ray.max_distance, self.origin_resolution),
+            time: ray.time.map(|time| u64::from(time.to_bits())),
+            ray_type,
+        }
+    }
+
+    /// Look up the cached result for a ray of type `ray_type`
+    ///
+    /// Returns `None` if there is no entry, `Some(None)` if the cached ray didn't hit anything and `Some(Some(index))`
+    /// with the index of the hit object otherwise
+    pub fn get(&self, ray: &Ray, ray_type: RayType) -> Option<Option<usize>> {
+        self.entries.get(&self.key(ray, ray_type)).copied()
+    }
+
+    /// Store the result of an intersection test, the index of the hit object or `None` if the ray missed
+    pub fn insert(&mut self, ray: &Ray, ray_type: RayType, result: Option<usize>) {
+        if self.entries.len() >= self.capacity {
+            // Dropping everything is crude but never leaves stale entries behind
+            self.entries.clear();
+        }
+
+        let key = self.key(ray, ray_type);
+        self.entries.insert(key, result);
+    }
+
+    /// Remove all entries; happens automatically when the cache is used with another scene or the objects change
+    pub fn invalidate(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Empty the cache if its entries were traced in another state of the scene
+    pub(crate) fn sync(&mut self, scene_state: u64) {
+        if self.scene_state != Some(scene_state) {
+            self.entries.clear();
+            self.scene_state = Some(scene_state);
+        }
+    }
+
+    /// Count a lookup for `hits()` or `misses()`
+    pub(crate) fn record_lookup(&mut self, answered: bool) {
+        if answered {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of lookups that were answered from the cache
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// Number of lookups that required a full intersection test
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Point3, Vector3};
+
+    use super::*;
+    use crate::validation::ReferenceScene;
+
+    #[test]
+    fn material_edits_invalidate_misses() {
+        let mut scene = ReferenceScene::Furnace.scene();
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), -Vector3::unit_z());
+        let mut cache = HitCache::new(0.1, 0.01, 16);
+
+        scene.materials[0].visibility.visible_to_camera = false;
+        assert!(scene.trace_cached(&ray, RayType::Primary, &mut cache).is_none());
+        // Shadow rays still see the sphere and don't share the cached miss
+        assert!(scene.trace_cached(&ray, RayType::Shadow, &mut cache).is_some());
+
+        scene.materials[0].visibility.visible_to_camera = true;
+        assert!(scene.trace_cached(&ray, RayType::Primary, &mut cache).is_some());
+    }
+}
@@ -1,4 +1,6 @@
 
+use crate::math_util::{float, Float};
+
 #[derive(Clone)]
 pub struct RgbImage {
     width: usize,
@@ -51,6 +53,19 @@ impl RgbImage {
         self.data[index + 2] = color.2;
     }
 
+    /// Copy all pixels of `src` into this image, with the top left corner of `src` at (`x`, `y`)
+    ///
+    /// Pixels that would lie outside of this image are ignored
+    pub fn copy_from(&mut self, src: &RgbImage, x: usize, y: usize) {
+        let w = src.width.min(self.width.saturating_sub(x));
+        let h = src.height.min(self.height.saturating_sub(y));
+        for src_y in 0..h {
+            let src_index = src.pixel_index(0, src_y);
+            let dst_index = self.pixel_index(x, y + src_y);
+            self.data[dst_index..(dst_index + w * 3)].copy_from_slice(&src.data[src_index..(src_index + w * 3)]);
+        }
+    }
+
     pub fn get_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
         let index = self.pixel_index(x, y);
         (
@@ -59,4 +74,280 @@ impl RgbImage {
             self.data[index + 2],
         )
     }
+
+    /// Like `put_pixel()`, but ignore pixels outside of the image
+    fn put_pixel_clipped(&mut self, x: usize, y: usize, color: &(u8, u8, u8)) {
+        if x < self.width && y < self.height {
+            self.put_pixel(x, y, color);
+        }
+    }
+
+    /// Draw a one pixel wide line from (`x0`, `y0`) to (`x1`, `y1`), including both end points
+    ///
+    /// Like all drawing functions, this is meant for annotating images for debugging; parts outside of the image are
+    /// ignored.
+    pub fn draw_line(&mut self, x0: usize, y0: usize, x1: usize, y1: usize, color: &(u8, u8, u8)) {
+        // Bresenham's algorithm
+        let (x1, y1) = (x1 as isize, y1 as isize);
+        let (mut x, mut y) = (x0 as isize, y0 as isize);
+        let dx = (x1 - x).abs();
+        let dy = -(y1 - y).abs();
+        let step_x = if x < x1 { 1 } else { -1 };
+        let step_y = if y < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        loop {
+            self.put_pixel_clipped(x as usize, y as usize, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let error2 = 2 * error;
+            if error2 >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if error2 <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Draw an antialiased line `width` pixels wide with round ends from (`x0`, `y0`) to (`x1`, `y1`), blending it over
+    /// the image
+    ///
+    /// The coordinates may be fractional, with pixel centers at whole numbers.
+    pub fn draw_line_smooth(&mut self, x0: Float, y0: Float, x1: Float, y1: Float, width: Float, color: &(u8, u8, u8)) {
+        // Pixels whose center is closer than this to the line are at least partially covered
+        let reach = width * 0.5 + 0.5;
+
+        // Walk along the axis along which the line is longer, so that every step covers a short span of pixels
+        let steep = (y1 - y0).abs() > (x1 - x0).abs();
+        let (a0, b0, a1, b1) = if steep { (y0, x0, y1, x1) } else { (x0, y0, x1, y1) };
+        let (major_size, minor_size) = if steep { (self.height, self.width) } else { (self.width, self.height) };
+        let (da, db) = (a1 - a0, b1 - b0);
+        let length_squared = da * da + db * db;
+        // Extent of the covered span across the line per unit of perpendicular reach
+        let span_factor = if da != 0.0 { length_squared.sqrt() / da.abs() } else { 1.0 };
+
+        let clamp_to = |value: Float, size: usize| value.clamp(0.0, size as Float) as usize;
+        let major_start = clamp_to((a0.min(a1) - reach).floor(), major_size);
+        let major_end = clamp_to((a0.max(a1) + reach).ceil() + 1.0, major_size);
+        for a in major_start..major_end {
+            let t_line = if da != 0.0 { ((a as Float - a0) / da).clamp(0.0, 1.0) } else { 0.0 };
+            let b_center = b0 + db * t_line;
+            let minor_start = clamp_to((b_center - reach * span_factor).floor(), minor_size);
+            let minor_end = clamp_to((b_center + reach * span_factor).ceil() + 1.0, minor_size);
+            for b in minor_start..minor_end {
+                // Distance from the pixel center to the closest point on the line
+                let (pa, pb) = (a as Float - a0, b as Float - b0);
+                let t = if length_squared > 0.0 { ((pa * da + pb * db) / length_squared).clamp(0.0, 1.0) } else { 0.0 };
+                let distance = (float::powi(pa - da * t, 2) + float::powi(pb - db * t, 2)).sqrt();
+                let coverage = (reach - distance).clamp(0.0, 1.0);
+                if coverage > 0.0 {
+                    let (x, y) = if steep { (b, a) } else { (a, b) };
+                    let old = self.get_pixel(x, y);
+                    let blend = |old: u8, new: u8| (old as Float + (new as Float - old as Float) * coverage).round() as u8;
+                    self.put_pixel(x, y, &(blend(old.0, color.0), blend(old.1, color.1), blend(old.2, color.2)));
+                }
+            }
+        }
+    }
+
+    /// Draw the one pixel wide outline of the `w` × `h` rectangle with its top left corner at (`x`, `y`)
+    pub fn draw_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: &(u8, u8, u8)) {
+        if w == 0 || h == 0 {
+            return;
+        }
+        let (right, bottom) = (x + w - 1, y + h - 1);
+        self.draw_line(x, y, right, y, color);
+        self.draw_line(x, bottom, right, bottom, color);
+        self.draw_line(x, y, x, bottom, color);
+        self.draw_line(right, y, right, bottom, color);
+    }
+
+    /// Fill the `w` × `h` rectangle with its top left corner at (`x`, `y`)
+    pub fn fill_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: &(u8, u8, u8)) {
+        for rect_y in y..(y + h).min(self.height) {
+            for rect_x in x..(x + w).min(self.width) {
+                self.put_pixel(rect_x, rect_y, color);
+            }
+        }
+    }
+
+    /// Draw `text` with its top left corner at (`x`, `y`) using a built-in 5x7 pixel font
+    ///
+    /// Each font pixel covers `scale` × `scale` image pixels. Glyphs are `6 * scale` pixels apart and lines
+    /// `9 * scale` pixels. The font covers printable ASCII with lowercase letters drawn as uppercase ones; other
+    /// characters are drawn as '?'.
+    pub fn blit_text(&mut self, x: usize, y: usize, text: &str, scale: usize, color: &(u8, u8, u8)) {
+        let scale = scale.max(1);
+        let mut glyph_x = x;
+        let mut glyph_y = y;
+        for c in text.chars() {
+            if c == '\n' {
+                glyph_x = x;
+                glyph_y += 9 * scale;
+                continue;
+            }
+
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for column in 0..5 {
+                    if bits & (0b10000 >> column) != 0 {
+                        self.fill_rect(glyph_x + column * scale, glyph_y + row * scale, scale, scale, color);
+                    }
+                }
+            }
+            glyph_x += 6 * scale;
+        }
+    }
+}
+
+/// Rows of the glyph for a character in `FONT`, top to bottom with the most significant of the 5 bits on the left
+fn glyph(c: char) -> &'static [u8; 7] {
+    let index = match c.to_ascii_uppercase() {
+        c @ ' '..='`' => c as usize - ' ' as usize,
+        c @ '{'..='~' => c as usize - '{' as usize + 65,
+        _ => '?' as usize - ' ' as usize,
+    };
+    &FONT[index]
+}
+
+/// 5x7 pixel glyphs for the characters ' ' to '`' followed by '{' to '~'
+const FONT: [[u8; 7]; 69] = [
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000], // ' '
+    [0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000, 0b00100], // '!'
+    [0b01010, 0b01010, 0b01010, 0b00000, 0b00000, 0b00000, 0b00000], // '"'
+    [0b01010, 0b01010, 0b11111, 0b01010, 0b11111, 0b01010, 0b01010], // '#'
+    [0b00100, 0b01111, 0b10100, 0b01110, 0b00101, 0b11110, 0b00100], // '$'
+    [0b11000, 0b11001, 0b00010, 0b00100, 0b01000, 0b10011, 0b00011], // '%'
+    [0b01100, 0b10010, 0b10100, 0b01000, 0b10101, 0b10010, 0b01101], // '&'
+    [0b00100, 0b00100, 0b01000, 0b00000, 0b00000, 0b00000, 0b00000], // "'"
+    [0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00100, 0b00010], // '('
+    [0b01000, 0b00100, 0b00010, 0b00010, 0b00010, 0b00100, 0b01000], // ')'
+    [0b00000, 0b00100, 0b10101, 0b01110, 0b10101, 0b00100, 0b00000], // '*'
+    [0b00000, 0b00100, 0b00100, 0b11111, 0b00100, 0b00100, 0b00000], // '+'
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b00100, 0b01000], // ','
+    [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000], // '-'
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100], // '.'
+    [0b00000, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b00000], // '/'
+    [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110], // '0'
+    [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110], // '1'
+    [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111], // '2'
+    [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110], // '3'
+    [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010], // '4'
+    [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110], // '5'
+    [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110], // '6'
+    [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000], // '7'
+    [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110], // '8'
+    [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100], // '9'
+    [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000], // ':'
+    [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b00100, 0b01000], // ';'
+    [0b00010, 0b00100, 0b01000, 0b10000, 0b01000, 0b00100, 0b00010], // '<'
+    [0b00000, 0b00000, 0b11111, 0b00000, 0b11111, 0b00000, 0b00000], // '='
+    [0b01000, 0b00100, 0b00010, 0b00001, 0b00010, 0b00100, 0b01000], // '>'
+    [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100], // '?'
+    [0b01110, 0b10001, 0b00001, 0b01101, 0b10101, 0b10101, 0b01110], // '@'
+    [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001], // 'A'
+    [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110], // 'B'
+    [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110], // 'C'
+    [0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100], // 'D'
+    [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111], // 'E'
+    [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000], // 'F'
+    [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111], // 'G'
+    [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001], // 'H'
+    [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110], // 'I'
+    [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100], // 'J'
+    [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001], // 'K'
+    [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111], // 'L'
+    [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001], // 'M'
+    [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001], // 'N'
+    [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110], // 'O'
+    [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000], // 'P'
+    [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101], // 'Q'
+    [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001], // 'R'
+    [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110], // 'S'
+    [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100], // 'T'
+    [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110], // 'U'
+    [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100], // 'V'
+    [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010], // 'W'
+    [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001], // 'X'
+    [0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100, 0b00100], // 'Y'
+    [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111], // 'Z'
+    [0b01110, 0b01000, 0b01000, 0b01000, 0b01000, 0b01000, 0b01110], // '['
+    [0b00000, 0b10000, 0b01000, 0b00100, 0b00010, 0b00001, 0b00000], // '\\'
+    [0b01110, 0b00010, 0b00010, 0b00010, 0b00010, 0b00010, 0b01110], // ']'
+    [0b00100, 0b01010, 0b10001, 0b00000, 0b00000, 0b00000, 0b00000], // '^'
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111], // '_'
+    [0b01000, 0b00100, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000], // '`'
+    [0b00010, 0b00100, 0b00100, 0b01000, 0b00100, 0b00100, 0b00010], // '{'
+    [0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100], // '|'
+    [0b01000, 0b00100, 0b00100, 0b00010, 0b00100, 0b00100, 0b01000], // '}'
+    [0b00000, 0b00000, 0b01000, 0b10101, 0b00010, 0b00000, 0b00000], // '~'
+];
+
+/// An 8-bit image with an alpha channel, e.g. a render with a transparent background
+///
+/// The color channels are not premultiplied with alpha.
+#[derive(Clone)]
+pub struct RgbaImage {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+impl RgbaImage {
+    pub fn new(w: usize, h: usize) -> RgbaImage {
+        RgbaImage {
+            width: w,
+            height: h,
+            data: vec![0; w * h * 4],
+        }
+    }
+
+    pub fn from_raw(w: usize, h: usize, mut data: Vec<u8>) -> RgbaImage {
+        data.resize(w * h * 4, 0);
+        RgbaImage {
+            width: w,
+            height: h,
+            data,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn data(&self) -> &Vec<u8> {
+        &self.data
+    }
+
+    pub fn into_raw(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn pixel_index(&self, x: usize, y: usize) -> usize {
+        (y * self.width + x) * 4
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: &(u8, u8, u8, u8)) {
+        let index = self.pixel_index(x, y);
+        self.data[index] = color.0;
+        self.data[index + 1] = color.1;
+        self.data[index + 2] = color.2;
+        self.data[index + 3] = color.3;
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> (u8, u8, u8, u8) {
+        let index = self.pixel_index(x, y);
+        (
+            self.data[index],
+            self.data[index + 1],
+            self.data[index + 2],
+            self.data[index + 3],
+        )
+    }
 }
@@ -0,0 +1,92 @@
+
+use std::ops::Range;
+
+use serde::{Serialize, Deserialize};
+
+use crate::color::Color;
+use crate::math_util::Float;
+use crate::region::Region;
+
+/// Bytes per pixel of the result of `Renderer::render_job()`: red, green and blue as little endian `f32`
+pub const JOB_BYTES_PER_PIXEL: usize = 12;
+
+/// A tile of a frame and a range of its samples per pixel, to be rendered by another thread, web worker or machine
+///
+/// Jobs are plain data that can be sent anywhere and rendered with `Renderer::render_job()`; the results are combined
+/// with `Renderer::merge_jobs()`. In deterministic builds, a job renders the same pixels wherever it runs, and the
+/// merged frame is the same whatever order the results arrive in.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RenderJob {
+    /// Identifies the scene to the application, e.g. its URL or a hash of the scene file
+    ///
+    /// The renderer doesn't interpret it; workers use it to pick the right scene before rendering the job.
+    pub scene: String,
+    /// Part of the full frame to render
+    pub region: Region,
+    /// Samples of each pixel to render, out of `Scene::aa_samples`
+    ///
+    /// Splitting the samples of the same tile over several jobs spreads a high-quality frame over more workers than
+    /// it has tiles.
+    pub samples: Range<usize>,
+    /// Mixed into the seed of the samples, 0 to render the same samples as `Renderer::render()`
+    ///
+    /// Jobs that only differ in the seed render different samples of the same pixels, e.g. to refine a frame
+    /// progressively. Ignored in builds without the `deterministic` feature.
+    pub seed: u64,
+}
+
+impl RenderJob {
+    /// All samples of a region with the default seed
+    pub fn new(scene: &str, region: Region, aa_samples: usize) -> RenderJob {
+        RenderJob {
+            scene: scene.to_string(),
+            region,
+            samples: 0..aa_samples,
+            seed: 0,
+        }
+    }
+
+    /// Jobs covering a frame in tiles of `tile_size` pixels, with the samples of each tile split into `sample_splits`
+    /// ranges of about the same length
+    pub fn split_frame(scene: &str, resolution: (usize, usize), aa_samples: usize, tile_size: usize, sample_splits: usize) -> Vec<RenderJob> {
+        let sample_splits = sample_splits.clamp(1, aa_samples.max(1));
+        Region::tiles(resolution, tile_size, Default::default()).into_iter()
+            .flat_map(|region| (0..sample_splits).map(move |split| RenderJob {
+                scene: scene.to_string(),
+                region,
+                samples: (aa_samples * split / sample_splits)..(aa_samples * (split + 1) / sample_splits),
+                seed: 0,
+            }))
+            .collect()
+    }
+
+    /// Number of samples per pixel
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+}
+
+/// Encode the pixels of a job result, see `JOB_BYTES_PER_PIXEL`
+// `Float` is only wider than `f32` with the `f64` feature
+#[allow(clippy::unnecessary_cast)]
+pub(crate) fn encode_pixels(pixels: &[Color]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(pixels.len() * JOB_BYTES_PER_PIXEL);
+    for color in pixels {
+        for component in [color.r, color.g, color.b] {
+            bytes.extend_from_slice(&(component as f32).to_le_bytes());
+        }
+    }
+    bytes
+}
+
+/// Decode the pixels of a job result, `None` if `bytes` has the wrong length
+#[allow(clippy::unnecessary_cast)]
+pub(crate) fn decode_pixels(bytes: &[u8], pixel_count: usize) -> Option<Vec<Color>> {
+    if bytes.len() != pixel_count * JOB_BYTES_PER_PIXEL {
+        return None;
+    }
+    let component = |bytes: &[u8]| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as Float;
+    Some(bytes.chunks_exact(JOB_BYTES_PER_PIXEL)
+        .map(|pixel| Color::new(component(&pixel[0..4]), component(&pixel[4..8]), component(&pixel[8..12])))
+        .collect())
+}
@@ -1,19 +1,91 @@
 mod math_util;
+mod error;
 mod color;
 mod image;
+mod hdr_image;
 mod material;
 mod ray;
 mod aabb;
 mod primitives;
+mod custom_shape;
 mod mesh;
+mod compact_mesh;
+mod mesh_primitives;
+mod subdivision;
+mod heightfield;
+mod volume;
+mod qbvh;
+mod top_level;
+mod packet;
+mod scratch;
 mod obj_parser;
+mod ply_parser;
 mod lights;
+mod environment;
+mod sky;
+mod animation;
+mod camera_path;
 mod scene;
+mod hit_cache;
+mod diagnostics;
+mod validation;
+mod stats;
+mod pixel_trace;
+mod generator;
 pub mod asset_loader;
 mod renderer;
+mod region;
+mod settings;
+mod job;
+#[cfg(feature = "network")]
+mod network;
+#[cfg(feature = "golden")]
+mod golden;
+mod denoise;
+mod post_process;
+mod heatmap;
+mod overlay;
 
-pub use image::RgbImage;
-pub use mesh::MeshData;
-pub use obj_parser::ObjParser;
-pub use scene::Scene;
-pub use renderer::Renderer;
+pub use math_util::Float;
+pub use error::RaytracerError;
+pub use color::{Color, srgb_to_linear, linear_to_srgb};
+pub use image::{RgbImage, RgbaImage};
+pub use material::{Material, Coloration, Texture, Parameter, Channel, ShadingModel, BumpMap, Translucency};
+pub use hdr_image::HdrImage;
+pub use mesh::{Mesh, MeshData, MeshStorage, IndexedTriangle, Acceleration, KDTreeOptions};
+pub use compact_mesh::{CompactMeshData, CompactMeshError, CompactMeshFileError};
+pub use subdivision::{Subdivision, SubdivisionScheme, Displacement};
+pub use heightfield::Heightfield;
+pub use volume::{Volume, DensityGrid, DensityGridError};
+pub use aabb::AABB;
+pub use top_level::TopLevelBvh;
+pub use primitives::{Plane, Sphere, SphereMapping};
+pub use ray::{Ray, Hit, Interval, UvChannel, MAX_UV_CHANNELS};
+pub use packet::PACKET_SIZE;
+pub use custom_shape::{CustomShape, RegisteredShape, ShapeParameter, ShapeParameters, register_shape};
+pub use obj_parser::{ObjParser, ObjParseError, ObjFileError};
+pub use ply_parser::{PlyParser, PlyParseError, PlyFileError};
+pub use scene::{Scene, Object, Shape, Transformation, Group, Visibility, CameraPose, Shutter, Vignetting, AmbientOcclusion, Fog, Background, Decal, ClippingPlane, RussianRoulette, Stereo, Eye};
+pub use animation::{Interpolate, Interpolation, Keyframe, Track};
+pub use camera_path::{CameraPath, CameraPathKeyframe, CameraPathParseError};
+pub use lights::{LightSampling, LightProjection};
+pub use environment::EnvironmentMap;
+pub use sky::Sky;
+pub use renderer::{Renderer, RenderMode, SceneMut, DEFAULT_AO_SAMPLES};
+pub use region::{Region, RenderedRegion, TileOrder, composite_regions};
+pub use settings::RenderSettings;
+pub use job::{RenderJob, JOB_BYTES_PER_PIXEL};
+#[cfg(feature = "network")]
+pub use network::{serve_tiles, run_worker};
+#[cfg(feature = "golden")]
+pub use golden::{GoldenScene, GoldenComparison, check_golden, diff_image, ssim, load_png, save_png, UPDATE_GOLDEN_VARIABLE};
+pub use denoise::{Aovs, Denoiser};
+pub use post_process::{Anaglyph, Bloom};
+pub use heatmap::{PixelCost, CostMetric, CostBuffer, ColorMap, Heatmap, Heatmaps};
+pub use overlay::StructureOverlay;
+pub use hit_cache::HitCache;
+pub use diagnostics::{Diagnostic, DiagnosticKind, Location, Severity};
+pub use validation::{ReferenceScene, Comparison};
+pub use stats::{RenderStats, BuildStats, BuildProgress, RayType};
+pub use pixel_trace::{PixelTrace, RaySegment, SegmentHit, LightQuery};
+pub use generator::{generate_room, generate_city, RoomParameters, CityParameters};
@@ -1,23 +1,78 @@
 
-use std::f32;
 
-use cgmath::{Vector3, Point3, InnerSpace};
+use cgmath::{Vector2, Vector3, Point3, InnerSpace};
 use serde::{Serialize, Deserialize};
+use rand::Rng;
 
 use crate::color::Color;
-use crate::math_util::deserialize_normalized;
+use crate::material::Texture;
+use crate::math_util::{deserialize_normalized, orthonormal_basis, float, Float, consts};
+
+/// Determines which lights are evaluated at a shading point
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub enum LightSampling {
+    /// Evaluate every light with one shadow ray each
+    #[default]
+    All,
+    /// Pick `count` lights with equal probability
+    Uniform { count: usize },
+    /// Pick `count` lights with a probability proportional to their brightness at the shading point
+    Power { count: usize },
+}
+
+impl LightSampling {
+    /// Choose the lights to evaluate at `point`
+    ///
+    /// Returns the light indices together with the factor their contribution has to be multiplied with, which is the
+    /// inverse of the expected number of times they are picked. A light may be picked multiple times.
+    pub fn select<R: Rng>(&self, lights: &[Light], point: &Point3<Float>, rng: &mut R) -> Vec<(usize, Float)> {
+        let reaching = lights.iter()
+            .enumerate()
+            .filter(|(_, light)| light.reaches(point));
+
+        let (count, candidates): (usize, Vec<(usize, Float)>) = match self {
+            LightSampling::All => return reaching.map(|(index, _)| (index, 1.0)).collect(),
+            LightSampling::Uniform { count } => (*count, reaching.map(|(index, _)| (index, 1.0)).collect()),
+            LightSampling::Power { count } => (*count, reaching
+                .map(|(index, light)| (index, light.intensity_at(point) * light.color_at(point).luminance()))
+                .filter(|&(_, power)| power > 0.0)
+                .collect()),
+        };
+
+        // Sampling can only add noise if every light could be evaluated anyway
+        if count >= candidates.len() {
+            return candidates.into_iter().map(|(index, _)| (index, 1.0)).collect();
+        }
+
+        let total: Float = candidates.iter().map(|&(_, weight)| weight).sum();
+        (0..count)
+            .map(|_| {
+                let mut remaining = rng.gen::<Float>() * total;
+                let &(index, weight) = candidates.iter()
+                    .find(|&&(_, weight)| {
+                        remaining -= weight;
+                        remaining < 0.0
+                    })
+                    .unwrap_or_else(|| candidates.last().unwrap());
+                (index, total / (weight * count as Float))
+            })
+            .collect()
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub enum Light {
     Directional(DirectionalLight),
     Point(PointLight),
+    Hemisphere(HemisphereLight),
 }
 
 impl Light {
-    pub fn direction_from(&self, point: &Point3<f32>) -> Vector3<f32> {
+    pub fn direction_from(&self, point: &Point3<Float>) -> Vector3<Float> {
         match self {
             Light::Directional(directional_light) => directional_light.direction_from(point),
             Light::Point(point_light) => point_light.direction_from(point),
+            Light::Hemisphere(hemisphere_light) => hemisphere_light.direction_from(point),
         }
     }
 
@@ -25,20 +80,85 @@ impl Light {
         match self {
             Light::Directional(directional_light) => directional_light.color(),
             Light::Point(point_light) => point_light.color(),
+            Light::Hemisphere(hemisphere_light) => hemisphere_light.color(),
         }
     }
 
-    pub fn intensity_at(&self, point: &Point3<f32>) -> f32 {
+    /// Color of the light arriving at `point`, which only differs from `color()` for lights with a projection
+    pub fn color_at(&self, point: &Point3<Float>) -> Color {
+        match self {
+            Light::Point(point_light) => point_light.color_at(point),
+            _ => self.color(),
+        }
+    }
+
+    pub fn intensity_at(&self, point: &Point3<Float>) -> Float {
         match self {
             Light::Directional(directional_light) => directional_light.intensity_at(point),
             Light::Point(point_light) => point_light.intensity_at(point),
+            Light::Hemisphere(hemisphere_light) => hemisphere_light.intensity_at(point),
         }
     }
 
-    pub fn distance_at(&self, point: &Point3<f32>) -> f32 {
+    pub fn distance_at(&self, point: &Point3<Float>) -> Float {
         match self {
             Light::Directional(directional_light) => directional_light.distance_at(point),
             Light::Point(point_light) => point_light.distance_at(point),
+            Light::Hemisphere(hemisphere_light) => hemisphere_light.distance_at(point),
+        }
+    }
+
+    /// Whether the light can contribute anything at `point`; if not, no shadow ray needs to be cast
+    ///
+    /// Always false for hemisphere lights, which don't cast shadows and are added to the ambient light instead.
+    pub fn reaches(&self, point: &Point3<Float>) -> bool {
+        match self {
+            Light::Directional(_) => true,
+            Light::Point(point_light) => point_light.reaches(point),
+            Light::Hemisphere(_) => false,
+        }
+    }
+
+    /// Directions towards points on the light and their distances, one shadow ray has to be cast along each
+    ///
+    /// Point lights with a radius yield several points spread over the light to produce soft shadows, all other lights
+    /// a single one.
+    pub fn shadow_samples<R: Rng>(&self, point: &Point3<Float>, rng: &mut R) -> Vec<(Vector3<Float>, Float)> {
+        match self {
+            Light::Point(point_light) if point_light.radius > 0.0 => point_light.shadow_samples(point, rng),
+            _ => vec![(self.direction_from(point), self.distance_at(point))],
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Light::Directional(directional_light) => directional_light.name.as_deref(),
+            Light::Point(point_light) => point_light.name.as_deref(),
+            Light::Hemisphere(hemisphere_light) => hemisphere_light.name.as_deref(),
+        }
+    }
+
+    /// Label for rendering the light's contribution separately, see `Renderer::render_light_groups()`
+    pub fn group(&self) -> Option<&str> {
+        match self {
+            Light::Directional(directional_light) => directional_light.group.as_deref(),
+            Light::Point(point_light) => point_light.group.as_deref(),
+            Light::Hemisphere(hemisphere_light) => hemisphere_light.group.as_deref(),
+        }
+    }
+
+    pub(crate) fn projection_texture_mut(&mut self) -> Option<&mut Texture> {
+        match self {
+            Light::Point(PointLight { projection: Some(projection), .. }) => Some(&mut projection.texture),
+            _ => None,
+        }
+    }
+
+    /// Light that arrives at a surface with the given normal from all directions, without casting shadows
+    pub fn ambient_color(&self, normal: &Vector3<Float>) -> Color {
+        match self {
+            Light::Hemisphere(hemisphere_light) => hemisphere_light.ambient_color(normal),
+            _ => Color::black(),
         }
     }
 }
@@ -47,14 +167,19 @@ impl Light {
 #[derive(Clone, Serialize, Deserialize)]
 pub struct DirectionalLight {
     #[serde(deserialize_with = "deserialize_normalized")]
-    pub direction: Vector3<f32>,
+    pub direction: Vector3<Float>,
     pub color: Color,
-    pub intensity: f32,
+    pub intensity: Float,
+    /// Used to look the light up with `Scene::light_index()`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
 }
 
 impl DirectionalLight {
     #[allow(unused_variables)]
-    fn direction_from(&self, point: &Point3<f32>) -> Vector3<f32> {
+    fn direction_from(&self, point: &Point3<Float>) -> Vector3<Float> {
         -self.direction
     }
 
@@ -63,26 +188,156 @@ impl DirectionalLight {
     }
 
     #[allow(unused_variables)]
-    fn intensity_at(&self, point: &Point3<f32>) -> f32 {
+    fn intensity_at(&self, point: &Point3<Float>) -> Float {
+        self.intensity
+    }
+
+    #[allow(unused_variables)]
+    fn distance_at(&self, point: &Point3<Float>) -> Float {
+        Float::INFINITY
+    }
+}
+
+/// Ambient light that blends between a sky and a ground color depending on which way a surface faces
+///
+/// A surface facing `up` receives the sky color, one facing down the ground color. Compared to a uniform ambient light
+/// color, this makes outdoor scenes look much less flat.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HemisphereLight {
+    pub sky_color: Color,
+    pub ground_color: Color,
+    #[serde(deserialize_with = "deserialize_normalized")]
+    pub up: Vector3<Float>,
+    pub intensity: Float,
+    /// Used to look the light up with `Scene::light_index()`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+impl HemisphereLight {
+    #[allow(unused_variables)]
+    fn direction_from(&self, point: &Point3<Float>) -> Vector3<Float> {
+        self.up
+    }
+
+    fn color(&self) -> Color {
+        self.sky_color
+    }
+
+    #[allow(unused_variables)]
+    fn intensity_at(&self, point: &Point3<Float>) -> Float {
         self.intensity
     }
 
     #[allow(unused_variables)]
-    fn distance_at(&self, point: &Point3<f32>) -> f32 {
-        f32::INFINITY
+    fn distance_at(&self, point: &Point3<Float>) -> Float {
+        Float::INFINITY
+    }
+
+    fn ambient_color(&self, normal: &Vector3<Float>) -> Color {
+        let sky_factor = (normal.dot(self.up) * 0.5 + 0.5).clamp(0.0, 1.0);
+        (self.ground_color * (1.0 - sky_factor) + self.sky_color * sky_factor) * self.intensity
+    }
+}
+
+/// Describes how the intensity of a point light decreases with distance
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub enum Falloff {
+    /// Physically correct inverse square law
+    #[default]
+    InverseSquare,
+    /// `intensity / (constant + linear * d + quadratic * d^2)`
+    Polynomial {
+        constant: Float,
+        linear: Float,
+        quadratic: Float,
+    },
+    /// Inverse square law that is smoothly faded out to reach zero at `radius`
+    Smooth {
+        radius: Float,
+    },
+}
+
+fn default_shadow_samples() -> usize {
+    8
+}
+
+/// A texture that a point light shines through, like a slide projector or a gobo in front of a stage light
+///
+/// The light then only illuminates the pyramid behind the texture, tinted by the texel in the direction of each point.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LightProjection {
+    pub texture: Texture,
+    /// Direction towards the center of the texture
+    #[serde(deserialize_with = "deserialize_normalized")]
+    pub direction: Vector3<Float>,
+    /// Direction towards the top of the texture, only the part orthogonal to `direction` is used
+    #[serde(default = "Vector3::unit_y")]
+    pub up: Vector3<Float>,
+    /// Horizontal opening angle in degrees, the vertical one follows from the aspect ratio of the texture
+    pub fov: Float,
+}
+
+impl LightProjection {
+    /// Texel the light passes through towards `direction`, `None` outside the texture
+    fn texel_towards(&self, direction: &Vector3<Float>) -> Option<Color> {
+        let depth = direction.dot(self.direction);
+        if depth <= 0.0 {
+            return None;
+        }
+
+        let right = self.direction.cross(self.up);
+        let right = if right.magnitude2() > 1e-12 { right.normalize() } else { orthonormal_basis(&self.direction).0 };
+        let up = right.cross(self.direction);
+
+        let (width, height) = (self.texture.img.width() as Float, self.texture.img.height() as Float);
+        let half_width = float::tan(self.fov.to_radians() / 2.0);
+        let half_height = half_width * height / width;
+        let u = 0.5 + direction.dot(right) / depth / (2.0 * half_width);
+        let v = 0.5 - direction.dot(up) / depth / (2.0 * half_height);
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return None;
+        }
+
+        // Keep the bilinear filter from wrapping around to the opposite edge
+        let tex_coords = Vector2::new(u.clamp(0.5 / width, 1.0 - 0.5 / width), v.clamp(0.5 / height, 1.0 - 0.5 / height));
+        Some(self.texture.sample_bilinear(&tex_coords))
     }
 }
 
 /// A light that's only a single point and radiates uniformly in all directions
+///
+/// With a non-zero `radius` it becomes a sphere that casts soft shadows, its intensity still falls off from the center.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PointLight {
-    pub point: Point3<f32>,
+    pub point: Point3<Float>,
     pub color: Color,
-    pub intensity: f32,
+    pub intensity: Float,
+    #[serde(default)]
+    pub falloff: Falloff,
+    /// Distance after which the light contributes nothing
+    #[serde(default)]
+    pub range: Option<Float>,
+    /// Radius of the sphere that emits the light, 0 for hard shadows
+    #[serde(default)]
+    pub radius: Float,
+    /// Number of shadow rays cast towards points on the sphere, only used if `radius` is non-zero
+    #[serde(default = "default_shadow_samples")]
+    pub shadow_samples: usize,
+    /// Turns the light into a projector that only shines through a texture
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub projection: Option<LightProjection>,
+    /// Used to look the light up with `Scene::light_index()`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
 }
 
 impl PointLight {
-    fn direction_from(&self, point: &Point3<f32>) -> Vector3<f32> {
+    fn direction_from(&self, point: &Point3<Float>) -> Vector3<Float> {
         (self.point - point).normalize()
     }
 
@@ -90,13 +345,65 @@ impl PointLight {
         self.color
     }
 
-    fn intensity_at(&self, point: &Point3<f32>) -> f32 {
-        // Inverse Square Law
+    fn color_at(&self, point: &Point3<Float>) -> Color {
+        match &self.projection {
+            Some(projection) => projection.texel_towards(&(point - self.point)).map_or(Color::black(), |texel| self.color * texel),
+            None => self.color,
+        }
+    }
+
+    fn intensity_at(&self, point: &Point3<Float>) -> Float {
+        if !self.reaches(point) {
+            return 0.0;
+        }
+
         let distance_squared = (self.point - point).magnitude2();
-        self.intensity / (4.0 * f32::consts::PI * distance_squared)
+        match &self.falloff {
+            Falloff::InverseSquare => {
+                // Inverse Square Law
+                self.intensity / (4.0 * consts::PI * distance_squared)
+            }
+            Falloff::Polynomial { constant, linear, quadratic } => {
+                let distance = distance_squared.sqrt();
+                self.intensity / (constant + linear * distance + quadratic * distance_squared)
+            }
+            Falloff::Smooth { radius } => {
+                // Windowing function as used in Unreal Engine 4, the +1 avoids the singularity at the light position
+                let window = float::powi((1.0 - float::powi(distance_squared / float::powi(*radius, 2), 2)).max(0.0), 2);
+                self.intensity * window / (4.0 * consts::PI * (distance_squared + 1.0))
+            }
+        }
     }
 
-    fn distance_at(&self, point: &Point3<f32>) -> f32 {
+    fn distance_at(&self, point: &Point3<Float>) -> Float {
         (self.point - point).magnitude()
     }
+
+    /// Points spread uniformly over the disk of the sphere that faces `point`, i.e. over its silhouette
+    fn shadow_samples<R: Rng>(&self, point: &Point3<Float>, rng: &mut R) -> Vec<(Vector3<Float>, Float)> {
+        let (tangent, bitangent) = orthonormal_basis(&self.direction_from(point));
+        (0..self.shadow_samples.max(1))
+            .map(|_| {
+                let radius = self.radius * rng.gen::<Float>().sqrt();
+                let angle = 2.0 * consts::PI * rng.gen::<Float>();
+                let target = self.point + (tangent * float::cos(angle) + bitangent * float::sin(angle)) * radius;
+                let to_target = target - point;
+                let distance = to_target.magnitude();
+                (to_target / distance, distance)
+            })
+            .collect()
+    }
+
+    fn reaches(&self, point: &Point3<Float>) -> bool {
+        let falloff_radius = match &self.falloff {
+            Falloff::Smooth { radius } => Some(*radius),
+            _ => None,
+        };
+
+        let in_range = match self.range.into_iter().chain(falloff_radius).reduce(Float::min) {
+            Some(max_distance) => self.distance_at(point) < max_distance,
+            None => true,
+        };
+        in_range && self.projection.as_ref().is_none_or(|projection| projection.texel_towards(&(point - self.point)).is_some())
+    }
 }
@@ -1,74 +1,257 @@
 
-use std::error::Error;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use serde::{Serialize, Deserialize, Deserializer, Serializer};
-use cgmath::Vector2;
+use cgmath::{Vector2, Vector3, InnerSpace, ElementWise};
+use once_cell::sync::OnceCell;
 
-use crate::math_util::Modulo;
+use crate::math_util::{Modulo, float, Float, consts};
 use crate::color::Color;
 use crate::image::RgbImage;
-use crate::asset_loader;
+use crate::ray::{Hit, MAX_UV_CHANNELS};
+use crate::asset_loader::{self, AssetLoader};
+use crate::scene::Visibility;
+use crate::error::RaytracerError;
+
+/// Either just the image file path of a texture, or the path together with options
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum DeserializableTexture {
+    Path(PathBuf),
+    Options {
+        path: PathBuf,
+        #[serde(default)]
+        linear: bool,
+        #[serde(default)]
+        uv_channel: usize,
+    },
+}
 
 /// Represents a texture.
 ///
-/// Serializes/deserializes to/from a string, which is the path to the image file
+/// Serializes/deserializes to/from a string, which is the path to the image file, or to/from a struct with the path
+/// and the `linear` flag and `uv_channel`.
 #[derive(Clone)]
 pub struct Texture {
     pub path: PathBuf,
-    pub img: RgbImage,
+    /// Shared with all other textures loaded from the same path
+    pub img: Arc<RgbImage>,
+    /// Whether the image holds linear data instead of sRGB encoded colors
+    ///
+    /// Should be set for textures that don't contain colors, e.g. bump maps and metallic-roughness textures, so their
+    /// values are used as they are stored.
+    pub linear: bool,
+    /// UV channel of meshes that provides the texture coordinates, e.g. 1 for a lightmap; 0 is the first channel
+    ///
+    /// Shapes without this channel, including all shapes that aren't meshes, use their first channel instead.
+    pub uv_channel: usize,
+    /// Successively halved copies of `img`, built when the texture is first sampled with a footprint
+    mip_levels: OnceCell<Arc<Vec<RgbImage>>>,
 }
 
 impl Serialize for Texture {
-    /// Serialize this texture to a string, which is the image file path
+    /// Serialize this texture to a string, which is the image file path, unless it holds linear data or uses another
+    /// UV channel than the first
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer
     {
-        // Serialize file path
-        self.path.serialize(serializer)
+        let path = self.path.clone();
+        if self.linear || self.uv_channel != 0 {
+            DeserializableTexture::Options { path, linear: self.linear, uv_channel: self.uv_channel }.serialize(serializer)
+        } else {
+            DeserializableTexture::Path(path).serialize(serializer)
+        }
     }
 }
 
 impl<'de> Deserialize<'de> for Texture {
-    /// Deserialize a texture from a string, which is the image file path
+    /// Deserialize a texture from a string, which is the image file path, or from a struct with path and options
     fn deserialize<D>(deserializer: D) -> Result<Texture, D::Error>
     where
         D: Deserializer<'de>
     {
-        // Deserialize file path
-        let path = PathBuf::deserialize(deserializer)?;
+        let (path, linear, uv_channel) = match DeserializableTexture::deserialize(deserializer)? {
+            DeserializableTexture::Path(path) => (path, false, 0),
+            DeserializableTexture::Options { path, linear, uv_channel } => (path, linear, uv_channel),
+        };
+        if uv_channel >= MAX_UV_CHANNELS {
+            return Err(serde::de::Error::custom(format!("UV channel {} of texture \"{}\" is out of range, there are {} channels", uv_channel, path.display(), MAX_UV_CHANNELS)));
+        }
         // Load texture image from path
-        Self::load(path.clone()).map_err(|err| {
+        let mut texture = Self::load(path.clone()).map_err(|err| {
             serde::de::Error::custom(format!("Unable to open image file \"{}\": {}", path.display(), err))
-        })
+        })?;
+        texture.linear = linear;
+        texture.uv_channel = uv_channel;
+        Ok(texture)
     }
 }
 
 impl Texture {
-    /// Load a texture from an image file
-    fn load(path: PathBuf) -> Result<Texture, Box<dyn Error>> {
-        let a = asset_loader::get_instance();
-        let img = a.load_image(&path)?;
-        Ok(Texture {
+    /// Create an sRGB texture from an image that was loaded from `path`
+    pub fn new(path: PathBuf, img: Arc<RgbImage>) -> Texture {
+        Texture {
             path,
             img,
-        })
+            linear: false,
+            uv_channel: 0,
+            mip_levels: OnceCell::new(),
+        }
+    }
+
+    /// Load a texture from an image file
+    fn load(path: PathBuf) -> Result<Texture, RaytracerError> {
+        let img = asset_loader::load_image_cached(&path)?;
+        Ok(Texture::new(path, img))
+    }
+
+    /// Load a texture through `loader` instead of the current asset loader, without sharing the image
+    pub fn load_with(path: PathBuf, loader: &dyn AssetLoader) -> Result<Texture, RaytracerError> {
+        let img = Arc::new(loader.load_image(&path).map_err(|err| RaytracerError::asset(&path, err))?);
+        Ok(Texture::new(path, img))
+    }
+
+    /// Linear color of a single pixel
+    pub(crate) fn texel(&self, x: usize, y: usize) -> Color {
+        self.texel_in(&self.img, x, y)
+    }
+
+    /// Linear color of a single pixel of `img`, which is either the image of this texture or one of its mip levels
+    fn texel_in(&self, img: &RgbImage, x: usize, y: usize) -> Color {
+        let rgb = img.get_pixel(x, y);
+        if self.linear {
+            Color::from_u8(&rgb)
+        } else {
+            Color::from_srgb_u8(&rgb)
+        }
+    }
+
+    /// Encode a linear color the same way as the pixels of this texture
+    fn encode(&self, color: Color) -> (u8, u8, u8) {
+        if self.linear {
+            let encode = |value: Float| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+            (encode(color.r), encode(color.g), encode(color.b))
+        } else {
+            color.to_srgb_u8()
+        }
+    }
+
+    /// Load the image again from the same path through the current asset loader
+    pub fn reload(&mut self) -> Result<(), RaytracerError> {
+        self.img = asset_loader::load_image_cached(&self.path)?;
+        self.mip_levels = OnceCell::new();
+        Ok(())
+    }
+
+    /// Mip level `level`, where 0 is the image itself and each further level has half the width and height
+    fn mip_level(&self, level: usize) -> &RgbImage {
+        if level == 0 {
+            return &self.img;
+        }
+        let mip_levels = self.mip_levels.get_or_init(|| Arc::new(self.build_mip_levels()));
+        &mip_levels[(level - 1).min(mip_levels.len().saturating_sub(1))]
+    }
+
+    /// Number of mip levels including the image itself
+    fn mip_level_count(&self) -> usize {
+        let size = self.img.width().max(self.img.height()).max(1);
+        (usize::BITS - size.leading_zeros()) as usize
+    }
+
+    /// Average 2x2 blocks of pixels in linear space until a single pixel is left; odd sizes are rounded up
+    fn build_mip_levels(&self) -> Vec<RgbImage> {
+        let mut levels: Vec<RgbImage> = Vec::new();
+        for _ in 1..self.mip_level_count() {
+            let src = levels.last().unwrap_or(&self.img);
+            let (w, h) = (src.width(), src.height());
+            let mut level = RgbImage::new(w.div_ceil(2).max(1), h.div_ceil(2).max(1));
+            for y in 0..level.height() {
+                for x in 0..level.width() {
+                    let (x0, x1) = ((2 * x).min(w - 1), (2 * x + 1).min(w - 1));
+                    let (y0, y1) = ((2 * y).min(h - 1), (2 * y + 1).min(h - 1));
+                    let sum = self.texel_in(src, x0, y0) + self.texel_in(src, x1, y0)
+                        + self.texel_in(src, x0, y1) + self.texel_in(src, x1, y1);
+                    level.put_pixel(x, y, &self.encode(sum * 0.25));
+                }
+            }
+            levels.push(level);
+        }
+        levels
     }
 
-    fn sample_nearest(&self, tex_coords: &Vector2<f32>) -> Color {
-        let tex_w = self.img.width() as f32;
-        let tex_h = self.img.height() as f32;
+    #[allow(dead_code)]
+    fn sample_nearest(&self, tex_coords: &Vector2<Float>) -> Color {
+        let tex_w = self.img.width() as Float;
+        let tex_h = self.img.height() as Float;
 
         let tex_x = (tex_coords.x * tex_w).round().modulo(tex_w) as usize;
         let tex_y = (tex_coords.y * tex_h).round().modulo(tex_h) as usize;
 
-        Color::from_u8(&self.img.get_pixel(tex_x, tex_y))
+        self.texel(tex_x, tex_y)
+    }
+
+    pub(crate) fn sample_bilinear(&self, tex_coords: &Vector2<Float>) -> Color {
+        self.sample_bilinear_in(&self.img, tex_coords)
+    }
+
+    /// Sample the texture at a hit point with the texture coordinates of its UV channel, see `sample_filtered()`
+    pub(crate) fn sample_hit(&self, hit: &Hit) -> Color {
+        let uv = hit.uv_channel(self.uv_channel);
+        self.sample_filtered(&uv.tex_coords, &uv.tex_coords_dx, &uv.tex_coords_dy)
+    }
+
+    /// Sample the texture over the footprint of a pixel, given by the change of the texture coordinates towards the
+    /// neighbouring pixels in x and y direction
+    ///
+    /// Elongated footprints, e.g. on surfaces seen at grazing angles, are covered by up to `MAX_ANISOTROPY` samples
+    /// along their longer axis, each interpolated trilinearly from the mip levels that match the shorter axis. Falls
+    /// back to `sample_bilinear()` if the footprint is unknown.
+    pub(crate) fn sample_filtered(&self, tex_coords: &Vector2<Float>, tex_coords_dx: &Vector2<Float>, tex_coords_dy: &Vector2<Float>) -> Color {
+        const MAX_ANISOTROPY: Float = 8.0;
+
+        let size = Vector2::new(self.img.width() as Float, self.img.height() as Float);
+        let length_x = tex_coords_dx.mul_element_wise(size).magnitude();
+        let length_y = tex_coords_dy.mul_element_wise(size).magnitude();
+        let (major_axis, major_length, minor_length) = if length_x >= length_y {
+            (tex_coords_dx, length_x, length_y)
+        } else {
+            (tex_coords_dy, length_y, length_x)
+        };
+        if !(major_length > 0.0 && major_length.is_finite()) {
+            return self.sample_bilinear(tex_coords);
+        }
+
+        // Blur along the shorter axis rather than taking too many samples along the longer one
+        let minor_length = minor_length.max(major_length / MAX_ANISOTROPY);
+        let max_level = (self.mip_level_count() - 1) as Float;
+        let level = (float::ln(minor_length) / consts::LN_2).clamp(0.0, max_level);
+        let sample_count = (major_length / minor_length).ceil().clamp(1.0, MAX_ANISOTROPY) as usize;
+
+        let color_sum = (0..sample_count).fold(Color::black(), |color_sum, i| {
+            let offset = (i as Float + 0.5) / sample_count as Float - 0.5;
+            color_sum + self.sample_trilinear(&(tex_coords + major_axis * offset), level)
+        });
+        color_sum / sample_count as Float
+    }
+
+    /// Interpolate between bilinear samples of the two mip levels around `level`
+    fn sample_trilinear(&self, tex_coords: &Vector2<Float>, level: Float) -> Color {
+        let level_1 = level.floor();
+        let t = level - level_1;
+        let color_1 = self.sample_bilinear_in(self.mip_level(level_1 as usize), tex_coords);
+        if t == 0.0 {
+            color_1
+        } else {
+            let color_2 = self.sample_bilinear_in(self.mip_level(level_1 as usize + 1), tex_coords);
+            color_1 * (1.0 - t) + color_2 * t
+        }
     }
 
-    fn sample_bilinear(&self, tex_coords: &Vector2<f32>) -> Color {
-        let tex_w = self.img.width() as f32;
-        let tex_h = self.img.height() as f32;
+    fn sample_bilinear_in(&self, img: &RgbImage, tex_coords: &Vector2<Float>) -> Color {
+        let tex_w = img.width() as Float;
+        let tex_h = img.height() as Float;
 
         let tex_x = tex_coords.x * tex_w;
         let tex_y = tex_coords.y * tex_h;
@@ -83,10 +266,10 @@ impl Texture {
         let tex_y_1_wrapped = tex_y_1.modulo(tex_h) as usize;
         let tex_y_2_wrapped = tex_y_2.modulo(tex_h) as usize;
 
-        let color_1_1 = Color::from_u8(&self.img.get_pixel(tex_x_1_wrapped, tex_y_1_wrapped));
-        let color_2_1 = Color::from_u8(&self.img.get_pixel(tex_x_2_wrapped, tex_y_1_wrapped));
-        let color_1_2 = Color::from_u8(&self.img.get_pixel(tex_x_1_wrapped, tex_y_2_wrapped));
-        let color_2_2 = Color::from_u8(&self.img.get_pixel(tex_x_2_wrapped, tex_y_2_wrapped));
+        let color_1_1 = self.texel_in(img, tex_x_1_wrapped, tex_y_1_wrapped);
+        let color_2_1 = self.texel_in(img, tex_x_2_wrapped, tex_y_1_wrapped);
+        let color_1_2 = self.texel_in(img, tex_x_1_wrapped, tex_y_2_wrapped);
+        let color_2_2 = self.texel_in(img, tex_x_2_wrapped, tex_y_2_wrapped);
 
         let x_exact = tex_x_1 == tex_x_2;
         let y_exact = tex_y_1 == tex_y_2;
@@ -112,24 +295,283 @@ pub enum Coloration {
     Color(Color),
     /// Get color for each point from a texture
     Texture(Texture),
+    /// Interpolate the vertex colors of meshes, white on shapes without vertex colors
+    VertexColor,
 }
 
 impl Coloration {
     /// Calculate color at a specific position
-    pub fn color(&self, tex_coords: &Vector2<f32>) -> Color {
+    pub fn color(&self, tex_coords: &Vector2<Float>) -> Color {
         match self {
             Coloration::Color(color) => *color,
             Coloration::Texture(tex) => tex.sample_bilinear(tex_coords),
+            Coloration::VertexColor => Color::white(),
+        }
+    }
+
+    /// Calculate the color at a hit point, filtering textures over the footprint of the pixel if it is known
+    pub fn filtered_color(&self, hit: &Hit) -> Color {
+        match self {
+            Coloration::Color(color) => *color,
+            Coloration::Texture(tex) => tex.sample_hit(hit),
+            Coloration::VertexColor => hit.vertex_color.unwrap_or_else(Color::white),
+        }
+    }
+
+    pub fn texture_mut(&mut self) -> Option<&mut Texture> {
+        match self {
+            Coloration::Color(_) | Coloration::VertexColor => None,
+            Coloration::Texture(tex) => Some(tex),
+        }
+    }
+}
+
+/// A single color channel of a texture
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+}
+
+impl Channel {
+    fn of(&self, color: Color) -> Float {
+        match self {
+            Channel::Red => color.r,
+            Channel::Green => color.g,
+            Channel::Blue => color.b,
+        }
+    }
+}
+
+/// A scalar material property that is either uniform or read from one channel of a texture
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Parameter {
+    /// Uniform value
+    Value(Float),
+    /// Get value for each point from a texture channel, e.g. to use glTF metallic-roughness textures
+    Texture(Texture, Channel),
+}
+
+impl Parameter {
+    /// Calculate value at a specific position
+    pub fn value(&self, tex_coords: &Vector2<Float>) -> Float {
+        match self {
+            Parameter::Value(value) => *value,
+            Parameter::Texture(tex, channel) => channel.of(tex.sample_bilinear(tex_coords)),
+        }
+    }
+
+    /// Calculate value at a hit point, using the texture coordinates of the texture's UV channel
+    pub fn value_at(&self, hit: &Hit) -> Float {
+        match self {
+            Parameter::Value(value) => *value,
+            Parameter::Texture(tex, channel) => channel.of(tex.sample_bilinear(&hit.uv_channel(tex.uv_channel).tex_coords)),
         }
     }
+
+    pub fn texture_mut(&mut self) -> Option<&mut Texture> {
+        match self {
+            Parameter::Value(_) => None,
+            Parameter::Texture(tex, _) => Some(tex),
+        }
+    }
+}
+
+/// Determines how light arriving directly from light sources is reflected
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub enum ShadingModel {
+    /// Ideal diffuse reflection scaled by the material's albedo
+    #[default]
+    Lambert,
+    /// Physically based metallic-roughness model with a GGX microfacet specular term
+    ///
+    /// The material's color is used as base color and its refractive index determines the reflectance of
+    /// non-metallic surfaces. The albedo is ignored.
+    MetallicRoughness {
+        metallic: Parameter,
+        roughness: Parameter,
+    },
+}
+
+/// Perturbs the shading normal according to a grayscale height map
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BumpMap {
+    pub texture: Texture,
+    /// Scales the height differences, negative values invert the bumps
+    pub strength: Float,
+}
+
+impl BumpMap {
+    fn height(&self, tex_coords: &Vector2<Float>) -> Float {
+        self.texture.sample_bilinear(tex_coords).luminance()
+    }
+
+    /// Calculate the perturbed normal at a hit point from its geometric normal and the direction of increasing U in the
+    /// texture's UV channel (see `Hit::tangent_along()`)
+    pub fn perturb_normal(&self, hit: &Hit) -> Vector3<Float> {
+        let uv = hit.uv_channel(self.texture.uv_channel);
+        let (tex_coords, normal, tangent) = (&uv.tex_coords, &hit.normal, hit.tangent_along(&uv.dpdu));
+
+        // Finite differences with a step size of one texel
+        let texel_u = Vector2::new(1.0 / self.texture.img.width() as Float, 0.0);
+        let texel_v = Vector2::new(0.0, 1.0 / self.texture.img.height() as Float);
+
+        let height = self.height(tex_coords);
+        let d_height_u = self.height(&(tex_coords + texel_u)) - height;
+        let d_height_v = self.height(&(tex_coords + texel_v)) - height;
+
+        let bitangent = normal.cross(tangent);
+
+        (normal - (tangent * d_height_u + bitangent * d_height_v) * self.strength).normalize()
+    }
+}
+
+/// Diffuse transmission through thin surfaces like leaves, paper or lampshades, which glow when lit from behind
+///
+/// Translucent surfaces are shaded from whichever side they are seen from. Light arriving at the other side is added
+/// as if it was reflected by a Lambertian surface with the translucency color, without the cost of refraction rays.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Translucency {
+    /// Fraction of the light arriving at the back side that leaves the front side, per channel
+    pub color: Color,
+}
+
+impl Translucency {
+    /// Evaluate the BTDF, which is the same for all pairs of directions on opposite sides of the surface
+    pub fn btdf(&self) -> Color {
+        self.color / consts::PI
+    }
+}
+
+fn default_alpha_cutoff() -> Float {
+    0.5
 }
 
 /// Data struct collecting various material properties
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Material {
     pub color: Coloration,
-    pub albedo: f32,
-    pub reflectivity: f32,
-    pub transparency: f32,
-    pub refractive_index: f32,
+    pub albedo: Float,
+    pub reflectivity: Float,
+    pub transparency: Float,
+    /// Index of refraction of the inside of objects with this material
+    ///
+    /// Refraction uses the ratio to the medium on the other side of the surface, e.g. water around glass, which is air
+    /// (1) outside of all objects.
+    pub refractive_index: Float,
+    #[serde(default)]
+    pub shading_model: ShadingModel,
+    /// Opacity map; all rays, including shadow rays, pass through points whose opacity is below `alpha_cutoff`
+    #[serde(default)]
+    pub opacity: Option<Parameter>,
+    #[serde(default = "default_alpha_cutoff")]
+    pub alpha_cutoff: Float,
+    #[serde(default)]
+    pub bump_map: Option<BumpMap>,
+    #[serde(default)]
+    pub translucency: Option<Translucency>,
+    /// Only show the shadows and reflections of other objects on the surface and let everything else through, e.g. to
+    /// composite a product shot with a transparent background onto a photo
+    ///
+    /// The surface's own color and lighting are ignored. With a transparent background, the shadows and reflections
+    /// are the only opaque parts, see `Renderer::render_rgba()`.
+    #[serde(default)]
+    pub shadow_catcher: bool,
+    /// Which kinds of rays see objects with this material, e.g. to keep a large emissive screen out of reflections
+    #[serde(default)]
+    pub visibility: Visibility,
+}
+
+impl Material {
+    /// Create a material without textures, opacity or bump map
+    pub fn new(color: Coloration, albedo: Float, reflectivity: Float, transparency: Float, refractive_index: Float) -> Material {
+        Material {
+            color,
+            albedo,
+            reflectivity,
+            transparency,
+            refractive_index,
+            shading_model: ShadingModel::default(),
+            opacity: None,
+            alpha_cutoff: default_alpha_cutoff(),
+            bump_map: None,
+            translucency: None,
+            shadow_catcher: false,
+            visibility: Visibility::default(),
+        }
+    }
+
+    /// All textures used by this material
+    pub fn textures_mut(&mut self) -> Vec<&mut Texture> {
+        let mut textures: Vec<&mut Texture> = Vec::new();
+        textures.extend(self.color.texture_mut());
+        if let ShadingModel::MetallicRoughness { metallic, roughness } = &mut self.shading_model {
+            textures.extend(metallic.texture_mut());
+            textures.extend(roughness.texture_mut());
+        }
+        textures.extend(self.opacity.as_mut().and_then(Parameter::texture_mut));
+        textures.extend(self.bump_map.as_mut().map(|bump_map| &mut bump_map.texture));
+        textures
+    }
+
+    /// Whether a ray should ignore a hit on this material (alpha testing)
+    pub fn is_cut_out(&self, hit: &Hit) -> bool {
+        match &self.opacity {
+            Some(opacity) => opacity.value_at(hit) < self.alpha_cutoff,
+            None => false,
+        }
+    }
+
+    /// Evaluate the BRDF for light arriving from `to_light` and leaving towards `to_viewer`
+    ///
+    /// `base_color` is the color of the material at `hit`, possibly with decals composited over it.
+    /// All vectors have to be normalized and point away from the surface
+    pub fn brdf(&self, base_color: Color, hit: &Hit, normal: &Vector3<Float>, to_light: &Vector3<Float>, to_viewer: &Vector3<Float>) -> Color {
+
+        match &self.shading_model {
+            ShadingModel::Lambert => base_color * (self.albedo / consts::PI),
+            ShadingModel::MetallicRoughness { metallic, roughness } => {
+                let metallic = metallic.value_at(hit).clamp(0.0, 1.0);
+                // Very low roughness values lead to numerical problems with point lights
+                let roughness = roughness.value_at(hit).clamp(0.045, 1.0);
+
+                let n_dot_l = normal.dot(*to_light);
+                let n_dot_v = normal.dot(*to_viewer);
+                if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+                    return Color::black();
+                }
+
+                let half = (to_light + to_viewer).normalize();
+                let n_dot_h = normal.dot(half).max(0.0);
+                let v_dot_h = to_viewer.dot(half).max(0.0);
+
+                // GGX / Trowbridge-Reitz normal distribution
+                let alpha = roughness * roughness;
+                let alpha2 = alpha * alpha;
+                let d_denominator = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+                let d = alpha2 / (consts::PI * d_denominator * d_denominator);
+
+                // Smith-Schlick geometry term
+                let k = alpha * 0.5;
+                let g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+                let g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+                let g = g_l * g_v;
+
+                // Schlick's approximation of the Fresnel term, metals tint their reflections with the base color
+                let f0_dielectric = float::powi((self.refractive_index - 1.0) / (self.refractive_index + 1.0), 2);
+                let f0 = Color::new(f0_dielectric, f0_dielectric, f0_dielectric) * (1.0 - metallic) + base_color * metallic;
+                let fresnel_weight = float::powi(1.0 - v_dot_h, 5);
+                let f = f0 * (1.0 - fresnel_weight) + Color::new(1.0, 1.0, 1.0) * fresnel_weight;
+
+                let specular = f * (d * g / (4.0 * n_dot_l * n_dot_v));
+
+                // Light that isn't reflected specularly enters the surface, metals absorb all of it
+                let k_d = Color::new(1.0 - f.r, 1.0 - f.g, 1.0 - f.b) * (1.0 - metallic);
+                let diffuse = k_d * base_color * (1.0 / consts::PI);
+
+                diffuse + specular
+            }
+        }
+    }
 }
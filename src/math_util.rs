@@ -1,8 +1,40 @@
 
 use std::ops::{Index, IndexMut};
 
-use cgmath::{VectorSpace, InnerSpace, BaseFloat, Vector3, Point3};
+
+use cgmath::{VectorSpace, InnerSpace, BaseFloat, Vector3, Point3, Matrix4};
 use serde::{Deserialize, Deserializer};
+use rand::Rng;
+
+/// Scalar type used for all geometry, colors and shading
+///
+/// Single precision is enough for most scenes. The `f64` feature switches to double precision for scenes with a large
+/// extent, e.g. kilometer-sized terrain with small objects on it, where single precision causes self-intersection
+/// artifacts.
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+/// Scalar type used for all geometry, colors and shading
+///
+/// Single precision is enough for most scenes. The `f64` feature switches to double precision for scenes with a large
+/// extent, e.g. kilometer-sized terrain with small objects on it, where single precision causes self-intersection
+/// artifacts.
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
+/// Unsigned integer with the same size as `Float`, for storing it with `Float::to_bits()`
+#[cfg(not(feature = "f64"))]
+pub type FloatBits = u32;
+/// Unsigned integer with the same size as `Float`, for storing it with `Float::to_bits()`
+#[cfg(feature = "f64")]
+pub type FloatBits = u64;
+
+/// Mathematical constants in the precision of `Float`
+pub mod consts {
+    #[cfg(not(feature = "f64"))]
+    pub use std::f32::consts::*;
+    #[cfg(feature = "f64")]
+    pub use std::f64::consts::*;
+}
 
 /// Deserialize a vector and normalize it
 ///
@@ -31,12 +63,211 @@ pub trait Modulo<RHS=Self> {
     fn modulo(&self, rhs: RHS) -> Self;
 }
 
-impl Modulo for f32 {
-    fn modulo(&self, rhs: f32) -> f32 {
+impl Modulo for Float {
+    fn modulo(&self, rhs: Float) -> Float {
         ((self % rhs) + rhs) % rhs
     }
 }
 
+/// Floating point functions whose results must not depend on the platform
+///
+/// The standard library forwards these to the system's math library, which may round differently on x86 and ARM.
+/// With the `deterministic` feature the pure Rust implementations of `libm` are used instead. Rust never contracts
+/// multiplications and additions into FMA instructions on its own, so all other arithmetic is already portable.
+#[allow(dead_code)]
+pub mod float {
+    use super::Float;
+
+    macro_rules! portable_functions {
+        ($($name:ident($($arg:ident),+) => $libm_name:ident, $libm_f64_name:ident, $std_name:ident;)+) => {
+            $(
+                #[cfg(all(feature = "deterministic", not(feature = "f64")))]
+                #[inline]
+                pub fn $name($($arg: Float),+) -> Float {
+                    libm::$libm_name($($arg),+)
+                }
+
+                #[cfg(all(feature = "deterministic", feature = "f64"))]
+                #[inline]
+                pub fn $name($($arg: Float),+) -> Float {
+                    libm::$libm_f64_name($($arg),+)
+                }
+
+                #[cfg(not(feature = "deterministic"))]
+                #[inline]
+                pub fn $name($($arg: Float),+) -> Float {
+                    portable_functions!(@std $std_name $($arg),+)
+                }
+            )+
+        };
+        (@std $std_name:ident $x:ident) => { $x.$std_name() };
+        (@std $std_name:ident $x:ident, $y:ident) => { $x.$std_name($y) };
+    }
+
+    portable_functions! {
+        sin(x) => sinf, sin, sin;
+        cos(x) => cosf, cos, cos;
+        tan(x) => tanf, tan, tan;
+        acos(x) => acosf, acos, acos;
+        atan2(y, x) => atan2f, atan2, atan2;
+        exp(x) => expf, exp, exp;
+        ln(x) => logf, log, ln;
+        powf(x, y) => powf, pow, powf;
+    }
+
+    /// `x` to the power of `n` by repeated multiplication, which unlike `Float::powi()` gives the same result on all
+    /// platforms
+    #[inline]
+    pub fn powi(x: Float, n: u32) -> Float {
+        (0..n).fold(1.0, |result, _| result * x)
+    }
+}
+
+/// Random number generator used for all sampling
+#[cfg(feature = "deterministic")]
+pub type SamplingRng = rand::rngs::StdRng;
+/// Random number generator used for all sampling
+#[cfg(not(feature = "deterministic"))]
+pub type SamplingRng = rand::rngs::ThreadRng;
+
+/// Get a random number generator for sampling
+///
+/// In deterministic mode the generator is seeded from `seed` (e.g. the pixel coordinates) so that the same samples are
+/// drawn regardless of the machine or tile that renders a pixel. Otherwise `seed` is ignored.
+#[cfg(feature = "deterministic")]
+// The bit pattern is already a `u64` with the `f64` feature
+#[allow(clippy::useless_conversion)]
+pub fn sampling_rng(seed: &[Float]) -> SamplingRng {
+    use rand::SeedableRng;
+
+    // FNV-1a over the bit patterns of the seed values
+    let hash = seed.iter().fold(0xcbf29ce484222325u64, |hash, value| {
+        (hash ^ u64::from(value.to_bits())).wrapping_mul(0x100000001b3)
+    });
+    SamplingRng::seed_from_u64(hash)
+}
+
+/// Get a random number generator for sampling
+///
+/// In deterministic mode the generator is seeded from `seed` (e.g. the pixel coordinates) so that the same samples are
+/// drawn regardless of the machine or tile that renders a pixel. Otherwise `seed` is ignored.
+#[cfg(not(feature = "deterministic"))]
+pub fn sampling_rng(_seed: &[Float]) -> SamplingRng {
+    rand::thread_rng()
+}
+
+/// A set of `count` stratified samples of the unit square, as offsets from its center
+///
+/// The square is split into a grid of at least `count` cells and each sample is jittered within its own cell, so the
+/// samples cover the square evenly and never leave it. If `count` isn't a square number, the grid has more cells than
+/// samples; each set then uses a random subset of the cells, so that no part of the square is left out on average.
+pub struct StratifiedSquare {
+    columns: usize,
+    rows: usize,
+    /// Seed of the permutation that assigns cells to samples, `None` if every cell gets a sample
+    pattern: Option<u32>,
+}
+
+impl StratifiedSquare {
+    pub fn new<R: Rng>(count: usize, rng: &mut R) -> StratifiedSquare {
+        let columns = ((count.max(1) as Float).sqrt().ceil() as usize).max(1);
+        let rows = count.max(1).div_ceil(columns);
+        let pattern = if columns * rows > count { Some(rng.gen()) } else { None };
+        StratifiedSquare { columns, rows, pattern }
+    }
+
+    /// Sample number `index` of the set; samples with different indices below `count` are in different cells
+    pub fn sample<R: Rng>(&self, index: usize, rng: &mut R) -> (Float, Float) {
+        let cell_count = self.columns * self.rows;
+        let cell = match self.pattern {
+            Some(pattern) => permute((index % cell_count) as u32, cell_count as u32, pattern) as usize,
+            None => index % cell_count,
+        };
+        let u = ((cell % self.columns) as Float + rng.gen::<Float>()) / self.columns as Float;
+        let v = ((cell / self.columns) as Float + rng.gen::<Float>()) / self.rows as Float;
+        // `gen()` is below 1, but the division can round up to it
+        (u.min(1.0 - Float::EPSILON) - 0.5, v.min(1.0 - Float::EPSILON) - 0.5)
+    }
+}
+
+/// Element `index` of a permutation of `0..length` chosen by `pattern`, without storing the permutation
+///
+/// This is the hash based permutation from Kensler's "Correlated Multi-Jittered Sampling", which walks the cycles of
+/// a permutation of the next power of two until it lands below `length`.
+fn permute(mut index: u32, length: u32, pattern: u32) -> u32 {
+    let mut mask = length - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    loop {
+        index ^= pattern;
+        index = index.wrapping_mul(0xe170893d);
+        index ^= pattern >> 16;
+        index ^= (index & mask) >> 4;
+        index ^= pattern >> 8;
+        index = index.wrapping_mul(0x0929eb3f);
+        index ^= pattern >> 23;
+        index ^= (index & mask) >> 1;
+        index = index.wrapping_mul(1 | pattern >> 27);
+        index = index.wrapping_mul(0x6935fa69);
+        index ^= (index & mask) >> 11;
+        index = index.wrapping_mul(0x74dcb303);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0x9e501cc3);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0xc860a3df);
+        index &= mask;
+        index ^= index >> 5;
+        if index < length {
+            return (index + pattern) % length;
+        }
+    }
+}
+
+/// Build a rotation matrix from euler angles in degrees, equivalent to `Matrix4::from(Euler)` from cgmath
+pub fn euler_rotation_matrix(rotation: Vector3<Float>) -> Matrix4<Float> {
+    let (x, y, z) = (rotation.x.to_radians(), rotation.y.to_radians(), rotation.z.to_radians());
+    let (sx, cx) = (float::sin(x), float::cos(x));
+    let (sy, cy) = (float::sin(y), float::cos(y));
+    let (sz, cz) = (float::sin(z), float::cos(z));
+
+    Matrix4::new(
+        cy * cz, cx * sz + sx * sy * cz, sx * sz - cx * sy * cz, 0.0,
+        -cy * sz, cx * cz - sx * sy * sz, sx * cz + cx * sy * sz, 0.0,
+        sy, -sx * cy, cx * cy, 0.0,
+        0.0, 0.0, 0.0, 1.0,
+    )
+}
+
+/// Calculate two unit vectors that form an orthonormal basis together with the unit vector `n`
+pub fn orthonormal_basis(n: &Vector3<Float>) -> (Vector3<Float>, Vector3<Float>) {
+    // Pick the axis that is least parallel to `n` to avoid numerical problems
+    let helper = if n.x.abs() > 0.9 {
+        Vector3::unit_y()
+    } else {
+        Vector3::unit_x()
+    };
+    let tangent = n.cross(helper).normalize();
+    let bitangent = n.cross(tangent);
+    (tangent, bitangent)
+}
+
+/// Sample a random direction in the hemisphere around the unit vector `normal` with a cosine-weighted distribution
+pub fn sample_hemisphere_cosine<R: Rng>(normal: &Vector3<Float>, rng: &mut R) -> Vector3<Float> {
+    let (tangent, bitangent) = orthonormal_basis(normal);
+
+    // Uniformly sample a disk and project it onto the hemisphere (Malley's method)
+    let r = rng.gen::<Float>().sqrt();
+    let phi = 2.0 * consts::PI * rng.gen::<Float>();
+    let x = r * float::cos(phi);
+    let y = r * float::sin(phi);
+    let z = (1.0 - r * r).max(0.0).sqrt();
+
+    (tangent * x + bitangent * y + normal * z).normalize()
+}
+
 #[derive(Copy, Clone)]
 pub enum Axis {
     X = 0,
@@ -82,3 +313,31 @@ impl<S> IndexMut<Axis> for Point3<S> {
         AsMut::<[S; 3]>::as_mut(self).index_mut(axis as usize)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+
+    #[test]
+    fn stratified_samples_are_centered() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for count in 1..=9 {
+            let sets = 20000;
+            let (mut sum_x, mut sum_y) = (0.0, 0.0);
+            for _ in 0..sets {
+                let stratified = StratifiedSquare::new(count, &mut rng);
+                for index in 0..count {
+                    let (x, y) = stratified.sample(index, &mut rng);
+                    assert!((-0.5..0.5).contains(&x) && (-0.5..0.5).contains(&y));
+                    sum_x += x;
+                    sum_y += y;
+                }
+            }
+            let samples = (sets * count) as Float;
+            assert!((sum_x / samples).abs() < 0.005 && (sum_y / samples).abs() < 0.005, "{} samples: {:?}", count, (sum_x / samples, sum_y / samples));
+        }
+    }
+}
@@ -1,53 +1,561 @@
 
-use std::error::Error;
-use std::path::PathBuf;
-use std::time::Instant;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
 use std::mem;
+use std::array;
+use std::collections::HashMap;
+use std::num::NonZeroU32;
 
 use serde::{Serialize, Deserialize, Deserializer};
-use cgmath::{Vector3, InnerSpace, Zero, EuclideanSpace, Vector2};
-
-use crate::ray::{Hit, Ray};
-use crate::asset_loader;
+use cgmath::{Vector3, InnerSpace, Zero, EuclideanSpace, Vector2, Point3, Matrix3, Matrix4, Transform};
+
+use crate::color::Color;
+use crate::compact_mesh::{CompactMeshData, CompactMeshFileError};
+use crate::error::RaytracerError;
+use crate::subdivision::Subdivision;
+use crate::ray::{Hit, Interval, Ray, RayDebugData, UvChannel, MAX_UV_CHANNELS};
+use crate::scratch::ScratchVec;
+use crate::asset_loader::{self, AssetLoader};
 use crate::aabb::AABB;
-use crate::math_util::Axis;
+use crate::math_util::{float, Axis, Float, FloatBits};
+use crate::qbvh::Qbvh;
+use crate::math_util::orthonormal_basis;
+use crate::stats::{BuildStats, BuildProgress};
+use crate::packet::{RayPacket, PACKET_SIZE};
+
+/// Indices into one vertex attribute for the three corners of a triangle
+///
+/// The indices are stored as 32 bit numbers offset by one, so that `Option<CornerIndices>` takes 12 bytes instead of
+/// the 32 bytes of three optional `usize`s. This keeps `IndexedTriangle` small for meshes with millions of triangles.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CornerIndices([NonZeroU32; 3]);
+
+impl CornerIndices {
+    /// Largest index that can be stored
+    pub const MAX_INDEX: usize = u32::MAX as usize - 1;
+
+    /// Panics if an index is larger than `MAX_INDEX`, use `try_new()` for indices that aren't known to be valid
+    pub fn new(i0: usize, i1: usize, i2: usize) -> CornerIndices {
+        CornerIndices::try_new(i0, i1, i2).expect("Vertex index doesn't fit into 32 bits")
+    }
+
+    /// `None` if an index is larger than `MAX_INDEX`
+    pub fn try_new(i0: usize, i1: usize, i2: usize) -> Option<CornerIndices> {
+        let encode = |index: usize| if index <= CornerIndices::MAX_INDEX { NonZeroU32::new(index as u32 + 1) } else { None };
+        Some(CornerIndices([encode(i0)?, encode(i1)?, encode(i2)?]))
+    }
+
+    pub fn get(self) -> (usize, usize, usize) {
+        let [i0, i1, i2] = self.to_array();
+        (i0, i1, i2)
+    }
+
+    pub fn to_array(self) -> [usize; 3] {
+        self.0.map(|index| index.get() as usize - 1)
+    }
+
+    /// Replace every index `i` by `f(i)`
+    pub fn map(self, f: impl FnMut(usize) -> usize) -> CornerIndices {
+        let [i0, i1, i2] = self.to_array().map(f);
+        CornerIndices::new(i0, i1, i2)
+    }
+
+    /// The indices of the triangle with the opposite winding
+    pub fn reversed(self) -> CornerIndices {
+        let [i0, i1, i2] = self.0;
+        CornerIndices([i0, i2, i1])
+    }
+}
+
+impl From<(usize, usize, usize)> for CornerIndices {
+    fn from((i0, i1, i2): (usize, usize, usize)) -> CornerIndices {
+        CornerIndices::new(i0, i1, i2)
+    }
+}
 
 #[derive(Clone)]
 pub struct IndexedTriangle {
-    pub position_indices: (usize, usize, usize),
-    pub normal_indices: Option<(usize, usize, usize)>,
-    pub tex_coords_indices: Option<(usize, usize, usize)>,
+    pub position_indices: CornerIndices,
+    pub normal_indices: Option<CornerIndices>,
+    pub tex_coords_indices: Option<CornerIndices>,
+    pub color_indices: Option<CornerIndices>,
+    /// Indices into `MeshData::extra_tex_coords` for the UV channels after the first
+    pub extra_tex_coords_indices: [Option<CornerIndices>; MAX_UV_CHANNELS - 1],
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct MeshData {
-    pub vertex_positions: Vec<(f32, f32, f32)>,
-    pub vertex_normals: Vec<(f32, f32, f32)>,
-    pub vertex_tex_coords: Vec<(f32, f32)>,
+    pub vertex_positions: Vec<(Float, Float, Float)>,
+    pub vertex_normals: Vec<(Float, Float, Float)>,
+    pub vertex_tex_coords: Vec<(Float, Float)>,
+    /// Linear RGB colors, e.g. baked into scanned meshes; shown by materials with `Coloration::VertexColor`
+    pub vertex_colors: Vec<(Float, Float, Float)>,
+    /// Texture coordinates of the UV channels after the first, e.g. for lightmaps; channel `i + 1` is at index `i`
+    pub extra_tex_coords: Vec<Vec<(Float, Float)>>,
     pub triangles: Vec<IndexedTriangle>,
 }
 
 impl MeshData {
-    fn get_vertex_position(&self, index: usize) -> &Vector3<f32> {
+    pub fn get_vertex_position(&self, index: usize) -> &Vector3<Float> {
         (&self.vertex_positions[index]).into()
     }
 
-    fn get_vertex_normal(&self, index: usize) -> &Vector3<f32> {
+    fn get_vertex_normal(&self, index: usize) -> &Vector3<Float> {
         (&self.vertex_normals[index]).into()
     }
 
-    fn get_vertex_tex_coords(&self, index: usize) -> &Vector2<f32> {
+    fn get_vertex_tex_coords(&self, index: usize) -> &Vector2<Float> {
         (&self.vertex_tex_coords[index]).into()
     }
+
+    /// Texture coordinates of the corners of a triangle in UV channel `channel`, if the triangle has them
+    pub(crate) fn get_triangle_tex_coords(&self, triangle: &IndexedTriangle, channel: usize) -> Option<[Vector2<Float>; 3]> {
+        if channel == 0 {
+            triangle.tex_coords_indices.map(|indices| indices.to_array().map(|index| *self.get_vertex_tex_coords(index)))
+        } else {
+            let tex_coords = &self.extra_tex_coords[channel - 1];
+            triangle.extra_tex_coords_indices[channel - 1].map(|indices| indices.to_array().map(|index| tex_coords[index].into()))
+        }
+    }
+
+    fn get_vertex_color(&self, index: usize) -> Color {
+        let (r, g, b) = self.vertex_colors[index];
+        Color::new(r, g, b)
+    }
+
+    /// Test a ray against a single triangle
+    pub fn intersect_triangle(&self, ray: &Ray, triangle_index: usize) -> Option<TriangleHit> {
+        let (i0, i1, i2) = self.triangles[triangle_index].position_indices.get();
+        let v0 = self.get_vertex_position(i0);
+        let v1 = self.get_vertex_position(i1);
+        let v2 = self.get_vertex_position(i2);
+
+        intersect_triangle(ray, v0, v1, v2)
+    }
+
+    /// Calculate coordinates, normal, texture coordinates and vertex color of a hit point on a triangle
+    pub fn create_hit(&self, ray: &Ray, triangle_index: usize, triangle_hit: &TriangleHit) -> Hit {
+        self.triangle_corners(triangle_index).create_hit(ray, triangle_hit)
+    }
+
+    /// Positions of the corners of a triangle
+    pub fn triangle_positions(&self, triangle_index: usize) -> [Vector3<Float>; 3] {
+        let (i0, i1, i2) = self.triangles[triangle_index].position_indices.get();
+        [*self.get_vertex_position(i0), *self.get_vertex_position(i1), *self.get_vertex_position(i2)]
+    }
+
+    /// Bytes of memory used by the vertex attributes and triangles
+    pub fn memory_usage(&self) -> usize {
+        fn vec_memory_usage<T>(values: &Vec<T>) -> usize {
+            values.capacity() * mem::size_of::<T>()
+        }
+
+        vec_memory_usage(&self.vertex_positions) + vec_memory_usage(&self.vertex_normals)
+            + vec_memory_usage(&self.vertex_tex_coords) + vec_memory_usage(&self.vertex_colors)
+            + self.extra_tex_coords.iter().map(vec_memory_usage).sum::<usize>() + vec_memory_usage(&self.triangles)
+    }
+
+    /// All vertex attributes at the corners of a triangle
+    fn triangle_corners(&self, triangle_index: usize) -> TriangleCorners {
+        let triangle = &self.triangles[triangle_index];
+
+        let mut tex_coords = [None; MAX_UV_CHANNELS];
+        for (channel, channel_tex_coords) in tex_coords.iter_mut().enumerate().take(self.extra_tex_coords.len() + 1) {
+            *channel_tex_coords = self.get_triangle_tex_coords(triangle, channel);
+        }
+
+        TriangleCorners {
+            positions: triangle.position_indices.to_array().map(|index| *self.get_vertex_position(index)),
+            normals: triangle.normal_indices.map(|indices| indices.to_array().map(|index| *self.get_vertex_normal(index))),
+            tex_coords,
+            colors: triangle.color_indices.map(|indices| indices.to_array().map(|index| self.get_vertex_color(index))),
+        }
+    }
+
+    /// Transform all vertices by `matrix`, e.g. to bake an object's transformation into the mesh
+    ///
+    /// Normals are transformed with the inverse transpose and normalized again. Matrices that mirror the mesh also
+    /// reverse the winding of the triangles, so face normals keep pointing outwards.
+    pub fn transform(&mut self, matrix: &Matrix4<Float>) {
+        for position in &mut self.vertex_positions {
+            let transformed = matrix.transform_point(Point3::new(position.0, position.1, position.2));
+            *position = transformed.into();
+        }
+
+        // The cofactor matrix is the inverse transpose scaled by the determinant, but exists for singular matrices too
+        let x = matrix.x.truncate();
+        let y = matrix.y.truncate();
+        let z = matrix.z.truncate();
+        let determinant = x.dot(y.cross(z));
+        let cofactor = Matrix3::from_cols(y.cross(z), z.cross(x), x.cross(y));
+        let sign = if determinant < 0.0 { -1.0 } else { 1.0 };
+        for normal in &mut self.vertex_normals {
+            let transformed = cofactor * Vector3::new(normal.0, normal.1, normal.2) * sign;
+            let length = transformed.magnitude();
+            if length > 0.0 {
+                *normal = (transformed / length).into();
+            }
+        }
+
+        if determinant < 0.0 {
+            self.reverse_winding();
+        }
+    }
+
+    /// Combine several meshes into one
+    pub fn merge(meshes: &[MeshData]) -> MeshData {
+        let mut merged = MeshData::default();
+        for mesh in meshes {
+            let position_offset = merged.vertex_positions.len();
+            let normal_offset = merged.vertex_normals.len();
+            let tex_coords_offset = merged.vertex_tex_coords.len();
+            let color_offset = merged.vertex_colors.len();
+            merged.vertex_positions.extend_from_slice(&mesh.vertex_positions);
+            merged.vertex_normals.extend_from_slice(&mesh.vertex_normals);
+            merged.vertex_tex_coords.extend_from_slice(&mesh.vertex_tex_coords);
+            merged.vertex_colors.extend_from_slice(&mesh.vertex_colors);
+            let extra_tex_coords_offsets: Vec<usize> = (0..mesh.extra_tex_coords.len())
+                .map(|index| merged.extra_tex_coords.get(index).map_or(0, Vec::len))
+                .collect();
+            for (index, tex_coords) in mesh.extra_tex_coords.iter().enumerate() {
+                if index == merged.extra_tex_coords.len() {
+                    merged.extra_tex_coords.push(Vec::new());
+                }
+                merged.extra_tex_coords[index].extend_from_slice(tex_coords);
+            }
+
+            let offset = |indices: CornerIndices, offset: usize| indices.map(|index| index + offset);
+            merged.triangles.extend(mesh.triangles.iter().map(|triangle| IndexedTriangle {
+                position_indices: offset(triangle.position_indices, position_offset),
+                normal_indices: triangle.normal_indices.map(|indices| offset(indices, normal_offset)),
+                tex_coords_indices: triangle.tex_coords_indices.map(|indices| offset(indices, tex_coords_offset)),
+                color_indices: triangle.color_indices.map(|indices| offset(indices, color_offset)),
+                extra_tex_coords_indices: array::from_fn(|index| triangle.extra_tex_coords_indices[index]
+                    .map(|indices| offset(indices, extra_tex_coords_offsets[index]))),
+            }));
+        }
+        merged
+    }
+
+    /// Turn the mesh inside out by reversing the winding of all triangles and negating the normals
+    pub fn flip(&mut self) {
+        for normal in &mut self.vertex_normals {
+            *normal = (-normal.0, -normal.1, -normal.2);
+        }
+        self.reverse_winding();
+    }
+
+    fn reverse_winding(&mut self) {
+        let reverse = |indices: &mut CornerIndices| *indices = indices.reversed();
+        for triangle in &mut self.triangles {
+            reverse(&mut triangle.position_indices);
+            triangle.normal_indices.as_mut().map(reverse);
+            triangle.tex_coords_indices.as_mut().map(reverse);
+            triangle.color_indices.as_mut().map(reverse);
+            for indices in triangle.extra_tex_coords_indices.iter_mut().flatten() {
+                reverse(indices);
+            }
+        }
+    }
+
+    /// Merge vertex positions, normals, texture coordinates and colors that are at most `epsilon` apart
+    ///
+    /// Each attribute is welded on its own, so e.g. the corners of a cube share their positions but keep the normals of
+    /// the adjacent faces. Unused values are dropped, as are triangles that collapse because two of their corners were
+    /// merged.
+    pub fn weld(&mut self, epsilon: Float) {
+        let (positions, position_map) = weld_values(&self.vertex_positions, |p| [p.0, p.1, p.2], epsilon);
+        let (normals, normal_map) = weld_values(&self.vertex_normals, |n| [n.0, n.1, n.2], epsilon);
+        let (tex_coords, tex_coords_map) = weld_values(&self.vertex_tex_coords, |t| [t.0, t.1, 0.0], epsilon);
+        let (colors, color_map) = weld_values(&self.vertex_colors, |c| [c.0, c.1, c.2], epsilon);
+        let (extra_tex_coords, extra_tex_coords_maps): (Vec<_>, Vec<_>) = self.extra_tex_coords.iter()
+            .map(|tex_coords| weld_values(tex_coords, |t| [t.0, t.1, 0.0], epsilon))
+            .unzip();
+
+        let remap = |indices: CornerIndices, map: &[usize]| indices.map(|index| map[index]);
+        let triangles = self.triangles.iter()
+            .map(|triangle| IndexedTriangle {
+                position_indices: remap(triangle.position_indices, &position_map),
+                normal_indices: triangle.normal_indices.map(|indices| remap(indices, &normal_map)),
+                tex_coords_indices: triangle.tex_coords_indices.map(|indices| remap(indices, &tex_coords_map)),
+                color_indices: triangle.color_indices.map(|indices| remap(indices, &color_map)),
+                extra_tex_coords_indices: array::from_fn(|index| triangle.extra_tex_coords_indices[index]
+                    .map(|indices| remap(indices, &extra_tex_coords_maps[index]))),
+            })
+            .filter(|triangle| {
+                let (a, b, c) = triangle.position_indices.get();
+                a != b && b != c && c != a
+            })
+            .collect();
+
+        *self = MeshData {
+            vertex_positions: positions,
+            vertex_normals: normals,
+            vertex_tex_coords: tex_coords,
+            vertex_colors: colors,
+            extra_tex_coords,
+            triangles,
+        };
+        self.remove_unused_vertices();
+    }
+
+    /// Drop all positions, normals, texture coordinates and colors that no triangle refers to
+    fn remove_unused_vertices(&mut self) {
+        fn compact<T: Copy>(values: &mut Vec<T>, indices: impl Iterator<Item=usize>) -> Vec<usize> {
+            let mut used = vec![false; values.len()];
+            for index in indices {
+                used[index] = true;
+            }
+            let mut map = vec![0; values.len()];
+            let mut kept = 0;
+            for index in 0..values.len() {
+                if used[index] {
+                    values[kept] = values[index];
+                    map[index] = kept;
+                    kept += 1;
+                }
+            }
+            values.truncate(kept);
+            map
+        }
+
+        let flatten = |indices: CornerIndices| indices.to_array();
+        let position_map = compact(&mut self.vertex_positions, self.triangles.iter()
+            .flat_map(|triangle| flatten(triangle.position_indices)));
+        let normal_map = compact(&mut self.vertex_normals, self.triangles.iter()
+            .filter_map(|triangle| triangle.normal_indices)
+            .flat_map(flatten));
+        let tex_coords_map = compact(&mut self.vertex_tex_coords, self.triangles.iter()
+            .filter_map(|triangle| triangle.tex_coords_indices)
+            .flat_map(flatten));
+        let color_map = compact(&mut self.vertex_colors, self.triangles.iter()
+            .filter_map(|triangle| triangle.color_indices)
+            .flat_map(flatten));
+        let triangles = &self.triangles;
+        let extra_tex_coords_maps: Vec<_> = self.extra_tex_coords.iter_mut().enumerate()
+            .map(|(index, tex_coords)| compact(tex_coords, triangles.iter()
+                .filter_map(|triangle| triangle.extra_tex_coords_indices[index])
+                .flat_map(flatten)))
+            .collect();
+
+        let remap = |indices: CornerIndices, map: &[usize]| indices.map(|index| map[index]);
+        for triangle in &mut self.triangles {
+            triangle.position_indices = remap(triangle.position_indices, &position_map);
+            triangle.normal_indices = triangle.normal_indices.map(|indices| remap(indices, &normal_map));
+            triangle.tex_coords_indices = triangle.tex_coords_indices.map(|indices| remap(indices, &tex_coords_map));
+            triangle.color_indices = triangle.color_indices.map(|indices| remap(indices, &color_map));
+            for (indices, map) in triangle.extra_tex_coords_indices.iter_mut().zip(&extra_tex_coords_maps) {
+                *indices = indices.map(|indices| remap(indices, map));
+            }
+        }
+    }
 }
 
-struct TriangleHit {
-    distance: f32,
-    u: f32,
-    v: f32,
+/// Mesh data in the form the acceleration structures use it
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+pub enum MeshStorage {
+    Full(MeshData),
+    /// Read-only data in compact arrays, see `CompactMeshData`
+    Compact(CompactMeshData),
 }
 
-fn intersect_triangle(ray: &Ray, v0: &Vector3<f32>, v1: &Vector3<f32>, v2: &Vector3<f32>) -> Option<TriangleHit> {
+impl MeshStorage {
+    pub fn triangle_count(&self) -> usize {
+        match self {
+            MeshStorage::Full(data) => data.triangles.len(),
+            MeshStorage::Compact(data) => data.triangle_count(),
+        }
+    }
+
+    /// Positions of the corners of a triangle
+    pub fn triangle_positions(&self, triangle_index: usize) -> [Vector3<Float>; 3] {
+        match self {
+            MeshStorage::Full(data) => data.triangle_positions(triangle_index),
+            MeshStorage::Compact(data) => data.triangle_positions(triangle_index),
+        }
+    }
+
+    /// Whether all vertex positions are finite numbers
+    pub fn has_finite_positions(&self) -> bool {
+        match self {
+            MeshStorage::Full(data) => data.vertex_positions.iter().all(|&(x, y, z)| x.is_finite() && y.is_finite() && z.is_finite()),
+            MeshStorage::Compact(data) => data.has_finite_positions(),
+        }
+    }
+
+    /// Test a ray against a single triangle
+    pub fn intersect_triangle(&self, ray: &Ray, triangle_index: usize) -> Option<TriangleHit> {
+        match self {
+            MeshStorage::Full(data) => data.intersect_triangle(ray, triangle_index),
+            MeshStorage::Compact(data) => data.intersect_triangle(ray, triangle_index),
+        }
+    }
+
+    /// Calculate coordinates, normal, texture coordinates and vertex color of a hit point on a triangle
+    pub fn create_hit(&self, ray: &Ray, triangle_index: usize, triangle_hit: &TriangleHit) -> Hit {
+        match self {
+            MeshStorage::Full(data) => data.create_hit(ray, triangle_index, triangle_hit),
+            MeshStorage::Compact(data) => data.create_hit(ray, triangle_index, triangle_hit),
+        }
+    }
+
+    /// Bytes of memory used by the mesh data, not counting memory-mapped files
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            MeshStorage::Full(data) => data.memory_usage(),
+            MeshStorage::Compact(data) => data.memory_usage(),
+        }
+    }
+
+    /// The editable mesh data, unless it is stored in compact form
+    pub fn mesh_data(&self) -> Option<&MeshData> {
+        match self {
+            MeshStorage::Full(data) => Some(data),
+            MeshStorage::Compact(_) => None,
+        }
+    }
+}
+
+/// The vertex attributes at the corners of a single triangle, independent of how the mesh stores them
+pub(crate) struct TriangleCorners {
+    pub positions: [Vector3<Float>; 3],
+    pub normals: Option<[Vector3<Float>; 3]>,
+    /// Texture coordinates of each UV channel
+    pub tex_coords: [Option<[Vector2<Float>; 3]>; MAX_UV_CHANNELS],
+    pub colors: Option<[Color; 3]>,
+}
+
+impl TriangleCorners {
+    /// Calculate coordinates, normal, texture coordinates and vertex color of a hit point on the triangle
+    pub fn create_hit(&self, ray: &Ray, triangle_hit: &TriangleHit) -> Hit {
+        let [v0, v1, v2] = &self.positions;
+
+        let normal = self.normals.map_or_else(|| {
+            // Calculate face normal from vertex positions
+            (v1 - v0).cross(v2 - v0).normalize()
+        }, |[n0, n1, n2]| {
+            // Interpolate vertex normals using the barycentric coordinates of the hit point
+            (1.0 - triangle_hit.u - triangle_hit.v) * n0 + triangle_hit.u * n1 + triangle_hit.v * n2
+        });
+
+        // Interpolate vertex texture coordinates using the barycentric coordinates of the hit point
+        let interpolate_tex_coords = |[t0, t1, t2]: [Vector2<Float>; 3]| {
+            (1.0 - triangle_hit.u - triangle_hit.v) * t0 + triangle_hit.u * t1 + triangle_hit.v * t2
+        };
+        let triangle_tex_coords = self.tex_coords[0];
+        let tex_coords = triangle_tex_coords.map_or_else(Vector2::zero, interpolate_tex_coords);
+
+        let vertex_color = self.colors.map(|[c0, c1, c2]| {
+            // Interpolate vertex colors using the barycentric coordinates of the hit point
+            c0 * (1.0 - triangle_hit.u - triangle_hit.v) + c1 * triangle_hit.u + c2 * triangle_hit.v
+        });
+
+        let (dpdu, dpdv) = self.calc_position_derivatives(triangle_tex_coords, &normal);
+
+        let mut extra_uv_channels = [None; MAX_UV_CHANNELS - 1];
+        for (uv_channel, channel_tex_coords) in extra_uv_channels.iter_mut().zip(&self.tex_coords[1..]) {
+            *uv_channel = channel_tex_coords.map(|triangle_tex_coords| {
+                let (dpdu, dpdv) = self.calc_position_derivatives(Some(triangle_tex_coords), &normal);
+                UvChannel::new(interpolate_tex_coords(triangle_tex_coords), dpdu, dpdv)
+            });
+        }
+
+        let hit = Hit::new(
+            ray.origin + ray.direction * triangle_hit.distance,
+            triangle_hit.distance,
+            normal,
+            tex_coords,
+            dpdu,
+            dpdv,
+        ).with_triangle_edges(v0, v1, v2);
+        Hit { vertex_color, extra_uv_channels, ..hit }
+    }
+
+    /// Calculate the partial derivatives of the position with respect to the texture coordinates on the triangle
+    fn calc_position_derivatives(&self, tex_coords: Option<[Vector2<Float>; 3]>, normal: &Vector3<Float>) -> (Vector3<Float>, Vector3<Float>) {
+        // Without (valid) texture coordinates any two vectors spanning the surface are fine
+        let fallback = || orthonormal_basis(&normal.normalize());
+
+        let [t0, t1, t2] = match tex_coords {
+            Some(tex_coords) => tex_coords,
+            None => return fallback(),
+        };
+
+        let [v0, v1, v2] = &self.positions;
+        let edge1 = v1 - v0;
+        let edge2 = v2 - v0;
+        let delta1 = t1 - t0;
+        let delta2 = t2 - t0;
+
+        let determinant = delta1.x * delta2.y - delta2.x * delta1.y;
+        if determinant.abs() < Float::EPSILON {
+            return fallback();
+        }
+
+        let dpdu = (edge1 * delta2.y - edge2 * delta1.y) / determinant;
+        let dpdv = (edge2 * delta1.x - edge1 * delta2.x) / determinant;
+        (dpdu, dpdv)
+    }
+}
+
+/// Merge values whose coordinates are at most `epsilon` apart, each value is merged into the first one close to it
+///
+/// Returns the remaining values and the new index of every original value.
+pub(crate) fn weld_values<T: Copy>(values: &[T], coords: impl Fn(&T) -> [Float; 3], epsilon: Float) -> (Vec<T>, Vec<usize>) {
+    // Close values are at most one grid cell apart
+    let cell_size = if epsilon > 0.0 { epsilon } else { 1.0 };
+    let cell_of = |c: &[Float; 3]| [
+        (c[0] / cell_size).floor() as i64,
+        (c[1] / cell_size).floor() as i64,
+        (c[2] / cell_size).floor() as i64,
+    ];
+
+    let mut grid: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+    let mut welded = Vec::new();
+    let mut welded_coords = Vec::new();
+    let mut map = Vec::with_capacity(values.len());
+    for value in values {
+        let c = coords(value);
+        let cell = cell_of(&c);
+
+        let mut existing = None;
+        'search: for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let neighbour = [cell[0] + dx, cell[1] + dy, cell[2] + dz];
+                    for &index in grid.get(&neighbour).into_iter().flatten() {
+                        let other: &[Float; 3] = &welded_coords[index];
+                        let distance_squared = (0..3).map(|axis| float::powi(c[axis] - other[axis], 2)).sum::<Float>();
+                        if distance_squared <= epsilon * epsilon {
+                            existing = Some(index);
+                            break 'search;
+                        }
+                    }
+                }
+            }
+        }
+
+        map.push(existing.unwrap_or_else(|| {
+            welded.push(*value);
+            welded_coords.push(c);
+            grid.entry(cell).or_default().push(welded.len() - 1);
+            welded.len() - 1
+        }));
+    }
+
+    (welded, map)
+}
+
+pub struct TriangleHit {
+    pub distance: Float,
+    pub(crate) u: Float,
+    pub(crate) v: Float,
+}
+
+pub(crate) fn intersect_triangle(ray: &Ray, v0: &Vector3<Float>, v1: &Vector3<Float>, v2: &Vector3<Float>) -> Option<TriangleHit> {
     // Möller-Trumbore ray-triangle intersection algorithm
 
     let v0v1: Vector3<_> = v1 - v0;
@@ -55,7 +563,7 @@ fn intersect_triangle(ray: &Ray, v0: &Vector3<f32>, v1: &Vector3<f32>, v2: &Vect
     let pvec = ray.direction.cross(v0v2);
     let det = v0v1.dot(pvec);
 
-    if det.abs() < f32::EPSILON {
+    if det.abs() < Float::EPSILON {
         return None;
     }
 
@@ -63,7 +571,7 @@ fn intersect_triangle(ray: &Ray, v0: &Vector3<f32>, v1: &Vector3<f32>, v2: &Vect
 
     let tvec = ray.origin.to_vec() - v0;
     let u = tvec.dot(pvec) * inv_det;
-    if u < 0.0 || u > 1.0 {
+    if !(0.0..=1.0).contains(&u) {
         return None;
     }
 
@@ -86,18 +594,25 @@ fn intersect_triangle(ray: &Ray, v0: &Vector3<f32>, v1: &Vector3<f32>, v2: &Vect
     })
 }
 
+/// Settings that trade K-D tree build time against intersection speed
+#[derive(Copy, Clone)]
 pub struct KDTreeOptions {
-    max_depth: Option<usize>,
-    max_leaf_size: usize,
-    debug: bool,
+    /// Maximum depth of the tree, `None` to derive it from the number of triangles
+    pub max_depth: Option<usize>,
+    /// Nodes with at most this many triangles are not split any further
+    pub max_leaf_size: usize,
+    /// Clip triangles to the bounds of each node instead of using their full bounding boxes ("perfect splits")
+    ///
+    /// Slows down construction but keeps leaves small for meshes with large triangles
+    pub clip_triangles: bool,
 }
 
 impl Default for KDTreeOptions {
     fn default() -> Self {
         KDTreeOptions {
             max_depth: None,
-            max_leaf_size: 16,
-            debug: false,
+            max_leaf_size: default_max_leaf_size(),
+            clip_triangles: false,
         }
     }
 }
@@ -108,22 +623,22 @@ pub struct LinearKDTreeNode {
     /// Inner node: the two LSBs store the split axis (0-2), the 30 MSBs hold the index of the second child node
     first_field: u32,
     /// Leaf node: the index of the first triangle in `linear_triangle_indices`
-    /// Inner node: the split position as f32 (using mem::transmute())
-    second_field: u32,
+    /// Inner node: the split position (using Float::to_bits())
+    second_field: FloatBits,
 }
 
 impl LinearKDTreeNode {
     fn new_leaf(triangle_count: u32, triangles_start_index: u32) -> LinearKDTreeNode {
         LinearKDTreeNode {
             first_field: triangle_count.checked_shl(2).unwrap() | 0x3,
-            second_field: triangles_start_index,
+            second_field: triangles_start_index as FloatBits,
         }
     }
 
-    fn new_inner(above_child_index: u32, split_axis: Axis, split_position: f32) -> LinearKDTreeNode {
+    fn new_inner(above_child_index: u32, split_axis: Axis, split_position: Float) -> LinearKDTreeNode {
         LinearKDTreeNode {
             first_field: above_child_index.checked_shl(2).unwrap() | split_axis as u32,
-            second_field: unsafe { mem::transmute(split_position) },
+            second_field: split_position.to_bits(),
         }
     }
 
@@ -147,14 +662,14 @@ impl LinearKDTreeNode {
         self.first_field >> 2
     }
 
+    // `FloatBits` is only wider than `u32` with the `f64` feature
+    #[allow(clippy::unnecessary_cast)]
     fn triangles_start_index(&self) -> u32 {
-        self.second_field
+        self.second_field as u32
     }
 
-    fn split_position(&self) -> f32 {
-        unsafe {
-            mem::transmute(self.second_field)
-        }
+    fn split_position(&self) -> Float {
+        Float::from_bits(self.second_field)
     }
 }
 
@@ -162,42 +677,104 @@ impl LinearKDTreeNode {
 pub struct LinearKDTree {
     /// All nodes are stored depth-first in this vector to improve traversal speed
     nodes: Vec<LinearKDTreeNode>,
-    linear_triangle_indices: Vec<usize>,
+    /// 32 bit like the start indices in the leaves, to halve the memory of this largest part of the tree
+    linear_triangle_indices: Vec<u32>,
     bounding_box: AABB,
-    data: MeshData,
+    data: MeshStorage,
     debug: bool,
     intersect_stack_capacity: usize,
+    stats: BuildStats,
 }
 
 /// Edge of a bounding box projected onto an axis
 struct BoundEdge {
-    position: f32,
-    triangle_index: usize,
+    position: Float,
+    triangle_index: u32,
     is_end: bool,
 }
 
+/// Node that still has to be built during K-D tree construction
+struct BuildItem {
+    /// Inner node that this is the second child of
+    second_child_of: Option<usize>,
+    /// Whether the triangle indices are in the space of `above_offset` rather than at the start of the indices below
+    is_above: bool,
+    /// Start of the part of the indices above that this node and its subtree use
+    above_offset: usize,
+    triangle_count: usize,
+    bounding_box: AABB,
+    depth: usize,
+}
+
 /// Node that still has to be traversed during K-D tree intersection test
 struct ToDoItem {
     node_index: usize,
-    t_min: f32,
-    t_max: f32,
+    t_min: Float,
+    t_max: Float,
+}
+
+thread_local! {
+    /// Traversal stacks reused by all rays and packets of a thread
+    static TODO_STACK: RefCell<Vec<ToDoItem>> = const { RefCell::new(Vec::new()) };
+    static PACKET_TODO_STACK: RefCell<Vec<PacketToDoItem>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Number of triangles a `Mailbox` remembers
+const MAILBOX_SIZE: usize = 32;
+
+/// Triangles tested during a single traversal, so that triangles that straddle split planes and are therefore
+/// stored in several leaves are only tested once
+///
+/// Direct mapped: a triangle may be tested again after another one took its slot, which only costs time since
+/// testing the same ray against the same triangle always gives the same result.
+struct Mailbox {
+    slots: [usize; MAILBOX_SIZE],
+}
+
+impl Mailbox {
+    fn new() -> Mailbox {
+        Mailbox {
+            slots: [usize::MAX; MAILBOX_SIZE],
+        }
+    }
+
+    /// Mark a triangle as tested, returns `false` if it already was
+    fn insert(&mut self, triangle_index: usize) -> bool {
+        let slot = &mut self.slots[triangle_index % MAILBOX_SIZE];
+        if *slot == triangle_index {
+            false
+        } else {
+            *slot = triangle_index;
+            true
+        }
+    }
+}
+
+/// Node that still has to be traversed by a ray packet, with the parametric range of each ray inside the node
+struct PacketToDoItem {
+    node_index: usize,
+    t_min: [Float; PACKET_SIZE],
+    t_max: [Float; PACKET_SIZE],
 }
 
 impl LinearKDTree {
-    pub fn build(data: MeshData, options: &KDTreeOptions) -> LinearKDTree {
-        let triangle_count = data.triangles.len();
+    /// Build the tree, calling `on_progress` after every node that is created
+    ///
+    /// Nodes are built depth first from an explicit work stack instead of recursively, so that large maximum depths
+    /// can't overflow the call stack.
+    pub fn build(data: MeshStorage, options: &KDTreeOptions, debug: bool, mut on_progress: impl FnMut(&BuildProgress)) -> LinearKDTree {
+        let start = Instant::now();
+        let triangle_count = data.triangle_count();
 
         // Formula taken from "Physically Based Rendering: From Theory To Implementation"
         let max_depth = options.max_depth
-            .unwrap_or_else(|| 8 + (1.3 * (triangle_count as f32).log2()).round() as usize);
+            .unwrap_or_else(|| 8 + (1.3 * (triangle_count as Float).log2()).round() as usize);
 
         let mut root_bounding_box = AABB::empty();
         let mut triangle_bounding_boxes = Vec::with_capacity(triangle_count);
-        for triangle in &data.triangles {
-            let v0 = data.get_vertex_position(triangle.position_indices.0);
-            let v1 = data.get_vertex_position(triangle.position_indices.1);
-            let v2 = data.get_vertex_position(triangle.position_indices.2);
-            let bounding_box = AABB::from_triangle(v0, v1, v2);
+        for triangle_index in 0..triangle_count {
+            let [v0, v1, v2] = data.triangle_positions(triangle_index);
+            let bounding_box = AABB::from_triangle(&v0, &v1, &v2);
             root_bounding_box = root_bounding_box.union(&bounding_box);
             triangle_bounding_boxes.push(bounding_box);
         }
@@ -205,94 +782,170 @@ impl LinearKDTree {
         // All required working memory is allocated up front
 
         // Initialize with indices of all triangles
-        let mut indices_below: Vec<_> = (0..triangle_count).collect();
+        let mut indices_below: Vec<_> = (0..triangle_count as u32).collect();
         // Reserve size for worst case
         let mut indices_above = vec![0; (max_depth + 1) * triangle_count];
         let mut edges = Vec::with_capacity(triangle_count * 2);
 
-        let mut nodes = Vec::new();
+        let mut nodes: Vec<LinearKDTreeNode> = Vec::new();
         let mut linear_triangle_indices = Vec::new();
+        let mut tree_depth = 0;
+        let mut progress = BuildProgress {
+            triangle_count,
+            triangles_processed: 0,
+            triangles_pending: triangle_count,
+            nodes_created: 0,
+        };
 
-        LinearKDTree::build_node(
-            &mut nodes,
-            &mut linear_triangle_indices,
-            &mut indices_below,
-            &mut indices_above,
-            // The initial set of triangles is passed in `indices_below`
-            false,
+        // The initial set of triangles is passed in `indices_below`
+        let mut work_stack = vec![BuildItem {
+            second_child_of: None,
+            is_above: false,
+            above_offset: 0,
             triangle_count,
-            &root_bounding_box,
-            &triangle_bounding_boxes,
-            max_depth,
-            options,
-            &mut edges,
-        );
+            bounding_box: root_bounding_box.clone(),
+            depth: 0,
+        }];
+        while let Some(item) = work_stack.pop() {
+            // The index of the second child node is only known now
+            if let Some(parent_index) = item.second_child_of {
+                let second_child_index = nodes.len() as u32;
+                nodes[parent_index].set_above_child_index(second_child_index);
+            }
+            tree_depth = tree_depth.max(item.depth + 1);
+            progress.triangles_pending -= item.triangle_count;
+
+            let children = LinearKDTree::build_node(
+                &mut nodes,
+                &mut linear_triangle_indices,
+                &mut indices_below,
+                &mut indices_above,
+                &item,
+                &triangle_bounding_boxes,
+                &data,
+                max_depth,
+                options,
+                &mut edges,
+            );
+            // The first child is built next, and all of its subtree before the second child
+            if let Some([below, above]) = children {
+                progress.triangles_pending += below.triangle_count + above.triangle_count;
+                work_stack.push(above);
+                work_stack.push(below);
+            }
+
+            progress.nodes_created = nodes.len();
+            progress.triangles_processed = linear_triangle_indices.len();
+            on_progress(&progress);
+        }
 
         nodes.shrink_to_fit();
         linear_triangle_indices.shrink_to_fit();
 
-        let max_depth = Self::max_depth_recursive(&nodes, 0);
-        let intersect_stack_capacity = (max_depth as f32 * 0.65).round() as usize;
+        let intersect_stack_capacity = (tree_depth as Float * 0.65).round() as usize;
+
+        let stats = BuildStats {
+            build_time: start.elapsed(),
+            node_count: nodes.len(),
+            leaf_count: nodes.iter().filter(|node| !node.is_inner()).count(),
+            max_depth: tree_depth,
+            triangle_references: linear_triangle_indices.len(),
+            memory_usage: nodes.capacity() * mem::size_of::<LinearKDTreeNode>()
+                + linear_triangle_indices.capacity() * mem::size_of::<u32>(),
+            ..BuildStats::default()
+        };
 
         LinearKDTree {
             nodes,
             linear_triangle_indices,
             bounding_box: root_bounding_box,
             data,
-            debug: options.debug,
+            debug,
             intersect_stack_capacity,
+            stats,
         }
     }
 
-    /// Construct a new node in place
+    /// Construct a new node in place and return the nodes that still have to be built below it, if it is an inner node
     ///
     /// Arguments:
     ///
     /// * `nodes`: All nodes in depth-first, left-to-right order
     /// * `linear_triangle_indices`: The indices of all triangles; all indices of one leaf node are grouped together
     /// * `triangle_indices_below`: Heap space for nodes below the previous split
-    /// * `triangle_indices_above`: Heap space for nodes above the previous split
-    /// * `is_above`: Whether `triangle_indices_below` or `triangle_indices_above` contains the triangle indices for this node
-    /// * `triangle_count`: Number of triangles in this node, also determines how many items of `triangle_indices_below` or `triangle_indices_above` are valid
-    /// * `node_bounding_box`: Bounding box of all triangles in this node
+    /// * `triangle_indices_above`: Heap space for nodes above the previous split, each subtree uses the part starting at
+    ///   its `above_offset`
+    /// * `item`: The node to build
     /// * `triangle_bounding_boxes`: Bounding boxes of all triangles
-    /// * `depth_remaining`: Decremented with each level of recursion
+    /// * `data`: The mesh, required for clipping triangles to the node bounds
+    /// * `max_depth`: Depth at which nodes become leaves regardless of their triangle count
     /// * `options`: Build options
     /// * `edges`: Pre-allocated heap space for bounding box edges
+    #[allow(clippy::too_many_arguments)]
     fn build_node(
         nodes: &mut Vec<LinearKDTreeNode>,
-        linear_triangle_indices: &mut Vec<usize>,
-        triangle_indices_below: &mut [usize],
-        triangle_indices_above: &mut [usize],
-        is_above: bool,
-        triangle_count: usize,
-        node_bounding_box: &AABB,
+        linear_triangle_indices: &mut Vec<u32>,
+        triangle_indices_below: &mut [u32],
+        triangle_indices_above: &mut [u32],
+        item: &BuildItem,
         triangle_bounding_boxes: &[AABB],
-        depth_remaining: usize,
+        data: &MeshStorage,
+        max_depth: usize,
         options: &KDTreeOptions,
         edges: &mut Vec<BoundEdge>,
-    ) {
-        let triangle_indices = if is_above {
-            &triangle_indices_above[..triangle_count]
+    ) -> Option<[BuildItem; 2]> {
+        let node_bounding_box = &item.bounding_box;
+        let triangle_count = item.triangle_count;
+        let triangle_indices = if item.is_above {
+            &triangle_indices_above[item.above_offset..item.above_offset + triangle_count]
         } else {
             &triangle_indices_below[..triangle_count]
         };
 
-        if triangle_count <= options.max_leaf_size || depth_remaining == 0 {
+        // Bounding box of the part of a triangle that overlaps this node, `None` if it doesn't overlap at all
+        let clipped_bounding_box = |triangle_index: u32| {
+            let bounding_box = &triangle_bounding_boxes[triangle_index as usize];
+            if options.clip_triangles {
+                let [v0, v1, v2] = data.triangle_positions(triangle_index as usize);
+                node_bounding_box.clip_triangle(&v0, &v1, &v2)
+            } else {
+                Some(bounding_box.clone())
+            }
+        };
+
+        if triangle_count <= options.max_leaf_size || item.depth >= max_depth {
             let start_index = linear_triangle_indices.len();
-            linear_triangle_indices.extend_from_slice(triangle_indices);
-            nodes.push(LinearKDTreeNode::new_leaf(triangle_count as u32, start_index as u32));
+            if options.clip_triangles {
+                linear_triangle_indices.extend(triangle_indices.iter()
+                    .filter(|&&triangle_index| clipped_bounding_box(triangle_index).is_some()));
+            } else {
+                linear_triangle_indices.extend_from_slice(triangle_indices);
+            }
+            let leaf_triangle_count = linear_triangle_indices.len() - start_index;
+            nodes.push(LinearKDTreeNode::new_leaf(leaf_triangle_count as u32, start_index as u32));
 
-            return;
+            return None;
         }
 
         let split_axis = node_bounding_box.maximum_extent();
 
         edges.clear();
         for &triangle_index in triangle_indices {
-            let bounding_box = &triangle_bounding_boxes[triangle_index];
-            edges.push(BoundEdge { position: bounding_box.min[split_axis], triangle_index, is_end: false });
-            edges.push(BoundEdge { position: bounding_box.max[split_axis], triangle_index, is_end: true });
+            // Triangles whose bounding box overlaps the node but that don't intersect it are dropped here
+            if let Some(bounding_box) = clipped_bounding_box(triangle_index) {
+                edges.push(BoundEdge { position: bounding_box.min[split_axis], triangle_index, is_end: false });
+                edges.push(BoundEdge { position: bounding_box.max[split_axis], triangle_index, is_end: true });
+            }
+        }
+
+        if edges.len() < 4 {
+            // Too few triangles left to split
+            let start_index = linear_triangle_indices.len();
+            linear_triangle_indices.extend(edges.iter().filter(|edge| !edge.is_end).map(|edge| edge.triangle_index));
+            let leaf_triangle_count = linear_triangle_indices.len() - start_index;
+            nodes.push(LinearKDTreeNode::new_leaf(leaf_triangle_count as u32, start_index as u32));
+
+            return None;
         }
 
         edges.sort_unstable_by(|a, b| {
@@ -302,6 +955,7 @@ impl LinearKDTree {
         // TODO: replace median with SAH
         let split_position = (edges[edges.len() / 2].position + edges[edges.len() / 2 + 1].position) * 0.5;
 
+        let triangle_indices_above = &mut triangle_indices_above[item.above_offset..];
         let mut n_below = 0;
         let mut n_above = 0;
 
@@ -331,80 +985,89 @@ impl LinearKDTree {
 
         let mut bounding_box_below = node_bounding_box.clone();
         bounding_box_below.max[split_axis] = split_position;
-        LinearKDTree::build_node(
-            nodes,
-            linear_triangle_indices,
-            triangle_indices_below,
-            // The first `n_above` items of `triangle_indices_above` need to be preserved for construction of the second child node
-            &mut triangle_indices_above[n_above..],
-            false,
-            n_below,
-            &bounding_box_below,
-            triangle_bounding_boxes,
-            depth_remaining - 1,
-            options,
-            edges,
-        );
-
-        // Update index of the second child node now that we know it
-        let second_child_index = nodes.len();
-        nodes[node_index].set_above_child_index(second_child_index as u32);
-
         let mut bounding_box_above = node_bounding_box.clone();
         bounding_box_above.min[split_axis] = split_position;
-        LinearKDTree::build_node(
-            nodes,
-            linear_triangle_indices,
-            triangle_indices_below,
-            triangle_indices_above,
-            true,
-            n_above,
-            &bounding_box_above,
-            triangle_bounding_boxes,
-            depth_remaining - 1,
-            options,
-            edges,
-        );
-    }
-
-    fn max_depth_recursive(nodes: &[LinearKDTreeNode], node_index: usize) -> usize {
-        let node = &nodes[node_index];
-        if node.is_inner() {
-            let first_child_index = node_index + 1;
-            let second_child_index = node.above_child_index() as usize;
-
-            let max_child_depth = usize::max(
-                Self::max_depth_recursive(nodes, first_child_index),
-                Self::max_depth_recursive(nodes, second_child_index)
-            );
 
-            max_child_depth + 1
-        } else {
-            1
-        }
+        Some([
+            BuildItem {
+                second_child_of: None,
+                is_above: false,
+                // The first `n_above` items of `triangle_indices_above` need to be preserved for construction of the second child node
+                above_offset: item.above_offset + n_above,
+                triangle_count: n_below,
+                bounding_box: bounding_box_below,
+                depth: item.depth + 1,
+            },
+            BuildItem {
+                second_child_of: Some(node_index),
+                is_above: true,
+                above_offset: item.above_offset,
+                triangle_count: n_above,
+                bounding_box: bounding_box_above,
+                depth: item.depth + 1,
+            },
+        ])
+    }
+
+    pub fn data(&self) -> &MeshStorage {
+        &self.data
+    }
+
+    pub fn stats(&self) -> &BuildStats {
+        &self.stats
     }
 
-    pub fn max_depth(&self) -> usize {
-        Self::max_depth_recursive(&self.nodes, 0)
+    pub fn bounding_box(&self) -> &AABB {
+        &self.bounding_box
+    }
+
+    /// Split planes of the inner nodes down to `max_depth` (the root has depth 0), each as a flat box covering its
+    /// node, together with their depth
+    pub fn split_planes(&self, max_depth: usize) -> Vec<(usize, AABB)> {
+        let mut planes = Vec::new();
+        let mut stack = vec![(0, self.bounding_box.clone(), 0)];
+        while let Some((node_index, bounds, depth)) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if !node.is_inner() || depth > max_depth {
+                continue;
+            }
+
+            let axis = node.split_axis() as usize;
+            let split_position = node.split_position();
+            let mut plane = bounds.clone();
+            plane.min[axis] = split_position;
+            plane.max[axis] = split_position;
+            planes.push((depth, plane));
+
+            let mut below = bounds.clone();
+            below.max[axis] = split_position;
+            let mut above = bounds;
+            above.min[axis] = split_position;
+            stack.push((node_index + 1, below, depth + 1));
+            stack.push((node.above_child_index() as usize, above, depth + 1));
+        }
+        planes
     }
 
     pub fn intersect(&self, ray: &Ray) -> Option<Hit> {
-        if let Some((bb_t_min, bb_t_max)) = self.bounding_box.intersects_p(ray) {
-            let mut todo_stack = Vec::with_capacity(self.intersect_stack_capacity);
+        if let Some((bb_t_min, bb_t_max)) = self.bounding_box.intersects_p(ray).filter(|&(t_min, _)| t_min <= ray.max_distance) {
+            let mut todo_stack = ScratchVec::take(&TODO_STACK, self.intersect_stack_capacity);
 
-            // Push root node onto stack
+            // Push root node onto stack; nodes beyond the maximum distance are never entered
             todo_stack.push(ToDoItem {
                 node_index: 0,
                 t_min: bb_t_min,
-                t_max: bb_t_max,
+                t_max: bb_t_max.min(ray.max_distance),
             });
 
             let mut nearest_hit: Option<(usize, TriangleHit)> = None;
 
             // Number of nodes we had to look up, for debugging purposes
             let mut lookups = 1;
+            let mut triangle_tests = 0;
+            let mut mailbox = Mailbox::new();
 
-            let inv_dir: Vector3<f32> = 1.0 / ray.direction;
+            let inv_dir: Vector3<Float> = 1.0 / ray.direction;
 
             while let Some(ToDoItem { node_index, t_min, t_max }) = todo_stack.pop() {
                 // Bail out if this node is behind the nearest hit that was found so far
@@ -473,14 +1136,10 @@ impl LinearKDTree {
                     let triangle_count = node.triangle_count() as usize;
                     let triangle_indices = &self.linear_triangle_indices[start_index..(start_index + triangle_count)];
 
-                    // Test ray against all triangles in this node
-                    for &triangle_index in triangle_indices {
-                        let triangle = &self.data.triangles[triangle_index];
-                        let v0 = self.data.get_vertex_position(triangle.position_indices.0);
-                        let v1 = self.data.get_vertex_position(triangle.position_indices.1);
-                        let v2 = self.data.get_vertex_position(triangle.position_indices.2);
-
-                        if let Some(hit) = intersect_triangle(ray, v0, v1, v2) {
+                    // Test ray against all triangles in this node that weren't already tested in another one
+                    for triangle_index in triangle_indices.iter().map(|&triangle_index| triangle_index as usize).filter(|&triangle_index| mailbox.insert(triangle_index)) {
+                        triangle_tests += 1;
+                        if let Some(hit) = self.data.intersect_triangle(ray, triangle_index).filter(|hit| hit.distance <= ray.max_distance) {
                             // Update `nearest_hit` only if it really is the nearest one
                             if let Some((_, current_nearest_hit)) = &nearest_hit {
                                 if hit.distance < current_nearest_hit.distance {
@@ -494,52 +1153,142 @@ impl LinearKDTree {
                 }
             }
 
-            if self.debug {
-                let mut debug_data = ray.debug_data.borrow_mut();
-                debug_data.kd_tree_lookups += lookups;
-            }
+            RayDebugData::record(RayDebugData {
+                kd_tree_lookups: if self.debug { lookups } else { 0 },
+                node_traversals: lookups,
+                triangle_tests,
+            });
 
             // Calculate coordinates, normal and texture coordinates of the hit point
-            nearest_hit.map(|(triangle_index, triangle_hit)| {
-                let triangle = &self.data.triangles[triangle_index];
-
-                let normal = triangle.normal_indices.map_or_else(|| {
-                    let v0 = self.data.get_vertex_position(triangle.position_indices.0);
-                    let v1 = self.data.get_vertex_position(triangle.position_indices.1);
-                    let v2 = self.data.get_vertex_position(triangle.position_indices.2);
-
-                    // Calculate face normal from vertex positions
-                    (v1 - v0).cross(v2 - v0).normalize()
-                }, |normal_indices| {
-                    let n0 = self.data.get_vertex_normal(normal_indices.0);
-                    let n1 = self.data.get_vertex_normal(normal_indices.1);
-                    let n2 = self.data.get_vertex_normal(normal_indices.2);
-
-                    // Interpolate vertex normals using the barycentric coordinates of the hit point
-                    (1.0 - triangle_hit.u - triangle_hit.v) * n0 + triangle_hit.u * n1 + triangle_hit.v * n2
-                });
+            nearest_hit.map(|(triangle_index, triangle_hit)| self.data.create_hit(ray, triangle_index, &triangle_hit))
+        } else {
+            None
+        }
+    }
+}
+
+impl LinearKDTree {
+    /// Intersect up to `PACKET_SIZE` rays at once
+    ///
+    /// The rays traverse the tree together and a node is only skipped if none of them intersect it, which saves
+    /// node lookups for coherent rays like primary rays. Rays that point into different octants are intersected one
+    /// by one instead.
+    pub fn intersect_packet(&self, rays: &[Ray]) -> [Option<Hit>; PACKET_SIZE] {
+        let mut hits: [Option<Hit>; PACKET_SIZE] = Default::default();
+
+        let packet = RayPacket::new(rays);
+        if !packet.has_uniform_signs() {
+            for (hit, ray) in hits.iter_mut().zip(rays) {
+                *hit = self.intersect(ray);
+            }
+            return hits;
+        }
 
-                let tex_coords = triangle.tex_coords_indices.map_or_else(|| {
-                    Vector2::zero()
-                }, |tex_coords_indices| {
-                    let t0 = self.data.get_vertex_tex_coords(tex_coords_indices.0);
-                    let t1 = self.data.get_vertex_tex_coords(tex_coords_indices.1);
-                    let t2 = self.data.get_vertex_tex_coords(tex_coords_indices.2);
+        let mut root = PacketToDoItem {
+            node_index: 0,
+            t_min: [Float::INFINITY; PACKET_SIZE],
+            t_max: [Float::NEG_INFINITY; PACKET_SIZE],
+        };
+        for (i, ray) in rays.iter().enumerate() {
+            if let Some((t_min, t_max)) = self.bounding_box.intersects_p(ray) {
+                // Parts of the ray behind its origin or beyond its maximum distance can't produce hits
+                root.t_min[i] = t_min.max(0.0);
+                root.t_max[i] = t_max.min(ray.max_distance);
+            }
+        }
+        if (0..PACKET_SIZE).all(|i| root.t_min[i] > root.t_max[i]) {
+            return hits;
+        }
 
-                    // Interpolate vertex texture coordinates using the barycentric coordinates of the hit point
-                    (1.0 - triangle_hit.u - triangle_hit.v) * t0 + triangle_hit.u * t1 + triangle_hit.v * t2
-                });
+        let mut todo_stack = ScratchVec::take(&PACKET_TODO_STACK, self.intersect_stack_capacity);
+        todo_stack.push(root);
 
-                Hit {
-                    point: ray.origin + ray.direction * triangle_hit.distance,
-                    distance: triangle_hit.distance,
-                    normal,
-                    tex_coords,
+        let mut nearest_hits: [Option<(usize, TriangleHit)>; PACKET_SIZE] = Default::default();
+        let mut lookups = 0;
+        let mut triangle_tests = 0;
+        let mut mailbox = Mailbox::new();
+
+        let mut max_distances = [Float::INFINITY; PACKET_SIZE];
+        for (max_distance, ray) in max_distances.iter_mut().zip(rays) {
+            *max_distance = ray.max_distance;
+        }
+
+        while let Some(PacketToDoItem { node_index, t_min, t_max }) = todo_stack.pop() {
+            let mut nearest_distances = max_distances;
+            for (i, nearest_hit) in nearest_hits.iter().enumerate() {
+                if let Some((_, hit)) = nearest_hit {
+                    nearest_distances[i] = hit.distance;
                 }
-            })
-        } else {
-            None
+            }
+
+            // Lanes that enter this node before their nearest hit so far
+            let mut active = [false; PACKET_SIZE];
+            for i in 0..PACKET_SIZE {
+                active[i] = packet.active[i] && t_min[i] <= t_max[i] && t_min[i] <= nearest_distances[i];
+            }
+            if !active.contains(&true) {
+                continue;
+            }
+
+            lookups += 1;
+
+            let node = &self.nodes[node_index];
+            if node.is_inner() {
+                let axis = node.split_axis() as usize;
+                let split_position = node.split_position();
+
+                // All rays point into the same direction, so they all cross the children in the same order
+                let (near_child_index, far_child_index) = if packet.is_positive(axis) {
+                    (node_index + 1, node.above_child_index() as usize)
+                } else {
+                    (node.above_child_index() as usize, node_index + 1)
+                };
+
+                let mut near = PacketToDoItem { node_index: near_child_index, t_min, t_max };
+                let mut far = PacketToDoItem { node_index: far_child_index, t_min, t_max };
+                for i in 0..PACKET_SIZE {
+                    let t_split = (split_position - packet.origin[axis][i]) * packet.inv_dir[axis][i];
+                    near.t_max[i] = t_max[i].min(t_split);
+                    far.t_min[i] = t_min[i].max(t_split);
+                }
+
+                // Stack is LIFO -> the near child is processed next
+                todo_stack.push(far);
+                todo_stack.push(near);
+            } else {
+                let start_index = node.triangles_start_index() as usize;
+                let triangle_count = node.triangle_count() as usize;
+                let triangle_indices = &self.linear_triangle_indices[start_index..(start_index + triangle_count)];
+
+                // All lanes are tested against a triangle at once, so the packet can share a mailbox
+                for triangle_index in triangle_indices.iter().map(|&triangle_index| triangle_index as usize).filter(|&triangle_index| mailbox.insert(triangle_index)) {
+                    triangle_tests += 1;
+                    let [v0, v1, v2] = self.data.triangle_positions(triangle_index);
+
+                    let hits = packet.intersect_triangle(&v0, &v1, &v2);
+                    for ((nearest_hit, hit), max_distance) in nearest_hits.iter_mut().zip(hits).zip(max_distances) {
+                        if let Some(hit) = hit.filter(|hit| hit.distance <= max_distance) {
+                            let is_nearer = nearest_hit.as_ref().is_none_or(|(_, nearest)| hit.distance < nearest.distance);
+                            if is_nearer {
+                                *nearest_hit = Some((triangle_index, hit));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // The work of the packet is attributed to each of its rays
+        RayDebugData::record(RayDebugData {
+            kd_tree_lookups: if self.debug { lookups * rays.len() } else { 0 },
+            node_traversals: lookups * rays.len(),
+            triangle_tests: triangle_tests * rays.len(),
+        });
+
+        for ((hit, ray), nearest_hit) in hits.iter_mut().zip(rays).zip(nearest_hits) {
+            *hit = nearest_hit.map(|(triangle_index, triangle_hit)| self.data.create_hit(ray, triangle_index, &triangle_hit));
         }
+        hits
     }
 }
 
@@ -547,11 +1296,63 @@ fn default_debug() -> bool {
     false
 }
 
+fn default_max_leaf_size() -> usize {
+    16
+}
+
+/// The acceleration structure that is used for intersection tests against a mesh
+#[derive(Copy, Clone, Default, Serialize, Deserialize)]
+pub enum Acceleration {
+    #[default]
+    KDTree,
+    /// 4-wide bounding volume hierarchy
+    Qbvh,
+}
+
+enum MeshAccelerator {
+    KDTree(LinearKDTree),
+    Qbvh(Qbvh),
+}
+
+impl MeshAccelerator {
+    fn stats_mut(&mut self) -> &mut BuildStats {
+        match self {
+            MeshAccelerator::KDTree(kdtree) => &mut kdtree.stats,
+            MeshAccelerator::Qbvh(qbvh) => qbvh.stats_mut(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct DeserializableMesh {
     path: PathBuf,
     #[serde(default = "default_debug")]
     debug: bool,
+    #[serde(default)]
+    clip_triangles: bool,
+    #[serde(default)]
+    max_depth: Option<usize>,
+    #[serde(default = "default_max_leaf_size")]
+    max_leaf_size: usize,
+    #[serde(default)]
+    acceleration: Acceleration,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subdivision: Option<Subdivision>,
+    /// Shorthand for `subdivision` with smooth Loop subdivision, see `Subdivision::smooth()`
+    #[serde(default, skip_serializing)]
+    subdivision_levels: Option<usize>,
+    #[serde(default)]
+    compact: bool,
+}
+
+impl DeserializableMesh {
+    fn kd_tree_options(&self) -> KDTreeOptions {
+        KDTreeOptions {
+            max_depth: self.max_depth,
+            max_leaf_size: self.max_leaf_size,
+            clip_triangles: self.clip_triangles,
+        }
+    }
 }
 
 impl From<Mesh> for DeserializableMesh {
@@ -559,6 +1360,13 @@ impl From<Mesh> for DeserializableMesh {
         DeserializableMesh {
             path: mesh.path,
             debug: mesh.debug,
+            clip_triangles: mesh.kd_tree_options.clip_triangles,
+            max_depth: mesh.kd_tree_options.max_depth,
+            max_leaf_size: mesh.kd_tree_options.max_leaf_size,
+            acceleration: mesh.acceleration,
+            subdivision: mesh.subdivision,
+            subdivision_levels: None,
+            compact: mesh.compact,
         }
     }
 }
@@ -567,8 +1375,16 @@ impl From<Mesh> for DeserializableMesh {
 #[serde(into = "DeserializableMesh")]
 pub struct Mesh {
     path: PathBuf,
-    kdtree: LinearKDTree,
+    /// Shared between clones, so that e.g. evaluating an animated scene doesn't copy all meshes
+    accelerator: Arc<MeshAccelerator>,
     debug: bool,
+    /// Only used if `acceleration` is `KDTree`
+    kd_tree_options: KDTreeOptions,
+    acceleration: Acceleration,
+    /// Applied to the loaded mesh data before building the accelerator
+    subdivision: Option<Subdivision>,
+    /// Whether the mesh data is kept as `CompactMeshData`
+    compact: bool,
 }
 
 impl<'de> Deserialize<'de> for Mesh {
@@ -577,39 +1393,263 @@ impl<'de> Deserialize<'de> for Mesh {
             D: Deserializer<'de>
     {
         let dmesh = DeserializableMesh::deserialize(deserializer)?;
-        Self::load(dmesh.path.clone(), dmesh.debug).map_err(|err| {
-            serde::de::Error::custom(format!("Unable to open mesh file \"{}\": {}", dmesh.path.display(), err))
+        let kd_tree_options = dmesh.kd_tree_options();
+        let DeserializableMesh { path, debug, acceleration, subdivision, subdivision_levels, compact, .. } = dmesh;
+        let subdivision = match (subdivision, subdivision_levels) {
+            (Some(_), Some(_)) => return Err(serde::de::Error::custom("Only one of \"subdivision\" and \"subdivision_levels\" can be given")),
+            (None, Some(levels)) => Some(Subdivision::smooth(levels)),
+            (subdivision, None) => subdivision,
+        };
+        Self::load_configured(path.clone(), subdivision, compact, debug, acceleration, kd_tree_options).map_err(|err| {
+            serde::de::Error::custom(format!("Unable to open mesh file \"{}\": {}", path.display(), err))
         })
     }
 }
 
 impl Mesh {
-    pub fn new(path: PathBuf, data: MeshData, debug: bool) -> Mesh {
-        let start = Instant::now();
-        let kdtree = LinearKDTree::build(data, &KDTreeOptions {
-            debug,
-            ..KDTreeOptions::default()
-        });
-        let duration = start.elapsed().as_secs_f64();
-        if debug {
-            let max_depth = kdtree.max_depth();
-            println!("K-D tree for {} built in {} s with a maximum depth of {} nodes", path.display(), duration, max_depth);
+    /// Build the acceleration structure for `data`, `kd_tree_options` are ignored unless `acceleration` is `KDTree`
+    pub fn new(path: PathBuf, data: MeshData, debug: bool, acceleration: Acceleration, kd_tree_options: KDTreeOptions) -> Mesh {
+        Mesh::build(path, MeshStorage::Full(data), debug, acceleration, kd_tree_options, &mut |_| {})
+    }
+
+    /// Like `new()`, but call `on_progress` while the acceleration structure is built, e.g. to show the progress of
+    /// huge meshes
+    ///
+    /// K-D trees report their progress after every node, a QBVH only once it is complete.
+    pub fn new_with_progress(path: PathBuf, data: MeshData, debug: bool, acceleration: Acceleration, kd_tree_options: KDTreeOptions, mut on_progress: impl FnMut(&BuildProgress)) -> Mesh {
+        Mesh::build(path, MeshStorage::Full(data), debug, acceleration, kd_tree_options, &mut on_progress)
+    }
+
+    /// Like `new()`, but keep the mesh data in compact form
+    pub fn new_compact(path: PathBuf, data: CompactMeshData, debug: bool, acceleration: Acceleration, kd_tree_options: KDTreeOptions) -> Mesh {
+        Mesh {
+            compact: true,
+            ..Mesh::build(path, MeshStorage::Compact(data), debug, acceleration, kd_tree_options, &mut |_| {})
         }
+    }
+
+    fn build(path: PathBuf, data: MeshStorage, debug: bool, acceleration: Acceleration, kd_tree_options: KDTreeOptions, on_progress: &mut dyn FnMut(&BuildProgress)) -> Mesh {
+        let mesh_memory_usage = data.memory_usage();
+        let mut accelerator = match acceleration {
+            Acceleration::KDTree => {
+                let kdtree = LinearKDTree::build(data, &kd_tree_options, debug, on_progress);
+                if debug {
+                    let stats = kdtree.stats();
+                    println!("K-D tree for {} built in {} s with a maximum depth of {} nodes", path.display(), stats.build_time.as_secs_f64(), stats.max_depth);
+                }
+                MeshAccelerator::KDTree(kdtree)
+            }
+            Acceleration::Qbvh => {
+                let triangle_count = data.triangle_count();
+                let qbvh = Qbvh::build(data, 4, debug);
+                on_progress(&BuildProgress {
+                    triangle_count,
+                    triangles_processed: qbvh.stats().triangle_references,
+                    triangles_pending: 0,
+                    nodes_created: qbvh.stats().node_count,
+                });
+                MeshAccelerator::Qbvh(qbvh)
+            }
+        };
+        accelerator.stats_mut().mesh_memory_usage = mesh_memory_usage;
 
         Mesh {
             path,
-            kdtree,
+            accelerator: Arc::new(accelerator),
             debug,
+            kd_tree_options,
+            acceleration,
+            subdivision: None,
+            compact: false,
+        }
+    }
+
+    /// Like `new()`, but subdivide and displace `data` first
+    pub fn new_subdivided(path: PathBuf, mut data: MeshData, subdivision: Subdivision, debug: bool, acceleration: Acceleration, kd_tree_options: KDTreeOptions) -> Mesh {
+        let subdivision_time = Mesh::subdivide(&mut data, &subdivision);
+        let mut mesh = Mesh {
+            subdivision: Some(subdivision),
+            ..Mesh::new(path, data, debug, acceleration, kd_tree_options)
+        };
+        mesh.set_subdivision_time(subdivision_time);
+        mesh
+    }
+
+    pub fn load(path: PathBuf, debug: bool, acceleration: Acceleration, kd_tree_options: KDTreeOptions) -> Result<Mesh, RaytracerError> {
+        let data = asset_loader::load_obj(&path)?;
+        Ok(Mesh::new(path, data, debug, acceleration, kd_tree_options))
+    }
+
+    /// Like `load()`, but load the mesh data through `loader` instead of the current asset loader
+    pub fn load_with(path: PathBuf, debug: bool, acceleration: Acceleration, kd_tree_options: KDTreeOptions, loader: &dyn AssetLoader) -> Result<Mesh, RaytracerError> {
+        let data = loader.load_obj(&path).map_err(|err| RaytracerError::asset(&path, err))?;
+        Ok(Mesh::new(path, data, debug, acceleration, kd_tree_options))
+    }
+
+    /// Like `load()`, but subdivide and displace the mesh data before building the accelerator
+    pub fn load_subdivided(path: PathBuf, subdivision: Subdivision, debug: bool, acceleration: Acceleration, kd_tree_options: KDTreeOptions) -> Result<Mesh, RaytracerError> {
+        let data = asset_loader::load_obj(&path)?;
+        Ok(Mesh::new_subdivided(path, data, subdivision, debug, acceleration, kd_tree_options))
+    }
+
+    /// Like `load()`, but keep the mesh data in compact form, see `asset_loader::load_compact_mesh()`
+    ///
+    /// Preprocessed `.rtmesh` files are memory-mapped by the `FileSystemLoader` if possible, so that huge meshes don't
+    /// have to be parsed and only the parts that are accessed take up memory.
+    pub fn load_compact(path: PathBuf, debug: bool, acceleration: Acceleration, kd_tree_options: KDTreeOptions) -> Result<Mesh, RaytracerError> {
+        let data = asset_loader::load_compact_mesh(&path)?;
+        Ok(Mesh::new_compact(path, data, debug, acceleration, kd_tree_options))
+    }
+
+    /// Load a mesh with the settings of a scene file
+    fn load_configured(path: PathBuf, subdivision: Option<Subdivision>, compact: bool, debug: bool, acceleration: Acceleration, kd_tree_options: KDTreeOptions) -> Result<Mesh, RaytracerError> {
+        match (subdivision, compact) {
+            (Some(subdivision), true) => {
+                // Subdivision needs the editable mesh data, only the result is stored compactly
+                let mut data = asset_loader::load_obj(&path)?;
+                let subdivision_time = Mesh::subdivide(&mut data, &subdivision);
+                let data = CompactMeshData::new(&data)
+                    .map_err(|error| CompactMeshFileError { path: path.clone(), error })?;
+                let mut mesh = Mesh {
+                    subdivision: Some(subdivision),
+                    ..Mesh::new_compact(path, data, debug, acceleration, kd_tree_options)
+                };
+                mesh.set_subdivision_time(subdivision_time);
+                Ok(mesh)
+            }
+            (Some(subdivision), false) => Mesh::load_subdivided(path, subdivision, debug, acceleration, kd_tree_options),
+            (None, true) => Mesh::load_compact(path, debug, acceleration, kd_tree_options),
+            (None, false) => Mesh::load(path, debug, acceleration, kd_tree_options),
         }
     }
 
-    pub fn load(path: PathBuf, debug: bool) -> Result<Mesh, Box<dyn Error>> {
-        let a = asset_loader::get_instance();
-        let data = a.load_obj(&path)?;
-        Ok(Mesh::new(path, data, debug))
+    /// Subdivide and displace `data`, returning how long that took
+    fn subdivide(data: &mut MeshData, subdivision: &Subdivision) -> Duration {
+        let start_time = Instant::now();
+        subdivision.apply(data);
+        start_time.elapsed()
+    }
+
+    /// Add the time `subdivide()` took to the build stats of the mesh, which has to be freshly built
+    fn set_subdivision_time(&mut self, subdivision_time: Duration) {
+        if let Some(accelerator) = Arc::get_mut(&mut self.accelerator) {
+            accelerator.stats_mut().subdivision_time = subdivision_time;
+        }
+    }
+
+    /// Load the mesh data and displacement map again through the current asset loader and rebuild the accelerator
+    pub fn reload(&mut self) -> Result<(), RaytracerError> {
+        let subdivision = match self.subdivision.take() {
+            Some(mut subdivision) => {
+                subdivision.reload()?;
+                Some(subdivision)
+            }
+            None => None,
+        };
+        *self = Mesh::load_configured(self.path.clone(), subdivision, self.compact, self.debug, self.acceleration, self.kd_tree_options)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn build_stats(&self) -> &BuildStats {
+        match self.accelerator.as_ref() {
+            MeshAccelerator::KDTree(kdtree) => kdtree.stats(),
+            MeshAccelerator::Qbvh(qbvh) => qbvh.stats(),
+        }
+    }
+
+    pub fn data(&self) -> &MeshStorage {
+        match self.accelerator.as_ref() {
+            MeshAccelerator::KDTree(kdtree) => kdtree.data(),
+            MeshAccelerator::Qbvh(qbvh) => qbvh.data(),
+        }
+    }
+
+    pub fn bounding_box(&self) -> &AABB {
+        match self.accelerator.as_ref() {
+            MeshAccelerator::KDTree(kdtree) => kdtree.bounding_box(),
+            MeshAccelerator::Qbvh(qbvh) => qbvh.bounding_box(),
+        }
+    }
+
+    /// Object space boxes outlining the acceleration structure down to `max_depth`, together with their depth
+    ///
+    /// These are the split planes of a K-D tree, as flat boxes covering the node they split, or the bounding boxes of
+    /// the nodes of a QBVH. Depth 0 is the first subdivision of the whole mesh.
+    pub fn structure_boxes(&self, max_depth: usize) -> Vec<(usize, AABB)> {
+        match self.accelerator.as_ref() {
+            MeshAccelerator::KDTree(kdtree) => kdtree.split_planes(max_depth),
+            MeshAccelerator::Qbvh(qbvh) => qbvh.node_bounds(max_depth),
+        }
     }
 
     pub fn intersect(&self, ray: &Ray) -> Option<Hit> {
-        self.kdtree.intersect(ray)
+        match self.accelerator.as_ref() {
+            MeshAccelerator::KDTree(kdtree) => kdtree.intersect(ray),
+            MeshAccelerator::Qbvh(qbvh) => qbvh.intersect(ray),
+        }
+    }
+
+    /// All sections of the ray inside the mesh, in the order along the ray
+    ///
+    /// The mesh has to be closed with consistently wound triangles: the ray enters through triangles facing it
+    /// (counterclockwise) and leaves through triangles facing away. Crossings that don't alternate, e.g. where shells
+    /// overlap, are merged into the enclosing interval. Unlike `intersect()` this tests every triangle, so it is meant
+    /// for CSG, volumes and tools rather than for tracing every ray.
+    pub fn intersect_interval(&self, ray: &Ray) -> Vec<Interval> {
+        if self.bounding_box().intersects_p(ray).is_none() {
+            return Vec::new();
+        }
+
+        let data = self.data();
+        let mut crossings: Vec<_> = (0..data.triangle_count())
+            .filter_map(|triangle_index| {
+                let triangle_hit = data.intersect_triangle(ray, triangle_index)?;
+                let [v0, v1, v2] = data.triangle_positions(triangle_index);
+                let is_entry = (v1 - v0).cross(v2 - v0).dot(ray.direction) < 0.0;
+                Some((triangle_hit, triangle_index, is_entry))
+            })
+            .collect();
+        crossings.sort_by(|(a, _, _), (b, _, _)| a.distance.partial_cmp(&b.distance).unwrap());
+
+        let mut intervals = Vec::new();
+        // Entry of the interval the ray is currently in, `Some(None)` if the ray started inside
+        let mut current_entry = match crossings.first() {
+            Some((_, _, false)) => Some(None),
+            _ => None,
+        };
+        for (triangle_hit, triangle_index, is_entry) in crossings {
+            match (&current_entry, is_entry) {
+                (None, true) => current_entry = Some(Some(data.create_hit(ray, triangle_index, &triangle_hit))),
+                (Some(_), false) => intervals.push(Interval {
+                    entry: current_entry.take().unwrap(),
+                    exit: Some(data.create_hit(ray, triangle_index, &triangle_hit)),
+                }),
+                _ => {}
+            }
+        }
+        // The mesh isn't closed if the ray never leaves it
+        if let Some(entry) = current_entry {
+            intervals.push(Interval { entry, exit: None });
+        }
+
+        intervals
+    }
+
+    /// Intersect up to `PACKET_SIZE` rays at once, see `LinearKDTree::intersect_packet()`
+    pub fn intersect_packet(&self, rays: &[Ray]) -> [Option<Hit>; PACKET_SIZE] {
+        match self.accelerator.as_ref() {
+            MeshAccelerator::KDTree(kdtree) => kdtree.intersect_packet(rays),
+            MeshAccelerator::Qbvh(qbvh) => {
+                let mut hits: [Option<Hit>; PACKET_SIZE] = Default::default();
+                for (hit, ray) in hits.iter_mut().zip(rays) {
+                    *hit = qbvh.intersect(ray);
+                }
+                hits
+            }
+        }
     }
 }
@@ -0,0 +1,233 @@
+
+use crate::ray::MAX_UV_CHANNELS;
+use crate::mesh::{MeshData, IndexedTriangle, CornerIndices};
+use crate::math_util::{float, Float, consts};
+
+/// Procedural meshes for building scenes without OBJ files
+///
+/// All meshes are centered at the origin and fit into the box from -1 to 1 on each axis (except for the torus, whose
+/// size is given), like the `Sphere` primitive. Triangles are wound counterclockwise when seen from the outside and
+/// every vertex has a normal and texture coordinates, with V running downwards in the image like on a `Sphere`.
+impl MeshData {
+    /// A sphere with a radius of 1, textured like a `Sphere` with equirectangular mapping
+    ///
+    /// `rings` is the number of bands between the poles (at least 2), `segments` the number of slices around the y
+    /// axis (at least 3).
+    pub fn uv_sphere(rings: usize, segments: usize) -> MeshData {
+        let rings = rings.max(2);
+        let segments = segments.max(3);
+        let mut builder = Builder::default();
+
+        // The seam and the poles need a vertex per column as their texture coordinates differ
+        for ring in 0..=rings {
+            let v = ring as Float / rings as Float;
+            let theta = consts::PI * v;
+            for segment in 0..=segments {
+                let u = segment as Float / segments as Float;
+                let phi = 2.0 * consts::PI * u - consts::PI;
+                let normal = (float::sin(theta) * float::cos(phi), float::cos(theta), float::sin(theta) * float::sin(phi));
+                builder.vertex(normal, normal, (u, v));
+            }
+        }
+
+        let index = |ring: usize, segment: usize| ring * (segments + 1) + segment;
+        for ring in 0..rings {
+            for segment in 0..segments {
+                let top_left = index(ring, segment);
+                let top_right = index(ring, segment + 1);
+                let bottom_left = index(ring + 1, segment);
+                let bottom_right = index(ring + 1, segment + 1);
+                // Skip the degenerate triangles touching the poles
+                if ring > 0 {
+                    builder.triangle(top_left, top_right, bottom_right);
+                }
+                if ring < rings - 1 {
+                    builder.triangle(top_left, bottom_right, bottom_left);
+                }
+            }
+        }
+
+        builder.finish()
+    }
+
+    /// A cube from -1 to 1 with flat normals, each face showing the whole texture
+    ///
+    /// The faces are oriented like in an OpenGL cube map.
+    pub fn cube() -> MeshData {
+        // Normal and the directions of U and V of each face
+        const FACES: [([Float; 3], [Float; 3], [Float; 3]); 6] = [
+            ([1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+            ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+            ([0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+            ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+            ([0.0, 0.0, -1.0], [-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+        ];
+
+        let mut builder = Builder::default();
+        for (normal, u_axis, v_axis) in &FACES {
+            let first_vertex = builder.vertex_count();
+            for &(u, v) in &[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+                let position = |axis: usize| normal[axis] + (2.0 * u - 1.0) * u_axis[axis] + (2.0 * v - 1.0) * v_axis[axis];
+                builder.vertex((position(0), position(1), position(2)), (normal[0], normal[1], normal[2]), (u, v));
+            }
+            builder.quad(first_vertex, first_vertex + 1, first_vertex + 2, first_vertex + 3);
+        }
+
+        builder.finish()
+    }
+
+    /// A square in the XZ plane from -1 to 1 facing up, divided into `columns` x `rows` quads
+    ///
+    /// U follows the x axis and V the z axis, so the texture is oriented like on a `Plane` without UV transformation.
+    /// Subdividing is useful for displacing the vertices afterwards.
+    pub fn plane_grid(columns: usize, rows: usize) -> MeshData {
+        let columns = columns.max(1);
+        let rows = rows.max(1);
+        let mut builder = Builder::default();
+
+        for row in 0..=rows {
+            let v = row as Float / rows as Float;
+            for column in 0..=columns {
+                let u = column as Float / columns as Float;
+                builder.vertex((2.0 * u - 1.0, 0.0, 2.0 * v - 1.0), (0.0, 1.0, 0.0), (u, v));
+            }
+        }
+
+        let index = |row: usize, column: usize| row * (columns + 1) + column;
+        for row in 0..rows {
+            for column in 0..columns {
+                builder.quad(index(row, column), index(row, column + 1), index(row + 1, column + 1), index(row + 1, column));
+            }
+        }
+
+        builder.finish()
+    }
+
+    /// A closed cylinder around the y axis with a radius of 1, reaching from -1 to 1, approximated by `segments` sides
+    /// (at least 3)
+    ///
+    /// The side is textured like a `Sphere` with equirectangular mapping, the caps are projected onto the XZ plane like
+    /// `plane_grid()`.
+    pub fn cylinder(segments: usize) -> MeshData {
+        let segments = segments.max(3);
+        let mut builder = Builder::default();
+
+        let angle = |segment: usize| 2.0 * consts::PI * segment as Float / segments as Float - consts::PI;
+
+        // Side, with smooth normals
+        for &(y, v) in &[(1.0, 0.0), (-1.0, 1.0)] {
+            for segment in 0..=segments {
+                let phi = angle(segment);
+                let (x, z) = (float::cos(phi), float::sin(phi));
+                builder.vertex((x, y, z), (x, 0.0, z), (segment as Float / segments as Float, v));
+            }
+        }
+        for segment in 0..segments {
+            let top_left = segment;
+            let bottom_left = segments + 1 + segment;
+            builder.triangle(top_left, top_left + 1, bottom_left + 1);
+            builder.triangle(top_left, bottom_left + 1, bottom_left);
+        }
+
+        // Caps, each with a vertex in the center
+        for &y in &[1.0, -1.0] {
+            let center = builder.vertex((0.0, y, 0.0), (0.0, y, 0.0), (0.5, 0.5));
+            for segment in 0..segments {
+                let phi = angle(segment);
+                let (x, z) = (float::cos(phi), float::sin(phi));
+                builder.vertex((x, y, z), (0.0, y, 0.0), ((x + 1.0) * 0.5, (z + 1.0) * 0.5));
+            }
+            for segment in 0..segments {
+                let current = center + 1 + segment;
+                let next = center + 1 + (segment + 1) % segments;
+                if y > 0.0 {
+                    builder.triangle(center, next, current);
+                } else {
+                    builder.triangle(center, current, next);
+                }
+            }
+        }
+
+        builder.finish()
+    }
+
+    /// A torus around the y axis
+    ///
+    /// The center of the tube is `major_radius` away from the origin and the tube has a radius of `minor_radius`.
+    /// `major_segments` is the number of sections around the y axis and `minor_segments` the number of sides of the tube
+    /// (both at least 3). U runs around the y axis like on a `Sphere` and V around the tube, starting at the outer
+    /// equator.
+    pub fn torus(major_radius: Float, minor_radius: Float, major_segments: usize, minor_segments: usize) -> MeshData {
+        let major_segments = major_segments.max(3);
+        let minor_segments = minor_segments.max(3);
+        let mut builder = Builder::default();
+
+        for minor in 0..=minor_segments {
+            let v = minor as Float / minor_segments as Float;
+            let theta = 2.0 * consts::PI * v;
+            for major in 0..=major_segments {
+                let u = major as Float / major_segments as Float;
+                let phi = 2.0 * consts::PI * u - consts::PI;
+                let normal = (float::cos(theta) * float::cos(phi), float::sin(theta), float::cos(theta) * float::sin(phi));
+                let position = (
+                    major_radius * float::cos(phi) + minor_radius * normal.0,
+                    minor_radius * normal.1,
+                    major_radius * float::sin(phi) + minor_radius * normal.2,
+                );
+                builder.vertex(position, normal, (u, v));
+            }
+        }
+
+        let index = |minor: usize, major: usize| minor * (major_segments + 1) + major;
+        for minor in 0..minor_segments {
+            for major in 0..major_segments {
+                builder.quad(index(minor, major), index(minor, major + 1), index(minor + 1, major + 1), index(minor + 1, major));
+            }
+        }
+
+        builder.finish()
+    }
+}
+
+/// Collects vertices that each have their own position, normal and texture coordinates
+#[derive(Default)]
+struct Builder {
+    data: MeshData,
+}
+
+impl Builder {
+    fn vertex_count(&self) -> usize {
+        self.data.vertex_positions.len()
+    }
+
+    /// Add a vertex and return its index
+    fn vertex(&mut self, position: (Float, Float, Float), normal: (Float, Float, Float), tex_coords: (Float, Float)) -> usize {
+        self.data.vertex_positions.push(position);
+        self.data.vertex_normals.push(normal);
+        self.data.vertex_tex_coords.push(tex_coords);
+        self.data.vertex_positions.len() - 1
+    }
+
+    fn triangle(&mut self, a: usize, b: usize, c: usize) {
+        let indices = CornerIndices::new(a, b, c);
+        self.data.triangles.push(IndexedTriangle {
+            position_indices: indices,
+            normal_indices: Some(indices),
+            tex_coords_indices: Some(indices),
+            color_indices: None,
+            extra_tex_coords_indices: [None; MAX_UV_CHANNELS - 1],
+        });
+    }
+
+    /// Add two triangles for a quad whose corners are given in the order (0, 0), (1, 0), (1, 1), (0, 1) in texture
+    /// space, where U x V points into the surface
+    fn quad(&mut self, a: usize, b: usize, c: usize, d: usize) {
+        self.triangle(a, c, b);
+        self.triangle(a, d, c);
+    }
+
+    fn finish(self) -> MeshData {
+        self.data
+    }
+}
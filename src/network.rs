@@ -0,0 +1,200 @@
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+use crate::image::RgbImage;
+use crate::region::{Region, RenderedRegion, TileOrder, composite_regions};
+use crate::renderer::Renderer;
+use crate::error::RaytracerError;
+
+/// Messages larger than this are rejected instead of allocating a buffer for them, a full 8K frame is about 100 MB
+const MAX_MESSAGE_SIZE: usize = 256 << 20;
+
+/// How often the coordinator checks for new workers and idle workers check for tiles given back by failed ones
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Serialize, Deserialize)]
+enum WorkerMessage {
+    /// First message of a worker, with the resolution of its scene
+    Ready { resolution: (usize, usize) },
+    /// RGB pixels of a finished tile, row by row
+    Tile { region: Region, pixels: Vec<u8> },
+}
+
+#[derive(Serialize, Deserialize)]
+enum CoordinatorMessage {
+    Render(Region),
+    /// All tiles are done, the worker can disconnect
+    Finished,
+    /// The worker can't help with this frame, e.g. because it loaded a different scene
+    Rejected(String),
+}
+
+/// Write a message as its length (4 bytes, little endian) followed by the bincode encoding
+fn send<T: Serialize>(stream: &mut TcpStream, message: &T) -> io::Result<()> {
+    let payload = bincode::serialize(message).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Message too large"));
+    }
+    stream.write_all(&(payload.len() as u32).to_le_bytes())?;
+    stream.write_all(&payload)?;
+    stream.flush()
+}
+
+fn receive<T: DeserializeOwned>(stream: &mut TcpStream) -> io::Result<T> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Message of {} bytes is too large", length)));
+    }
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload)?;
+    bincode::deserialize(&payload).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Tiles shared by the connections of a coordinator
+struct TileQueue {
+    pending: VecDeque<Region>,
+    /// Tiles that aren't received yet, including those being rendered
+    remaining: usize,
+}
+
+/// Render a frame on workers connecting to `listener`, see `run_worker()`
+///
+/// The frame of the given resolution is split into tiles like in `Renderer::render_tiles()`, which workers take one at
+/// a time, so faster machines render more of them. `on_tile` is called as soon as each tile arrives. Tiles of workers
+/// that disconnect or send garbage are given to the next worker asking for one. The same happens to workers that take
+/// longer than `tile_timeout` to answer, so a hung machine can't stall the frame; `None` waits indefinitely. Returns
+/// the assembled frame once all tiles are in; workers that connect later are sent away.
+///
+/// The protocol has no authentication or encryption, only use it in trusted networks.
+pub fn serve_tiles(listener: &TcpListener, resolution: (usize, usize), tile_size: usize, order: TileOrder, tile_timeout: Option<Duration>, mut on_tile: impl FnMut(&RenderedRegion)) -> Result<RgbImage, RaytracerError> {
+    let tiles = Region::tiles(resolution, tile_size, order);
+    let tile_count = tiles.len();
+    let queue = Arc::new(Mutex::new(TileQueue {
+        pending: tiles.into(),
+        remaining: tile_count,
+    }));
+    let (sender, receiver) = mpsc::channel();
+
+    listener.set_nonblocking(true)?;
+    let mut rendered = Vec::with_capacity(tile_count);
+    while rendered.len() < tile_count {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let queue = queue.clone();
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    // The worker is gone, its tile is already back in the queue
+                    let _ = serve_worker(stream, resolution, tile_timeout, &queue, &sender);
+                });
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(rendered_region) => {
+                on_tile(&rendered_region);
+                rendered.push(rendered_region);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => unreachable!("The coordinator holds a sender itself"),
+        }
+    }
+    listener.set_nonblocking(false)?;
+
+    Ok(composite_regions(resolution, &rendered))
+}
+
+/// Hand tiles to a single worker until all are done
+fn serve_worker(mut stream: TcpStream, resolution: (usize, usize), tile_timeout: Option<Duration>, queue: &Mutex<TileQueue>, sender: &Sender<RenderedRegion>) -> io::Result<()> {
+    // Accepted streams inherit non-blocking mode from the listener on some platforms
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    // A read that times out fails like a disconnect, which gives the tile back
+    stream.set_read_timeout(tile_timeout)?;
+
+    match receive(&mut stream)? {
+        WorkerMessage::Ready { resolution: worker_resolution } if worker_resolution == resolution => {}
+        WorkerMessage::Ready { resolution: worker_resolution } => {
+            let reason = format!("Frame is {}x{}, but the worker's scene is {}x{}", resolution.0, resolution.1, worker_resolution.0, worker_resolution.1);
+            return send(&mut stream, &CoordinatorMessage::Rejected(reason));
+        }
+        WorkerMessage::Tile { .. } => return send(&mut stream, &CoordinatorMessage::Rejected("Tile sent before handshake".to_string())),
+    }
+
+    loop {
+        let region = {
+            let mut queue = queue.lock().unwrap();
+            if queue.remaining == 0 {
+                return send(&mut stream, &CoordinatorMessage::Finished);
+            }
+            queue.pending.pop_front()
+        };
+        let region = match region {
+            Some(region) => region,
+            None => {
+                // Other workers are rendering the last tiles, one of them might fail
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+        };
+
+        match render_remotely(&mut stream, region) {
+            Ok(image) => {
+                queue.lock().unwrap().remaining -= 1;
+                // The coordinator only stops listening once it received every tile, including this one
+                let _ = sender.send(RenderedRegion { region, image });
+            }
+            Err(err) => {
+                queue.lock().unwrap().pending.push_back(region);
+                return Err(err);
+            }
+        }
+    }
+}
+
+fn render_remotely(stream: &mut TcpStream, region: Region) -> io::Result<RgbImage> {
+    send(stream, &CoordinatorMessage::Render(region))?;
+    match receive(stream)? {
+        WorkerMessage::Tile { region: tile_region, pixels } if tile_region == region && pixels.len() == region.width * region.height * 3 => {
+            Ok(RgbImage::from_raw(region.width, region.height, pixels))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Worker sent a different tile")),
+    }
+}
+
+/// Connect to a coordinator running `serve_tiles()` and render tiles for it with `Renderer::render_rect()` until the
+/// frame is done
+///
+/// The worker has to have loaded the same scene as the coordinator's frame, including the assets it references; only
+/// the resolution is checked. Returns the number of tiles rendered. A renderer renders a tile on a single thread, so
+/// run one worker per core (e.g. in scoped threads sharing the renderer) to use a whole machine.
+pub fn run_worker(renderer: &Renderer, address: impl ToSocketAddrs) -> Result<usize, RaytracerError> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_nodelay(true)?;
+    send(&mut stream, &WorkerMessage::Ready { resolution: renderer.scene().camera.resolution })?;
+
+    let mut tile_count = 0;
+    loop {
+        match receive(&mut stream)? {
+            CoordinatorMessage::Render(region) => {
+                let image = renderer.render_rect(region.x, region.y, region.width, region.height);
+                send(&mut stream, &WorkerMessage::Tile { region, pixels: image.into_raw() })?;
+                tile_count += 1;
+            }
+            CoordinatorMessage::Finished => return Ok(tile_count),
+            CoordinatorMessage::Rejected(reason) => return Err(RaytracerError::InvalidConfiguration(reason)),
+        }
+    }
+}
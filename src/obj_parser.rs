@@ -2,8 +2,19 @@
 use std::error::Error;
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::num::NonZeroU32;
 
-use crate::mesh::{MeshData, IndexedTriangle};
+use std::collections::HashMap;
+
+use cgmath::{Vector2, Vector3, InnerSpace, Zero};
+
+use crate::ray::MAX_UV_CHANNELS;
+use crate::mesh::{MeshData, IndexedTriangle, CornerIndices};
+use crate::math_util::{float, orthonormal_basis, Float};
+use crate::color::srgb_to_linear;
 
 #[derive(Debug)]
 pub enum ObjParseError {
@@ -11,9 +22,12 @@ pub enum ObjParseError {
     TooManyArguments(usize, String),
     MultipleObjects(usize),
     InvalidFloat(usize),
+    InvalidSmoothingGroup(usize),
     InvalidKeyword(usize, String),
     InvalidVertexReference(usize, String),
     IndexOutOfBounds(String),
+    /// Reading the given line failed, or opening the file if there is no line number
+    Io(Option<usize>, io::Error),
 }
 
 impl Display for ObjParseError {
@@ -23,21 +37,43 @@ impl Display for ObjParseError {
             ObjParseError::TooManyArguments(line_number, keyword) => write!(f, "Too many arguments to '{}' in line {}", keyword, line_number),
             ObjParseError::MultipleObjects(line_number) => write!(f, "More than one object (second object starts in line {})", line_number),
             ObjParseError::InvalidFloat(line_number) => write!(f, "Invalid float in line {}", line_number),
+            ObjParseError::InvalidSmoothingGroup(line_number) => write!(f, "Invalid smoothing group in line {}", line_number),
             ObjParseError::InvalidKeyword(line_number, keyword) => write!(f, "Invalid keyword '{}' in line {}", keyword, line_number),
             ObjParseError::InvalidVertexReference(line_number, msg) => write!(f, "Invalid vertex reference in line {}: {}", line_number, msg),
             ObjParseError::IndexOutOfBounds(name) => write!(f, "Vertex {} index out of bounds", name),
+            ObjParseError::Io(Some(line_number), err) => write!(f, "Unable to read line {}: {}", line_number, err),
+            ObjParseError::Io(None, err) => write!(f, "Unable to open file: {}", err),
         }
     }
 }
 
 impl Error for ObjParseError {}
 
+/// An `ObjParseError` together with the file it occurred in
+#[derive(Debug)]
+pub struct ObjFileError {
+    pub path: PathBuf,
+    pub error: ObjParseError,
+}
+
+impl Display for ObjFileError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.error)
+    }
+}
+
+impl Error for ObjFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
 fn parse_multiple<I: Iterator, R, E, P: FnMut(I::Item) -> Result<R, E>>(it: I, parse_fn: P) -> Result<Vec<R>, E> {
     it.map(parse_fn)
         .collect::<Result<_, _>>()
 }
 
-fn parse_multiple_float<'s, I: Iterator>(it: I, line_number: usize) -> Result<Vec<f32>, ObjParseError>
+fn parse_multiple_float<'s, I>(it: I, line_number: usize) -> Result<Vec<Float>, ObjParseError>
     where
         I: Iterator<Item=&'s str>
 {
@@ -48,15 +84,16 @@ fn parse_multiple_float<'s, I: Iterator>(it: I, line_number: usize) -> Result<Ve
 fn parse_vertex_ref(s: &str, line_number: usize) -> Result<(usize, Option<usize>, Option<usize>), ObjParseError> {
     let mut parts = s.split('/');
 
-    let pos_index: usize = parts.next()
+    // Indices in .obj start at 1, parsing them as non-zero 32 bit numbers keeps them within the range of `CornerIndices`
+    let pos_index: NonZeroU32 = parts.next()
         .ok_or_else(|| ObjParseError::InvalidVertexReference(line_number, "missing position index".to_string()))?
         .parse()
         .map_err(|_| ObjParseError::InvalidVertexReference(line_number, "invalid position index".to_string()))?;
-    let tex_coord_index: Option<usize> = parts.next()
+    let tex_coord_index: Option<NonZeroU32> = parts.next()
         .map(|s| s.parse())
         .transpose()
         .map_err(|_| ObjParseError::InvalidVertexReference(line_number, "invalid texture coordinate index".to_string()))?;
-    let normal_index: Option<usize> = parts.next()
+    let normal_index: Option<NonZeroU32> = parts.next()
         .map(|s| s.parse())
         .transpose()
         .map_err(|_| ObjParseError::InvalidVertexReference(line_number, "invalid normal index".to_string()))?;
@@ -65,153 +102,308 @@ fn parse_vertex_ref(s: &str, line_number: usize) -> Result<(usize, Option<usize>
         return Err(ObjParseError::InvalidVertexReference(line_number, "too many slashes".to_string()));
     }
 
-    // Indices in .obj start at 1
-    let pos_index_0 = pos_index - 1;
-    let tex_coord_index_0 = tex_coord_index.map(|i| i - 1);
-    let normal_index_0 = normal_index.map(|i| i - 1);
+    let to_index_0 = |index: NonZeroU32| index.get() as usize - 1;
+    let pos_index_0 = to_index_0(pos_index);
+    let tex_coord_index_0 = tex_coord_index.map(to_index_0);
+    let normal_index_0 = normal_index.map(to_index_0);
 
     Ok((pos_index_0, tex_coord_index_0, normal_index_0))
 }
 
-pub struct ObjParser {}
+/// Split a planar polygon into triangles, returned as indices into `positions` with the polygon's winding
+///
+/// Convex polygons are split into a fan, all others are ear clipped, so that concave n-gons keep their shape.
+pub(crate) fn triangulate_polygon(positions: &[Vector3<Float>]) -> Vec<(usize, usize, usize)> {
+    let fan = || (2..positions.len()).map(|i| (0, i - 1, i)).collect();
+    if positions.len() <= 3 {
+        return fan();
+    }
+
+    // Newell's method gives a robust normal even for concave and slightly non-planar polygons
+    let mut normal = Vector3::zero();
+    for (i, current) in positions.iter().enumerate() {
+        let next = positions[(i + 1) % positions.len()];
+        normal += Vector3::new(
+            (current.y - next.y) * (current.z + next.z),
+            (current.z - next.z) * (current.x + next.x),
+            (current.x - next.x) * (current.y + next.y),
+        );
+    }
+    if normal.magnitude2() < Float::EPSILON {
+        return fan();
+    }
+
+    // Project into the plane of the polygon, where it winds counterclockwise
+    let (tangent, bitangent) = orthonormal_basis(&normal.normalize());
+    let points: Vec<_> = positions.iter()
+        .map(|position| Vector2::new(position.dot(tangent), position.dot(bitangent)))
+        .collect();
+    let cross = |a: usize, b: usize, c: usize| {
+        let (ab, ac) = (points[b] - points[a], points[c] - points[a]);
+        ab.x * ac.y - ab.y * ac.x
+    };
+
+    let is_convex = (0..points.len()).all(|i| cross(i, (i + 1) % points.len(), (i + 2) % points.len()) >= 0.0);
+    if is_convex {
+        return fan();
+    }
+
+    // Ear clipping: repeatedly cut off a convex corner whose triangle contains no other vertex
+    let mut remaining: Vec<usize> = (0..points.len()).collect();
+    let mut triangles = Vec::with_capacity(points.len() - 2);
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let is_ear = |i: usize| {
+            let (a, b, c) = (remaining[(i + n - 1) % n], remaining[i], remaining[(i + 1) % n]);
+            cross(a, b, c) > 0.0 && remaining.iter()
+                .filter(|&&other| other != a && other != b && other != c)
+                .all(|&other| cross(a, b, other) < 0.0 || cross(b, c, other) < 0.0 || cross(c, a, other) < 0.0)
+        };
+        // Self-intersecting polygons may have no ear left, cut off any corner then to guarantee progress
+        let ear = (0..n).find(|&i| is_ear(i)).unwrap_or(0);
+        triangles.push((remaining[(ear + n - 1) % n], remaining[ear], remaining[(ear + 1) % n]));
+        remaining.remove(ear);
+    }
+    triangles.push((remaining[0], remaining[1], remaining[2]));
+
+    triangles
+}
+
+/// Parses OBJ files line by line
+pub struct ObjParser {
+    object_name: Option<String>,
+    vertex_positions: Vec<(Float, Float, Float)>,
+    /// Linear color of each position, if given by the common `v <x> <y> <z> <r> <g> <b>` extension
+    vertex_colors: Vec<Option<(Float, Float, Float)>>,
+    vertex_normals: Vec<(Float, Float, Float)>,
+    vertex_tex_coords: Vec<(Float, Float)>,
+    triangles: Vec<IndexedTriangle>,
+    /// Smoothing group set by the last `s` line, `None` for `s off` or `s 0`
+    smoothing_group: Option<u32>,
+    /// Smoothing group of each triangle
+    triangle_smoothing_groups: Vec<Option<u32>>,
+}
 
 impl ObjParser {
-    pub fn parse(obj_str: &str) -> Result<MeshData, ObjParseError> {
-        let mut object_name = None;
-        let mut vertex_positions = Vec::new();
-        let mut vertex_normals = Vec::new();
-        let mut vertex_tex_coords = Vec::new();
-        let mut triangles = Vec::new();
+    fn new() -> ObjParser {
+        ObjParser {
+            object_name: None,
+            vertex_positions: Vec::new(),
+            vertex_colors: Vec::new(),
+            vertex_normals: Vec::new(),
+            vertex_tex_coords: Vec::new(),
+            triangles: Vec::new(),
+            smoothing_group: None,
+            triangle_smoothing_groups: Vec::new(),
+        }
+    }
 
+    pub fn parse(obj_str: &str) -> Result<MeshData, ObjParseError> {
+        let mut parser = ObjParser::new();
         for (i, line) in obj_str.lines().enumerate() {
-            let line_number = i + 1;
-
-            let line = line.trim_start();
-            if line.starts_with("#") {
-                // Ignore comments
-            } else {
-                let mut parts = line.split_whitespace();
-                let keyword = parts.next();
-                if let Some(keyword) = keyword {
-                    match keyword {
-                        "mtllib" | "usemtl" => {
-                            // Materials not supported
-                        }
-                        "s" => {
-                            // Smoothing groups not supported
-                        }
-                        "o" => {
-                            let name = parts.next()
-                                .ok_or_else(|| ObjParseError::NotEnoughArguments(line_number, "o".to_string()))?;
+            parser.parse_line(line, i + 1)?;
+        }
+        parser.finish()
+    }
 
-                            if parts.next().is_some() {
-                                return Err(ObjParseError::TooManyArguments(line_number, "o".to_string()))
-                            }
+    /// Like `parse()`, but read the file line by line instead of requiring all of it in memory
+    pub fn parse_reader(mut reader: impl BufRead) -> Result<MeshData, ObjParseError> {
+        let mut parser = ObjParser::new();
+        let mut line = String::new();
+        let mut line_number = 1;
+        loop {
+            line.clear();
+            let bytes_read = reader.read_line(&mut line)
+                .map_err(|err| ObjParseError::Io(Some(line_number), err))?;
+            if bytes_read == 0 {
+                break;
+            }
 
-                            if object_name.is_some() {
-                                return Err(ObjParseError::MultipleObjects(line_number));
-                            }
+            parser.parse_line(line.trim_end_matches(&['\n', '\r'][..]), line_number)?;
+            line_number += 1;
+        }
+        parser.finish()
+    }
 
-                            object_name = Some(name.to_string());
+    /// Parse an OBJ file from the file system, the errors include the path
+    pub fn parse_file(path: &Path) -> Result<MeshData, ObjFileError> {
+        let to_file_error = |error| ObjFileError { path: path.to_path_buf(), error };
+        let file = File::open(path)
+            .map_err(|err| to_file_error(ObjParseError::Io(None, err)))?;
+        ObjParser::parse_reader(BufReader::new(file))
+            .map_err(to_file_error)
+    }
+
+    fn parse_line(&mut self, line: &str, line_number: usize) -> Result<(), ObjParseError> {
+        let line = line.trim_start();
+        if line.starts_with("#") {
+            // Ignore comments
+        } else {
+            let mut parts = line.split_whitespace();
+            let keyword = parts.next();
+            if let Some(keyword) = keyword {
+                match keyword {
+                    "mtllib" | "usemtl" => {
+                        // Materials not supported
+                    }
+                    "s" => {
+                        // s <group>
+                        // s off
+                        let group = parts.next()
+                            .ok_or_else(|| ObjParseError::NotEnoughArguments(line_number, "s".to_string()))?;
+
+                        if parts.next().is_some() {
+                            return Err(ObjParseError::TooManyArguments(line_number, "s".to_string()))
                         }
-                        "v" => {
-                            // v <x> <y> <z> [w=1.0]
-                            let parts_parsed = parse_multiple_float(parts, line_number)?;
-                            if parts_parsed.len() < 3 {
-                                return Err(ObjParseError::NotEnoughArguments(line_number, "v".to_string()));
-                            } else if parts_parsed.len() > 4 {
-                                return Err(ObjParseError::TooManyArguments(line_number, "v".to_string()));
-                            }
 
-                            let x = parts_parsed[0];
-                            let y = parts_parsed[1];
-                            let z = parts_parsed[2];
+                        self.smoothing_group = match group {
+                            "off" => None,
+                            group => match group.parse() {
+                                Ok(0) => None,
+                                Ok(group) => Some(group),
+                                Err(_) => return Err(ObjParseError::InvalidSmoothingGroup(line_number)),
+                            },
+                        };
+                    }
+                    "o" => {
+                        let name = parts.next()
+                            .ok_or_else(|| ObjParseError::NotEnoughArguments(line_number, "o".to_string()))?;
 
-                            vertex_positions.push((x, y, z));
+                        if parts.next().is_some() {
+                            return Err(ObjParseError::TooManyArguments(line_number, "o".to_string()))
+                        }
+
+                        if self.object_name.is_some() {
+                            return Err(ObjParseError::MultipleObjects(line_number));
                         }
-                        "vn" => {
-                            // vn <x> <y> <z>
-                            let parts_parsed = parse_multiple_float(parts, line_number)?;
-                            if parts_parsed.len() < 3 {
-                                return Err(ObjParseError::NotEnoughArguments(line_number, "vn".to_string()));
-                            } else if parts_parsed.len() > 3 {
-                                return Err(ObjParseError::TooManyArguments(line_number, "vn".to_string()));
-                            }
 
-                            let x = parts_parsed[0];
-                            let y = parts_parsed[1];
-                            let z = parts_parsed[2];
+                        self.object_name = Some(name.to_string());
+                    }
+                    "v" => {
+                        // v <x> <y> <z> [w=1.0]
+                        // v <x> <y> <z> <r> <g> <b>
+                        let parts_parsed = parse_multiple_float(parts, line_number)?;
+                        if parts_parsed.len() < 3 {
+                            return Err(ObjParseError::NotEnoughArguments(line_number, "v".to_string()));
+                        } else if parts_parsed.len() == 5 || parts_parsed.len() > 6 {
+                            return Err(ObjParseError::TooManyArguments(line_number, "v".to_string()));
+                        }
 
-                            let mag = (x.powi(2) + y.powi(2) + z.powi(2)).sqrt();
+                        let x = parts_parsed[0];
+                        let y = parts_parsed[1];
+                        let z = parts_parsed[2];
 
-                            vertex_normals.push((x / mag, y / mag, z / mag));
+                        // Vertex colors are given in sRGB in the range [0, 1]
+                        let color = if parts_parsed.len() == 6 {
+                            Some((srgb_to_linear(parts_parsed[3]), srgb_to_linear(parts_parsed[4]), srgb_to_linear(parts_parsed[5])))
+                        } else {
+                            None
+                        };
+
+                        self.vertex_positions.push((x, y, z));
+                        self.vertex_colors.push(color);
+                    }
+                    "vn" => {
+                        // vn <x> <y> <z>
+                        let parts_parsed = parse_multiple_float(parts, line_number)?;
+                        if parts_parsed.len() < 3 {
+                            return Err(ObjParseError::NotEnoughArguments(line_number, "vn".to_string()));
+                        } else if parts_parsed.len() > 3 {
+                            return Err(ObjParseError::TooManyArguments(line_number, "vn".to_string()));
                         }
-                        "vt" => {
-                            // vt <u> [v=0] [w=0]
-                            let parts_parsed = parse_multiple_float(parts, line_number)?;
-                            if parts_parsed.len() < 1 {
-                                return Err(ObjParseError::NotEnoughArguments(line_number, "vt".to_string()));
-                            } else if parts_parsed.len() > 3 {
-                                return Err(ObjParseError::TooManyArguments(line_number, "vt".to_string()));
-                            }
 
-                            let u = parts_parsed[0];
-                            let v = parts_parsed.get(1).cloned().unwrap_or(0.0);
+                        let x = parts_parsed[0];
+                        let y = parts_parsed[1];
+                        let z = parts_parsed[2];
+
+                        let mag = (float::powi(x, 2) + float::powi(y, 2) + float::powi(z, 2)).sqrt();
 
-                            vertex_tex_coords.push((u, v));
+                        self.vertex_normals.push((x / mag, y / mag, z / mag));
+                    }
+                    "vt" => {
+                        // vt <u> [v=0] [w=0]
+                        let parts_parsed = parse_multiple_float(parts, line_number)?;
+                        if parts_parsed.is_empty() {
+                            return Err(ObjParseError::NotEnoughArguments(line_number, "vt".to_string()));
+                        } else if parts_parsed.len() > 3 {
+                            return Err(ObjParseError::TooManyArguments(line_number, "vt".to_string()));
                         }
-                        "f" => {
-                            // f <v0> <v1> <v2>
-                            let parts_parsed = parse_multiple(parts, |part| parse_vertex_ref(part, line_number))?;
-                            if parts_parsed.len() < 3 {
-                                return Err(ObjParseError::NotEnoughArguments(line_number, "f".to_string()));
-                            }
 
-                            let has_tex_coords = parts_parsed[0].1.is_some();
-                            let has_normals = parts_parsed[0].2.is_some();
+                        let u = parts_parsed[0];
+                        let v = parts_parsed.get(1).cloned().unwrap_or(0.0);
 
-                            for part in &parts_parsed {
-                                if part.1.is_some() != has_tex_coords {
-                                    return Err(ObjParseError::InvalidVertexReference(line_number, "only some vertices have texture coordinates".to_string()));
-                                }
+                        self.vertex_tex_coords.push((u, v));
+                    }
+                    "f" => {
+                        // f <v0> <v1> <v2>
+                        let parts_parsed = parse_multiple(parts, |part| parse_vertex_ref(part, line_number))?;
+                        if parts_parsed.len() < 3 {
+                            return Err(ObjParseError::NotEnoughArguments(line_number, "f".to_string()));
+                        }
+
+                        let has_tex_coords = parts_parsed[0].1.is_some();
+                        let has_normals = parts_parsed[0].2.is_some();
 
-                                if part.2.is_some() != has_normals {
-                                    return Err(ObjParseError::InvalidVertexReference(line_number, "only some vertices have normals".to_string()));
-                                }
+                        for part in &parts_parsed {
+                            if part.1.is_some() != has_tex_coords {
+                                return Err(ObjParseError::InvalidVertexReference(line_number, "only some vertices have texture coordinates".to_string()));
                             }
 
-                            for i in 2..parts_parsed.len() {
-                                let vert0 = parts_parsed[0];
-                                let vert1 = parts_parsed[i - 1];
-                                let vert2 = parts_parsed[i];
-
-                                let position_indices = (vert0.0, vert1.0, vert2.0);
-                                let tex_coords_indices = if has_tex_coords {
-                                    Some((vert0.1.unwrap(), vert1.1.unwrap(), vert2.1.unwrap()))
-                                } else {
-                                    None
-                                };
-                                let normal_indices = if has_normals {
-                                    Some((vert0.2.unwrap(), vert1.2.unwrap(), vert2.2.unwrap()))
-                                } else {
-                                    None
-                                };
-
-                                triangles.push(IndexedTriangle {
-                                    position_indices,
-                                    normal_indices,
-                                    tex_coords_indices,
-                                });
+                            if part.2.is_some() != has_normals {
+                                return Err(ObjParseError::InvalidVertexReference(line_number, "only some vertices have normals".to_string()));
                             }
                         }
-                        keyword => return Err(ObjParseError::InvalidKeyword(line_number, keyword.to_string()))
+
+                        // Vertices that are only defined later can't be looked up yet, fall back to a fan for those
+                        let positions: Option<Vec<_>> = parts_parsed.iter()
+                            .map(|part| self.vertex_positions.get(part.0).map(|&position| Vector3::from(position)))
+                            .collect();
+                        let polygon_triangles = match positions {
+                            Some(positions) => triangulate_polygon(&positions),
+                            None => (2..parts_parsed.len()).map(|i| (0, i - 1, i)).collect(),
+                        };
+
+                        for (i0, i1, i2) in polygon_triangles {
+                            let vert0 = parts_parsed[i0];
+                            let vert1 = parts_parsed[i1];
+                            let vert2 = parts_parsed[i2];
+
+                            let position_indices = CornerIndices::new(vert0.0, vert1.0, vert2.0);
+                            let tex_coords_indices = if has_tex_coords {
+                                Some(CornerIndices::new(vert0.1.unwrap(), vert1.1.unwrap(), vert2.1.unwrap()))
+                            } else {
+                                None
+                            };
+                            let normal_indices = if has_normals {
+                                Some(CornerIndices::new(vert0.2.unwrap(), vert1.2.unwrap(), vert2.2.unwrap()))
+                            } else {
+                                None
+                            };
+
+                            self.triangles.push(IndexedTriangle {
+                                position_indices,
+                                normal_indices,
+                                tex_coords_indices,
+                                color_indices: None,
+                                extra_tex_coords_indices: [None; MAX_UV_CHANNELS - 1],
+                            });
+                            self.triangle_smoothing_groups.push(self.smoothing_group);
+                        }
                     }
+                    keyword => return Err(ObjParseError::InvalidKeyword(line_number, keyword.to_string()))
                 }
             }
         }
 
-        let indices_exist = |indices: &(usize, usize, usize), len: usize| {
-            indices.0 < len && indices.1 < len && indices.2 < len
+        Ok(())
+    }
+
+    /// Check the vertex references of all faces and return the mesh
+    fn finish(self) -> Result<MeshData, ObjParseError> {
+        let ObjParser { vertex_positions, vertex_colors, mut vertex_normals, vertex_tex_coords, mut triangles, triangle_smoothing_groups, .. } = self;
+
+        let indices_exist = |indices: &CornerIndices, len: usize| {
+            indices.to_array().iter().all(|&index| index < len)
         };
 
         for triangle in &triangles {
@@ -232,11 +424,59 @@ impl ObjParser {
             }
         }
 
+        smooth_groups(&vertex_positions, &mut vertex_normals, &mut triangles, &triangle_smoothing_groups);
+
+        // Colors belong to the positions, so they share their indices; positions without a color are white
+        let vertex_colors = if vertex_colors.iter().any(Option::is_some) {
+            for triangle in &mut triangles {
+                triangle.color_indices = Some(triangle.position_indices);
+            }
+            vertex_colors.into_iter().map(|color| color.unwrap_or((1.0, 1.0, 1.0))).collect()
+        } else {
+            Vec::new()
+        };
+
         Ok(MeshData {
             vertex_positions,
             vertex_normals,
             vertex_tex_coords,
+            vertex_colors,
+            extra_tex_coords: Vec::new(),
             triangles,
         })
     }
 }
+
+/// Give the triangles without normals that are in a smoothing group the normals of the group
+///
+/// The normal of a vertex is the area weighted average of the triangles around it in the same group, so that edges
+/// between groups stay hard. Triangles outside of any group stay flat.
+fn smooth_groups(vertex_positions: &[(Float, Float, Float)], vertex_normals: &mut Vec<(Float, Float, Float)>, triangles: &mut [IndexedTriangle], smoothing_groups: &[Option<u32>]) {
+    let first_normal = vertex_normals.len();
+    let mut normal_sums = Vec::new();
+    let mut group_normals: HashMap<(usize, u32), usize> = HashMap::new();
+
+    for (triangle, group) in triangles.iter_mut().zip(smoothing_groups) {
+        let group = match group {
+            Some(group) if triangle.normal_indices.is_none() => *group,
+            _ => continue,
+        };
+
+        let (a, b, c) = triangle.position_indices.get();
+        let [p0, p1, p2] = [a, b, c].map(|index| Vector3::from(vertex_positions[index]));
+        let face_normal = (p1 - p0).cross(p2 - p0);
+
+        let [n0, n1, n2] = [a, b, c].map(|position_index| {
+            let index = *group_normals.entry((position_index, group)).or_insert_with(|| {
+                normal_sums.push(Vector3::zero());
+                normal_sums.len() - 1
+            });
+            normal_sums[index] += face_normal;
+            first_normal + index
+        });
+        triangle.normal_indices = Some(CornerIndices::new(n0, n1, n2));
+    }
+
+    vertex_normals.extend(normal_sums.into_iter()
+        .map(|sum: Vector3<Float>| if sum.magnitude2() > 0.0 { sum.normalize().into() } else { (0.0, 1.0, 0.0) }));
+}
@@ -0,0 +1,102 @@
+
+use cgmath::{Matrix4, Point3, SquareMatrix, Transform};
+
+use crate::aabb::AABB;
+use crate::image::RgbImage;
+use crate::math_util::{float, Float};
+use crate::scene::Camera;
+
+/// Lines drawn over a rendered image by `Renderer::draw_overlay()` to check the bounds of objects and the quality of
+/// acceleration structures
+#[derive(Clone, Debug)]
+pub struct StructureOverlay {
+    /// Outline the world space bounding box of every object that has one, in white
+    pub object_bounds: bool,
+    /// Index into `Scene::objects` of a mesh whose acceleration structure is outlined as well, colored by depth from
+    /// dark purple (the first subdivision) to yellow
+    pub mesh: Option<usize>,
+    /// Deepest level of the acceleration structure to outline, see `Mesh::structure_boxes()`
+    pub max_depth: usize,
+    /// In pixels
+    pub line_width: Float,
+}
+
+impl Default for StructureOverlay {
+    fn default() -> StructureOverlay {
+        StructureOverlay {
+            object_bounds: true,
+            mesh: None,
+            max_depth: 4,
+            line_width: 1.5,
+        }
+    }
+}
+
+/// Draws lines given in world space onto a full frame image as seen by a camera
+pub(crate) struct LineProjector<'a> {
+    img: &'a mut RgbImage,
+    world_to_camera: Matrix4<Float>,
+    /// Scale from camera space to normalized screen coordinates in y direction
+    fov_factor: Float,
+    aspect_ratio: Float,
+    line_width: Float,
+}
+
+impl<'a> LineProjector<'a> {
+    pub fn new(img: &'a mut RgbImage, camera: &Camera, line_width: Float) -> LineProjector<'a> {
+        LineProjector {
+            world_to_camera: camera.transformation_matrix.invert().unwrap_or_else(Matrix4::identity),
+            fov_factor: float::tan(camera.fov.to_radians() / 2.0),
+            aspect_ratio: camera.resolution.0 as Float / camera.resolution.1 as Float,
+            img,
+            line_width,
+        }
+    }
+
+    /// Pixel coordinates of a point in camera space in front of the camera, inverting `Ray::from_screen_coordinates()`
+    fn project(&self, point: Point3<Float>) -> (Float, Float) {
+        let x_relative = point.x / -point.z / (self.aspect_ratio * self.fov_factor);
+        let y_relative = point.y / -point.z / self.fov_factor;
+        let x = (x_relative + 1.0) * 0.5 * self.img.width() as Float - 0.5;
+        let y = (1.0 - y_relative) * 0.5 * self.img.height() as Float - 0.5;
+        (x, y)
+    }
+
+    pub fn draw_line(&mut self, start: Point3<Float>, end: Point3<Float>, color: &(u8, u8, u8)) {
+        // Cut off the part behind the camera, which can't be projected
+        const NEAR: Float = 1e-3;
+
+        let mut start = self.world_to_camera.transform_point(start);
+        let mut end = self.world_to_camera.transform_point(end);
+        if start.z > -NEAR && end.z > -NEAR {
+            return;
+        }
+        if start.z > -NEAR {
+            start = start + (end - start) * ((-NEAR - start.z) / (end.z - start.z));
+        } else if end.z > -NEAR {
+            end = end + (start - end) * ((-NEAR - end.z) / (start.z - end.z));
+        }
+
+        let (x0, y0) = self.project(start);
+        let (x1, y1) = self.project(end);
+        self.img.draw_line_smooth(x0, y0, x1, y1, self.line_width, color);
+    }
+
+    /// Draw the twelve edges of `bounding_box` after transforming it by `transformation`
+    pub fn draw_box(&mut self, bounding_box: &AABB, transformation: &Matrix4<Float>, color: &(u8, u8, u8)) {
+        let corner = |index: usize| {
+            let pick = |bit: usize, axis: usize| if index & bit == 0 { bounding_box.min[axis] } else { bounding_box.max[axis] };
+            transformation.transform_point(Point3::new(pick(1, 0), pick(2, 1), pick(4, 2)))
+        };
+        let corners: Vec<_> = (0..8).map(corner).collect();
+
+        // Corners whose indices differ in a single bit share an edge
+        for index in 0..8 {
+            for bit in [1, 2, 4] {
+                if index & bit == 0 {
+                    self.draw_line(corners[index], corners[index | bit], color);
+                }
+            }
+        }
+    }
+}
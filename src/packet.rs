@@ -0,0 +1,139 @@
+
+use cgmath::Vector3;
+
+use crate::ray::Ray;
+use crate::aabb::AABB;
+use crate::mesh::TriangleHit;
+use crate::math_util::Float;
+
+/// Number of rays that are traced together
+pub const PACKET_SIZE: usize = 4;
+
+/// Origins and directions of up to `PACKET_SIZE` rays, stored as structure of arrays
+///
+/// All per-ray loops over the lanes are written so that they can be auto-vectorized. Unused lanes are inactive and
+/// never produce hits.
+pub struct RayPacket {
+    pub origin: [[Float; PACKET_SIZE]; 3],
+    pub direction: [[Float; PACKET_SIZE]; 3],
+    pub inv_dir: [[Float; PACKET_SIZE]; 3],
+    pub active: [bool; PACKET_SIZE],
+}
+
+impl RayPacket {
+    /// Pack the given rays, panics if there are more than `PACKET_SIZE`
+    pub fn new(rays: &[Ray]) -> RayPacket {
+        assert!(!rays.is_empty() && rays.len() <= PACKET_SIZE, "A packet holds between 1 and {} rays", PACKET_SIZE);
+
+        let mut packet = RayPacket {
+            origin: [[0.0; PACKET_SIZE]; 3],
+            // Unused lanes get a valid direction so that they don't produce NaNs
+            direction: [[1.0; PACKET_SIZE]; 3],
+            inv_dir: [[1.0; PACKET_SIZE]; 3],
+            active: [false; PACKET_SIZE],
+        };
+        for (i, ray) in rays.iter().enumerate() {
+            for axis in 0..3 {
+                packet.origin[axis][i] = ray.origin[axis];
+                packet.direction[axis][i] = ray.direction[axis];
+                packet.inv_dir[axis][i] = 1.0 / ray.direction[axis];
+            }
+            packet.active[i] = true;
+        }
+        packet
+    }
+
+    /// Slab test of all rays against a bounding box, inactive rays never intersect
+    pub fn intersects_box(&self, bounding_box: &AABB) -> [bool; PACKET_SIZE] {
+        let mut t_min = [Float::NEG_INFINITY; PACKET_SIZE];
+        let mut t_max = [Float::INFINITY; PACKET_SIZE];
+        for axis in 0..3 {
+            for i in 0..PACKET_SIZE {
+                let t1 = (bounding_box.min[axis] - self.origin[axis][i]) * self.inv_dir[axis][i];
+                let t2 = (bounding_box.max[axis] - self.origin[axis][i]) * self.inv_dir[axis][i];
+                t_min[i] = t_min[i].max(t1.min(t2));
+                t_max[i] = t_max[i].min(t1.max(t2));
+            }
+        }
+
+        let mut intersects = [false; PACKET_SIZE];
+        for i in 0..PACKET_SIZE {
+            intersects[i] = self.active[i] && t_max[i] >= 0.0 && t_min[i] <= t_max[i];
+        }
+        intersects
+    }
+
+    /// Whether all active rays point into the same octant, which is required for traversing a K-D tree as a packet
+    pub fn has_uniform_signs(&self) -> bool {
+        (0..3).all(|axis| {
+            let mut positive = 0;
+            let mut count = 0;
+            for i in 0..PACKET_SIZE {
+                if self.active[i] {
+                    count += 1;
+                    // The sign of the inverse direction also distinguishes -0.0 and 0.0
+                    if self.inv_dir[axis][i] >= 0.0 {
+                        positive += 1;
+                    }
+                }
+            }
+            positive == 0 || positive == count
+        })
+    }
+
+    /// Sign of the directions along `axis`, only meaningful if `has_uniform_signs()` is true
+    pub fn is_positive(&self, axis: usize) -> bool {
+        (0..PACKET_SIZE)
+            .find(|&i| self.active[i])
+            .is_none_or(|i| self.inv_dir[axis][i] >= 0.0)
+    }
+
+    /// Test all active rays against a single triangle using the Möller-Trumbore algorithm
+    pub fn intersect_triangle(&self, v0: &Vector3<Float>, v1: &Vector3<Float>, v2: &Vector3<Float>) -> [Option<TriangleHit>; PACKET_SIZE] {
+        let e1 = v1 - v0;
+        let e2 = v2 - v0;
+
+        let mut t: [Float; PACKET_SIZE] = [0.0; PACKET_SIZE];
+        let mut u: [Float; PACKET_SIZE] = [0.0; PACKET_SIZE];
+        let mut v: [Float; PACKET_SIZE] = [0.0; PACKET_SIZE];
+        let mut valid = [false; PACKET_SIZE];
+
+        let [dx, dy, dz] = &self.direction;
+        let [ox, oy, oz] = &self.origin;
+        for i in 0..PACKET_SIZE {
+            // pvec = direction x e2
+            let px = dy[i] * e2.z - dz[i] * e2.y;
+            let py = dz[i] * e2.x - dx[i] * e2.z;
+            let pz = dx[i] * e2.y - dy[i] * e2.x;
+            let det = e1.x * px + e1.y * py + e1.z * pz;
+            let inv_det = 1.0 / det;
+
+            let tx = ox[i] - v0.x;
+            let ty = oy[i] - v0.y;
+            let tz = oz[i] - v0.z;
+            u[i] = (tx * px + ty * py + tz * pz) * inv_det;
+
+            // qvec = tvec x e1
+            let qx = ty * e1.z - tz * e1.y;
+            let qy = tz * e1.x - tx * e1.z;
+            let qz = tx * e1.y - ty * e1.x;
+            v[i] = (dx[i] * qx + dy[i] * qy + dz[i] * qz) * inv_det;
+            t[i] = (e2.x * qx + e2.y * qy + e2.z * qz) * inv_det;
+
+            valid[i] = self.active[i]
+                && det.abs() >= Float::EPSILON
+                && (0.0..=1.0).contains(&u[i])
+                && v[i] >= 0.0
+                && u[i] + v[i] <= 1.0
+                && t[i] >= 0.0;
+        }
+
+        let mut hits: [Option<TriangleHit>; PACKET_SIZE] = Default::default();
+        for i in 0..PACKET_SIZE {
+            if valid[i] {
+                hits[i] = Some(TriangleHit { distance: t[i], u: u[i], v: v[i] });
+            }
+        }
+        hits
+    }
+}
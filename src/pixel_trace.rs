@@ -0,0 +1,148 @@
+
+use std::cell::RefCell;
+
+use cgmath::{Point3, Vector3};
+
+use crate::color::Color;
+use crate::ray::{Ray, Hit};
+use crate::stats::RayType;
+use crate::math_util::Float;
+
+/// Everything that happened while rendering a single pixel, returned by `Renderer::trace_pixel_debug()`
+#[derive(Clone, Debug, Default)]
+pub struct PixelTrace {
+    pub x: usize,
+    pub y: usize,
+    /// Final color of the pixel, the same as rendering the pixel yields except for the camera's bloom
+    pub color: Color,
+    /// Color of each anti-aliasing sample, including vignetting but before the camera's exposure
+    pub sample_colors: Vec<Color>,
+    /// All rays in the order they were traced, including shadow and occlusion rays
+    pub segments: Vec<RaySegment>,
+    /// All tests whether a light reaches a point
+    pub light_queries: Vec<LightQuery>,
+}
+
+/// A ray that was traced through the scene
+#[derive(Clone, Debug)]
+pub struct RaySegment {
+    /// Anti-aliasing sample the ray belongs to
+    pub sample: usize,
+    pub ray_type: RayType,
+    pub origin: Point3<Float>,
+    pub direction: Vector3<Float>,
+    /// Closest hit, `None` if the ray left the scene
+    pub hit: Option<SegmentHit>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SegmentHit {
+    /// Index into `Scene::objects`
+    pub object_index: usize,
+    /// Index into `Scene::materials`
+    pub material_index: usize,
+    pub distance: Float,
+    pub point: Point3<Float>,
+    /// Geometric normal, before any bump mapping
+    pub normal: Vector3<Float>,
+}
+
+/// A shadow ray cast towards a light
+#[derive(Clone, Debug)]
+pub struct LightQuery {
+    /// Anti-aliasing sample the query belongs to
+    pub sample: usize,
+    /// Index into `Scene::lights`, `None` for directions sampled from an environment map
+    pub light_index: Option<usize>,
+    /// Index of the shadow ray in `PixelTrace::segments`
+    pub shadow_ray: usize,
+    /// Whether the shadow ray reached the light
+    pub in_light: bool,
+    /// Light added at the shaded point, black if it is in shadow
+    pub contribution: Color,
+}
+
+thread_local! {
+    /// Trace being recorded by `Renderer::trace_pixel_debug()` on this thread, together with the current sample
+    static RECORDING: RefCell<Option<(PixelTrace, usize)>> = const { RefCell::new(None) };
+}
+
+/// Record everything traced on this thread into `trace` while `f` runs, then return it
+pub(crate) fn record(trace: PixelTrace, f: impl FnOnce()) -> PixelTrace {
+    /// Stops the recording again even if `f` panics
+    struct RecordingGuard;
+
+    impl Drop for RecordingGuard {
+        fn drop(&mut self) {
+            RECORDING.with(|recording| recording.borrow_mut().take());
+        }
+    }
+
+    RECORDING.with(|recording| *recording.borrow_mut() = Some((trace, 0)));
+    let guard = RecordingGuard;
+    f();
+    let trace = RECORDING.with(|recording| recording.borrow_mut().take())
+        .map(|(trace, _)| trace)
+        .unwrap_or_default();
+    drop(guard);
+    trace
+}
+
+/// Attribute everything recorded from now on to anti-aliasing sample `sample`
+pub(crate) fn begin_sample(sample: usize) {
+    RECORDING.with(|recording| {
+        if let Some((_, current_sample)) = recording.borrow_mut().as_mut() {
+            *current_sample = sample;
+        }
+    });
+}
+
+pub(crate) fn record_sample_color(color: Color) {
+    RECORDING.with(|recording| {
+        if let Some((trace, _)) = recording.borrow_mut().as_mut() {
+            trace.sample_colors.push(color);
+        }
+    });
+}
+
+/// Record a traced ray if a recording is active; `hit` holds the object and material index
+pub(crate) fn record_segment(ray: &Ray, ray_type: RayType, hit: Option<(usize, usize, &Hit)>) {
+    RECORDING.with(|recording| {
+        if let Some((trace, sample)) = recording.borrow_mut().as_mut() {
+            trace.segments.push(RaySegment {
+                sample: *sample,
+                ray_type,
+                origin: ray.origin,
+                direction: ray.direction,
+                hit: hit.map(|(object_index, material_index, hit)| SegmentHit {
+                    object_index,
+                    material_index,
+                    distance: hit.distance,
+                    point: hit.point,
+                    normal: hit.normal,
+                }),
+            });
+        }
+    });
+}
+
+/// Record a light query whose shadow ray was the last recorded segment
+pub(crate) fn record_light_query(light_index: Option<usize>, in_light: bool, contribution: Color) {
+    RECORDING.with(|recording| {
+        if let Some((trace, sample)) = recording.borrow_mut().as_mut() {
+            let shadow_ray = trace.segments.len().saturating_sub(1);
+            trace.light_queries.push(LightQuery {
+                sample: *sample,
+                light_index,
+                shadow_ray,
+                in_light,
+                contribution,
+            });
+        }
+    });
+}
+
+/// Whether a recording is active on this thread, to skip work that only feeds the recording
+pub(crate) fn is_recording() -> bool {
+    RECORDING.with(|recording| recording.borrow().is_some())
+}
@@ -0,0 +1,412 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use cgmath::Vector3;
+
+use crate::ray::MAX_UV_CHANNELS;
+use crate::mesh::{MeshData, IndexedTriangle, CornerIndices};
+use crate::math_util::Float;
+use crate::color::srgb_to_linear;
+use crate::obj_parser::triangulate_polygon;
+
+#[derive(Debug)]
+pub enum PlyParseError {
+    /// The header is malformed, with a description of what is wrong
+    InvalidHeader(String),
+    UnsupportedFormat(String),
+    /// A vertex property that every mesh needs, like a coordinate of the position, is missing
+    MissingProperty(String),
+    /// The file ends before all elements declared in the header were read
+    UnexpectedEnd,
+    /// A value in an ASCII file isn't a valid number
+    InvalidValue(String),
+    IndexOutOfBounds,
+    /// Reading the file failed
+    Io(io::Error),
+}
+
+impl Display for PlyParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PlyParseError::InvalidHeader(msg) => write!(f, "Invalid header: {}", msg),
+            PlyParseError::UnsupportedFormat(format) => write!(f, "Unsupported format '{}'", format),
+            PlyParseError::MissingProperty(name) => write!(f, "Vertices have no property '{}'", name),
+            PlyParseError::UnexpectedEnd => write!(f, "Unexpected end of file"),
+            PlyParseError::InvalidValue(value) => write!(f, "Invalid value '{}'", value),
+            PlyParseError::IndexOutOfBounds => write!(f, "Vertex index out of bounds"),
+            PlyParseError::Io(err) => write!(f, "Unable to read file: {}", err),
+        }
+    }
+}
+
+impl Error for PlyParseError {}
+
+/// A `PlyParseError` together with the file it occurred in
+#[derive(Debug)]
+pub struct PlyFileError {
+    pub path: PathBuf,
+    pub error: PlyParseError,
+}
+
+impl Display for PlyFileError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.error)
+    }
+}
+
+impl Error for PlyFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum ScalarType {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl ScalarType {
+    fn from_name(name: &str) -> Option<ScalarType> {
+        Some(match name {
+            "char" | "int8" => ScalarType::Int8,
+            "uchar" | "uint8" => ScalarType::UInt8,
+            "short" | "int16" => ScalarType::Int16,
+            "ushort" | "uint16" => ScalarType::UInt16,
+            "int" | "int32" => ScalarType::Int32,
+            "uint" | "uint32" => ScalarType::UInt32,
+            "float" | "float32" => ScalarType::Float32,
+            "double" | "float64" => ScalarType::Float64,
+            _ => return None,
+        })
+    }
+
+    /// Value that corresponds to full intensity if a color channel is stored with this type
+    fn color_range(&self) -> f64 {
+        match self {
+            ScalarType::Int8 | ScalarType::UInt8 => 255.0,
+            ScalarType::Int16 | ScalarType::UInt16 => 65535.0,
+            ScalarType::Int32 | ScalarType::UInt32 => 4294967295.0,
+            ScalarType::Float32 | ScalarType::Float64 => 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum PropertyType {
+    Scalar(ScalarType),
+    /// A list with the type of its length and the type of its items
+    List(ScalarType, ScalarType),
+}
+
+#[derive(Clone, Debug)]
+struct Property {
+    name: String,
+    property_type: PropertyType,
+}
+
+#[derive(Clone, Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Element {
+    fn find_property(&self, names: &[&str]) -> Option<usize> {
+        self.properties.iter().position(|property| names.contains(&property.name.as_str()))
+    }
+}
+
+/// Reads the values of the elements following the header
+struct BodyReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    format: Format,
+}
+
+impl<'a> BodyReader<'a> {
+    fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N], PlyParseError> {
+        let bytes = self.bytes.get(self.position..self.position + N)
+            .ok_or(PlyParseError::UnexpectedEnd)?;
+        self.position += N;
+        let mut array = [0; N];
+        array.copy_from_slice(bytes);
+        Ok(array)
+    }
+
+    fn read_token(&mut self) -> Result<&'a str, PlyParseError> {
+        while self.bytes.get(self.position).is_some_and(u8::is_ascii_whitespace) {
+            self.position += 1;
+        }
+        let start = self.position;
+        while self.bytes.get(self.position).is_some_and(|byte| !byte.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+        if start == self.position {
+            return Err(PlyParseError::UnexpectedEnd);
+        }
+        let token = &self.bytes[start..self.position];
+        std::str::from_utf8(token)
+            .map_err(|_| PlyParseError::InvalidValue(String::from_utf8_lossy(token).into_owned()))
+    }
+
+    /// Read a single value, integers are exactly representable as `f64`
+    fn read(&mut self, scalar_type: ScalarType) -> Result<f64, PlyParseError> {
+        macro_rules! read_binary {
+            ($t:ty) => {{
+                let bytes = self.read_bytes()?;
+                (if self.format == Format::BinaryLittleEndian { <$t>::from_le_bytes(bytes) } else { <$t>::from_be_bytes(bytes) }) as f64
+            }};
+        }
+
+        Ok(match (self.format, scalar_type) {
+            (Format::Ascii, _) => {
+                let token = self.read_token()?;
+                token.parse().map_err(|_| PlyParseError::InvalidValue(token.to_string()))?
+            }
+            (_, ScalarType::Int8) => read_binary!(i8),
+            (_, ScalarType::UInt8) => read_binary!(u8),
+            (_, ScalarType::Int16) => read_binary!(i16),
+            (_, ScalarType::UInt16) => read_binary!(u16),
+            (_, ScalarType::Int32) => read_binary!(i32),
+            (_, ScalarType::UInt32) => read_binary!(u32),
+            (_, ScalarType::Float32) => read_binary!(f32),
+            (_, ScalarType::Float64) => read_binary!(f64),
+        })
+    }
+
+    fn read_list_length(&mut self, length_type: ScalarType) -> Result<usize, PlyParseError> {
+        let length = self.read(length_type)?;
+        if length < 0.0 || length.fract() != 0.0 {
+            return Err(PlyParseError::InvalidValue(length.to_string()));
+        }
+        Ok(length as usize)
+    }
+
+    /// Read all properties of an element instance, the scalars into `scalars` and the items of the lists into `lists`
+    fn read_element(&mut self, element: &Element, scalars: &mut [f64], lists: &mut [Vec<f64>]) -> Result<(), PlyParseError> {
+        for (i, property) in element.properties.iter().enumerate() {
+            match property.property_type {
+                PropertyType::Scalar(scalar_type) => scalars[i] = self.read(scalar_type)?,
+                PropertyType::List(length_type, item_type) => {
+                    let length = self.read_list_length(length_type)?;
+                    lists[i].clear();
+                    for _ in 0..length {
+                        let item = self.read(item_type)?;
+                        lists[i].push(item);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_header(bytes: &[u8]) -> Result<(Format, Vec<Element>, usize), PlyParseError> {
+    let invalid = |msg: &str| PlyParseError::InvalidHeader(msg.to_string());
+
+    let mut lines = Vec::new();
+    let mut position = 0;
+    loop {
+        let line_end = bytes[position..].iter().position(|&byte| byte == b'\n')
+            .ok_or_else(|| invalid("missing 'end_header'"))?;
+        let line = std::str::from_utf8(&bytes[position..position + line_end])
+            .map_err(|_| invalid("not ASCII"))?
+            .trim();
+        position += line_end + 1;
+        if line == "end_header" {
+            break;
+        }
+        lines.push(line);
+    }
+
+    if lines.first() != Some(&"ply") {
+        return Err(invalid("missing 'ply' magic number"));
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in &lines[1..] {
+        let parts: Vec<_> = line.split_whitespace().collect();
+        match parts.as_slice() {
+            [] | ["comment", ..] | ["obj_info", ..] => {}
+            ["format", name, _version] => {
+                format = Some(match *name {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    name => return Err(PlyParseError::UnsupportedFormat(name.to_string())),
+                });
+            }
+            ["element", name, count] => {
+                elements.push(Element {
+                    name: name.to_string(),
+                    count: count.parse().map_err(|_| invalid("invalid element count"))?,
+                    properties: Vec::new(),
+                });
+            }
+            ["property", "list", length_type, item_type, name] => {
+                let parse_type = |name: &str| ScalarType::from_name(name).ok_or_else(|| invalid("unknown property type"));
+                let property_type = PropertyType::List(parse_type(length_type)?, parse_type(item_type)?);
+                elements.last_mut()
+                    .ok_or_else(|| invalid("property outside of an element"))?
+                    .properties.push(Property { name: name.to_string(), property_type });
+            }
+            ["property", scalar_type, name] => {
+                let property_type = PropertyType::Scalar(ScalarType::from_name(scalar_type).ok_or_else(|| invalid("unknown property type"))?);
+                elements.last_mut()
+                    .ok_or_else(|| invalid("property outside of an element"))?
+                    .properties.push(Property { name: name.to_string(), property_type });
+            }
+            _ => return Err(PlyParseError::InvalidHeader(format!("invalid line '{}'", line))),
+        }
+    }
+
+    let format = format.ok_or_else(|| invalid("missing 'format'"))?;
+    Ok((format, elements, position))
+}
+
+/// Parses PLY files in ASCII or binary format
+///
+/// Reads the positions, normals, texture coordinates and colors of the vertices and the faces, which are triangulated
+/// like in OBJ files. All other elements and properties are skipped. Colors are converted from sRGB to linear.
+pub struct PlyParser;
+
+impl PlyParser {
+    pub fn parse(bytes: &[u8]) -> Result<MeshData, PlyParseError> {
+        let (format, elements, body_start) = parse_header(bytes)?;
+        let mut reader = BodyReader { bytes, position: body_start, format };
+
+        let mut data = MeshData::default();
+        // Element counts come from the file, don't let them reserve more memory than the file could fill
+        let capacity = |count: usize| count.min(bytes.len());
+
+        for element in &elements {
+            let mut scalars = vec![0.0; element.properties.len()];
+            let mut lists = vec![Vec::new(); element.properties.len()];
+
+            match element.name.as_str() {
+                "vertex" => {
+                    let property = |name: &str| element.find_property(&[name])
+                        .ok_or_else(|| PlyParseError::MissingProperty(name.to_string()));
+                    let position = (property("x")?, property("y")?, property("z")?);
+                    let normal = match (element.find_property(&["nx"]), element.find_property(&["ny"]), element.find_property(&["nz"])) {
+                        (Some(x), Some(y), Some(z)) => Some((x, y, z)),
+                        _ => None,
+                    };
+                    let tex_coords = match (element.find_property(&["u", "s", "texture_u", "texture_s"]), element.find_property(&["v", "t", "texture_v", "texture_t"])) {
+                        (Some(u), Some(v)) => Some((u, v)),
+                        _ => None,
+                    };
+                    let color = match (element.find_property(&["red", "r"]), element.find_property(&["green", "g"]), element.find_property(&["blue", "b"])) {
+                        (Some(r), Some(g), Some(b)) => Some((r, g, b)),
+                        _ => None,
+                    };
+                    let color_range = |index: usize| match element.properties[index].property_type {
+                        PropertyType::Scalar(scalar_type) => scalar_type.color_range(),
+                        PropertyType::List(..) => 1.0,
+                    };
+
+                    data.vertex_positions.reserve(capacity(element.count));
+                    for _ in 0..element.count {
+                        reader.read_element(element, &mut scalars, &mut lists)?;
+
+                        data.vertex_positions.push((scalars[position.0] as Float, scalars[position.1] as Float, scalars[position.2] as Float));
+                        if let Some((x, y, z)) = normal {
+                            data.vertex_normals.push((scalars[x] as Float, scalars[y] as Float, scalars[z] as Float));
+                        }
+                        if let Some((u, v)) = tex_coords {
+                            data.vertex_tex_coords.push((scalars[u] as Float, scalars[v] as Float));
+                        }
+                        if let Some((r, g, b)) = color {
+                            let channel = |index: usize| srgb_to_linear((scalars[index] / color_range(index)) as Float);
+                            data.vertex_colors.push((channel(r), channel(g), channel(b)));
+                        }
+                    }
+                }
+                "face" => {
+                    let indices_property = element.find_property(&["vertex_indices", "vertex_index"])
+                        .ok_or_else(|| PlyParseError::InvalidHeader("faces have no 'vertex_indices' list".to_string()))?;
+
+                    data.triangles.reserve(capacity(element.count));
+                    for _ in 0..element.count {
+                        reader.read_element(element, &mut scalars, &mut lists)?;
+
+                        let indices: Vec<usize> = lists[indices_property].iter()
+                            .map(|&index| if index >= 0.0 { index as usize } else { usize::MAX })
+                            .collect();
+                        // Faces that refer to vertices that aren't read yet can't be triangulated by shape, use a fan
+                        let positions: Option<Vec<_>> = indices.iter()
+                            .map(|&index| data.vertex_positions.get(index).map(|&position| Vector3::from(position)))
+                            .collect();
+                        let polygon_triangles = match positions {
+                            Some(positions) => triangulate_polygon(&positions),
+                            None => (2..indices.len()).map(|i| (0, i - 1, i)).collect(),
+                        };
+
+                        for (i0, i1, i2) in polygon_triangles {
+                            let position_indices = CornerIndices::try_new(indices[i0], indices[i1], indices[i2])
+                                .ok_or(PlyParseError::IndexOutOfBounds)?;
+                            data.triangles.push(IndexedTriangle {
+                                position_indices,
+                                normal_indices: None,
+                                tex_coords_indices: None,
+                                color_indices: None,
+                                extra_tex_coords_indices: [None; MAX_UV_CHANNELS - 1],
+                            });
+                        }
+                    }
+                }
+                _ => {
+                    for _ in 0..element.count {
+                        reader.read_element(element, &mut scalars, &mut lists)?;
+                    }
+                }
+            }
+        }
+
+        // All vertex attributes share the indices of the positions
+        let vertex_count = data.vertex_positions.len();
+        let has_normals = !data.vertex_normals.is_empty();
+        let has_tex_coords = !data.vertex_tex_coords.is_empty();
+        let has_colors = !data.vertex_colors.is_empty();
+        for triangle in &mut data.triangles {
+            let (a, b, c) = triangle.position_indices.get();
+            if a >= vertex_count || b >= vertex_count || c >= vertex_count {
+                return Err(PlyParseError::IndexOutOfBounds);
+            }
+            triangle.normal_indices = Some(triangle.position_indices).filter(|_| has_normals);
+            triangle.tex_coords_indices = Some(triangle.position_indices).filter(|_| has_tex_coords);
+            triangle.color_indices = Some(triangle.position_indices).filter(|_| has_colors);
+        }
+
+        Ok(data)
+    }
+
+    /// Parse a PLY file from the file system, the errors include the path
+    pub fn parse_file(path: &Path) -> Result<MeshData, PlyFileError> {
+        let to_file_error = |error| PlyFileError { path: path.to_path_buf(), error };
+        let bytes = fs::read(path)
+            .map_err(|err| to_file_error(PlyParseError::Io(err)))?;
+        PlyParser::parse(&bytes)
+            .map_err(to_file_error)
+    }
+}
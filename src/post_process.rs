@@ -0,0 +1,198 @@
+
+use serde::{Serialize, Deserialize};
+
+use crate::color::Color;
+use crate::hdr_image::HdrImage;
+use crate::image::RgbImage;
+use crate::math_util::{float, Float};
+
+fn default_bloom_threshold() -> Float {
+    1.0
+}
+
+fn default_bloom_intensity() -> Float {
+    0.2
+}
+
+fn default_bloom_radius() -> Float {
+    8.0
+}
+
+/// Glow around bright parts of the image, as caused by light scattering in a real lens
+///
+/// The part of each pixel's brightness above `threshold` is blurred and added back onto the image. Since this happens
+/// before the colors are clamped, the glow reveals how bright light sources and highlights are.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Bloom {
+    /// Luminance above which pixels start to glow
+    #[serde(default = "default_bloom_threshold")]
+    pub threshold: Float,
+    /// Factor applied to the glow before it's added to the image
+    #[serde(default = "default_bloom_intensity")]
+    pub intensity: Float,
+    /// Standard deviation of the Gaussian blur, in pixels of the full frame
+    #[serde(default = "default_bloom_radius")]
+    pub radius: Float,
+}
+
+impl Bloom {
+    /// Distance in pixels up to which the glow of a pixel reaches
+    pub fn margin(&self) -> usize {
+        (3.0 * self.radius.max(0.0)).ceil() as usize
+    }
+
+    /// Add the glow to all pixels of `img`
+    ///
+    /// The blur only takes pixels within the image into account, so applying this to a crop of the frame that extends
+    /// `margin()` pixels beyond the pixels of interest (where the frame allows) yields the same result for them as
+    /// applying it to the full frame.
+    pub fn apply(&self, img: &HdrImage) -> HdrImage {
+        let w = img.width();
+        let h = img.height();
+
+        let mut bright = HdrImage::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let color = img.get_pixel(x, y);
+                let luminance = color.luminance();
+                if luminance > self.threshold {
+                    bright.put_pixel(x, y, color * ((luminance - self.threshold) / luminance));
+                }
+            }
+        }
+
+        let kernel = gaussian_kernel(self.radius, self.margin());
+        let blurred = blur(&blur(&bright, &kernel, true), &kernel, false);
+
+        let mut result = HdrImage::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                result.put_pixel(x, y, img.get_pixel(x, y) + blurred.get_pixel(x, y) * self.intensity);
+            }
+        }
+        result
+    }
+}
+
+/// Weights of a Gaussian with standard deviation `sigma` at the offsets 0..=`radius`
+fn gaussian_kernel(sigma: Float, radius: usize) -> Vec<Float> {
+    (0..=radius)
+        .map(|offset| {
+            if sigma > 0.0 {
+                float::exp(-float::powi(offset as Float, 2) / (2.0 * sigma * sigma))
+            } else if offset == 0 {
+                1.0
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+/// Blur horizontally or vertically with a symmetric kernel, normalized over the taps that lie within the image
+fn blur(img: &HdrImage, kernel: &[Float], horizontal: bool) -> HdrImage {
+    let w = img.width();
+    let h = img.height();
+    let radius = kernel.len() as isize - 1;
+
+    let mut result = HdrImage::new(w, h);
+    for y in 0..h {
+        for x in 0..w {
+            let mut sum = Color::black();
+            let mut weight_sum = 0.0;
+            for offset in -radius..=radius {
+                let (tap_x, tap_y) = if horizontal {
+                    (x as isize + offset, y as isize)
+                } else {
+                    (x as isize, y as isize + offset)
+                };
+                if tap_x < 0 || tap_y < 0 || tap_x >= w as isize || tap_y >= h as isize {
+                    continue;
+                }
+                let weight = kernel[offset.unsigned_abs()];
+                sum += img.get_pixel(tap_x as usize, tap_y as usize) * weight;
+                weight_sum += weight;
+            }
+            result.put_pixel(x, y, sum / weight_sum);
+        }
+    }
+    result
+}
+
+/// Combines the views of a left and a right eye into one image for viewing with red-cyan glasses
+///
+/// Each output color is the sum of the two input colors multiplied with a channel mixing matrix each, in linear RGB.
+/// The left eye's matrix should only produce red and the right eye's only green and blue, or the other eye sees
+/// ghosts through its filter.
+#[derive(Clone, Debug)]
+pub struct Anaglyph {
+    /// Rows give the output red, green and blue as weights of the input red, green and blue
+    pub left: [[Float; 3]; 3],
+    pub right: [[Float; 3]; 3],
+}
+
+/// Weights of the relative luminance, as in `Color::luminance()`
+const LUMINANCE: [Float; 3] = [0.2126, 0.7152, 0.0722];
+
+impl Anaglyph {
+    /// Red channel of the left view, green and blue of the right view; colors are true but objects of saturated
+    /// colors look different to the two eyes, which causes retinal rivalry
+    pub fn color() -> Anaglyph {
+        Anaglyph {
+            left: [[1.0, 0.0, 0.0], [0.0; 3], [0.0; 3]],
+            right: [[0.0; 3], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        }
+    }
+
+    /// Both views in grayscale, without any rivalry but also without colors
+    pub fn gray() -> Anaglyph {
+        Anaglyph {
+            left: [LUMINANCE, [0.0; 3], [0.0; 3]],
+            right: [[0.0; 3], LUMINANCE, LUMINANCE],
+        }
+    }
+
+    /// The left view in grayscale and the right one in color, a compromise between `color()` and `gray()`
+    pub fn half_color() -> Anaglyph {
+        Anaglyph {
+            left: [LUMINANCE, [0.0; 3], [0.0; 3]],
+            right: [[0.0; 3], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        }
+    }
+
+    /// Least squares fit to the transmission of typical red-cyan glasses after Dubois (2001), with little ghosting
+    /// and fairly true colors
+    pub fn dubois() -> Anaglyph {
+        Anaglyph {
+            left: [[0.456, 0.500, 0.176], [-0.040, -0.038, -0.016], [-0.015, -0.021, -0.005]],
+            right: [[-0.043, -0.088, -0.002], [0.378, 0.734, -0.018], [-0.072, -0.113, 1.226]],
+        }
+    }
+
+    /// Combine two sRGB images of the same size
+    pub fn apply(&self, left: &RgbImage, right: &RgbImage) -> RgbImage {
+        assert!(left.width() == right.width() && left.height() == right.height(), "Left and right image differ in size");
+
+        let mix = |matrix: &[[Float; 3]; 3], color: Color| {
+            let [r, g, b] = matrix.map(|[r, g, b]| r * color.r + g * color.g + b * color.b);
+            Color::new(r, g, b)
+        };
+
+        let mut img = RgbImage::new(left.width(), left.height());
+        for y in 0..img.height() {
+            for x in 0..img.width() {
+                let left_color = Color::from_srgb_u8(&left.get_pixel(x, y));
+                let right_color = Color::from_srgb_u8(&right.get_pixel(x, y));
+                let color = mix(&self.left, left_color) + mix(&self.right, right_color);
+                img.put_pixel(x, y, &color.to_srgb_u8());
+            }
+        }
+        img
+    }
+}
+
+impl Default for Anaglyph {
+    fn default() -> Anaglyph {
+        Anaglyph::dubois()
+    }
+}
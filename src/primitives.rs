@@ -1,16 +1,58 @@
 
-use std::f32;
 
-use cgmath::{InnerSpace, Vector3, EuclideanSpace, Vector2};
+use cgmath::{InnerSpace, Point3, Vector3, EuclideanSpace, Vector2, Zero};
 use serde::{Serialize, Deserialize};
 
-use crate::ray::{Ray, Hit};
+use crate::ray::{Ray, Hit, Interval};
+use crate::aabb::AABB;
+use crate::math_util::{float, consts, Float};
+
+fn default_uv_scale() -> Vector2<Float> {
+    Vector2::new(1.0, 1.0)
+}
 
 /// A plane
+///
+/// Texture coordinates are the X and Z coordinates of the hit point, rotated by `uv_rotation`, multiplied by
+/// `uv_scale` and shifted by `uv_offset`, so with the defaults a texture repeats once per unit.
 #[derive(Clone, Serialize, Deserialize)]
-pub struct Plane {}
+pub struct Plane {
+    #[serde(default = "default_uv_scale")]
+    pub uv_scale: Vector2<Float>,
+    /// Counterclockwise rotation of the texture coordinates around the origin, in degrees
+    #[serde(default)]
+    pub uv_rotation: Float,
+    #[serde(default = "Vector2::zero")]
+    pub uv_offset: Vector2<Float>,
+    /// Size along X and Z of a rectangle centered on the origin that the plane is cut to, `None` for an infinite plane
+    ///
+    /// Unlike an infinite plane, a finite one has a bounding box and is included in `Scene::bounds()`.
+    #[serde(default)]
+    pub size: Option<Vector2<Float>>,
+}
+
+impl Default for Plane {
+    fn default() -> Plane {
+        Plane {
+            uv_scale: default_uv_scale(),
+            uv_rotation: 0.0,
+            uv_offset: Vector2::zero(),
+            size: None,
+        }
+    }
+}
 
 impl Plane {
+    /// Bounding box of a finite plane, which is flat in Y
+    pub fn bounding_box(&self) -> Option<AABB> {
+        self.size.map(|size| AABB::new(&Point3::new(-size.x / 2.0, 0.0, -size.y / 2.0), &Point3::new(size.x / 2.0, 0.0, size.y / 2.0)))
+    }
+
+    /// Whether a point on the plane lies within its `size`
+    fn contains(&self, point: &Point3<Float>) -> bool {
+        self.size.is_none_or(|size| point.x.abs() <= size.x / 2.0 && point.z.abs() <= size.y / 2.0)
+    }
+
     pub fn intersect(&self, ray: &Ray) -> Option<Hit> {
         // The normal has to be inverted for this calculation
         let normal = -Vector3::unit_y();
@@ -20,32 +62,85 @@ impl Plane {
         if denominator > 0.0 {
             let to_p0 = -ray.origin.to_vec();
             let distance = to_p0.dot(normal) / denominator;
-            if distance > 0.0 {
-                let hit_point = ray.origin + distance * ray.direction;
-
-                // Calculate two perpendicular axes (unit vectors) that lie on the plane
-                let x_axis = Vector3::unit_x();
-                let y_axis = Vector3::unit_z();
+            if distance > 0.0 && self.contains(&(ray.origin + distance * ray.direction)) {
+                return Some(self.hit_at(ray, distance));
+            }
+        }
 
-                // Vector from plane origin to hit point
-                let hit_vec = hit_point.to_vec();
+        None
+    }
 
-                // Project onto the two plane axes to get the UV coordinates
-                let tex_coords = Vector2::new(hit_vec.dot(x_axis), hit_vec.dot(y_axis));
+    /// Sections of the ray inside the half-space below the plane, i.e. behind its front face
+    ///
+    /// The half-space is unbounded even if the plane has a `size`.
+    pub fn intersect_interval(&self, ray: &Ray) -> Vec<Interval> {
+        let starts_inside = ray.origin.y <= 0.0;
+        if ray.direction.y == 0.0 {
+            return if starts_inside { vec![Interval { entry: None, exit: None }] } else { Vec::new() };
+        }
 
-                return Some(Hit::new(hit_point, distance, Vector3::unit_y(), tex_coords))
-            }
+        let distance = -ray.origin.y / ray.direction.y;
+        match (starts_inside, ray.direction.y > 0.0) {
+            (true, true) => vec![Interval { entry: None, exit: Some(self.hit_at(ray, distance)) }],
+            (true, false) => vec![Interval { entry: None, exit: None }],
+            (false, false) => vec![Interval { entry: Some(self.hit_at(ray, distance)), exit: None }],
+            (false, true) => Vec::new(),
         }
+    }
 
-        None
+    fn hit_at(&self, ray: &Ray, distance: Float) -> Hit {
+        let hit_point = ray.origin + distance * ray.direction;
+
+        // Calculate two perpendicular axes (unit vectors) that lie on the plane
+        let x_axis = Vector3::unit_x();
+        let y_axis = Vector3::unit_z();
+
+        // Vector from plane origin to hit point
+        let hit_vec = hit_point.to_vec();
+
+        // Project onto the two plane axes, then rotate, scale and offset to get the UV coordinates
+        let (sin, cos) = (float::sin(self.uv_rotation.to_radians()), float::cos(self.uv_rotation.to_radians()));
+        let x = hit_vec.dot(x_axis);
+        let y = hit_vec.dot(y_axis);
+        let tex_coords = Vector2::new(
+            (x * cos - y * sin) * self.uv_scale.x + self.uv_offset.x,
+            (x * sin + y * cos) * self.uv_scale.y + self.uv_offset.y,
+        );
+
+        // Inverse of the mapping above, applied to unit steps in U and V
+        let dpdu = (x_axis * cos - y_axis * sin) / self.uv_scale.x;
+        let dpdv = (x_axis * sin + y_axis * cos) / self.uv_scale.y;
+
+        Hit::new(hit_point, distance, Vector3::unit_y(), tex_coords, dpdu, dpdv)
     }
 }
 
+/// How texture coordinates are assigned to the surface of a sphere
+#[derive(Copy, Clone, Default, Serialize, Deserialize)]
+pub enum SphereMapping {
+    /// Longitude and latitude; U wraps around the equator and V runs from the north (+y) to the south pole
+    #[default]
+    Equirectangular,
+    /// Project onto the faces of a cube, which avoids the distortion at the poles
+    ///
+    /// The faces are arranged in a 3x2 grid: +x, -x, +y in the upper row and -y, +z, -z in the lower row. Each face is
+    /// oriented like in an OpenGL cube map.
+    Cube,
+}
+
 /// A sphere
-#[derive(Clone, Serialize, Deserialize)]
-pub struct Sphere {}
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Sphere {
+    #[serde(default)]
+    pub mapping: SphereMapping,
+}
 
 impl Sphere {
+    /// The sphere has a radius of 1 around the origin
+    pub fn bounding_box(&self) -> AABB {
+        AABB::new(&Point3::new(-1.0, -1.0, -1.0), &Point3::new(1.0, 1.0, 1.0))
+    }
+
     pub fn intersect(&self, ray: &Ray) -> Option<Hit> {
         // Calculate vector from ray origin to sphere center (hypotenuse)
         let to_center = -ray.origin.to_vec();
@@ -53,15 +148,15 @@ impl Sphere {
         // Project to_center onto ray direction vector to get length of adjacent side
         let adjacent = to_center.dot(ray.direction);
 
-        // Is the sphere behind the ray origin?
-        if adjacent < 0.0 {
-            return None;
-        }
-
         // The length of the hypotenuse is just he magnitude of the vector connecting the ray origin and the sphere center
         let center_distance_squared = to_center.magnitude2();
+
+        // Is the sphere behind the ray origin? Rays starting inside it, e.g. refracted ones, still hit the far side
+        if adjacent < 0.0 && center_distance_squared > 1.0 {
+            return None;
+        }
         // Length of opposite side (pythagorean theorem)
-        let distance_squared = center_distance_squared - adjacent.powi(2);
+        let distance_squared = center_distance_squared - float::powi(adjacent, 2);
 
         // The opposite side is the smallest distance between the ray and the sphere center
         // Compare the opposite side and the sphere radius to determine whether the ray goes through the sphere
@@ -83,27 +178,117 @@ impl Sphere {
         // Choose the intersection point that is closer to the ray origin
         let distance = if t0 < 0.0 {
             t1
-        } else if t1 < 0.0 {
-            t0
-        } else if t0 < t1 {
+        } else if t1 < 0.0 || t0 < t1 {
             t0
         } else {
             t1
         };
 
-        let hit_point = ray.origin + distance * ray.direction;
+        Some(self.hit_at(ray, distance))
+    }
 
-        // Vector from sphere origin to hit point
-        let hit_vec = hit_point.to_vec();
+    /// The section of the ray inside the sphere, if any
+    ///
+    /// Unlike `intersect()`, this also finds the exit of rays that start inside the sphere.
+    pub fn intersect_interval(&self, ray: &Ray) -> Vec<Interval> {
+        // Solve |origin + t * direction|^2 = 1 for t, the direction has unit length
+        let origin = ray.origin.to_vec();
+        let half_b = origin.dot(ray.direction);
+        let c = origin.magnitude2() - 1.0;
+        let discriminant = half_b * half_b - c;
+        if discriminant < 0.0 {
+            return Vec::new();
+        }
+
+        let thickness_half = discriminant.sqrt();
+        let t0 = -half_b - thickness_half;
+        let t1 = -half_b + thickness_half;
+        if t1 < 0.0 {
+            return Vec::new();
+        }
+
+        vec![Interval {
+            entry: if t0 >= 0.0 { Some(self.hit_at(ray, t0)) } else { None },
+            exit: Some(self.hit_at(ray, t1)),
+        }]
+    }
 
-        let normal = hit_vec.normalize();
+    fn hit_at(&self, ray: &Ray, distance: Float) -> Hit {
+        let hit_point = ray.origin + distance * ray.direction;
 
-        // Calculate UV coordinates from spherical coordinates
-        let tex_x = (1.0 + hit_vec.z.atan2(hit_vec.x) / f32::consts::PI) * 0.5;
-        let tex_y = hit_vec.y.acos() / f32::consts::PI;
+        // The hit point lies on the unit sphere only up to rounding errors, so use the normalized vector from the sphere
+        // origin to the hit point for everything else
+        let normal = hit_point.to_vec().normalize();
 
-        let tex_coords = Vector2::new(tex_x, tex_y);
+        let (tex_coords, dpdu, dpdv) = match self.mapping {
+            SphereMapping::Equirectangular => equirectangular_mapping(&normal),
+            SphereMapping::Cube => cube_mapping(&normal),
+        };
 
-        Some(Hit::new(hit_point, distance, normal, tex_coords))
+        Hit::new(hit_point, distance, normal, tex_coords, dpdu, dpdv)
     }
 }
+
+/// Texture coordinates and their partial derivatives of a point on the unit sphere in spherical coordinates
+fn equirectangular_mapping(point: &Vector3<Float>) -> (Vector2<Float>, Vector3<Float>, Vector3<Float>) {
+    let radius_xz = (float::powi(point.x, 2) + float::powi(point.z, 2)).sqrt();
+
+    // The longitude is undefined at the poles, use that of the seam instead of whatever atan2(0, 0) yields
+    let tex_x = if radius_xz < 1e-6 {
+        0.0
+    } else {
+        (1.0 + float::atan2(point.z, point.x) / consts::PI) * 0.5
+    };
+    // acos() returns NaN for values that exceed 1 due to rounding errors
+    let tex_y = float::acos(point.y.clamp(-1.0, 1.0)) / consts::PI;
+
+    // Partial derivatives of the spherical coordinates (phi = 2 pi u - pi, theta = pi v)
+    let (dpdu, dpdv) = if radius_xz < 1e-6 {
+        // The derivatives are degenerate at the poles
+        (Vector3::unit_x(), Vector3::unit_z())
+    } else {
+        let dpdu = 2.0 * consts::PI * Vector3::new(-point.z, 0.0, point.x);
+        let dpdv = consts::PI * Vector3::new(point.y * point.x / radius_xz, -radius_xz, point.y * point.z / radius_xz);
+        (dpdu, dpdv)
+    };
+
+    (Vector2::new(tex_x, tex_y), dpdu, dpdv)
+}
+
+/// Texture coordinates and their partial derivatives of a point on the unit sphere projected onto a cube, see
+/// `SphereMapping::Cube`
+fn cube_mapping(point: &Vector3<Float>) -> (Vector2<Float>, Vector3<Float>, Vector3<Float>) {
+    let abs = Vector3::new(point.x.abs(), point.y.abs(), point.z.abs());
+
+    // Grid cell of the face and the directions in which its S and T coordinates increase
+    let (column, row, major, s_axis, t_axis) = if abs.x >= abs.y && abs.x >= abs.z {
+        if point.x > 0.0 {
+            (0.0, 0.0, abs.x, -Vector3::unit_z(), -Vector3::unit_y())
+        } else {
+            (1.0, 0.0, abs.x, Vector3::unit_z(), -Vector3::unit_y())
+        }
+    } else if abs.y >= abs.z {
+        if point.y > 0.0 {
+            (2.0, 0.0, abs.y, Vector3::unit_x(), Vector3::unit_z())
+        } else {
+            (0.0, 1.0, abs.y, Vector3::unit_x(), -Vector3::unit_z())
+        }
+    } else if point.z > 0.0 {
+        (1.0, 1.0, abs.z, Vector3::unit_x(), -Vector3::unit_y())
+    } else {
+        (2.0, 1.0, abs.z, -Vector3::unit_x(), -Vector3::unit_y())
+    };
+
+    // Clamp to the face, rounding errors could otherwise bleed into the neighboring face in the texture
+    let s = ((point.dot(s_axis) / major + 1.0) * 0.5).clamp(0.0, 1.0);
+    let t = ((point.dot(t_axis) / major + 1.0) * 0.5).clamp(0.0, 1.0);
+    let tex_coords = Vector2::new((column + s) / 3.0, (row + t) / 2.0);
+
+    // S spans two units on the cube face and a third of the texture, T spans two units and half of the texture;
+    // projecting onto the tangent plane approximates the derivatives on the sphere
+    let tangent = |axis: Vector3<Float>| axis - point * point.dot(axis);
+    let dpdu = tangent(s_axis) * (6.0 * major);
+    let dpdv = tangent(t_axis) * (4.0 * major);
+
+    (tex_coords, dpdu, dpdv)
+}
@@ -1,111 +1,340 @@
 
 use std::cmp::Ordering;
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::Cell;
 
-use cgmath::{Point3, Vector3, InnerSpace, Matrix4, Transform, MetricSpace, Vector2};
+use cgmath::{Point3, Vector3, InnerSpace, Matrix4, Transform, MetricSpace, Vector2, EuclideanSpace, Zero};
 
+use crate::math_util::{orthonormal_basis, float, Float};
+use crate::color::Color;
+
+/// Work done by acceleration structures while tracing rays
+///
+/// The counts are kept per thread rather than per ray so that rays don't need a shared allocation that their
+/// object space copies can write to. The work done for a ray is the difference between the counts of its thread
+/// before and after tracing it.
+#[derive(Copy, Clone, Default)]
 pub struct RayDebugData {
+    /// Only counted for meshes with debugging enabled, visualized by the renderer
     pub kd_tree_lookups: usize,
+    /// Acceleration structure nodes visited, collected into `RenderStats`
+    pub node_traversals: usize,
+    /// Ray-triangle intersection tests, collected into `RenderStats`
+    pub triangle_tests: usize,
+}
+
+thread_local! {
+    static DEBUG_DATA: Cell<RayDebugData> = const { Cell::new(RayDebugData { kd_tree_lookups: 0, node_traversals: 0, triangle_tests: 0 }) };
+}
+
+impl RayDebugData {
+    /// Work done on the current thread so far
+    pub fn current() -> RayDebugData {
+        DEBUG_DATA.with(Cell::get)
+    }
+
+    /// Work done between taking the counts `earlier` and `self`
+    pub fn since(&self, earlier: &RayDebugData) -> RayDebugData {
+        RayDebugData {
+            kd_tree_lookups: self.kd_tree_lookups - earlier.kd_tree_lookups,
+            node_traversals: self.node_traversals - earlier.node_traversals,
+            triangle_tests: self.triangle_tests - earlier.triangle_tests,
+        }
+    }
+
+    /// Add the work done by a traversal to the counts of the current thread
+    pub fn record(work: RayDebugData) {
+        DEBUG_DATA.with(|debug_data| {
+            let total = debug_data.get();
+            debug_data.set(RayDebugData {
+                kd_tree_lookups: total.kd_tree_lookups + work.kd_tree_lookups,
+                node_traversals: total.node_traversals + work.node_traversals,
+                triangle_tests: total.triangle_tests + work.triangle_tests,
+            });
+        });
+    }
+}
+
+/// Offset rays through neighbouring pixels, used to estimate the footprint of a ray on a surface
+#[derive(Copy, Clone)]
+pub struct RayDifferentials {
+    pub rx_origin: Point3<Float>,
+    pub rx_direction: Vector3<Float>,
+    pub ry_origin: Point3<Float>,
+    pub ry_direction: Vector3<Float>,
+}
+
+impl RayDifferentials {
+    pub fn transform(&self, transformation: &Matrix4<Float>) -> RayDifferentials {
+        RayDifferentials {
+            rx_origin: transformation.transform_point(self.rx_origin),
+            rx_direction: transformation.transform_vector(self.rx_direction).normalize(),
+            ry_origin: transformation.transform_point(self.ry_origin),
+            ry_direction: transformation.transform_vector(self.ry_direction).normalize(),
+        }
+    }
 }
 
 /// Represents a single ray with origin and direction
+#[derive(Clone)]
 pub struct Ray {
     /// Ray origin
-    pub origin: Point3<f32>,
+    pub origin: Point3<Float>,
     /// Unit vector representing the rays direction
-    pub direction: Vector3<f32>,
-
-    pub debug_data: Rc<RefCell<RayDebugData>>,
+    pub direction: Vector3<Float>,
+    /// Point in time (in seconds) at which animated objects are intersected, `None` to use their static transformation
+    pub time: Option<Float>,
+    pub differentials: Option<RayDifferentials>,
+    /// Distance along the ray beyond which hits don't matter, e.g. the distance to the light for shadow rays
+    ///
+    /// Meshes skip the parts of their acceleration structure beyond it and don't report farther hits, other shapes
+    /// may still do.
+    pub max_distance: Float,
 }
 
 impl Ray {
-    pub fn new(origin: Point3<f32>, direction: Vector3<f32>) -> Ray {
+    pub fn new(origin: Point3<Float>, direction: Vector3<Float>) -> Ray {
         Ray {
             origin,
             direction,
-            debug_data: Rc::new(RefCell::new(RayDebugData {
-                kd_tree_lookups: 0,
-            })),
+            time: None,
+            differentials: None,
+            max_distance: Float::INFINITY,
         }
     }
 
-    pub fn transform(&self, transformation: &Matrix4<f32>) -> Ray {
+    pub fn transform(&self, transformation: &Matrix4<Float>) -> Ray {
+        let direction = transformation.transform_vector(self.direction);
         Ray {
             origin: transformation.transform_point(self.origin),
-            direction: transformation.transform_vector(self.direction).normalize(),
-            debug_data: self.debug_data.clone(),
+            direction: direction.normalize(),
+            time: self.time,
+            differentials: self.differentials.map(|differentials| differentials.transform(transformation)),
+            // Scaling stretches distances along the ray as well
+            max_distance: self.max_distance * (direction.magnitude() / self.direction.magnitude()),
+        }
+    }
+
+    /// Set the point in time of this ray, e.g. to propagate it from the incident ray to a secondary ray
+    pub fn with_time(mut self, time: Option<Float>) -> Ray {
+        self.time = time;
+        self
+    }
+
+    /// Ignore hits farther away than `max_distance`, see `Ray::max_distance`
+    pub fn with_max_distance(mut self, max_distance: Float) -> Ray {
+        self.max_distance = max_distance;
+        self
+    }
+
+    /// The same ray starting just past `hit`, e.g. to see what lies behind a surface that lets the ray through
+    ///
+    /// The differentials still describe the neighbouring rays as the ray doesn't change direction.
+    pub fn continued_past(&self, hit: &Hit) -> Ray {
+        Ray {
+            origin: hit.point + self.direction * 1e-4,
+            max_distance: self.max_distance - hit.distance,
+            ..self.clone()
         }
     }
 
     /// Create a ray with the appropriate direction for the specified pixel position and field of view
-    pub fn from_screen_coordinates(x: f32, y: f32, width: usize, height: usize, fov: f32) -> Ray {
-        let fov_factor = (fov.to_radians() / 2.0).tan();
+    ///
+    /// The ray differentials are set to the rays through the neighbouring pixels at `x + 1` and `y + 1`
+    pub fn from_screen_coordinates(x: Float, y: Float, width: usize, height: usize, fov: Float) -> Ray {
+        let fov_factor = float::tan(fov.to_radians() / 2.0);
 
-        let aspect_ratio = width as f32 / height as f32;
+        let aspect_ratio = width as Float / height as Float;
 
-        // Calculate screen coordinates between 0 and 1
-        let x_01 = (x + 0.5) / width as f32;
-        let y_01 = (y + 0.5) / height as f32;
+        let direction_at = |x: Float, y: Float| {
+            // Calculate screen coordinates between 0 and 1
+            let x_01 = (x + 0.5) / width as Float;
+            let y_01 = (y + 0.5) / height as Float;
 
-        // Translate screen coordinates in range [0.0, 1.0] to range [-1.0, 1.0]
-        let x_relative = x_01 * 2.0 - 1.0;
-        let y_relative = -(y_01 * 2.0 - 1.0);
+            // Translate screen coordinates in range [0.0, 1.0] to range [-1.0, 1.0]
+            let x_relative = x_01 * 2.0 - 1.0;
+            let y_relative = -(y_01 * 2.0 - 1.0);
 
-        // Calculate ray direction from screen coordinates
-        let ray_x = x_relative * aspect_ratio * fov_factor;
-        let ray_y = y_relative * fov_factor;
+            // Calculate ray direction from screen coordinates
+            let ray_x = x_relative * aspect_ratio * fov_factor;
+            let ray_y = y_relative * fov_factor;
 
-        let direction_normalized = Vector3::new(ray_x, ray_y, -1.0).normalize();
+            Vector3::new(ray_x, ray_y, -1.0).normalize()
+        };
 
-        Ray::new(
-            Point3::new(0.0, 0.0, 0.0),
-            direction_normalized,
-        )
+        let origin = Point3::new(0.0, 0.0, 0.0);
+
+        let mut ray = Ray::new(origin, direction_at(x, y));
+        ray.differentials = Some(RayDifferentials {
+            rx_origin: origin,
+            rx_direction: direction_at(x + 1.0, y),
+            ry_origin: origin,
+            ry_direction: direction_at(x, y + 1.0),
+        });
+        ray
     }
 
-    pub fn create_reflection(normal: &Vector3<f32>, incident: &Vector3<f32>, hit_point: &Point3<f32>) -> Ray {
+    pub fn create_reflection(normal: &Vector3<Float>, incident: &Vector3<Float>, hit_point: &Point3<Float>) -> Ray {
+        // Stay on the side the incident ray came from, which is the inside for reflections within a refractive object
+        let offset = if incident.dot(*normal) > 0.0 { -1e-5 } else { 1e-5 };
         Ray::new(
-            hit_point + 1e-5 * normal,
-            incident - (2.0 * incident.dot(*normal) * normal),
+            hit_point + offset * normal,
+            reflect(normal, incident),
         )
     }
 
-    pub fn create_transmission(normal: &Vector3<f32>, incident: &Vector3<f32>, hit_point: &Point3<f32>, refractive_index: f32) -> Option<Ray> {
-        let ref_n;
-        let eta_t;
-        let eta_i;
-        let mut i_dot_n = incident.dot(*normal);
-        if i_dot_n < 0.0 {
-            i_dot_n = -i_dot_n;
+    pub fn create_transmission(normal: &Vector3<Float>, incident: &Vector3<Float>, hit_point: &Point3<Float>, refractive_index: Float) -> Option<Ray> {
+        let (direction, ref_n) = refract(normal, incident, refractive_index)?;
+        Some(Ray::new(hit_point - 1e-5 * ref_n, direction))
+    }
 
-            ref_n = *normal;
-            eta_t = refractive_index;
-            eta_i = 1.0;
-        } else {
-            ref_n = -*normal;
-            eta_t = 1.0;
-            eta_i = refractive_index;
-        }
+    /// Derive the differentials of this reflected ray from those of the `incident` ray that hit the surface at `hit`
+    ///
+    /// The offset rays are reflected at the offset hit points, assuming that the normal doesn't change in between
+    /// (as in "Physically Based Rendering" without the normal derivatives).
+    pub fn with_reflected_differentials(mut self, incident: &Ray, hit: &Hit) -> Ray {
+        self.differentials = incident.differentials.map(|differentials| RayDifferentials {
+            rx_origin: self.origin + hit.dpdx,
+            rx_direction: reflect(&hit.normal, &differentials.rx_direction),
+            ry_origin: self.origin + hit.dpdy,
+            ry_direction: reflect(&hit.normal, &differentials.ry_direction),
+        });
+        self
+    }
 
-        let eta = eta_i / eta_t;
-        let k = 1.0 - eta.powi(2) * (1.0 - i_dot_n.powi(2));
-        if k < 0.0 {
-            None
-        } else {
-            Some(Ray::new(
-                hit_point - 1e-5 * ref_n,
-                incident * eta + (i_dot_n * eta - k.sqrt()) * ref_n,
-            ))
+    /// Like `with_reflected_differentials()`, but for a refracted ray; the differentials are dropped if an offset ray
+    /// would be totally reflected
+    pub fn with_refracted_differentials(mut self, incident: &Ray, hit: &Hit, refractive_index: Float) -> Ray {
+        self.differentials = incident.differentials.and_then(|differentials| {
+            let (rx_direction, _) = refract(&hit.normal, &differentials.rx_direction, refractive_index)?;
+            let (ry_direction, _) = refract(&hit.normal, &differentials.ry_direction, refractive_index)?;
+            Some(RayDifferentials {
+                rx_origin: self.origin + hit.dpdx,
+                rx_direction,
+                ry_origin: self.origin + hit.dpdy,
+                ry_direction,
+            })
+        });
+        self
+    }
+}
+
+/// Mirror `incident` at the surface with the given normal
+fn reflect(normal: &Vector3<Float>, incident: &Vector3<Float>) -> Vector3<Float> {
+    incident - (2.0 * incident.dot(*normal) * normal)
+}
+
+/// Refract `incident` at the surface with the given normal, entering the material if `incident` points against the
+/// normal and leaving it otherwise
+///
+/// Returns the refracted direction and the normal on the side the refracted direction points to, `None` for total
+/// internal reflection.
+fn refract(normal: &Vector3<Float>, incident: &Vector3<Float>, refractive_index: Float) -> Option<(Vector3<Float>, Vector3<Float>)> {
+    let ref_n;
+    let eta_t;
+    let eta_i;
+    let mut i_dot_n = incident.dot(*normal);
+    if i_dot_n < 0.0 {
+        i_dot_n = -i_dot_n;
+
+        ref_n = *normal;
+        eta_t = refractive_index;
+        eta_i = 1.0;
+    } else {
+        ref_n = -*normal;
+        eta_t = 1.0;
+        eta_i = refractive_index;
+    }
+
+    let eta = eta_i / eta_t;
+    let k = 1.0 - float::powi(eta, 2) * (1.0 - float::powi(i_dot_n, 2));
+    if k < 0.0 {
+        None
+    } else {
+        Some((incident * eta + (i_dot_n * eta - k.sqrt()) * ref_n, ref_n))
+    }
+}
+
+/// Number of UV channels a hit can carry texture coordinates for, e.g. for glTF's `TEXCOORD_n` attributes or lightmaps
+pub const MAX_UV_CHANNELS: usize = 4;
+
+/// Texture coordinates of a hit point in one UV channel together with their derivatives, see `Hit::uv_channel()`
+#[derive(Copy, Clone, Debug)]
+pub struct UvChannel {
+    pub tex_coords: Vector2<Float>,
+    /// Partial derivative of the hit point with respect to the U texture coordinate of this channel
+    pub dpdu: Vector3<Float>,
+    /// Partial derivative of the hit point with respect to the V texture coordinate of this channel
+    pub dpdv: Vector3<Float>,
+    /// Change of the texture coordinates between neighbouring pixels in x direction, zero if unknown
+    pub tex_coords_dx: Vector2<Float>,
+    /// Change of the texture coordinates between neighbouring pixels in y direction, zero if unknown
+    pub tex_coords_dy: Vector2<Float>,
+}
+
+impl UvChannel {
+    pub fn new(tex_coords: Vector2<Float>, dpdu: Vector3<Float>, dpdv: Vector3<Float>) -> UvChannel {
+        UvChannel {
+            tex_coords,
+            dpdu,
+            dpdv,
+            tex_coords_dx: Vector2::zero(),
+            tex_coords_dy: Vector2::zero(),
         }
     }
 }
 
+#[derive(Clone)]
 pub struct Hit {
-    pub point: Point3<f32>,
-    pub distance: f32,
-    pub normal: Vector3<f32>,
-    pub tex_coords: Vector2<f32>,
+    pub point: Point3<Float>,
+    pub distance: Float,
+    pub normal: Vector3<Float>,
+    pub tex_coords: Vector2<Float>,
+    /// Partial derivative of the hit point with respect to the U texture coordinate
+    pub dpdu: Vector3<Float>,
+    /// Partial derivative of the hit point with respect to the V texture coordinate
+    pub dpdv: Vector3<Float>,
+    /// Change of the texture coordinates between neighbouring pixels in x direction, zero if unknown
+    pub tex_coords_dx: Vector2<Float>,
+    /// Change of the texture coordinates between neighbouring pixels in y direction, zero if unknown
+    pub tex_coords_dy: Vector2<Float>,
+    /// Offset to the point the ray through the neighbouring pixel in x direction hits, zero if unknown
+    pub dpdx: Vector3<Float>,
+    /// Offset to the point the ray through the neighbouring pixel in y direction hits, zero if unknown
+    pub dpdy: Vector3<Float>,
+    /// Offset to the closest point on the edges of the hit triangle, `None` for shapes that aren't made of triangles
+    pub edge_offset: Option<Vector3<Float>>,
+    /// Interpolated vertex color, `None` for shapes without vertex colors
+    pub vertex_color: Option<Color>,
+    /// UV channels after the first, whose texture coordinates are the fields above; `None` where the shape has none
+    pub extra_uv_channels: [Option<UvChannel>; MAX_UV_CHANNELS - 1],
+}
+
+/// A section of a ray that lies inside a closed shape
+#[derive(Clone)]
+pub struct Interval {
+    /// Where the ray enters the shape, `None` if it starts inside
+    pub entry: Option<Hit>,
+    /// Where the ray leaves the shape, `None` if it never does (e.g. the half-space below a plane)
+    pub exit: Option<Hit>,
+}
+
+impl Interval {
+    /// Distance along the ray at which the interval starts, 0 if the ray starts inside
+    pub fn entry_distance(&self) -> Float {
+        self.entry.as_ref().map_or(0.0, |hit| hit.distance)
+    }
+
+    /// Distance along the ray at which the interval ends, infinity if the ray never leaves the shape
+    pub fn exit_distance(&self) -> Float {
+        self.exit.as_ref().map_or(Float::INFINITY, |hit| hit.distance)
+    }
+
+    pub fn transform(&self, transformation: &Matrix4<Float>, ray_origin: &Point3<Float>) -> Interval {
+        Interval {
+            entry: self.entry.as_ref().map(|hit| hit.transform(transformation, ray_origin)),
+            exit: self.exit.as_ref().map(|hit| hit.transform(transformation, ray_origin)),
+        }
+    }
 }
 
 impl PartialEq for Hit {
@@ -132,11 +361,121 @@ impl Ord for Hit {
 }
 
 impl Hit {
-    pub fn new(point: Point3<f32>, distance: f32, normal: Vector3<f32>, tex_coords: Vector2<f32>) -> Hit {
-        Hit { point, distance, normal, tex_coords }
+    /// Whether a ray with the given direction hits the side the normal points to, i.e. enters the shape
+    pub fn is_front_face(&self, direction: &Vector3<Float>) -> bool {
+        direction.dot(self.normal) < 0.0
+    }
+
+    pub fn new(point: Point3<Float>, distance: Float, normal: Vector3<Float>, tex_coords: Vector2<Float>, dpdu: Vector3<Float>, dpdv: Vector3<Float>) -> Hit {
+        Hit {
+            point,
+            distance,
+            normal,
+            tex_coords,
+            dpdu,
+            dpdv,
+            tex_coords_dx: Vector2::zero(),
+            tex_coords_dy: Vector2::zero(),
+            dpdx: Vector3::zero(),
+            dpdy: Vector3::zero(),
+            edge_offset: None,
+            vertex_color: None,
+            extra_uv_channels: [None; MAX_UV_CHANNELS - 1],
+        }
     }
 
-    pub fn transform(&self, transformation: &Matrix4<f32>, ray_origin: &Point3<f32>) -> Hit {
+    /// Set `edge_offset` for a hit on the triangle with the corners `v0`, `v1` and `v2`
+    pub fn with_triangle_edges(self, v0: &Vector3<Float>, v1: &Vector3<Float>, v2: &Vector3<Float>) -> Hit {
+        let point = self.point.to_vec();
+        let closest_on_edge = |a: &Vector3<Float>, b: &Vector3<Float>| {
+            let edge = b - a;
+            let t = ((point - a).dot(edge) / edge.magnitude2()).clamp(0.0, 1.0);
+            // Degenerate edges have no direction
+            if t.is_finite() { a + edge * t } else { *a }
+        };
+        let edge_offset = [closest_on_edge(v0, v1), closest_on_edge(v1, v2), closest_on_edge(v2, v0)].iter()
+            .map(|&closest| closest - point)
+            .min_by(|a, b| a.magnitude2().partial_cmp(&b.magnitude2()).unwrap());
+        Hit { edge_offset, ..self }
+    }
+
+    /// Texture coordinates in UV channel `channel`, falling back to the first channel if the shape doesn't have it
+    pub fn uv_channel(&self, channel: usize) -> UvChannel {
+        let extra = channel.checked_sub(1).and_then(|index| self.extra_uv_channels.get(index).copied().flatten());
+        extra.unwrap_or(UvChannel {
+            tex_coords: self.tex_coords,
+            dpdu: self.dpdu,
+            dpdv: self.dpdv,
+            tex_coords_dx: self.tex_coords_dx,
+            tex_coords_dy: self.tex_coords_dy,
+        })
+    }
+
+    /// Unit vector along the direction of increasing U texture coordinate, perpendicular to the normal
+    pub fn tangent(&self) -> Vector3<Float> {
+        self.tangent_along(&self.dpdu)
+    }
+
+    /// Unit vector along `dpdu`, e.g. of another UV channel, made perpendicular to the normal
+    pub fn tangent_along(&self, dpdu: &Vector3<Float>) -> Vector3<Float> {
+        let tangent = dpdu - self.normal * self.normal.dot(*dpdu);
+        if tangent.magnitude2() < Float::EPSILON {
+            orthonormal_basis(&self.normal).0
+        } else {
+            tangent.normalize()
+        }
+    }
+
+    /// Estimate the texture coordinate derivatives from the ray differentials (see "Physically Based Rendering")
+    pub fn compute_differentials(&mut self, differentials: &RayDifferentials) {
+        // Intersect the offset rays with the tangent plane at the hit point
+        let plane_distance = self.normal.dot(self.point.to_vec());
+        let intersect_plane = |origin: &Point3<Float>, direction: &Vector3<Float>| {
+            let t = (plane_distance - self.normal.dot(origin.to_vec())) / self.normal.dot(*direction);
+            origin + direction * t
+        };
+        let dpdx = intersect_plane(&differentials.rx_origin, &differentials.rx_direction) - self.point;
+        let dpdy = intersect_plane(&differentials.ry_origin, &differentials.ry_direction) - self.point;
+        let is_finite = |v: &Vector3<Float>| v.x.is_finite() && v.y.is_finite() && v.z.is_finite();
+        if is_finite(&dpdx) && is_finite(&dpdy) {
+            self.dpdx = dpdx;
+            self.dpdy = dpdy;
+        }
+
+        // Project onto the two axes that are least perpendicular to the normal and solve for the UV derivatives
+        let (dim0, dim1) = if self.normal.x.abs() > self.normal.y.abs() && self.normal.x.abs() > self.normal.z.abs() {
+            (1, 2)
+        } else if self.normal.y.abs() > self.normal.z.abs() {
+            (0, 2)
+        } else {
+            (0, 1)
+        };
+
+        let uv_derivatives = |dpdu: &Vector3<Float>, dpdv: &Vector3<Float>| {
+            let a = [[dpdu[dim0], dpdv[dim0]], [dpdu[dim1], dpdv[dim1]]];
+            let determinant = a[0][0] * a[1][1] - a[0][1] * a[1][0];
+            if determinant.abs() < 1e-12 || !dpdx.x.is_finite() || !dpdy.x.is_finite() {
+                return (Vector2::zero(), Vector2::zero());
+            }
+
+            let solve = |b: [Float; 2]| Vector2::new(
+                (a[1][1] * b[0] - a[0][1] * b[1]) / determinant,
+                (a[0][0] * b[1] - a[1][0] * b[0]) / determinant,
+            );
+            (solve([dpdx[dim0], dpdx[dim1]]), solve([dpdy[dim0], dpdy[dim1]]))
+        };
+
+        let (tex_coords_dx, tex_coords_dy) = uv_derivatives(&self.dpdu, &self.dpdv);
+        self.tex_coords_dx = tex_coords_dx;
+        self.tex_coords_dy = tex_coords_dy;
+        for channel in self.extra_uv_channels.iter_mut().flatten() {
+            let (tex_coords_dx, tex_coords_dy) = uv_derivatives(&channel.dpdu, &channel.dpdv);
+            channel.tex_coords_dx = tex_coords_dx;
+            channel.tex_coords_dy = tex_coords_dy;
+        }
+    }
+
+    pub fn transform(&self, transformation: &Matrix4<Float>, ray_origin: &Point3<Float>) -> Hit {
         let transformed_point = transformation.transform_point(self.point);
         let transformed_distance = ray_origin.distance(transformed_point);
 
@@ -145,6 +484,19 @@ impl Hit {
             distance: transformed_distance,
             normal: transformation.transform_vector(self.normal).normalize(),
             tex_coords: self.tex_coords,
+            dpdu: transformation.transform_vector(self.dpdu),
+            dpdv: transformation.transform_vector(self.dpdv),
+            tex_coords_dx: self.tex_coords_dx,
+            tex_coords_dy: self.tex_coords_dy,
+            dpdx: transformation.transform_vector(self.dpdx),
+            dpdy: transformation.transform_vector(self.dpdy),
+            edge_offset: self.edge_offset.map(|offset| transformation.transform_vector(offset)),
+            vertex_color: self.vertex_color,
+            extra_uv_channels: self.extra_uv_channels.map(|channel| channel.map(|channel| UvChannel {
+                dpdu: transformation.transform_vector(channel.dpdu),
+                dpdv: transformation.transform_vector(channel.dpdv),
+                ..channel
+            })),
         }
     }
 }
@@ -0,0 +1,164 @@
+use serde::{Serialize, Deserialize};
+
+use crate::image::RgbImage;
+
+/// A rectangular crop window, in pixel coordinates of the full frame
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Region {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Region {
+        Region { x, y, width, height }
+    }
+
+    /// Region covering the full frame
+    pub fn full(resolution: (usize, usize)) -> Region {
+        Region::new(0, 0, resolution.0, resolution.1)
+    }
+
+    /// Clip this region so that it lies within a frame of the given size
+    pub fn clip(&self, resolution: (usize, usize)) -> Region {
+        let x = self.x.min(resolution.0);
+        let y = self.y.min(resolution.1);
+        Region {
+            x,
+            y,
+            width: self.width.min(resolution.0 - x),
+            height: self.height.min(resolution.1 - y),
+        }
+    }
+
+    /// Split the full frame into horizontal strips of at most `strip_height` rows
+    pub fn strips(resolution: (usize, usize), strip_height: usize) -> Vec<Region> {
+        let strip_height = strip_height.max(1);
+        (0..resolution.1).step_by(strip_height)
+            .map(|y| Region::new(0, y, resolution.0, strip_height.min(resolution.1 - y)))
+            .collect()
+    }
+
+    /// Split the full frame into square tiles of at most `tile_size` pixels, in the given order
+    ///
+    /// Tiles at the right and bottom edge are cut off by the frame.
+    pub fn tiles(resolution: (usize, usize), tile_size: usize, order: TileOrder) -> Vec<Region> {
+        let tile_size = tile_size.max(1);
+        let columns = resolution.0.div_ceil(tile_size);
+        let rows = resolution.1.div_ceil(tile_size);
+
+        let cells = match order {
+            TileOrder::Scanline => (0..rows).flat_map(|row| (0..columns).map(move |column| (column, row))).collect(),
+            TileOrder::Spiral => spiral_cells(columns, rows),
+            TileOrder::Hilbert => hilbert_cells(columns, rows),
+        };
+
+        cells.into_iter()
+            .map(|(column, row)| {
+                let x = column * tile_size;
+                let y = row * tile_size;
+                Region::new(x, y, tile_size.min(resolution.0 - x), tile_size.min(resolution.1 - y))
+            })
+            .collect()
+    }
+}
+
+/// All cells of a grid, walking a square spiral outwards from the center cell
+fn spiral_cells(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let count = columns * rows;
+    let mut cells = Vec::with_capacity(count);
+    if count == 0 {
+        return cells;
+    }
+
+    let mut x = ((columns - 1) / 2) as isize;
+    let mut y = ((rows - 1) / 2) as isize;
+    cells.push((x as usize, y as usize));
+
+    // Right, down, left, up, with the run length growing by one every two turns
+    const DIRECTIONS: [(isize, isize); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut run_length = 1;
+    let mut direction = 0;
+    while cells.len() < count {
+        for _ in 0..2 {
+            let (dx, dy) = DIRECTIONS[direction];
+            for _ in 0..run_length {
+                x += dx;
+                y += dy;
+                // The spiral leaves the grid on the shorter side of non-square grids
+                if (0..columns as isize).contains(&x) && (0..rows as isize).contains(&y) {
+                    cells.push((x as usize, y as usize));
+                }
+            }
+            direction = (direction + 1) % 4;
+        }
+        run_length += 1;
+    }
+    cells
+}
+
+/// All cells of a grid along a Hilbert curve covering the next larger power of two square
+fn hilbert_cells(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let side = columns.max(rows).next_power_of_two();
+    (0..side * side)
+        .map(|index| hilbert_index_to_cell(side, index))
+        .filter(|&(x, y)| x < columns && y < rows)
+        .collect()
+}
+
+/// Position of the cell with the given index along a Hilbert curve through a `side` x `side` grid
+fn hilbert_index_to_cell(side: usize, index: usize) -> (usize, usize) {
+    let mut x = 0;
+    let mut y = 0;
+    let mut t = index;
+    let mut s = 1;
+    while s < side {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        // Rotate the quadrant so that the sub-curves connect
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+/// Order in which `Region::tiles()` returns the tiles of a frame
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TileOrder {
+    /// Row by row from the top left
+    #[default]
+    Scanline,
+    /// Outwards from the center of the frame, so that a preview shows the (usually most interesting) center first
+    Spiral,
+    /// Along a Hilbert curve, which keeps consecutive tiles adjacent for better cache locality
+    Hilbert,
+}
+
+/// The image of a region together with its placement in the full frame
+#[derive(Clone)]
+pub struct RenderedRegion {
+    pub region: Region,
+    pub image: RgbImage,
+}
+
+/// Assemble rendered regions into a full-resolution image
+///
+/// Parts of the frame that aren't covered by any region remain black, overlapping regions overwrite each other in order
+pub fn composite_regions(resolution: (usize, usize), regions: &[RenderedRegion]) -> RgbImage {
+    let mut img = RgbImage::new(resolution.0, resolution.1);
+    for rendered_region in regions {
+        img.copy_from(&rendered_region.image, rendered_region.region.x, rendered_region.region.y);
+    }
+    img
+}
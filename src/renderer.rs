@@ -1,23 +1,465 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut, Range};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use std::f32;
-
-use cgmath::{InnerSpace, Vector3};
-use rand::{thread_rng, Rng};
-use rand_distr::Normal;
+use cgmath::{InnerSpace, Matrix4, SquareMatrix, Transform, Vector3, Zero};
+use rand::Rng;
 
 use crate::color::Color;
-use crate::image::RgbImage;
-use crate::ray::{Ray, Hit};
-use crate::scene::{Scene, Object};
+use crate::image::{RgbImage, RgbaImage};
+use crate::hdr_image::HdrImage;
+use crate::ray::{Ray, RayDebugData, Hit};
+use crate::scene::{Scene, Object, Shape, AmbientOcclusion, Fog, Background, Eye};
+use crate::math_util::{sample_hemisphere_cosine, sampling_rng, StratifiedSquare, float, Float, consts, SamplingRng, Modulo};
+use crate::material::{Material, Coloration};
+use crate::environment::EnvironmentMap;
+use crate::region::{Region, RenderedRegion, TileOrder, composite_regions};
+use crate::settings::RenderSettings;
+use crate::job::{self, RenderJob, JOB_BYTES_PER_PIXEL};
+use crate::stats::{RenderStats, RenderCounters, RayType};
+use crate::packet::PACKET_SIZE;
+use crate::pixel_trace::{self, PixelTrace, SegmentHit};
+use crate::denoise::{Aovs, Denoiser};
+use crate::post_process::{Anaglyph, Bloom};
+use crate::heatmap::{self, CostBuffer, CostMetric, ColorMap, Heatmap, Heatmaps};
+use crate::overlay::{StructureOverlay, LineProjector};
+use crate::error::RaytracerError;
+use crate::scratch::ScratchVec;
+
+/// Position of a ray along a chain of reflections and refractions
+#[derive(Copy, Clone)]
+struct PathState {
+    depth: u32,
+    reflection_depth: u32,
+    refraction_depth: u32,
+    /// Fraction of the ray's color that ends up in the pixel
+    throughput: Float,
+    media: MediumStack,
+}
+
+impl PathState {
+    fn primary() -> PathState {
+        PathState {
+            depth: 0,
+            reflection_depth: 0,
+            refraction_depth: 0,
+            throughput: 1.0,
+            media: MediumStack::empty(),
+        }
+    }
+
+    /// State of a reflected ray whose color is weighted with `weight`
+    fn reflected(&self, weight: Float) -> PathState {
+        PathState {
+            depth: self.depth + 1,
+            reflection_depth: self.reflection_depth + 1,
+            throughput: self.throughput * weight,
+            ..*self
+        }
+    }
+
+    /// State of a ray whose color is weighted with `weight` and that was refracted into `media`
+    fn refracted(&self, weight: Float, media: MediumStack) -> PathState {
+        PathState {
+            depth: self.depth + 1,
+            refraction_depth: self.refraction_depth + 1,
+            throughput: self.throughput * weight,
+            media,
+            ..*self
+        }
+    }
+}
+
+/// Number of nested media a ray keeps track of, it treats media inside of those as if it stayed in the innermost one
+const MAX_NESTED_MEDIA: usize = 8;
+
+/// Media a ray has been refracted into, as material index and refractive index, innermost last
+///
+/// Lets refraction use the two media that actually meet at a surface, e.g. glass inside water, instead of assuming air
+/// on the outside.
+#[derive(Copy, Clone)]
+struct MediumStack {
+    media: [(usize, Float); MAX_NESTED_MEDIA],
+    len: usize,
+}
+
+impl MediumStack {
+    /// Outside of all objects, in air
+    fn empty() -> MediumStack {
+        MediumStack {
+            media: [(0, 1.0); MAX_NESTED_MEDIA],
+            len: 0,
+        }
+    }
+
+    /// Refractive index on the other side of a surface of the material `material_index` than its inside
+    ///
+    /// When leaving the material, that is the medium it is nested in. Rays that leave a material they never entered
+    /// (e.g. because the camera is inside it) leave into the innermost medium they know.
+    fn outside_index(&self, material_index: usize, entering: bool) -> Float {
+        let media = &self.media[..self.len];
+        let inside = if entering { None } else { media.iter().rposition(|&(index, _)| index == material_index) };
+        media.iter()
+            .enumerate()
+            .rev()
+            .find(|&(position, _)| Some(position) != inside)
+            .map_or(1.0, |(_, &(_, refractive_index))| refractive_index)
+    }
+
+    /// Media after crossing a surface of the material `material_index`
+    fn crossed(&self, material_index: usize, refractive_index: Float, entering: bool) -> MediumStack {
+        let mut stack = *self;
+        if entering {
+            if stack.len < MAX_NESTED_MEDIA {
+                stack.media[stack.len] = (material_index, refractive_index);
+                stack.len += 1;
+            }
+        } else if let Some(position) = stack.media[..stack.len].iter().rposition(|&(index, _)| index == material_index) {
+            stack.media.copy_within(position + 1..stack.len, position);
+            stack.len -= 1;
+        }
+        stack
+    }
+}
+
+thread_local! {
+    /// Lights that contribute while `Renderer::render_light_groups()` renders a group on this thread, indexed like
+    /// `Scene::lights`; light that doesn't come from any light source is left out then as well
+    static LIGHT_MASK: RefCell<Option<Vec<bool>>> = const { RefCell::new(None) };
+}
+
+/// Whether light `light_index` contributes in the pass being rendered on this thread
+fn is_light_active(light_index: usize) -> bool {
+    LIGHT_MASK.with(|mask| mask.borrow().as_ref().is_none_or(|mask| mask[light_index]))
+}
+
+/// Whether light that doesn't come from a light source (ambient light, background, environment map and the fog color)
+/// contributes in the pass being rendered on this thread
+fn is_unlit_active() -> bool {
+    LIGHT_MASK.with(|mask| mask.borrow().is_none())
+}
+
+thread_local! {
+    /// Eye of the camera's stereo setup whose view is being rendered on this thread, `None` for the center view
+    static EYE: Cell<Option<Eye>> = const { Cell::new(None) };
+}
+
+/// Samples of each pixel rendered by `Renderer::render_job()`
+#[derive(Copy, Clone)]
+struct JobSamples {
+    start: usize,
+    end: usize,
+    seed: u64,
+}
+
+thread_local! {
+    /// Samples of the job being rendered on this thread, `None` to render all samples of each pixel
+    static JOB_SAMPLES: Cell<Option<JobSamples>> = const { Cell::new(None) };
+}
+
+thread_local! {
+    /// Object that blocked the last shadow ray cast on this thread towards each light, see
+    /// `Renderer::set_shadow_cache()`
+    ///
+    /// Indexed like `Scene::lights`, with the last entry standing for the environment map. Entries are only hints
+    /// that get verified before use, so they may safely outlive the renderer that wrote them.
+    static SHADOW_CACHE: RefCell<Vec<Option<usize>>> = const { RefCell::new(Vec::new()) };
+}
+
+thread_local! {
+    /// Primary rays of the run of pixels being rendered on this thread, with their path state
+    static PRIMARY_RAYS: RefCell<Vec<(Ray, PathState)>> = const { RefCell::new(Vec::new()) };
+    /// Pixel and vignetting factor of each of the `PRIMARY_RAYS`
+    static PRIMARY_PIXELS: RefCell<Vec<(usize, Float)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Mutable access to the scene of a renderer, see `Renderer::scene_mut()`
+///
+/// Refits the top-level BVH when dropped if objects were edited, so that rays don't fall back to testing all objects.
+pub struct SceneMut<'a> {
+    scene: &'a mut Scene,
+}
+
+impl Deref for SceneMut<'_> {
+    type Target = Scene;
+
+    fn deref(&self) -> &Scene {
+        self.scene
+    }
+}
+
+impl DerefMut for SceneMut<'_> {
+    fn deref_mut(&mut self) -> &mut Scene {
+        self.scene
+    }
+}
+
+impl Drop for SceneMut<'_> {
+    fn drop(&mut self) {
+        if !self.scene.acceleration.is_current(&self.scene.objects) {
+            self.scene.refit();
+        }
+    }
+}
+
+/// What the renderer shows of the surfaces hit by primary rays
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    /// Fully lit and shaded image
+    #[default]
+    Shaded,
+    /// Triangle edges of meshes and heightfields as white lines over gray surfaces, darker where they face away from
+    /// the camera
+    Wireframe,
+    /// Interpolated normals (before bump mapping), with each component mapped from [-1, 1] to [0, 1] in RGB
+    Normals,
+    /// Texture coordinates, with the fractional part of U in red and of V in green
+    TexCoords,
+    /// Lit image with every surface shaded with the same gray diffuse material, to judge the lighting and modeling
+    /// independently of the materials
+    ///
+    /// Opacity maps and the visibility of materials still apply, as do fog and volumes.
+    Clay,
+    /// Ambient occlusion of the surfaces hit by primary rays, from white where nothing is nearby to black where the
+    /// hemisphere above the surface is fully blocked
+    ///
+    /// Uses the scene's `ambient_occlusion` settings, or `DEFAULT_AO_SAMPLES` rays with a radius of a tenth of the
+    /// scene's size if it has none.
+    AmbientOcclusion,
+}
+
+impl RenderMode {
+    /// Whether the mode shows the lit scene, with exposure, bloom and sRGB encoded output; the other modes write their
+    /// values linearly, so that e.g. a normal can be read back from the image
+    fn is_lit(self) -> bool {
+        matches!(self, RenderMode::Shaded | RenderMode::Clay)
+    }
+}
+
+/// Number of occlusion rays per primary hit in `RenderMode::AmbientOcclusion` if the scene has no ambient occlusion
+pub const DEFAULT_AO_SAMPLES: usize = 16;
+
+/// Color of a primary ray, and what it hit
+struct PrimarySample {
+    color: Color,
+    /// Coverage of the sample if the background is transparent; objects cover it fully, shadow catchers partially
+    alpha: Float,
+    surface: Option<SurfaceSample>,
+}
+
+/// What a shadow catcher puts over the light arriving from behind it
+struct CaughtShadow {
+    /// Fraction of the light that isn't blocked by objects between the catcher and the lights
+    transmittance: Float,
+    /// Color of the reflected objects
+    reflection: Color,
+    /// Fraction of the light from behind that the reflection replaces
+    reflection_weight: Float,
+}
+
+impl CaughtShadow {
+    /// Fraction of the light from behind that remains
+    fn behind_weight(&self) -> Float {
+        self.transmittance * (1.0 - self.reflection_weight)
+    }
+
+    fn composite(&self, behind: Color) -> Color {
+        behind * self.behind_weight() + self.reflection * self.reflection_weight
+    }
+}
+
+/// Contribution of a primary ray to the `Aovs`
+struct SurfaceSample {
+    normal: Vector3<Float>,
+    depth: Float,
+    albedo: Color,
+}
+
+/// Optional per-pixel outputs of `Renderer::render_rect_internal()` besides the color
+#[derive(Default)]
+struct RectOutputs<'a> {
+    quality: Option<&'a mut RgbImage>,
+    /// Row by row
+    alpha: Option<&'a mut Vec<Float>>,
+    aovs: Option<&'a mut Aovs>,
+}
 
 pub struct Renderer {
     scene: Scene,
+    /// Maximum number of rays that may be cast, pixels rendered after it is exhausted use reduced quality
+    ray_budget: Option<usize>,
+    /// Number of rays cast so far, shared by all threads using this renderer
+    rays_cast: AtomicUsize,
+    counters: RenderCounters,
+    /// Trace primary rays in packets instead of one by one
+    packet_tracing: bool,
+    /// Test the last occluder of each light first when casting shadow rays
+    shadow_cache: bool,
+    render_mode: RenderMode,
+    /// Material of all surfaces in `RenderMode::Clay`
+    clay_material: Material,
+    /// Material that replaces all of the scene's materials, see `set_material_override()`
+    material_override: Option<Material>,
+    /// Materials that replace the scene's materials with the same index, see `remap_material()`
+    material_remaps: HashMap<usize, Material>,
 }
 
 impl Renderer {
-    pub fn new(scene: Scene) -> Renderer {
+    pub fn new(mut scene: Scene) -> Renderer {
+        scene.refit();
         Renderer {
             scene,
+            ray_budget: None,
+            rays_cast: AtomicUsize::new(0),
+            counters: RenderCounters::default(),
+            packet_tracing: true,
+            shadow_cache: false,
+            render_mode: RenderMode::Shaded,
+            clay_material: Material::new(Coloration::Color(Color::new(0.7, 0.7, 0.7)), 1.0, 0.0, 0.0, 1.0),
+            material_override: None,
+            material_remaps: HashMap::new(),
+        }
+    }
+
+    pub fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    /// Edit the scene between frames, e.g. with `Scene::object_mut()`
+    ///
+    /// Like `update_material()`, this can never happen in the middle of a pass. The top-level BVH is refitted once the
+    /// returned guard is dropped, so several objects can be moved at once.
+    pub fn scene_mut(&mut self) -> SceneMut<'_> {
+        SceneMut { scene: &mut self.scene }
+    }
+
+    /// Replace the material at `index` without reloading the scene, e.g. for look-dev
+    ///
+    /// Returns the previous material, or `None` if there is no material at `index` (in which case nothing is changed).
+    /// Since all render methods borrow the renderer immutably, a swap can never happen in the middle of a pass;
+    /// images rendered before the swap have to be discarded by the caller.
+    ///
+    /// The swap empties the `HitCache`s used with the scene, as the new material may be visible to other rays or have
+    /// another opacity map.
+    pub fn update_material(&mut self, index: usize, material: Material) -> Option<Material> {
+        let old_material = self.scene.materials.get_mut(index)
+            .map(|old_material| std::mem::replace(old_material, material))?;
+        self.scene.acceleration.bump_generation();
+        Some(old_material)
+    }
+
+    /// Replace the material with the given name, see `update_material()` and `Scene::material_names`
+    ///
+    /// Returns `None` if the scene has no material with this name (in which case nothing is changed).
+    pub fn update_material_named(&mut self, name: &str, material: Material) -> Option<Material> {
+        let index = self.scene.material_index(name)?;
+        self.update_material(index, material)
+    }
+
+    /// Shade all objects with `material` instead of their own, or with their own again for `None`
+    ///
+    /// Unlike `update_material()`, this leaves the scene untouched, so the same scene can be rendered with different
+    /// looks. Only the shading changes: opacity maps and the visibility of the scene's materials still decide which
+    /// surfaces rays hit. The override takes precedence over `remap_material()`.
+    pub fn set_material_override(&mut self, material: Option<Material>) {
+        self.material_override = material;
+    }
+
+    /// Shade the objects that use the material at `index` with `material` instead, without changing the scene
+    ///
+    /// E.g. renders the same car with three paint colors by remapping its paint material before each render. Like
+    /// with `set_material_override()`, opacity and visibility still come from the scene's material.
+    pub fn remap_material(&mut self, index: usize, material: Material) -> Result<(), RaytracerError> {
+        if index >= self.scene.materials.len() {
+            return Err(RaytracerError::InvalidConfiguration(format!("Can't remap material {}, the scene only has {} materials", index, self.scene.materials.len())));
+        }
+        self.material_remaps.insert(index, material);
+        Ok(())
+    }
+
+    /// Remap the material with the given name, see `remap_material()` and `Scene::material_names`
+    pub fn remap_material_named(&mut self, name: &str, material: Material) -> Result<(), RaytracerError> {
+        let index = self.scene.material_index(name)
+            .ok_or_else(|| RaytracerError::InvalidConfiguration(format!("Can't remap material \"{}\", the scene has no material with this name", name)))?;
+        self.remap_material(index, material)
+    }
+
+    /// Shade all objects with the scene's materials again, except for the material override
+    pub fn clear_material_remaps(&mut self) {
+        self.material_remaps.clear();
+    }
+
+    /// Limit the total number of rays this renderer casts
+    ///
+    /// Once the budget is exhausted, all remaining pixels are rendered with a single sample and without reflections,
+    /// refractions and ambient occlusion. This bounds the cost of a render at the expense of quality.
+    pub fn set_ray_budget(&mut self, ray_budget: Option<usize>) {
+        self.ray_budget = ray_budget;
+    }
+
+    /// Trace primary rays in packets of `PACKET_SIZE` (the default) or one by one
+    ///
+    /// Both produce the same image; packets are faster for meshes because neighboring rays share acceleration
+    /// structure lookups. The scalar path is mainly useful for comparing against and for debugging.
+    pub fn set_packet_tracing(&mut self, packet_tracing: bool) {
+        self.packet_tracing = packet_tracing;
+    }
+
+    /// Remember the object that blocked the last shadow ray towards each light and test it first next time
+    ///
+    /// Neighboring points tend to be shadowed by the same object, so most shadow rays of a shadowed area are settled
+    /// by a single intersection test instead of a traversal of the whole scene. The image is the same either way;
+    /// the cache is kept per thread and pays off in scenes with large shadowed areas and many objects or big meshes.
+    /// Disabled by default.
+    pub fn set_shadow_cache(&mut self, shadow_cache: bool) {
+        self.shadow_cache = shadow_cache;
+    }
+
+    /// Show the lit scene (the default) or visualize the geometry, e.g. to check imported meshes and UV layouts
+    ///
+    /// The visualizations ignore lights, materials, fog and the camera's exposure and bloom, and show the background
+    /// as black. `RenderMode::Clay` is lit like the shaded image and only replaces the materials.
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode;
+    }
+
+    /// Total number of rays cast since the renderer was created or `reset_ray_count()` was called
+    pub fn rays_cast(&self) -> usize {
+        self.rays_cast.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_ray_count(&mut self) {
+        self.rays_cast.store(0, Ordering::Relaxed);
+    }
+
+    /// Ray and intersection counters accumulated since the renderer was created or `reset_stats()` was called
+    pub fn stats(&self) -> RenderStats {
+        self.counters.snapshot()
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.counters.reset();
+    }
+
+    fn is_budget_exhausted(&self) -> bool {
+        match self.ray_budget {
+            Some(ray_budget) => self.rays_cast() >= ray_budget,
+            None => false,
+        }
+    }
+
+    /// The camera's bloom, if it applies in the current render mode
+    fn bloom(&self) -> Option<&Bloom> {
+        self.scene.camera.bloom.as_ref().filter(|_| self.render_mode.is_lit())
+    }
+
+    /// Quantize a render to 8 bits, sRGB encoded unless the render mode shows data
+    fn to_rgb_image(&self, img: &HdrImage) -> RgbImage {
+        if self.render_mode.is_lit() {
+            img.to_rgb_image()
+        } else {
+            img.to_linear_rgb_image()
         }
     }
 
@@ -27,79 +469,935 @@ impl Renderer {
         self.render_rect(0, 0, size.0, size.1)
     }
 
+    /// Render the scene to a new image with an alpha channel
+    ///
+    /// If the scene has a transparent background, alpha is the fraction of each pixel's samples that hit an object
+    /// and the background doesn't contribute to the color; otherwise the image is fully opaque.
+    pub fn render_rgba(&self) -> RgbaImage {
+        let size = self.scene.camera.resolution;
+        self.render_rect_rgba(0, 0, size.0, size.1)
+    }
+
+    /// Render the scene to a new image and additionally return `levels` downscaled versions (1/2, 1/4, ... scale)
+    ///
+    /// The downscaled images are computed from the unquantized colors and are useful as thumbnails
+    pub fn render_with_pyramid(&self, levels: usize) -> (RgbImage, Vec<RgbImage>) {
+        let size = self.scene.camera.resolution;
+        let img = self.render_rect_hdr(0, 0, size.0, size.1);
+        let pyramid = img.pyramid(levels).iter()
+            .map(|level| self.to_rgb_image(level))
+            .collect();
+
+        (self.to_rgb_image(&img), pyramid)
+    }
+
+    /// Render the scene with the given quality, e.g. `RenderSettings::preview(4)` for a quick look while editing
+    ///
+    /// Reduced resolutions are rendered smaller and upscaled to the camera's resolution, so the returned image always
+    /// has the full size. The render is counted towards the ray count, but not the stats, of this renderer.
+    pub fn render_with_settings(&self, settings: &RenderSettings) -> RgbImage {
+        if *settings == RenderSettings::full() {
+            return self.render();
+        }
+
+        let (w, h) = self.scene.camera.resolution;
+        let renderer = self.with_scene(settings.apply(&self.scene));
+        let (preview_w, preview_h) = renderer.scene.camera.resolution;
+        let img = renderer.render_rect_hdr(0, 0, preview_w, preview_h);
+        self.rays_cast.fetch_add(renderer.rays_cast(), Ordering::Relaxed);
+        self.to_rgb_image(&img.resize(w, h))
+    }
+
+    /// New renderer for another scene with the same options as this one
+    fn with_scene(&self, scene: Scene) -> Renderer {
+        Renderer {
+            ray_budget: self.ray_budget.map(|ray_budget| ray_budget.saturating_sub(self.rays_cast())),
+            packet_tracing: self.packet_tracing,
+            shadow_cache: self.shadow_cache,
+            render_mode: self.render_mode,
+            material_override: self.material_override.clone(),
+            material_remaps: self.material_remaps.clone(),
+            ..Renderer::new(scene)
+        }
+    }
+
+    /// Render the frames `frames` of the scene's animation, one image per frame
+    ///
+    /// Frame `n` shows the scene at time `n / fps` seconds. Frames are rendered lazily as the iterator is advanced,
+    /// with the options of this renderer; their rays count towards its ray count and budget, but not its stats. Fails
+    /// if `fps` isn't a positive number.
+    pub fn render_sequence(&self, frames: Range<usize>, fps: Float) -> Result<impl Iterator<Item=RgbImage> + '_, RaytracerError> {
+        if !(fps > 0.0 && fps.is_finite()) {
+            return Err(RaytracerError::InvalidConfiguration(format!("Can't render a sequence at {} frames per second", fps)));
+        }
+
+        Ok(frames.map(move |frame| {
+            let renderer = self.with_scene(self.scene.at_time(frame as Float / fps));
+            let img = renderer.render();
+            self.rays_cast.fetch_add(renderer.rays_cast(), Ordering::Relaxed);
+            img
+        }))
+    }
+
+    /// Render a crop window of the full frame
+    ///
+    /// The camera mapping is that of the full frame, i.e. rendering all regions of `Region::strips()` and assembling
+    /// them with `composite_regions()` yields the same image as `render()`. The region is clipped to the frame.
+    pub fn render_region(&self, region: Region) -> RenderedRegion {
+        let region = region.clip(self.scene.camera.resolution);
+        let image = self.render_rect(region.x, region.y, region.width, region.height);
+        RenderedRegion {
+            region,
+            image,
+        }
+    }
+
+    /// Render the frame tile by tile in the given order, calling `on_tile` as soon as each tile is done
+    ///
+    /// This lets frontends show the image while it is being rendered. Returns the assembled frame, which is the same
+    /// as `render()` yields.
+    pub fn render_tiles(&self, tile_size: usize, order: TileOrder, mut on_tile: impl FnMut(&RenderedRegion)) -> RgbImage {
+        let resolution = self.scene.camera.resolution;
+        let tiles: Vec<_> = Region::tiles(resolution, tile_size, order).into_iter()
+            .map(|region| {
+                let rendered_region = self.render_region(region);
+                on_tile(&rendered_region);
+                rendered_region
+            })
+            .collect();
+        composite_regions(resolution, &tiles)
+    }
+
+    /// Render a job, e.g. one sent to a web worker, and return its pixels as raw bytes
+    ///
+    /// Each pixel is the average of the job's samples in linear color without the camera's exposure and bloom,
+    /// encoded as described for `JOB_BYTES_PER_PIXEL`, row by row. Combine the results of all jobs of a frame with
+    /// `merge_jobs()`. Fails if the job's region or samples lie outside the scene's frame.
+    pub fn render_job(&self, job: &RenderJob) -> Result<Vec<u8>, RaytracerError> {
+        let region = job.region;
+        if region.clip(self.scene.camera.resolution) != region {
+            return Err(RaytracerError::InvalidConfiguration(format!("Region {:?} of the job lies outside the frame", region)));
+        }
+        if job.samples.is_empty() || job.samples.end > self.scene.aa_samples {
+            return Err(RaytracerError::InvalidConfiguration(format!("Samples {:?} of the job don't lie within the scene's {} samples", job.samples, self.scene.aa_samples)));
+        }
+
+        /// Renders all samples again even if rendering panics
+        struct JobGuard(Option<JobSamples>);
+
+        impl Drop for JobGuard {
+            fn drop(&mut self) {
+                JOB_SAMPLES.with(|job_samples| job_samples.set(self.0));
+            }
+        }
+
+        let job_samples = JobSamples { start: job.samples.start, end: job.samples.end, seed: job.seed };
+        let _guard = JobGuard(JOB_SAMPLES.with(|current| current.replace(Some(job_samples))));
+        let img = self.render_rect_internal(region.x, region.y, region.width, region.height, RectOutputs::default());
+        Ok(job::encode_pixels(img.data()))
+    }
+
+    /// Assemble the full frame from the results of `render_job()`, applying the camera's exposure and bloom
+    ///
+    /// Pixels covered by several jobs are weighted by their number of samples. Results are combined in a fixed order,
+    /// so the frame doesn't depend on the order of `results`. Parts of the frame without results stay black.
+    pub fn merge_jobs(&self, results: &[(RenderJob, Vec<u8>)]) -> Result<HdrImage, RaytracerError> {
+        let (w, h) = self.scene.camera.resolution;
+
+        let mut results: Vec<_> = results.iter().collect();
+        results.sort_by_key(|(job, _)| (job.samples.start, job.samples.end, job.seed, job.region.y, job.region.x));
+
+        let mut decoded = Vec::with_capacity(results.len());
+        let mut sample_counts = vec![0; w * h];
+        for (job, bytes) in results {
+            let region = job.region;
+            if region.clip((w, h)) != region {
+                return Err(RaytracerError::InvalidConfiguration(format!("Region {:?} of the job lies outside the frame", region)));
+            }
+            let pixels = job::decode_pixels(bytes, region.width * region.height)
+                .ok_or_else(|| RaytracerError::InvalidConfiguration(format!("Result of the job for region {:?} has {} bytes, expected {}", region, bytes.len(), region.width * region.height * JOB_BYTES_PER_PIXEL)))?;
+            for y in region.y..(region.y + region.height) {
+                for x in region.x..(region.x + region.width) {
+                    sample_counts[y * w + x] += job.sample_count();
+                }
+            }
+            decoded.push((job, pixels));
+        }
+
+        let mut img = HdrImage::new(w, h);
+        for (job, pixels) in decoded {
+            let region = job.region;
+            for y_local in 0..region.height {
+                for x_local in 0..region.width {
+                    let (x, y) = (region.x + x_local, region.y + y_local);
+                    let weight = job.sample_count() as Float / sample_counts[y * w + x] as Float;
+                    img.put_pixel(x, y, img.get_pixel(x, y) + pixels[y_local * region.width + x_local] * weight);
+                }
+            }
+        }
+
+        if self.render_mode.is_lit() {
+            let exposure_factor = self.scene.camera.exposure_factor();
+            for y in 0..h {
+                for x in 0..w {
+                    img.put_pixel(x, y, img.get_pixel(x, y) * exposure_factor);
+                }
+            }
+        }
+        Ok(match self.bloom() {
+            Some(bloom) => bloom.apply(&img),
+            None => img,
+        })
+    }
+
+    /// Render the pixels `x..(x + w)` × `y..(y + h)` of the full frame; the returned image is indexed locally
     pub fn render_rect(&self, x: usize, y: usize, w: usize, h: usize) -> RgbImage {
-        let camera = &self.scene.camera;
-        let full_image_size = camera.resolution;
+        self.to_rgb_image(&self.render_rect_hdr(x, y, w, h))
+    }
+
+    /// Like `render_rect()`, but with an alpha channel as described for `render_rgba()`
+    ///
+    /// The camera's bloom isn't applied, as it would have to spread into the transparent parts.
+    pub fn render_rect_rgba(&self, x: usize, y: usize, w: usize, h: usize) -> RgbaImage {
+        let mut alpha = vec![0.0; w * h];
+        let img = self.render_rect_internal(x, y, w, h, RectOutputs { alpha: Some(&mut alpha), ..RectOutputs::default() });
+        if self.render_mode.is_lit() {
+            img.to_rgba_image(&alpha)
+        } else {
+            img.to_linear_rgba_image(&alpha)
+        }
+    }
+
+    /// Like `render_rect()`, but return the accumulated colors without quantizing them
+    pub fn render_rect_hdr(&self, x: usize, y: usize, w: usize, h: usize) -> HdrImage {
+        let bloom = match self.bloom() {
+            Some(bloom) => bloom,
+            None => return self.render_rect_internal(x, y, w, h, RectOutputs::default()),
+        };
+
+        // Also render the surrounding pixels that glow into the rect, so that rects match the full frame
+        let resolution = self.scene.camera.resolution;
+        let margin = bloom.margin();
+        let x_start = x.saturating_sub(margin);
+        let y_start = y.saturating_sub(margin);
+        let x_end = (x + w + margin).min(resolution.0).max(x + w);
+        let y_end = (y + h + margin).min(resolution.1).max(y + h);
+        let img = self.render_rect_internal(x_start, y_start, x_end - x_start, y_end - y_start, RectOutputs::default());
+        bloom.apply(&img).crop(x - x_start, y - y_start, w, h)
+    }
+
+    /// Like `render_rect_hdr()`, but additionally return the normals, depths and albedos seen by the camera
+    ///
+    /// The camera's bloom isn't applied, so that the image can be denoised first.
+    pub fn render_rect_with_aovs(&self, x: usize, y: usize, w: usize, h: usize) -> (HdrImage, Aovs) {
+        let mut aovs = Aovs::new(w, h);
+        let img = self.render_rect_internal(x, y, w, h, RectOutputs { aovs: Some(&mut aovs), ..RectOutputs::default() });
+        (img, aovs)
+    }
+
+    /// Render the contribution of each light group separately, see `Light::group()`
+    ///
+    /// Returns the beauty image with all lights and one image per group, in the order in which the groups first
+    /// appear in `Scene::lights`. Lights without a group form a group of their own, named `None`. Light that doesn't
+    /// come from any light source, e.g. the ambient light and the background, is only part of the beauty image.
+    /// Except where colors are clamped, the beauty image is the sum of the group images plus that light.
+    ///
+    /// The camera's bloom isn't applied, as it isn't additive.
+    pub fn render_light_groups(&self) -> (HdrImage, Vec<(Option<String>, HdrImage)>) {
+        /// Removes the mask again even if rendering panics
+        struct MaskGuard;
+
+        impl Drop for MaskGuard {
+            fn drop(&mut self) {
+                LIGHT_MASK.with(|mask| mask.borrow_mut().take());
+            }
+        }
+
+        let size = self.scene.camera.resolution;
+        let beauty = self.render_rect_internal(0, 0, size.0, size.1, RectOutputs::default());
+
+        let mut groups: Vec<Option<&str>> = Vec::new();
+        for light in &self.scene.lights {
+            if !groups.contains(&light.group()) {
+                groups.push(light.group());
+            }
+        }
+
+        let group_images = groups.into_iter()
+            .map(|group| {
+                let mask = self.scene.lights.iter().map(|light| light.group() == group).collect();
+                LIGHT_MASK.with(|light_mask| *light_mask.borrow_mut() = Some(mask));
+                let _guard = MaskGuard;
+                let img = self.render_rect_internal(0, 0, size.0, size.1, RectOutputs::default());
+                (group.map(String::from), img)
+            })
+            .collect();
+
+        (beauty, group_images)
+    }
+
+    /// Render the view of one eye of the camera's stereo setup, like `render_rect_hdr()` renders the center view
+    pub fn render_eye_rect_hdr(&self, eye: Eye, x: usize, y: usize, w: usize, h: usize) -> HdrImage {
+        /// Restores the previous eye even if rendering panics
+        struct EyeGuard(Option<Eye>);
+
+        impl Drop for EyeGuard {
+            fn drop(&mut self) {
+                EYE.with(|eye| eye.set(self.0));
+            }
+        }
+
+        let _guard = EyeGuard(EYE.with(|current| current.replace(Some(eye))));
+        self.render_rect_hdr(x, y, w, h)
+    }
+
+    /// Size of the side by side image of both eyes, twice as wide as the camera's resolution
+    pub fn side_by_side_resolution(&self) -> (usize, usize) {
+        let (w, h) = self.scene.camera.resolution;
+        (2 * w, h)
+    }
+
+    /// Render the pixels `x..(x + w)` × `y..(y + h)` of the side by side image, whose left half shows the left eye's
+    /// view and whose right half the right eye's
+    ///
+    /// Like with `render_rect()`, any split of the image into rects (e.g. `Region::tiles()` of
+    /// `side_by_side_resolution()`) can be rendered independently and on several threads, so both eyes are rendered
+    /// with the same tiles and threads.
+    pub fn render_side_by_side_rect(&self, x: usize, y: usize, w: usize, h: usize) -> HdrImage {
+        let eye_width = self.scene.camera.resolution.0;
+        let mut img = HdrImage::new(w, h);
+        for (eye, eye_x) in [(Eye::Left, 0), (Eye::Right, eye_width)] {
+            let x_start = x.max(eye_x);
+            let x_end = (x + w).min(eye_x + eye_width);
+            if x_start >= x_end {
+                continue;
+            }
+
+            let part = self.render_eye_rect_hdr(eye, x_start - eye_x, y, x_end - x_start, h);
+            for y_local in 0..h {
+                for x_part in 0..part.width() {
+                    img.put_pixel(x_start - x + x_part, y_local, part.get_pixel(x_part, y_local));
+                }
+            }
+        }
+        img
+    }
+
+    /// Render both eyes of the camera's stereo setup side by side into one image, e.g. for VR headsets
+    pub fn render_side_by_side(&self) -> RgbImage {
+        let (w, h) = self.side_by_side_resolution();
+        self.to_rgb_image(&self.render_side_by_side_rect(0, 0, w, h))
+    }
+
+    /// Render the views of the left and the right eye of the camera's stereo setup as separate images
+    pub fn render_stereo(&self) -> (RgbImage, RgbImage) {
+        let (w, h) = self.scene.camera.resolution;
+        (
+            self.to_rgb_image(&self.render_eye_rect_hdr(Eye::Left, 0, 0, w, h)),
+            self.to_rgb_image(&self.render_eye_rect_hdr(Eye::Right, 0, 0, w, h)),
+        )
+    }
+
+    /// Render a red-cyan anaglyph of the camera's stereo setup for viewing with colored glasses
+    pub fn render_anaglyph(&self, anaglyph: &Anaglyph) -> RgbImage {
+        let (left, right) = self.render_stereo();
+        anaglyph.apply(&left, &right)
+    }
+
+    /// Render the scene and smooth the noise of stochastic effects with `denoiser`, see `Denoiser`
+    pub fn render_denoised(&self, denoiser: &Denoiser) -> RgbImage {
+        let size = self.scene.camera.resolution;
+        let (img, aovs) = self.render_rect_with_aovs(0, 0, size.0, size.1);
+        let img = denoiser.denoise(&img, &aovs);
+        match self.bloom() {
+            Some(bloom) => bloom.apply(&img).to_rgb_image(),
+            None => self.to_rgb_image(&img),
+        }
+    }
+
+    /// Like `render_rect_hdr()`, but additionally return a quality AOV
+    ///
+    /// Pixels of the AOV are white if the corresponding pixel was rendered at full quality and black if it was
+    /// rendered at reduced quality because the ray budget was exhausted. The camera's bloom isn't applied.
+    pub fn render_rect_with_quality(&self, x: usize, y: usize, w: usize, h: usize) -> (HdrImage, RgbImage) {
+        let mut quality = RgbImage::new(w, h);
+        let img = self.render_rect_internal(x, y, w, h, RectOutputs { quality: Some(&mut quality), ..RectOutputs::default() });
+        (img, quality)
+    }
+
+    /// Render the full frame pixel by pixel, measuring the work each pixel takes
+    ///
+    /// Primary rays are traced one by one, as packets would mix the costs of neighbouring pixels, so the total time
+    /// is higher than that of `render()`. Nothing else differs and the statistics are updated as usual.
+    pub fn render_costs(&self) -> CostBuffer {
+        let (w, h) = self.scene.camera.resolution;
+        let mut costs = CostBuffer::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let cost = heatmap::record(|| {
+                    self.primary_samples(x, y, self.is_budget_exhausted(), |ray, _, path| {
+                        self.cast_primary_ray(&ray, path);
+                    });
+                });
+                costs.put_pixel(x, y, cost);
+            }
+        }
+        costs
+    }
+
+    /// Render heatmaps of all `CostMetric`s with the same settings, see `render_costs()`
+    pub fn render_heatmaps(&self, heatmap: &Heatmap) -> Heatmaps {
+        let costs = self.render_costs();
+        Heatmaps {
+            triangle_tests: heatmap.render(&costs, CostMetric::TriangleTests),
+            node_traversals: heatmap.render(&costs, CostMetric::NodeTraversals),
+            secondary_rays: heatmap.render(&costs, CostMetric::SecondaryRays),
+            time: heatmap.render(&costs, CostMetric::Time),
+        }
+    }
 
-        let mut img = RgbImage::new(w, h);
+    /// Render a rect, filling in the requested `outputs` along the way
+    fn render_rect_internal(&self, x: usize, y: usize, w: usize, h: usize, mut outputs: RectOutputs) -> HdrImage {
+        let mut img = HdrImage::new(w, h);
+        // Jobs are exposed once they are merged
+        let exposure_factor = if self.render_mode.is_lit() && JOB_SAMPLES.with(Cell::get).is_none() {
+            self.scene.camera.exposure_factor()
+        } else {
+            1.0
+        };
 
-        let aa_samples = self.scene.aa_samples;
-        let mut rng = thread_rng();
-        let distr = Normal::new(0.0f32, 0.4).unwrap();
+        let mut rays = ScratchVec::take(&PRIMARY_RAYS, PACKET_SIZE * self.scene.aa_samples);
+        let mut pixels = ScratchVec::take(&PRIMARY_PIXELS, rays.capacity());
 
-        // Iterate over the entire image pixel by pixel
+        // Iterate over the entire image in runs of `PACKET_SIZE` pixels whose primary rays are traced together
         for y_local in 0..h {
-            for x_local in 0..w {
-                let mut color_sum = Color::black();
-                for _ in 0..aa_samples {
-                    // This is not a true bivariate normal distribution but it's good enough
-                    let sample_x = (x + x_local) as f32 + rng.sample::<f32, _>(distr);
-                    let sample_y = (y + y_local) as f32 + rng.sample::<f32, _>(distr);
-                    // Construct ray
-                    let camera_ray = Ray::from_screen_coordinates(sample_x, sample_y, full_image_size.0, full_image_size.1, camera.fov);
-                    let world_ray = camera_ray.transform(&camera.transformation_matrix);
-                    // Assign appropriate color
-                    let color = self.cast_ray(&world_ray, 0);
-
-                    color_sum += color;
+            for x_start in (0..w).step_by(PACKET_SIZE) {
+                let x_end = (x_start + PACKET_SIZE).min(w);
+
+                rays.clear();
+                pixels.clear();
+                for x_local in x_start..x_end {
+                    let reduced_quality = self.is_budget_exhausted();
+                    if let Some(quality) = &mut outputs.quality {
+                        let value = if reduced_quality { 0 } else { 255 };
+                        quality.put_pixel(x_local, y_local, &(value, value, value));
+                    }
+
+                    self.primary_samples(x + x_local, y + y_local, reduced_quality, |ray, vignetting_factor, path| {
+                        rays.push((ray, path));
+                        pixels.push((x_local, vignetting_factor));
+                    });
+                }
+
+                // Average the samples of each pixel
+                let mut color_sums = [Color::black(); PACKET_SIZE];
+                let mut sample_counts = [0; PACKET_SIZE];
+                let mut alpha_sums = [0.0; PACKET_SIZE];
+                let mut hit_counts = [0; PACKET_SIZE];
+                let mut surface_sums = [(Vector3::zero(), 0.0, Color::black()); PACKET_SIZE];
+                self.cast_primary_rays(&rays, |index, sample| {
+                    let (x_local, vignetting_factor) = pixels[index];
+                    let i = x_local - x_start;
+                    color_sums[i] += sample.color * vignetting_factor;
+                    sample_counts[i] += 1;
+                    alpha_sums[i] += sample.alpha;
+                    if let Some(surface) = sample.surface {
+                        hit_counts[i] += 1;
+                        let (normal_sum, depth_sum, albedo_sum) = &mut surface_sums[i];
+                        *normal_sum += surface.normal;
+                        *depth_sum += surface.depth;
+                        *albedo_sum += surface.albedo;
+                    }
+                });
+                for x_local in x_start..x_end {
+                    let i = x_local - x_start;
+                    let sample_count = sample_counts[i] as Float;
+                    // Assign pixel value
+                    img.put_pixel(x_local, y_local, color_sums[i] / sample_count * exposure_factor);
+                    if let Some(alpha) = &mut outputs.alpha {
+                        alpha[y_local * w + x_local] = if self.scene.transparent_background {
+                            alpha_sums[i] / sample_count
+                        } else {
+                            1.0
+                        };
+                    }
+                    if let (Some(aovs), true) = (&mut outputs.aovs, hit_counts[i] > 0) {
+                        let (normal_sum, depth_sum, albedo_sum) = surface_sums[i];
+                        let hit_count = hit_counts[i] as Float;
+                        let normal = if normal_sum.is_zero() { normal_sum } else { normal_sum.normalize() };
+                        aovs.put_pixel(x_local, y_local, normal, depth_sum / hit_count, albedo_sum / hit_count);
+                    }
                 }
-                let color = color_sum / aa_samples as f32;
-                // Assign pixel value
-                img.put_pixel(x_local, y_local, &color.to_u8());
             }
         }
 
         img
     }
 
-    fn cast_ray(&self, ray: &Ray, depth: u32) -> Color {
-        if depth > self.scene.max_recursion_depth {
+    /// Pass the primary rays of the pixel (`x`, `y`) of the full frame to `f`, with their vignetting factor and path state
+    fn primary_samples(&self, x: usize, y: usize, reduced_quality: bool, mut f: impl FnMut(Ray, Float, PathState)) {
+        let camera = &self.scene.camera;
+        let full_image_size = camera.resolution;
+
+        if reduced_quality {
+            let camera_ray = self.eye_ray(Ray::from_screen_coordinates(x as Float, y as Float, full_image_size.0, full_image_size.1, camera.fov));
+            // Starting at the maximum depth suppresses all secondary rays except for shadow rays
+            let path = PathState { depth: self.scene.max_recursion_depth, ..PathState::primary() };
+            f(camera_ray.transform(&camera.transformation_matrix), camera.vignetting_factor(&camera_ray.direction), path);
+            return;
+        }
+
+        let job_samples = JOB_SAMPLES.with(Cell::get);
+        let mut rng = match job_samples {
+            // Split into parts that are exactly representable as `Float`
+            Some(JobSamples { seed, .. }) if seed != 0 => sampling_rng(&[
+                x as Float, y as Float, self.scene.time,
+                (seed & 0xffff) as Float, (seed >> 16 & 0xffff) as Float, (seed >> 32 & 0xffff) as Float, (seed >> 48) as Float,
+            ]),
+            _ => sampling_rng(&[x as Float, y as Float, self.scene.time]),
+        };
+        let (start, end) = job_samples.map_or((0, self.scene.aa_samples), |job_samples| (job_samples.start, job_samples.end));
+
+        // Samples before `start` are still drawn, so that each sample is the same in every job
+        let stratified = StratifiedSquare::new(self.scene.aa_samples, &mut rng);
+        for index in 0..end {
+            // Stay within the pixel, so that each pixel only depends on its own samples
+            let (offset_x, offset_y) = stratified.sample(index, &mut rng);
+            let sample_x = x as Float + offset_x;
+            let sample_y = y as Float + offset_y;
+            // Pick a random point in time while the shutter is open
+            let time = camera.shutter.as_ref()
+                .map(|shutter| self.scene.time + shutter.open + (shutter.close - shutter.open) * rng.gen::<Float>());
+            // Construct ray
+            let camera_ray = self.eye_ray(Ray::from_screen_coordinates(sample_x, sample_y, full_image_size.0, full_image_size.1, camera.fov))
+                .with_time(time);
+            if index >= start {
+                f(camera_ray.transform(&camera.transformation_matrix_at(time)), camera.vignetting_factor(&camera_ray.direction), PathState::primary());
+            }
+        }
+    }
+
+    /// The camera space ray of the eye being rendered on this thread in place of the center camera's `camera_ray`
+    fn eye_ray(&self, camera_ray: Ray) -> Ray {
+        match (EYE.with(Cell::get), &self.scene.camera.stereo) {
+            (Some(eye), Some(stereo)) => stereo.eye_ray(&camera_ray, eye),
+            _ => camera_ray,
+        }
+    }
+
+    /// The object seen through the center of the pixel (`x`, `y`) of the full frame, e.g. for click-to-select
+    ///
+    /// Only the primary ray is traced, at the scene's time and without counting towards the ray budget or statistics.
+    /// Objects that are invisible to the camera can't be picked.
+    pub fn pick(&self, x: usize, y: usize) -> Option<SegmentHit> {
+        let camera = &self.scene.camera;
+        let camera_ray = Ray::from_screen_coordinates(x as Float, y as Float, camera.resolution.0, camera.resolution.1, camera.fov);
+        let ray = camera_ray.transform(&camera.transformation_matrix);
+        self.scene.closest_hit(&ray, RayType::Primary).map(|(object_index, hit)| SegmentHit {
+            object_index,
+            material_index: self.scene.objects[object_index].material_index,
+            distance: hit.distance,
+            point: hit.point,
+            normal: hit.normal,
+        })
+    }
+
+    /// Draw the lines requested by `overlay` over `img`, which has to be a render of the full frame
+    ///
+    /// The lines are projected onto the image without testing for occlusion, so boxes behind objects are visible too.
+    pub fn draw_overlay(&self, img: &mut RgbImage, overlay: &StructureOverlay) {
+        let camera = &self.scene.camera;
+        assert!((img.width(), img.height()) == camera.resolution, "Image and camera resolution differ");
+
+        let mut projector = LineProjector::new(img, camera, overlay.line_width);
+
+        let mesh = overlay.mesh.and_then(|index| self.scene.objects.get(index)).and_then(|obj| match &obj.shape {
+            Shape::Mesh(mesh) => Some((obj, mesh)),
+            _ => None,
+        });
+        if let Some((obj, mesh)) = mesh {
+            let mut boxes = mesh.structure_boxes(overlay.max_depth);
+            // Draw the deepest levels first so that the coarse subdivisions stay visible on top
+            boxes.sort_by_key(|&(depth, _)| std::cmp::Reverse(depth));
+            for (depth, bounding_box) in boxes {
+                let color = ColorMap::Viridis.color(depth as Float / overlay.max_depth.max(1) as Float);
+                projector.draw_box(&bounding_box, &obj.transformation_matrix, &color);
+            }
+        }
+
+        if overlay.object_bounds {
+            for bounding_box in self.scene.objects.iter().filter_map(|obj| obj.world_bounds()) {
+                projector.draw_box(&bounding_box, &Matrix4::identity(), &(255, 255, 255));
+            }
+        }
+    }
+
+    /// Render the pixel (`x`, `y`) of the full frame and record every ray, hit and light query on the way
+    ///
+    /// This is meant for finding out why a specific pixel looks wrong. The pixel gets the same color as in a regular
+    /// render without bloom, and its rays count towards the ray budget and statistics as usual.
+    pub fn trace_pixel_debug(&self, x: usize, y: usize) -> PixelTrace {
+        let trace = PixelTrace {
+            x,
+            y,
+            ..PixelTrace::default()
+        };
+
+        let mut color = Color::black();
+        let mut trace = pixel_trace::record(trace, || {
+            let mut samples = Vec::new();
+            self.primary_samples(x, y, self.is_budget_exhausted(), |ray, vignetting_factor, path| samples.push((ray, vignetting_factor, path)));
+            let sample_count = samples.len();
+            for (sample, (ray, vignetting_factor, path)) in samples.into_iter().enumerate() {
+                pixel_trace::begin_sample(sample);
+                let sample_color = self.cast_primary_ray(&ray, path).color * vignetting_factor;
+                pixel_trace::record_sample_color(sample_color);
+                color += sample_color;
+            }
+            color = color / sample_count as Float;
+        });
+        // Bloom needs the neighbouring pixels, so only the exposure is applied like in `render_rect_internal()`
+        let exposure_factor = if self.render_mode.is_lit() { self.scene.camera.exposure_factor() } else { 1.0 };
+        trace.color = color * exposure_factor;
+        trace
+    }
+
+    /// Cast primary rays, in packets if packet tracing is enabled
+    fn cast_primary_rays(&self, rays: &[(Ray, PathState)], mut f: impl FnMut(usize, PrimarySample)) {
+        if !self.packet_tracing {
+            for (index, (ray, path)) in rays.iter().enumerate() {
+                f(index, self.cast_primary_ray(ray, *path));
+            }
+            return;
+        }
+
+        for (packet_index, rays) in rays.chunks(PACKET_SIZE).enumerate() {
+            // Lanes after the last ray are never read
+            let packet: [Ray; PACKET_SIZE] = std::array::from_fn(|i| rays[i.min(rays.len() - 1)].0.clone());
+            let debug_data_before = RayDebugData::current();
+            let traced = self.trace_packet(&packet[..rays.len()]);
+            // Every ray of a packet takes part in the whole traversal
+            let total_work = RayDebugData::current().since(&debug_data_before);
+            let work = RayDebugData { kd_tree_lookups: total_work.kd_tree_lookups / rays.len(), ..total_work };
+            for (i, ((ray, path), traced)) in rays.iter().zip(traced).enumerate() {
+                f(packet_index * PACKET_SIZE + i, self.shade_primary(ray, traced, &work, *path));
+            }
+        }
+    }
+
+    /// Like `cast_ray()` for a single primary ray, additionally returning what it hit
+    ///
+    /// Primary rays are never terminated by the depth limits or Russian roulette, so only the handling of the
+    /// background differs from `cast_ray()`.
+    fn cast_primary_ray(&self, ray: &Ray, path: PathState) -> PrimarySample {
+        let debug_data_before = RayDebugData::current();
+        let traced = self.trace(ray, RayType::Primary);
+        let work = RayDebugData::current().since(&debug_data_before);
+        self.shade_primary(ray, traced, &work, path)
+    }
+
+    /// Color of a primary ray given the result of tracing it and the work that took, and what it hit
+    fn shade_primary(&self, ray: &Ray, traced: Option<(&Object, Hit)>, work: &RayDebugData, path: PathState) -> PrimarySample {
+        let surface = traced.as_ref().map(|(obj, hit)| SurfaceSample {
+            normal: hit.normal,
+            depth: hit.distance,
+            albedo: self.material_color(self.material(obj), hit),
+        });
+        if surface.is_none() && self.scene.transparent_background {
+            return PrimarySample { color: Color::black(), alpha: 0.0, surface };
+        }
+        if !self.render_mode.is_lit() {
+            return PrimarySample { color: self.visualize(ray, traced.map(|(_, hit)| hit)), alpha: 1.0, surface };
+        }
+
+        // Shadow catchers let what lies behind them show through, including the transparent background
+        if let Some((obj, hit)) = traced.as_ref().filter(|(obj, _)| self.material(obj).shadow_catcher) {
+            let behind = self.cast_primary_ray(&ray.continued_past(hit), path);
+            let caught = self.catch_shadow(ray, obj, hit, path);
+            let color = caught.composite(behind.color);
+            let color = match &self.scene.fog {
+                Some(fog) => self.apply_fog(ray, color, hit.distance, path.depth, fog),
+                None => color,
+            };
+            return PrimarySample {
+                color: color + self.debug_color(work),
+                alpha: 1.0 - caught.behind_weight() * (1.0 - behind.alpha),
+                surface,
+            };
+        }
+
+        PrimarySample {
+            color: self.shade(ray, RayType::Primary, traced, path) + self.debug_color(work),
+            alpha: 1.0,
+            surface,
+        }
+    }
+
+    /// Color of a primary ray in a render mode that isn't lit
+    fn visualize(&self, ray: &Ray, hit: Option<Hit>) -> Color {
+        let hit = match hit {
+            Some(hit) => hit,
+            None => return Color::black(),
+        };
+
+        match self.render_mode {
+            RenderMode::Shaded | RenderMode::Clay => unreachable!(),
+            RenderMode::Wireframe => {
+                let on_edge = hit.edge_offset.is_some_and(|offset| self.pixel_distance(&hit, offset) < 0.5);
+                if on_edge {
+                    Color::white()
+                } else {
+                    let value = 0.1 + 0.4 * hit.normal.dot(ray.direction).abs();
+                    Color::new(value, value, value)
+                }
+            }
+            RenderMode::Normals => Color::new(hit.normal.x * 0.5 + 0.5, hit.normal.y * 0.5 + 0.5, hit.normal.z * 0.5 + 0.5),
+            RenderMode::TexCoords => Color::new(hit.tex_coords.x.modulo(1.0), hit.tex_coords.y.modulo(1.0), 0.0),
+            RenderMode::AmbientOcclusion => {
+                let value = 1.0 - self.calc_occlusion(ray, &hit, &self.ambient_occlusion_settings());
+                Color::new(value, value, value)
+            }
+        }
+    }
+
+    /// The scene's ambient occlusion settings, or defaults derived from its size for `RenderMode::AmbientOcclusion`
+    fn ambient_occlusion_settings(&self) -> AmbientOcclusion {
+        self.scene.ambient_occlusion.clone().unwrap_or_else(|| AmbientOcclusion {
+            samples: DEFAULT_AO_SAMPLES,
+            radius: self.scene.bounds().map_or(1.0, |bounds| (bounds.max - bounds.min).magnitude() * 0.1),
+        })
+    }
+
+    /// Length in pixels that `offset` along the surface at `hit` covers on the screen
+    fn pixel_distance(&self, hit: &Hit, offset: Vector3<Float>) -> Float {
+        // Express the offset in terms of the steps to the neighbouring pixels if they are known
+        let (dx, dy) = (hit.dpdx, hit.dpdy);
+        let (xx, xy, yy) = (dx.dot(dx), dx.dot(dy), dy.dot(dy));
+        let determinant = xx * yy - xy * xy;
+        if determinant > Float::EPSILON * xx * yy {
+            let (bx, by) = (offset.dot(dx), offset.dot(dy));
+            let steps_x = (yy * bx - xy * by) / determinant;
+            let steps_y = (xx * by - xy * bx) / determinant;
+            return (steps_x * steps_x + steps_y * steps_y).sqrt();
+        }
+
+        // Otherwise use the size of a pixel in the center of the screen at the hit's distance
+        let camera = &self.scene.camera;
+        let pixel_size = hit.distance * 2.0 * float::tan(camera.fov.to_radians() / 2.0) / camera.resolution.1 as Float;
+        offset.magnitude() / pixel_size
+    }
+
+    /// Trace a ray through the scene, counting it towards the ray budget and the statistics
+    fn trace(&self, ray: &Ray, ray_type: RayType) -> Option<(&Object, Hit)> {
+        self.trace_with(ray, ray_type, || self.scene.closest_hit(ray, ray_type))
+    }
+
+    /// Whether nothing blocks `ray` closer than its `max_distance`, for a shadow ray towards light `light_index` or
+    /// towards the environment map if `None`
+    fn is_unoccluded(&self, ray: &Ray, light_index: Option<usize>) -> bool {
+        // Meshes already skip everything beyond the light, but other shapes report their nearest hit regardless
+        let blocks = |hit: &Hit| hit.distance <= ray.max_distance;
+
+        if !self.shadow_cache {
+            return !self.trace(ray, RayType::Shadow).is_some_and(|(_, hit)| blocks(&hit));
+        }
+
+        let slot = light_index.unwrap_or(self.scene.lights.len());
+        let occluder = self.trace_with(ray, RayType::Shadow, || {
+            let cached = SHADOW_CACHE.with(|cache| cache.borrow().get(slot).copied().flatten());
+            let cached_hit = cached.and_then(|index| self.scene.trace_object(index, ray, RayType::Shadow).map(|(_, hit)| (index, hit)));
+            if let Some(hit) = cached_hit.filter(|(_, hit)| blocks(hit)) {
+                return Some(hit);
+            }
+
+            let hit = self.scene.closest_hit(ray, RayType::Shadow).filter(|(_, hit)| blocks(hit));
+            if let Some(&(index, _)) = hit.as_ref() {
+                SHADOW_CACHE.with(|cache| {
+                    let mut cache = cache.borrow_mut();
+                    if cache.len() <= slot {
+                        cache.resize(slot + 1, None);
+                    }
+                    cache[slot] = Some(index);
+                });
+            }
+            hit
+        });
+        occluder.is_none()
+    }
+
+    /// Fraction of the light that arrives along a shadow ray: none if an object blocks it, otherwise what the volumes
+    /// in between let through
+    fn light_transmittance(&self, ray: &Ray, light_index: Option<usize>) -> Float {
+        if self.is_unoccluded(ray, light_index) {
+            self.scene.volume_transmittance(ray)
+        } else {
+            0.0
+        }
+    }
+
+    /// Count a ray traced by `trace` towards the stats, the ray budget and the recordings
+    ///
+    /// `trace` returns the index of the object that was hit, like `Scene::closest_hit()`.
+    fn trace_with(&self, ray: &Ray, ray_type: RayType, trace: impl FnOnce() -> Option<(usize, Hit)>) -> Option<(&Object, Hit)> {
+        self.rays_cast.fetch_add(1, Ordering::Relaxed);
+
+        let debug_data_before = RayDebugData::current();
+        let result = trace();
+        if pixel_trace::is_recording() {
+            let hit = result.as_ref().map(|&(index, ref hit)| (index, self.scene.objects[index].material_index, hit));
+            pixel_trace::record_segment(ray, ray_type, hit);
+        }
+        let work = RayDebugData::current().since(&debug_data_before);
+        self.counters.record_ray(ray_type, work.triangle_tests, work.node_traversals);
+        heatmap::record_ray(ray_type, work.triangle_tests, work.node_traversals);
+
+        result.map(|(index, hit)| (&self.scene.objects[index], hit))
+    }
+
+    /// Like `trace()`, but for a packet of up to `PACKET_SIZE` primary rays
+    fn trace_packet(&self, rays: &[Ray]) -> [Option<(&Object, Hit)>; PACKET_SIZE] {
+        self.rays_cast.fetch_add(rays.len(), Ordering::Relaxed);
+
+        let debug_data_before = RayDebugData::current();
+        let result = self.scene.trace_packet(rays, RayType::Primary);
+        let work = RayDebugData::current().since(&debug_data_before);
+        self.counters.record_rays(RayType::Primary, rays.len(), work.triangle_tests, work.node_traversals);
+        // Primary rays only add their work to the cost, so the packet can be recorded as a whole
+        heatmap::record_ray(RayType::Primary, work.triangle_tests, work.node_traversals);
+
+        result
+    }
+
+    fn cast_ray(&self, ray: &Ray, ray_type: RayType, path: PathState) -> Color {
+        let scene = &self.scene;
+        if self.exceeds_depth_limits(path) {
             return Color::black();
         }
 
-        let base_color = self.scene.trace(ray)
-            .map(|(obj, hit)| self.get_color(ray, obj, &hit, depth))
-            .unwrap_or(self.scene.clear_color);
+        // Terminate rays that contribute little at random and boost the survivors, which keeps the expected color
+        let survival_probability = match &scene.russian_roulette {
+            Some(russian_roulette) if path.depth >= russian_roulette.start_depth.max(1) => russian_roulette.survival_probability(path.throughput),
+            _ => 1.0,
+        };
+        if survival_probability < 1.0 {
+            let mut rng = sampling_rng(&[ray.origin.x, ray.origin.y, ray.origin.z, ray.direction.x, ray.direction.y, ray.direction.z, 2.0]);
+            if rng.gen::<Float>() >= survival_probability {
+                return Color::black();
+            }
+        }
+
+        let debug_data_before = RayDebugData::current();
+        let traced = self.trace(ray, ray_type);
+        let work = RayDebugData::current().since(&debug_data_before);
+        self.shade(ray, ray_type, traced, path) / survival_probability + self.debug_color(&work)
+    }
+
+    /// Whether a path went through more bounces than the scene allows
+    fn exceeds_depth_limits(&self, path: PathState) -> bool {
+        let scene = &self.scene;
+        path.depth > scene.max_recursion_depth
+            || scene.max_reflection_depth.is_some_and(|max_depth| path.reflection_depth > max_depth)
+            || scene.max_refraction_depth.is_some_and(|max_depth| path.refraction_depth > max_depth)
+    }
 
-        let debug_data = ray.debug_data.borrow();
-        let kd_tree_lookups_value = debug_data.kd_tree_lookups.min(100) as f32 * (1.0 / 100.0);
-        let debug_color = Color::new(kd_tree_lookups_value, 0.0, 0.0);
+    /// Color of a ray of type `ray_type` given the result of tracing it
+    fn shade(&self, ray: &Ray, ray_type: RayType, traced: Option<(&Object, Hit)>, path: PathState) -> Color {
+        let (base_color, distance) = match traced {
+            Some((obj, hit)) if self.material(obj).shadow_catcher => {
+                let behind = self.cast_ray(&ray.continued_past(&hit), ray_type, path);
+                (self.catch_shadow(ray, obj, &hit, path).composite(behind), hit.distance)
+            }
+            Some((obj, hit)) if matches!(obj.shape, Shape::Volume(_)) => (self.shade_volume(ray, ray_type, obj, path), hit.distance),
+            Some((obj, hit)) => (self.get_color(ray, obj, &hit, path), hit.distance),
+            None => {
+                let background_color = if is_unlit_active() { self.scene.background_color(ray) } else { Color::black() };
+                (background_color, Float::INFINITY)
+            }
+        };
 
-        base_color + debug_color
+        match &self.scene.fog {
+            Some(fog) => self.apply_fog(ray, base_color, distance, path.depth, fog),
+            None => base_color,
+        }
     }
 
-    fn get_color(&self, ray: &Ray, obj: &Object, hit: &Hit, depth: u32) -> Color {
-        let material = &self.scene.materials[obj.material_index];
+    /// Visualization of the acceleration structure lookups of a ray, black unless a mesh has debugging enabled
+    fn debug_color(&self, work: &RayDebugData) -> Color {
+        let kd_tree_lookups_value = work.kd_tree_lookups.min(100) as Float * (1.0 / 100.0);
+        Color::new(kd_tree_lookups_value, 0.0, 0.0)
+    }
+
+    /// Material that an object is shaded with, taking the render mode and the overrides into account
+    fn material(&self, obj: &Object) -> &Material {
+        if self.render_mode == RenderMode::Clay {
+            return &self.clay_material;
+        }
+        self.material_override.as_ref()
+            .or_else(|| self.material_remaps.get(&obj.material_index))
+            .unwrap_or(&self.scene.materials[obj.material_index])
+    }
+
+    /// Color of `material` at `hit` with the scene's decals over it, which clay surfaces don't get
+    fn material_color(&self, material: &Material, hit: &Hit) -> Color {
+        let color = material.color.filtered_color(hit);
+        match self.render_mode {
+            RenderMode::Clay => color,
+            _ => self.scene.apply_decals(hit, color),
+        }
+    }
+
+    fn get_color(&self, ray: &Ray, obj: &Object, hit: &Hit, path: PathState) -> Color {
+        let material = self.material(obj);
+        // Decided by the geometric normal, as the shading normal may point away from the viewer even on the front
+        let entering = hit.is_front_face(&ray.direction);
+
+        // Replace the normal with the shading normal
+        let bumped_hit;
+        let hit = match &material.bump_map {
+            Some(bump_map) => {
+                bumped_hit = Hit {
+                    normal: bump_map.perturb_normal(hit),
+                    ..hit.clone()
+                };
+                &bumped_hit
+            }
+            None => hit,
+        };
 
         let is_refractive = material.transparency > 0.0;
         let is_reflective = material.reflectivity > 0.0 || is_refractive;
 
-        let diffuse_color = self.shade_diffuse(obj, hit);
+        let diffuse_color = self.shade_diffuse(ray, obj, hit, path.depth);
+
+        // Only the ratio of the refractive indices matters for refraction and the Fresnel equations
+        let relative_index = material.refractive_index / path.media.outside_index(obj.material_index, entering);
+
+        let k_r = if is_refractive {
+            self.calc_fresnel_reflectivity(&hit.normal, &ray.direction, relative_index)
+        } else {
+            0.0
+        };
 
         let reflective_color = if is_reflective {
-            let reflection_ray = Ray::create_reflection(&hit.normal, &ray.direction, &hit.point);
-            self.cast_ray(&reflection_ray, depth + 1)
+            let reflection_ray = Ray::create_reflection(&hit.normal, &ray.direction, &hit.point)
+                .with_time(ray.time)
+                .with_reflected_differentials(ray, hit);
+            let weight = material.reflectivity + material.transparency * k_r;
+            self.cast_ray(&reflection_ray, RayType::Reflection, path.reflected(weight))
         } else {
             Color::black()
         };
 
         let refractive_color = if is_refractive {
-            let k_r = self.calc_fresnel_reflectivity(&hit.normal, &ray.direction, material.refractive_index);
-
-            let transmission_ray = Ray::create_transmission(&hit.normal, &ray.direction, &hit.point, material.refractive_index);
+            let transmission_ray = Ray::create_transmission(&hit.normal, &ray.direction, &hit.point, relative_index)
+                .map(|transmission_ray| transmission_ray
+                    .with_time(ray.time)
+                    .with_refracted_differentials(ray, hit, relative_index));
+            let weight = material.transparency * (1.0 - k_r);
+            let media = path.media.crossed(obj.material_index, material.refractive_index, entering);
             let refractive_color = transmission_ray
-                .map(|transmission_ray| self.cast_ray(&transmission_ray, depth + 1))
-                .unwrap_or_else(|| Color::black());
+                .map(|transmission_ray| self.cast_ray(&transmission_ray, RayType::Refraction, path.refracted(weight, media)))
+                .unwrap_or_else(Color::black);
 
             k_r * reflective_color + (1.0 - k_r) * refractive_color
         } else {
@@ -109,39 +1407,314 @@ impl Renderer {
         (diffuse_color * (1.0 - material.reflectivity - material.transparency) + reflective_color * material.reflectivity + refractive_color * material.transparency).clamp()
     }
 
-    fn shade_diffuse(&self, obj: &Object, hit: &Hit) -> Color {
-        let material = &self.scene.materials[obj.material_index];
-        let material_color = material.color.color(&hit.tex_coords);
+    /// Shadows and reflections that a shadow catcher puts over what lies behind it
+    fn catch_shadow(&self, ray: &Ray, obj: &Object, hit: &Hit, path: PathState) -> CaughtShadow {
+        let material = self.material(obj);
 
-        let mut color = material_color * self.scene.ambient_light_color;
+        let ambient_factor = match &self.scene.ambient_occlusion {
+            Some(ambient_occlusion) if path.depth == 0 => 1.0 - self.calc_occlusion(ray, hit, ambient_occlusion),
+            _ => 1.0,
+        };
+
+        // Share of the light that would arrive without any objects and still arrives with them
+        let mut lit_power = 0.0;
+        let mut total_power = 0.0;
+        let mut rng = sampling_rng(&[hit.point.x, hit.point.y, hit.point.z, ray.direction.x, ray.direction.y, ray.direction.z, 1.0]);
+        for (light_index, light_weight) in self.scene.light_sampling.select(&self.scene.lights, &hit.point, &mut rng) {
+            if !is_light_active(light_index) {
+                continue;
+            }
+            let light = &self.scene.lights[light_index];
+
+            let shadow_samples = light.shadow_samples(&hit.point, &mut rng);
+            let sample_weight = light_weight / shadow_samples.len() as Float;
+            for (to_light, light_distance) in shadow_samples {
+                let power = hit.normal.dot(to_light).max(0.0) * light.intensity_at(&hit.point) * light.color_at(&hit.point).luminance() * sample_weight;
+                if power <= 0.0 {
+                    continue;
+                }
+                total_power += power;
+
+                let shadow_ray = Ray::new(hit.point + hit.normal * 1e-5, to_light).with_time(ray.time).with_max_distance(light_distance);
+                let transmittance = self.light_transmittance(&shadow_ray, Some(light_index));
+                pixel_trace::record_light_query(Some(light_index), transmittance > 0.0, Color::black());
+                lit_power += power * transmittance;
+            }
+        }
+        let transmittance = if total_power > 0.0 { lit_power / total_power } else { 1.0 } * ambient_factor;
+
+        // Only objects are reflected, the background is already seen through the catcher
+        let reflected_path = path.reflected(material.reflectivity);
+        let reflection = if material.reflectivity > 0.0 && !self.exceeds_depth_limits(reflected_path) {
+            let reflection_ray = Ray::create_reflection(&hit.normal, &ray.direction, &hit.point)
+                .with_time(ray.time)
+                .with_reflected_differentials(ray, hit);
+            self.trace(&reflection_ray, RayType::Reflection)
+                .map(|traced| self.shade(&reflection_ray, RayType::Reflection, Some(traced), reflected_path))
+        } else {
+            None
+        };
+
+        CaughtShadow {
+            transmittance,
+            reflection_weight: if reflection.is_some() { material.reflectivity } else { 0.0 },
+            reflection: reflection.unwrap_or_else(Color::black),
+        }
+    }
 
-        // Sum contributions by all light sources
-        for light in self.scene.lights.iter() {
-            // Vector that points towards the light
-            let to_light = light.direction_from(&hit.point);
+    fn shade_diffuse(&self, ray: &Ray, obj: &Object, hit: &Hit, depth: u32) -> Color {
+        let material = self.material(obj);
+        let material_color = self.material_color(material, hit);
+        let to_viewer = -ray.direction;
 
-            // Cast ray towards the light to check whether the point lies in the shadow
-            let shadow_ray = Ray::new(hit.point + hit.normal * 1e-5, to_light);
-            let shadow_hit = self.scene.trace(&shadow_ray);
-            // Is there any object in the direction of the light that is closer than the light source?
-            let in_light = match shadow_hit {
-                Some((_, shadow_hit)) => shadow_hit.distance > light.distance_at(&hit.point),
-                None => true,
+        // Thin translucent surfaces have no inside, so they are shaded from the side they are seen from
+        let flipped_hit;
+        let hit = if material.translucency.is_some() && hit.normal.dot(to_viewer) < 0.0 {
+            flipped_hit = Hit {
+                normal: -hit.normal,
+                ..hit.clone()
             };
+            &flipped_hit
+        } else {
+            hit
+        };
+
+        // Ambient occlusion is only calculated for primary hits because it is barely noticeable in reflections
+        let ambient_factor = match &self.scene.ambient_occlusion {
+            Some(ambient_occlusion) if depth == 0 => 1.0 - self.calc_occlusion(ray, hit, ambient_occlusion),
+            _ => 1.0,
+        };
 
-            if in_light {
-                // Calculate color using Lambert's Cosine Law
-                let light_power = hit.normal.dot(to_light).max(0.0) * light.intensity_at(&hit.point);
-                let reflection_factor = material.albedo / f32::consts::PI;
-                color += material_color * light.color() * light_power * reflection_factor;
+        let base_ambient_light_color = if is_unlit_active() { self.scene.ambient_light_color } else { Color::black() };
+        let ambient_light_color = self.scene.lights.iter().enumerate()
+            .filter(|&(light_index, _)| is_light_active(light_index))
+            .fold(base_ambient_light_color, |ambient_light_color, (_, light)| ambient_light_color + light.ambient_color(&hit.normal));
+        let mut color = material_color * ambient_light_color * ambient_factor;
+
+        // Lights that are out of range are never selected, so that no shadow ray is wasted on them
+        // The trailing constant decorrelates the light selection from the ambient occlusion samples in deterministic mode
+        let mut rng = sampling_rng(&[hit.point.x, hit.point.y, hit.point.z, ray.direction.x, ray.direction.y, ray.direction.z, 1.0]);
+        let selected_lights = self.scene.light_sampling.select(&self.scene.lights, &hit.point, &mut rng);
+
+        // Sum contributions by the selected light sources
+        for (light_index, light_weight) in selected_lights {
+            if !is_light_active(light_index) {
+                continue;
             }
+            let light = &self.scene.lights[light_index];
+
+            // Lights with an extent are sampled at several points, which are averaged to get soft shadows
+            let shadow_samples = light.shadow_samples(&hit.point, &mut rng);
+            let sample_weight = light_weight / shadow_samples.len() as Float;
+
+            for (to_light, light_distance) in shadow_samples {
+                // Light behind a translucent surface shines through it
+                let n_dot_l = hit.normal.dot(to_light);
+                let translucency = material.translucency.as_ref().filter(|_| n_dot_l < 0.0);
+                let side = if translucency.is_some() { -hit.normal } else { hit.normal };
+
+                // Cast ray towards the light to check whether the point lies in the shadow
+                // Only objects closer than the light source can block it
+                let shadow_ray = Ray::new(hit.point + side * 1e-5, to_light).with_time(ray.time).with_max_distance(light_distance);
+                let transmittance = self.light_transmittance(&shadow_ray, Some(light_index));
+                let in_light = transmittance > 0.0;
+
+                let contribution = match (in_light, translucency) {
+                    (false, _) => Color::black(),
+                    (true, Some(translucency)) => {
+                        let light_power = -n_dot_l * light.intensity_at(&hit.point);
+                        translucency.btdf() * light.color_at(&hit.point) * (light_power * sample_weight * transmittance)
+                    }
+                    (true, None) => {
+                        // Calculate color using Lambert's Cosine Law
+                        let light_power = n_dot_l.max(0.0) * light.intensity_at(&hit.point);
+                        let reflection_factor = material.brdf(material_color, hit, &hit.normal, &to_light, &to_viewer);
+                        reflection_factor * light.color_at(&hit.point) * (light_power * sample_weight * transmittance)
+                    }
+                };
+                pixel_trace::record_light_query(Some(light_index), in_light, contribution);
+                color += contribution;
+            }
+        }
+
+        if let (Background::Environment(environment), true) = (&self.scene.background, is_unlit_active()) {
+            color += self.sample_environment(ray, hit, material, material_color, environment, &mut rng);
         }
 
         // Ensure that color components are between 0.0 and 1.0
         color.clamp()
     }
 
-    fn calc_fresnel_reflectivity(&self, normal: &Vector3<f32>, incident: &Vector3<f32>, refractive_index: f32) -> f32 {
+    /// Estimate the light reflected from an environment map by importance sampling it, with a shadow ray per sample
+    fn sample_environment(&self, ray: &Ray, hit: &Hit, material: &Material, material_color: Color, environment: &EnvironmentMap, rng: &mut SamplingRng) -> Color {
+        let mut color = Color::black();
+        if environment.light_samples == 0 {
+            return color;
+        }
+
+        let to_viewer = -ray.direction;
+        for _ in 0..environment.light_samples {
+            let (to_light, radiance, pdf) = match environment.sample(rng) {
+                Some(sample) => sample,
+                None => continue,
+            };
+
+            let cos = hit.normal.dot(to_light);
+            if cos <= 0.0 {
+                continue;
+            }
+
+            // The environment is infinitely far away, so any hit blocks it
+            let shadow_ray = Ray::new(hit.point + hit.normal * 1e-5, to_light).with_time(ray.time);
+            let transmittance = self.light_transmittance(&shadow_ray, None);
+            let in_light = transmittance > 0.0;
+            let contribution = if in_light {
+                let reflection_factor = material.brdf(material_color, hit, &hit.normal, &to_light, &to_viewer);
+                reflection_factor * radiance * (cos * transmittance / pdf) / environment.light_samples as Float
+            } else {
+                Color::black()
+            };
+            pixel_trace::record_light_query(None, in_light, contribution);
+            color += contribution;
+        }
+
+        color
+    }
+
+    /// Light scattered towards the viewer by a volume, over the attenuated color of what lies behind it
+    fn shade_volume(&self, ray: &Ray, ray_type: RayType, obj: &Object, path: PathState) -> Color {
+        let (volume, object_ray, transformation_matrix) = match obj.volume_ray(ray) {
+            Some(volume_ray) => volume_ray,
+            None => return Color::black(),
+        };
+        let (start, end) = match volume.segment(&object_ray) {
+            Some(segment) => segment,
+            None => return Color::black(),
+        };
+
+        // The trailing constant decorrelates the sample positions from those of the fog
+        let mut rng = sampling_rng(&[ray.origin.x, ray.origin.y, ray.origin.z, ray.direction.x, ray.direction.y, ray.direction.z, 3.0]);
+        // Isotropic phase function
+        let phase = 1.0 / (4.0 * consts::PI);
+        let ambient_light_color = if is_unlit_active() { self.scene.ambient_light_color } else { Color::black() };
+
+        let mut color = Color::black();
+        let mut transmittance = 1.0;
+        // Jitter the sample positions to turn banding into noise
+        for (t, step_length) in volume.steps(start, end, rng.gen()) {
+            let object_point = object_ray.origin + object_ray.direction * t;
+            let extinction = volume.extinction_at(&object_point);
+            if extinction <= 0.0 {
+                continue;
+            }
+
+            let point = transformation_matrix.transform_point(object_point);
+            let scattering = extinction * step_length * transmittance;
+            color += volume.albedo * ambient_light_color * scattering;
+
+            for (light_index, light) in self.scene.lights.iter().enumerate() {
+                if !light.reaches(&point) || !is_light_active(light_index) {
+                    continue;
+                }
+
+                // The shadow ray passes through the volume itself, which is what shades its far side
+                let to_light = light.direction_from(&point);
+                let shadow_ray = Ray::new(point, to_light).with_time(ray.time).with_max_distance(light.distance_at(&point));
+                let light_transmittance = self.light_transmittance(&shadow_ray, Some(light_index));
+                let contribution = volume.albedo * light.color_at(&point) * (light.intensity_at(&point) * scattering * phase * light_transmittance);
+                pixel_trace::record_light_query(Some(light_index), light_transmittance > 0.0, contribution);
+                color += contribution;
+            }
+
+            transmittance *= float::exp(-extinction * step_length);
+            // Nothing behind a nearly opaque section is visible
+            if transmittance < 1e-4 {
+                return color;
+            }
+        }
+
+        let exit = transformation_matrix.transform_point(object_ray.origin + object_ray.direction * end);
+        let behind_ray = Ray {
+            origin: exit + ray.direction * 1e-4,
+            max_distance: ray.max_distance - (exit - ray.origin).magnitude(),
+            ..ray.clone()
+        };
+        color + self.cast_ray(&behind_ray, ray_type, path) * transmittance
+    }
+
+    /// Attenuate the color seen along a ray towards the fog color and add light scattered by the fog
+    fn apply_fog(&self, ray: &Ray, color: Color, distance: Float, depth: u32, fog: &Fog) -> Color {
+        let transmittance = fog.transmittance(ray, distance);
+        let mut color = color * transmittance;
+        if is_unlit_active() {
+            color += fog.color * (1.0 - transmittance);
+        }
+
+        // Single scattering is only ray marched for primary rays as it requires a shadow ray per step and light
+        if fog.scattering_steps > 0 && depth == 0 {
+            let mut rng = sampling_rng(&[ray.origin.x, ray.origin.y, ray.origin.z, ray.direction.x, ray.direction.y, ray.direction.z]);
+
+            let march_distance = distance.min(fog.scattering_distance);
+            let step_size = march_distance / fog.scattering_steps as Float;
+            // Isotropic phase function
+            let phase = 1.0 / (4.0 * consts::PI);
+
+            for step in 0..fog.scattering_steps {
+                // Jitter the sample positions to turn banding into noise
+                let t = (step as Float + rng.gen::<Float>()) * step_size;
+                if t < fog.start_distance {
+                    continue;
+                }
+                let point = ray.origin + ray.direction * t;
+                let scattering = fog.density_at(&point) * fog.transmittance(ray, t) * step_size * phase;
+
+                for (light_index, light) in self.scene.lights.iter().enumerate() {
+                    if !light.reaches(&point) || !is_light_active(light_index) {
+                        continue;
+                    }
+
+                    let to_light = light.direction_from(&point);
+                    let shadow_ray = Ray::new(point, to_light).with_time(ray.time).with_max_distance(light.distance_at(&point));
+                    let transmittance = self.light_transmittance(&shadow_ray, Some(light_index));
+                    let in_light = transmittance > 0.0;
+
+                    let contribution = if in_light {
+                        fog.color * light.color_at(&point) * (light.intensity_at(&point) * scattering * transmittance)
+                    } else {
+                        Color::black()
+                    };
+                    pixel_trace::record_light_query(Some(light_index), in_light, contribution);
+                    color += contribution;
+                }
+            }
+        }
+
+        color
+    }
+
+    /// Calculate the fraction of the hemisphere above the hit point that is blocked by nearby geometry
+    fn calc_occlusion(&self, ray: &Ray, hit: &Hit, ambient_occlusion: &AmbientOcclusion) -> Float {
+        if ambient_occlusion.samples == 0 {
+            return 0.0;
+        }
+
+        let mut rng = sampling_rng(&[hit.point.x, hit.point.y, hit.point.z, ray.direction.x, ray.direction.y, ray.direction.z]);
+
+        let occluded_count = (0..ambient_occlusion.samples)
+            .filter(|_| {
+                let direction = sample_hemisphere_cosine(&hit.normal, &mut rng);
+                let occlusion_ray = Ray::new(hit.point + hit.normal * 1e-5, direction).with_time(ray.time).with_max_distance(ambient_occlusion.radius);
+                match self.trace(&occlusion_ray, RayType::Occlusion) {
+                    Some((_, occlusion_hit)) => occlusion_hit.distance < ambient_occlusion.radius,
+                    None => false,
+                }
+            })
+            .count();
+
+        occluded_count as Float / ambient_occlusion.samples as Float
+    }
+
+    fn calc_fresnel_reflectivity(&self, normal: &Vector3<Float>, incident: &Vector3<Float>, refractive_index: Float) -> Float {
         let eta_t;
         let eta_i;
         let mut i_dot_n = incident.dot(*normal);
@@ -155,15 +1728,31 @@ impl Renderer {
             eta_i = refractive_index;
         }
 
-        let sin_theta_t = eta_i / eta_t * (1.0 - i_dot_n.powi(2)).sqrt();
+        let sin_theta_t = eta_i / eta_t * (1.0 - float::powi(i_dot_n, 2)).sqrt();
 
         if sin_theta_t >= 1.0 {
             1.0
         } else {
-            let cos_theta_t = (1.0 - sin_theta_t.powi(2)).sqrt();
+            let cos_theta_t = (1.0 - float::powi(sin_theta_t, 2)).sqrt();
             let r_s = (eta_t * i_dot_n - eta_i * cos_theta_t) / (eta_t * i_dot_n + eta_i * cos_theta_t);
             let r_p = (eta_i * i_dot_n - eta_t * cos_theta_t) / (eta_i * i_dot_n + eta_t * cos_theta_t);
-            0.5 * (r_s.powi(2) + r_p.powi(2))
+            0.5 * (float::powi(r_s, 2) + float::powi(r_p, 2))
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::ReferenceScene;
+
+    #[test]
+    fn normals_are_written_linearly() {
+        let mut renderer = Renderer::new(ReferenceScene::LambertSphere.scene());
+        renderer.set_render_mode(RenderMode::Normals);
+        let (w, h) = renderer.scene().camera.resolution;
+        // The center of the sphere faces the camera, its normal (0, 0, 1) maps to (0.5, 0.5, 1), which would be about
+        // 188 in sRGB; the samples of the pixel are slightly off the center
+        let (r, g, b) = renderer.render().get_pixel(w / 2, h / 2);
+        assert!(r.abs_diff(128) <= 4 && g.abs_diff(128) <= 4 && b == 255, "{:?}", (r, g, b));
+    }
+}
@@ -1,73 +1,297 @@
 
-use serde::{Serialize, Deserialize};
-use cgmath::{Matrix4, SquareMatrix, Vector3, Euler, Deg, Point3};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::fmt;
+use std::sync::Arc;
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, Visitor, SeqAccess, MapAccess};
+use serde::ser::SerializeMap;
+use cgmath::{Matrix4, SquareMatrix, Vector2, Vector3, Point3, InnerSpace, VectorSpace, MetricSpace, EuclideanSpace, Zero, Transform};
 
 use crate::color::Color;
-use crate::ray::{Ray, Hit};
-use crate::lights::Light;
-use crate::material::Material;
+use crate::ray::{Ray, RayDifferentials, Hit, Interval};
+use crate::lights::{Light, LightSampling};
+use crate::environment::EnvironmentMap;
+use crate::sky::Sky;
+use crate::material::{Material, Coloration, Parameter};
 use crate::primitives::{Plane, Sphere};
 use crate::mesh::Mesh;
+use crate::heightfield::Heightfield;
+use crate::volume::Volume;
+use crate::custom_shape::RegisteredShape;
+use crate::hit_cache::HitCache;
+use crate::animation::{Interpolate, Track};
+use crate::math_util::{deserialize_normalized, euler_rotation_matrix, orthonormal_basis, float, Float, consts};
+use crate::stats::{BuildStats, RayType};
+use crate::packet::{RayPacket, PACKET_SIZE};
+use crate::diagnostics::{Diagnostic, DiagnosticKind, Location, Severity};
+use crate::asset_loader::{self, AssetLoader};
+use crate::aabb::AABB;
+use crate::top_level::TopLevelBvh;
+use crate::post_process::Bloom;
+use crate::error::RaytracerError;
+
+/// Invert a matrix, falling back to the zero matrix so that invalid scenes can still be loaded and reported by
+/// `Scene::validate()` instead of panicking
+fn invert_or_zero(matrix: Matrix4<Float>) -> Matrix4<Float> {
+    matrix.invert().unwrap_or_else(Matrix4::zero)
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Transformation {
-    translation: Vector3<f32>,
-    rotation: Vector3<f32>,
-    scale: f32,
+    translation: Vector3<Float>,
+    rotation: Vector3<Float>,
+    scale: Float,
 }
 
 impl Transformation {
-    fn to_matrix(&self) -> Matrix4<f32> {
+    /// Create a transformation from a translation, euler angles in degrees and a uniform scale factor
+    pub fn new(translation: Vector3<Float>, rotation: Vector3<Float>, scale: Float) -> Transformation {
+        Transformation { translation, rotation, scale }
+    }
+
+    pub fn translation(&self) -> Vector3<Float> {
+        self.translation
+    }
+
+    /// Euler angles in degrees
+    pub fn rotation(&self) -> Vector3<Float> {
+        self.rotation
+    }
+
+    pub fn scale(&self) -> Float {
+        self.scale
+    }
+
+    fn to_matrix(&self) -> Matrix4<Float> {
         let translation_matrix = Matrix4::from_translation(self.translation);
-        let rotation_matrix = Matrix4::from(Euler {
-            x: Deg(self.rotation.x),
-            y: Deg(self.rotation.y),
-            z: Deg(self.rotation.z),
-        });
+        let rotation_matrix = euler_rotation_matrix(self.rotation);
         let scale_matrix = Matrix4::from_scale(self.scale);
 
-        let transform_matrix = translation_matrix * rotation_matrix * scale_matrix;
+        translation_matrix * rotation_matrix * scale_matrix
+    }
+}
 
-        transform_matrix
+impl Interpolate for Transformation {
+    fn interpolate(&self, other: &Transformation, t: Float) -> Transformation {
+        Transformation {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation.lerp(other.rotation, t),
+            scale: self.scale.interpolate(&other.scale, t),
+        }
     }
 }
 
+/// Reference to an entry of `Scene::materials`, either by position or by name
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum MaterialReference {
+    Index(usize),
+    Name(String),
+}
+
 #[derive(Serialize, Deserialize)]
 struct DeserializableObject {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub shape: Shape,
-    pub material_index: usize,
+    #[serde(alias = "material_index")]
+    pub material: MaterialReference,
     pub transform: Transformation,
+    #[serde(default)]
+    pub animation: Option<Track<Transformation>>,
+    #[serde(default)]
+    pub visibility: Visibility,
+}
+
+impl DeserializableObject {
+    fn into_object(self, material_index: usize) -> Object {
+        let transform_matrix = self.transform.to_matrix();
+        let inv_transform_matrix = invert_or_zero(transform_matrix);
+        Object {
+            name: self.name,
+            shape: self.shape,
+            material_index,
+            transformation: self.transform,
+            transformation_matrix: transform_matrix,
+            inv_transformation_matrix: inv_transform_matrix,
+            animation: self.animation,
+            visibility: self.visibility,
+            group: None,
+            parent_matrix: Matrix4::identity(),
+        }
+    }
+}
+
+/// Entry of the object list in a scene file, which is either an object or a group with a list of further entries
+#[derive(Serialize, Deserialize)]
+struct DeserializableNode {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shape: Option<Shape>,
+    #[serde(default, alias = "material_index", skip_serializing_if = "Option::is_none")]
+    pub material: Option<MaterialReference>,
+    pub transform: Transformation,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub animation: Option<Track<Transformation>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<Visibility>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub children: Option<Vec<DeserializableNode>>,
+}
+
+impl From<DeserializableObject> for DeserializableNode {
+    fn from(d: DeserializableObject) -> DeserializableNode {
+        DeserializableNode {
+            name: d.name,
+            shape: Some(d.shape),
+            material: Some(d.material),
+            transform: d.transform,
+            animation: d.animation,
+            visibility: Some(d.visibility).filter(|visibility| *visibility != Visibility::default()),
+            children: None,
+        }
+    }
+}
+
+impl DeserializableNode {
+    /// Append the objects and groups of this node and all nodes below it in depth-first order
+    fn flatten(self, parent: Option<usize>, resolve_material: &dyn Fn(&MaterialReference) -> Result<usize, String>, objects: &mut Vec<Object>, groups: &mut Vec<Group>) -> Result<(), String> {
+        match self.children {
+            Some(children) => {
+                let name = self.name.as_deref().unwrap_or("<unnamed>");
+                if self.shape.is_some() || self.material.is_some() {
+                    return Err(format!("Group \"{}\" can't have a shape or material, add an object to its children instead", name));
+                }
+                if self.animation.is_some() {
+                    return Err(format!("Group \"{}\" can't be animated, only the objects in it", name));
+                }
+                if self.visibility.is_some() {
+                    return Err(format!("Group \"{}\" can't have visibility flags, only the objects in it", name));
+                }
+
+                let index = groups.len();
+                groups.push(Group {
+                    name: self.name,
+                    transformation: self.transform,
+                    parent,
+                });
+                for child in children {
+                    child.flatten(Some(index), resolve_material, objects, groups)?;
+                }
+            }
+            None => {
+                let (shape, material) = match (self.shape, self.material) {
+                    (Some(shape), Some(material)) => (shape, material),
+                    _ => return Err("Objects need a shape and a material, groups a list of children".to_string()),
+                };
+                let material_index = resolve_material(&material)?;
+                let mut object = DeserializableObject {
+                    name: self.name,
+                    shape,
+                    material,
+                    transform: self.transform,
+                    animation: self.animation,
+                    visibility: self.visibility.unwrap_or_default(),
+                }.into_object(material_index);
+                object.group = parent;
+                objects.push(object);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl From<Object> for DeserializableObject {
     fn from(o: Object) -> DeserializableObject {
         DeserializableObject {
+            name: o.name,
             shape: o.shape,
-            material_index: o.material_index,
+            material: MaterialReference::Index(o.material_index),
             transform: o.transformation,
+            animation: o.animation,
+            visibility: o.visibility,
         }
     }
 }
 
-impl From<DeserializableObject> for Object {
-    fn from(d: DeserializableObject) -> Object {
-        let transform_matrix = d.transform.to_matrix();
-        let inv_transform_matrix = transform_matrix.invert().unwrap();
-        Object {
-            shape: d.shape,
-            material_index: d.material_index,
-            transformation: d.transform,
-            transformation_matrix: transform_matrix,
-            inv_transformation_matrix: inv_transform_matrix,
+impl TryFrom<DeserializableObject> for Object {
+    type Error = RaytracerError;
+
+    fn try_from(d: DeserializableObject) -> Result<Object, RaytracerError> {
+        match d.material {
+            MaterialReference::Index(index) => Ok(d.into_object(index)),
+            MaterialReference::Name(ref name) => Err(RaytracerError::InvalidScene(format!("Material name \"{}\" can only be resolved as part of a scene", name))),
         }
     }
 }
 
+/// Which kinds of rays see an object, e.g. to hide a shadow caster from the camera
+///
+/// Both objects and materials have visibility flags; an object is only hit by the rays that both allow, see
+/// `Scene::is_visible()`.
+///
+/// Fields that are missing in the scene file default to `true`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Visibility {
+    /// Whether primary rays hit the object
+    pub visible_to_camera: bool,
+    /// Whether the object blocks shadow and ambient occlusion rays
+    pub casts_shadows: bool,
+    /// Whether reflected and refracted rays hit the object
+    pub visible_in_reflections: bool,
+}
+
+impl Default for Visibility {
+    fn default() -> Visibility {
+        Visibility {
+            visible_to_camera: true,
+            casts_shadows: true,
+            visible_in_reflections: true,
+        }
+    }
+}
+
+impl Visibility {
+    /// Whether rays of the given type hit the object
+    pub fn includes(&self, ray_type: RayType) -> bool {
+        match ray_type {
+            RayType::Primary => self.visible_to_camera,
+            RayType::Shadow | RayType::Occlusion => self.casts_shadows,
+            RayType::Reflection | RayType::Refraction => self.visible_in_reflections,
+        }
+    }
+}
+
+/// A set of objects and nested groups that are moved together, e.g. the parts of a car
+///
+/// Groups are listed in the scene file like objects, with a list of `children` instead of a shape and material.
+#[derive(Clone)]
+pub struct Group {
+    /// Used to look the group up with `Scene::group_index()`
+    pub name: Option<String>,
+    /// Relative to the parent group; call `Scene::update_group_matrices()` after changing this
+    pub transformation: Transformation,
+    /// Index into `Scene::groups`, `None` for groups at the top level
+    pub parent: Option<usize>,
+}
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Serialize, Deserialize)]
 pub enum Shape {
     Plane(Plane),
     Sphere(Sphere),
     Mesh(Mesh),
+    Heightfield(Heightfield),
+    /// Smoke or clouds, which attenuate and scatter light instead of having a surface
+    Volume(Volume),
+    /// A shape registered with `register_shape()`
+    Custom(RegisteredShape),
 }
 
 impl Shape {
@@ -76,42 +300,211 @@ impl Shape {
             Shape::Plane(plane) => plane.intersect(ray),
             Shape::Sphere(sphere) => sphere.intersect(ray),
             Shape::Mesh(mesh) => mesh.intersect(ray),
+            Shape::Heightfield(heightfield) => heightfield.intersect(ray),
+            Shape::Volume(volume) => volume.intersect(ray),
+            Shape::Custom(custom) => custom.shape().intersect(ray),
+        }
+    }
+
+    /// All sections of the ray inside the shape, in the order along the ray
+    pub fn intersect_interval(&self, ray: &Ray) -> Vec<Interval> {
+        match self {
+            Shape::Plane(plane) => plane.intersect_interval(ray),
+            Shape::Sphere(sphere) => sphere.intersect_interval(ray),
+            Shape::Mesh(mesh) => mesh.intersect_interval(ray),
+            Shape::Heightfield(heightfield) => heightfield.intersect_interval(ray),
+            Shape::Volume(volume) => volume.intersect_interval(ray),
+            Shape::Custom(custom) => custom.shape().intersect_interval(ray),
+        }
+    }
+
+    /// Object space bounding box, `None` for infinite planes and unbounded custom shapes
+    pub fn bounding_box(&self) -> Option<AABB> {
+        match self {
+            Shape::Plane(plane) => plane.bounding_box(),
+            Shape::Sphere(sphere) => Some(sphere.bounding_box()),
+            Shape::Mesh(mesh) => Some(mesh.bounding_box().clone()),
+            Shape::Heightfield(heightfield) => Some(heightfield.bounding_box()),
+            Shape::Volume(volume) => Some(volume.bounding_box()),
+            Shape::Custom(custom) => custom.shape().bounding_box(),
         }
     }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
-#[serde(from = "DeserializableObject")]
+#[serde(try_from = "DeserializableObject")]
 #[serde(into = "DeserializableObject")]
 pub struct Object {
+    /// Used to look the object up with `Scene::object_index()`
+    pub name: Option<String>,
     pub shape: Shape,
     pub material_index: usize,
+    /// Call `set_transformation()` to change this, which keeps the matrices below consistent
     pub transformation: Transformation,
-    pub transformation_matrix: Matrix4<f32>,
-    pub inv_transformation_matrix: Matrix4<f32>,
+    /// Object-to-world matrix cached from `transformation` and the enclosing groups
+    pub transformation_matrix: Matrix4<Float>,
+    pub inv_transformation_matrix: Matrix4<Float>,
+    /// Keyframes that replace `transformation` when the scene is evaluated with `Scene::at_time()`
+    pub animation: Option<Track<Transformation>>,
+    pub visibility: Visibility,
+    /// Index into `Scene::groups`, `None` for objects at the top level
+    ///
+    /// `transformation` and `animation` are relative to the group. Call `Scene::update_group_matrices()` after
+    /// changing this.
+    pub group: Option<usize>,
+    /// Object-to-world matrix of the group the object belongs to, included in `transformation_matrix`
+    parent_matrix: Matrix4<Float>,
 }
 
 impl Object {
+    pub fn new(shape: Shape, material_index: usize, transformation: Transformation) -> Object {
+        DeserializableObject {
+            name: None,
+            shape,
+            material: MaterialReference::Index(material_index),
+            transform: transformation,
+            animation: None,
+            visibility: Visibility::default(),
+        }.into_object(material_index)
+    }
+
+    /// Get the object-to-world and world-to-object matrices at a point in time
+    fn matrices_at(&self, time: Option<Float>) -> (Matrix4<Float>, Matrix4<Float>) {
+        let animated_transformation = time
+            .and_then(|time| self.animation.as_ref().and_then(|track| track.sample(time)));
+
+        match animated_transformation {
+            Some(transformation) => {
+                let matrix = self.parent_matrix * transformation.to_matrix();
+                (matrix, invert_or_zero(matrix))
+            }
+            None => (self.transformation_matrix, self.inv_transformation_matrix),
+        }
+    }
+
+    /// World space bounding box of the object in its static transformation, `None` if the object is unbounded
+    /// (infinite planes) or empty (meshes without triangles)
+    pub fn world_bounds(&self) -> Option<AABB> {
+        self.shape.bounding_box()
+            .filter(|bounding_box| !bounding_box.is_empty())
+            .map(|bounding_box| bounding_box.transform(&self.transformation_matrix))
+    }
+
+    /// Set the transformation and update the cached matrices
+    pub fn set_transformation(&mut self, transformation: Transformation) {
+        self.transformation_matrix = self.parent_matrix * transformation.to_matrix();
+        self.inv_transformation_matrix = invert_or_zero(self.transformation_matrix);
+        self.transformation = transformation;
+    }
+
+    /// Place the object in a group with the given object-to-world matrix and update the cached matrices
+    fn set_parent_matrix(&mut self, parent_matrix: Matrix4<Float>) {
+        self.parent_matrix = parent_matrix;
+        self.set_transformation(self.transformation.clone());
+    }
+
     pub fn intersect(&self, ray: &Ray) -> Option<(&Object, Hit)> {
+        // Rays with a time intersect animated objects at their position at that time (motion blur)
+        let (transformation_matrix, inv_transformation_matrix) = self.matrices_at(ray.time);
+
         // Transform ray origin and direction into object space
-        let object_ray = ray.transform(&self.inv_transformation_matrix);
+        let object_ray = ray.transform(&inv_transformation_matrix);
         let object_hit = self.shape.intersect(&object_ray);
         // Transform the hit point back to world space
         let world_hit = object_hit.map(|hit| {
-            hit.transform(&self.transformation_matrix, &ray.origin)
+            let mut world_hit = hit.transform(&transformation_matrix, &ray.origin);
+            if let Some(differentials) = &ray.differentials {
+                world_hit.compute_differentials(differentials);
+            }
+            world_hit
         });
 
         world_hit.map(|hit| (self, hit))
     }
+
+    /// The volume of a `Shape::Volume` object together with `ray` in object space and the object-to-world matrix at
+    /// the ray's point in time
+    pub(crate) fn volume_ray(&self, ray: &Ray) -> Option<(&Volume, Ray, Matrix4<Float>)> {
+        match &self.shape {
+            Shape::Volume(volume) => {
+                let (transformation_matrix, inv_transformation_matrix) = self.matrices_at(ray.time);
+                Some((volume, ray.transform(&inv_transformation_matrix), transformation_matrix))
+            }
+            _ => None,
+        }
+    }
+
+    /// All sections of the ray inside the object, with world space hits, see `Shape::intersect_interval()`
+    pub fn intersect_interval(&self, ray: &Ray) -> Vec<Interval> {
+        let (transformation_matrix, inv_transformation_matrix) = self.matrices_at(ray.time);
+
+        let object_ray = ray.transform(&inv_transformation_matrix);
+        self.shape.intersect_interval(&object_ray).iter()
+            .map(|interval| interval.transform(&transformation_matrix, &ray.origin))
+            .collect()
+    }
+
+    /// Intersect up to `PACKET_SIZE` rays at once, only meshes actually trace them as a packet
+    pub fn intersect_packet(&self, rays: &[Ray]) -> [Option<(&Object, Hit)>; PACKET_SIZE] {
+        let mut hits: [Option<(&Object, Hit)>; PACKET_SIZE] = Default::default();
+
+        // The rays can only share the transformation if they are at the same point in time
+        let mesh = match &self.shape {
+            Shape::Mesh(mesh) if rays.iter().all(|ray| ray.time == rays[0].time) => mesh,
+            _ => {
+                for (hit, ray) in hits.iter_mut().zip(rays) {
+                    *hit = self.intersect(ray);
+                }
+                return hits;
+            }
+        };
+        let (transformation_matrix, inv_transformation_matrix) = self.matrices_at(rays[0].time);
+
+        // The object space rays serve both the box test and the mesh; lanes after the last ray are never read
+        let object_rays: [Ray; PACKET_SIZE] = std::array::from_fn(|i| rays[i.min(rays.len() - 1)].transform(&inv_transformation_matrix));
+        let object_rays = &object_rays[..rays.len()];
+
+        // Reject the whole packet before traversing the mesh if possible
+        if !RayPacket::new(object_rays).intersects_box(mesh.bounding_box()).contains(&true) {
+            return hits;
+        }
+
+        let object_hits = mesh.intersect_packet(object_rays);
+
+        for ((hit, ray), object_hit) in hits.iter_mut().zip(rays).zip(object_hits) {
+            *hit = object_hit.map(|hit| {
+                let mut world_hit = hit.transform(&transformation_matrix, &ray.origin);
+                if let Some(differentials) = &ray.differentials {
+                    world_hit.compute_differentials(differentials);
+                }
+                (self, world_hit)
+            });
+        }
+        hits
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct DeserializableCamera {
     pub resolution: (usize, usize),
-    pub fov: f32,
-    pub position: Point3<f32>,
-    pub direction: Vector3<f32>,
-    pub up: Vector3<f32>,
+    pub fov: Float,
+    pub position: Point3<Float>,
+    pub direction: Vector3<Float>,
+    pub up: Vector3<Float>,
+    #[serde(default)]
+    pub roll: Float,
+    #[serde(default)]
+    pub animation: Option<Track<CameraPose>>,
+    #[serde(default)]
+    pub shutter: Option<Shutter>,
+    #[serde(default)]
+    pub vignetting: Option<Vignetting>,
+    #[serde(default)]
+    pub exposure: Float,
+    #[serde(default)]
+    pub bloom: Option<Bloom>,
+    #[serde(default)]
+    pub stereo: Option<Stereo>,
 }
 
 impl From<Camera> for DeserializableCamera {
@@ -122,55 +515,1542 @@ impl From<Camera> for DeserializableCamera {
             position: o.position,
             direction: o.direction,
             up: o.up,
+            roll: o.roll,
+            animation: o.animation,
+            shutter: o.shutter,
+            vignetting: o.vignetting,
+            exposure: o.exposure,
+            bloom: o.bloom,
+            stereo: o.stereo,
         }
     }
 }
 
-impl From<DeserializableCamera> for Camera {
-    fn from(d: DeserializableCamera) -> Camera {
-        let transformation_matrix = Matrix4::look_at_dir(d.position, d.direction, d.up).invert().unwrap();
-        Camera {
-            resolution: d.resolution,
+impl TryFrom<DeserializableCamera> for Camera {
+    type Error = RaytracerError;
+
+    fn try_from(d: DeserializableCamera) -> Result<Camera, RaytracerError> {
+        let pose = CameraPose {
             fov: d.fov,
             position: d.position,
             direction: d.direction,
             up: d.up,
+            roll: d.roll,
+        };
+        let (direction, up) = pose.orthonormal_basis()?;
+        let transformation_matrix = pose.camera_to_world()?;
+        if let Some(animation) = &d.animation {
+            for keyframe in animation.keyframes() {
+                keyframe.value.orthonormal_basis()
+                    .map_err(|err| RaytracerError::InvalidConfiguration(format!("{} in camera keyframe at {} s", err, keyframe.time)))?;
+            }
+        }
+
+        Ok(Camera {
+            resolution: d.resolution,
+            fov: d.fov,
+            position: d.position,
+            direction,
+            up,
+            roll: d.roll,
             transformation_matrix,
+            animation: d.animation,
+            shutter: d.shutter,
+            vignetting: d.vignetting,
+            exposure: d.exposure,
+            bloom: d.bloom,
+            stereo: d.stereo,
+        })
+    }
+}
+
+/// The interval during which the shutter is open, relative to the scene time
+///
+/// Rays are distributed uniformly over this interval, so that animated objects and cameras are motion blurred
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Shutter {
+    pub open: Float,
+    pub close: Float,
+}
+
+/// The animatable properties of a camera
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CameraPose {
+    pub fov: Float,
+    pub position: Point3<Float>,
+    pub direction: Vector3<Float>,
+    /// Only needs to point roughly upwards, it is made orthogonal to the direction
+    pub up: Vector3<Float>,
+    /// Rotation around the viewing direction in degrees, counterclockwise as seen from behind the camera (so the image
+    /// turns clockwise)
+    #[serde(default)]
+    pub roll: Float,
+}
+
+impl CameraPose {
+    /// Normalized direction and the part of the up vector orthogonal to it, normalized as well, before applying `roll`
+    ///
+    /// Fails if the two vectors don't determine an orientation, i.e. if one of them is zero or not finite or if they
+    /// are parallel.
+    pub fn orthonormal_basis(&self) -> Result<(Vector3<Float>, Vector3<Float>), RaytracerError> {
+        let is_finite = |v: &Vector3<Float>| v.x.is_finite() && v.y.is_finite() && v.z.is_finite();
+        let format = |v: &Vector3<Float>| format!("({}, {}, {})", v.x, v.y, v.z);
+        if !is_finite(&self.direction) || self.direction.magnitude2() == 0.0 {
+            return Err(RaytracerError::InvalidConfiguration(format!("Camera direction {} is not a valid direction", format(&self.direction))));
+        }
+        if !is_finite(&self.up) || self.up.magnitude2() == 0.0 {
+            return Err(RaytracerError::InvalidConfiguration(format!("Camera up vector {} is not a valid direction", format(&self.up))));
+        }
+
+        let direction = self.direction.normalize();
+        let right = direction.cross(self.up.normalize());
+        // Nearly parallel vectors leave the orientation to rounding errors
+        if right.magnitude2() < 1e-8 {
+            return Err(RaytracerError::InvalidConfiguration(format!(
+                "Camera up vector {} is parallel to the direction {}",
+                format(&self.up),
+                format(&self.direction),
+            )));
+        }
+        Ok((direction, right.normalize().cross(direction)))
+    }
+
+    /// Camera-to-world matrix of this pose
+    pub fn camera_to_world(&self) -> Result<Matrix4<Float>, RaytracerError> {
+        let (direction, up) = self.orthonormal_basis()?;
+        let right = direction.cross(up);
+
+        let (sin_roll, cos_roll) = (float::sin(self.roll.to_radians()), float::cos(self.roll.to_radians()));
+        let rolled_right = right * cos_roll + up * sin_roll;
+        let rolled_up = up * cos_roll - right * sin_roll;
+
+        // The camera looks along its negative Z axis
+        Ok(Matrix4::from_cols(
+            rolled_right.extend(0.0),
+            rolled_up.extend(0.0),
+            (-direction).extend(0.0),
+            self.position.to_homogeneous(),
+        ))
+    }
+}
+
+impl Interpolate for CameraPose {
+    fn interpolate(&self, other: &CameraPose, t: Float) -> CameraPose {
+        CameraPose {
+            fov: self.fov.interpolate(&other.fov, t),
+            position: self.position + (other.position - self.position) * t,
+            direction: self.direction.lerp(other.direction, t).normalize(),
+            up: self.up.lerp(other.up, t).normalize(),
+            roll: self.roll.interpolate(&other.roll, t),
         }
     }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
-#[serde(from = "DeserializableCamera")]
+#[serde(try_from = "DeserializableCamera")]
 #[serde(into = "DeserializableCamera")]
 pub struct Camera {
     pub resolution: (usize, usize),
-    pub fov: f32,
-    pub position: Point3<f32>,
-    pub direction: Vector3<f32>,
-    pub up: Vector3<f32>,
-    pub transformation_matrix: Matrix4<f32>,
+    pub fov: Float,
+    pub position: Point3<Float>,
+    /// Normalized
+    pub direction: Vector3<Float>,
+    /// Normalized and orthogonal to `direction`, before applying `roll`
+    pub up: Vector3<Float>,
+    /// Rotation around the viewing direction in degrees, see `CameraPose::roll`
+    pub roll: Float,
+    pub transformation_matrix: Matrix4<Float>,
+    /// Keyframes that replace the pose when the scene is evaluated with `Scene::at_time()`
+    pub animation: Option<Track<CameraPose>>,
+    /// Motion blur is disabled if this is `None`
+    pub shutter: Option<Shutter>,
+    /// Corner darkening is disabled if this is `None`
+    pub vignetting: Option<Vignetting>,
+    /// Brightness adjustment in stops (EV), each one doubles the brightness of the image
+    pub exposure: Float,
+    /// Glow around bright areas is disabled if this is `None`
+    pub bloom: Option<Bloom>,
+    /// Eyes used by the stereo render methods of `Renderer`, which show the same view twice if this is `None`
+    pub stereo: Option<Stereo>,
+}
+
+impl Camera {
+    /// Fails if `direction` and `up` don't determine an orientation, see `CameraPose::orthonormal_basis()`
+    pub fn new(resolution: (usize, usize), fov: Float, position: Point3<Float>, direction: Vector3<Float>, up: Vector3<Float>) -> Result<Camera, RaytracerError> {
+        Camera::try_from(DeserializableCamera {
+            resolution,
+            fov,
+            position,
+            direction,
+            up,
+            roll: 0.0,
+            animation: None,
+            shutter: None,
+            vignetting: None,
+            exposure: 0.0,
+            bloom: None,
+            stereo: None,
+        })
+    }
+
+    /// Get the camera-to-world matrix at a point in time, taking the animation into account
+    pub fn transformation_matrix_at(&self, time: Option<Float>) -> Matrix4<Float> {
+        let animated_pose = time
+            .and_then(|time| self.animation.as_ref().and_then(|track| track.sample(time)));
+
+        // Interpolating between valid keyframes can still pass through a degenerate pose, e.g. if the direction turns
+        // around, which keeps the static pose
+        animated_pose
+            .and_then(|pose| pose.camera_to_world().ok())
+            .unwrap_or(self.transformation_matrix)
+    }
+
+    /// Fraction of light that reaches the sensor along a ray with the given direction in camera space
+    pub fn vignetting_factor(&self, direction: &Vector3<Float>) -> Float {
+        match &self.vignetting {
+            Some(vignetting) => {
+                let aspect_ratio = self.resolution.0 as Float / self.resolution.1 as Float;
+                let corner_tan = float::tan(self.fov.to_radians() / 2.0) * (aspect_ratio * aspect_ratio + 1.0).sqrt();
+                vignetting.factor(direction, corner_tan)
+            }
+            None => 1.0,
+        }
+    }
+
+    /// Factor that all colors are multiplied with according to `exposure`
+    pub fn exposure_factor(&self) -> Float {
+        float::powf(2.0, self.exposure)
+    }
+
+    /// Place the camera on a sphere around `target`, looking at it
+    ///
+    /// `azimuth` rotates the camera around the Y axis, starting on the positive Z axis, and `elevation` raises it above
+    /// the XZ plane, both in degrees. The up vector is tilted along with the elevation, so that looking straight down
+    /// or up works as well. Orbiting with increasing azimuth makes a turntable animation.
+    pub fn orbit_around(&mut self, target: Point3<Float>, azimuth: Float, elevation: Float, distance: Float) -> Result<(), RaytracerError> {
+        let (azimuth, elevation) = (azimuth.to_radians(), elevation.to_radians());
+        let (sin_azimuth, cos_azimuth) = (float::sin(azimuth), float::cos(azimuth));
+        let (sin_elevation, cos_elevation) = (float::sin(elevation), float::cos(elevation));
+
+        let offset = Vector3::new(cos_elevation * sin_azimuth, sin_elevation, cos_elevation * cos_azimuth);
+        let up = Vector3::new(-sin_elevation * sin_azimuth, cos_elevation, -sin_elevation * cos_azimuth);
+        self.set_pose(CameraPose {
+            fov: self.fov,
+            position: target + offset * distance,
+            direction: -offset,
+            up,
+            roll: self.roll,
+        })
+    }
+
+    /// Set fov, position and orientation and update the cached matrix
+    ///
+    /// Leaves the camera unchanged if the pose doesn't determine an orientation, see
+    /// `CameraPose::orthonormal_basis()`.
+    pub fn set_pose(&mut self, pose: CameraPose) -> Result<(), RaytracerError> {
+        let (direction, up) = pose.orthonormal_basis()?;
+        self.transformation_matrix = pose.camera_to_world()?;
+        self.fov = pose.fov;
+        self.position = pose.position;
+        self.direction = direction;
+        self.up = up;
+        self.roll = pose.roll;
+        Ok(())
+    }
+}
+
+/// One of the two views of a stereo camera
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+/// Two horizontally offset eyes in place of the camera, for viewing the scene in stereo
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Stereo {
+    /// Distance between the eyes, in scene units
+    pub eye_separation: Float,
+    /// Distance in front of the camera at which both eyes see the same image point, which therefore appears at the
+    /// depth of the screen; closer objects pop out of it
+    pub convergence_distance: Float,
+}
+
+impl Stereo {
+    /// Turn a ray of the center camera, in camera space and starting at the origin, into the corresponding ray of `eye`
+    ///
+    /// The eyes look in parallel and their images are shifted towards each other instead (off-axis projection), which
+    /// avoids the vertical parallax that turning them in towards the convergence point would cause.
+    pub(crate) fn eye_ray(&self, ray: &Ray, eye: Eye) -> Ray {
+        let offset = match eye {
+            Eye::Left => -0.5 * self.eye_separation,
+            Eye::Right => 0.5 * self.eye_separation,
+        };
+        let origin = Point3::new(offset, 0.0, 0.0);
+        // Both eyes see the point where the center camera's ray crosses the convergence plane
+        let eye_direction = |direction: Vector3<Float>| {
+            let converged = direction * (self.convergence_distance / -direction.z);
+            (Point3::from_vec(converged) - origin).normalize()
+        };
+
+        Ray {
+            origin,
+            direction: eye_direction(ray.direction),
+            differentials: ray.differentials.map(|differentials| RayDifferentials {
+                rx_origin: origin,
+                rx_direction: eye_direction(differentials.rx_direction),
+                ry_origin: origin,
+                ry_direction: eye_direction(differentials.ry_direction),
+            }),
+            ..ray.clone()
+        }
+    }
+}
+
+fn default_natural_falloff() -> bool {
+    true
+}
+
+/// Darkening towards the corners of the image as caused by a real lens
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Vignetting {
+    /// Natural falloff according to the cosine-fourth law
+    #[serde(default = "default_natural_falloff")]
+    pub natural: bool,
+    /// Mechanical vignetting: radius of the lens barrel opening relative to the aperture radius, disabled if `None`
+    ///
+    /// Off-axis, the barrel opening is shifted against the aperture and clips it. The shift reaches twice the aperture
+    /// radius in the image corners, so a ratio of 1 makes the corners black and larger ratios confine the darkening to
+    /// the corners.
+    #[serde(default)]
+    pub barrel_ratio: Option<Float>,
+}
+
+impl Vignetting {
+    /// Fraction of light that passes along a ray with the given camera space direction, where `corner_tan` is the
+    /// tangent of the angle between the optical axis and the rays through the image corners
+    pub fn factor(&self, direction: &Vector3<Float>, corner_tan: Float) -> Float {
+        let cos_theta = (-direction.z / direction.magnitude()).clamp(0.0, 1.0);
+
+        let mut factor = 1.0;
+        if self.natural {
+            factor *= float::powi(cos_theta, 4);
+        }
+        if let Some(barrel_ratio) = self.barrel_ratio {
+            let tan_theta = (1.0 - cos_theta * cos_theta).sqrt() / cos_theta;
+            let offset = 2.0 * tan_theta / corner_tan;
+            factor *= circle_overlap(barrel_ratio, offset) / consts::PI;
+        }
+        factor
+    }
+}
+
+/// Area of the intersection of a unit circle and a circle with radius `radius` whose centers are `distance` apart
+fn circle_overlap(radius: Float, distance: Float) -> Float {
+    if distance >= 1.0 + radius {
+        return 0.0;
+    }
+    if distance <= (radius - 1.0).abs() {
+        return consts::PI * float::powi(radius.min(1.0), 2);
+    }
+
+    let r2 = radius * radius;
+    let d2 = distance * distance;
+    let alpha = float::acos(((d2 + 1.0 - r2) / (2.0 * distance)).clamp(-1.0, 1.0));
+    let beta = float::acos(((d2 + r2 - 1.0) / (2.0 * distance * radius)).clamp(-1.0, 1.0));
+    let kite = ((-distance + 1.0 + radius) * (distance + 1.0 - radius) * (distance - 1.0 + radius) * (distance + 1.0 + radius)).max(0.0).sqrt();
+    alpha + r2 * beta - 0.5 * kite
+}
+
+/// Settings for darkening the ambient light in places that are occluded by nearby geometry
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AmbientOcclusion {
+    /// Number of rays cast into the hemisphere around each primary hit
+    pub samples: usize,
+    /// Only geometry closer than this distance occludes the ambient light
+    pub radius: Float,
+}
+
+/// What is seen by rays that don't hit any object
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub enum Background {
+    /// The scene's uniform `clear_color`
+    #[default]
+    ClearColor,
+    /// Vertical gradient between `bottom` (looking straight down) and `top` (looking straight up)
+    Gradient {
+        top: Color,
+        bottom: Color,
+    },
+    /// Outdoor backdrop: fades from `zenith` (looking straight up) to `horizon` and is `ground` below the horizon
+    Horizon {
+        zenith: Color,
+        horizon: Color,
+        ground: Color,
+    },
+    /// An image surrounding the scene, which can also light it
+    Environment(EnvironmentMap),
+    /// Analytic daytime sky, which can follow a directional light acting as the sun
+    Sky(Sky),
+}
+
+fn default_russian_roulette_start_depth() -> u32 {
+    3
+}
+
+fn default_min_survival_probability() -> Float {
+    0.05
+}
+
+/// Randomly terminates reflection and refraction rays that contribute little to the image
+///
+/// Surviving rays are weighted up accordingly, so that deep paths through glass stay correct on average instead of
+/// being cut off. The resulting noise is averaged out by the anti-aliasing samples.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RussianRoulette {
+    /// Recursion depth from which on rays may be terminated
+    #[serde(default = "default_russian_roulette_start_depth")]
+    pub start_depth: u32,
+    /// Lower bound for the survival probability, so that dim paths aren't boosted excessively
+    #[serde(default = "default_min_survival_probability")]
+    pub min_survival_probability: Float,
+}
+
+impl RussianRoulette {
+    /// Probability of continuing a ray whose color contributes `throughput` to the pixel
+    pub fn survival_probability(&self, throughput: Float) -> Float {
+        throughput.clamp(self.min_survival_probability.clamp(Float::EPSILON, 1.0), 1.0)
+    }
+}
+
+fn default_scattering_distance() -> Float {
+    100.0
+}
+
+/// Participating medium that fills the whole scene
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Fog {
+    /// Color that distant objects fade to
+    pub color: Color,
+    /// Extinction coefficient per world unit (at y = 0 if there is a height falloff)
+    pub density: Float,
+    /// Rate at which the density decreases exponentially with height, `None` for uniform density
+    #[serde(default)]
+    pub height_falloff: Option<Float>,
+    /// Distance along each ray that is free of fog, e.g. to keep the foreground of a landscape clear while the
+    /// distance fades into haze
+    #[serde(default)]
+    pub start_distance: Float,
+    /// Number of ray marching steps for light scattered towards the camera (light shafts), 0 to disable
+    #[serde(default)]
+    pub scattering_steps: usize,
+    /// Maximum distance along primary rays up to which scattering is ray marched
+    #[serde(default = "default_scattering_distance")]
+    pub scattering_distance: Float,
+}
+
+impl Fog {
+    /// Density at a specific point
+    pub fn density_at(&self, point: &Point3<Float>) -> Float {
+        match self.height_falloff {
+            Some(falloff) => self.density * float::exp(-falloff * point.y),
+            None => self.density,
+        }
+    }
+
+    /// Fraction of light that travels the distance `distance` along `ray` without being absorbed or scattered
+    ///
+    /// This is `exp(-distance * density)` for uniform fog, counting only the distance beyond `start_distance`.
+    pub fn transmittance(&self, ray: &Ray, distance: Float) -> Float {
+        let start_distance = self.start_distance.max(0.0);
+        if distance <= start_distance {
+            return 1.0;
+        }
+        let start = ray.origin + ray.direction * start_distance;
+        let distance = distance - start_distance;
+
+        let optical_depth = match self.height_falloff {
+            Some(falloff) if (falloff * ray.direction.y).abs() > 1e-6 => {
+                // Integral of the exponential density along the ray
+                let k = falloff * ray.direction.y;
+                let start_density = self.density_at(&start);
+                if distance.is_infinite() {
+                    if k > 0.0 { start_density / k } else { Float::INFINITY }
+                } else {
+                    start_density * (1.0 - float::exp(-k * distance)) / k
+                }
+            }
+            _ => self.density_at(&start) * distance,
+        };
+
+        float::exp(-optical_depth)
+    }
+}
+
+fn default_size() -> Vector3<Float> {
+    Vector3::new(1.0, 1.0, 1.0)
+}
+
+fn default_decal_opacity() -> Parameter {
+    Parameter::Value(1.0)
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeserializableDecal {
+    pub transform: Transformation,
+    #[serde(default = "default_size")]
+    pub size: Vector3<Float>,
+    pub color: Coloration,
+    #[serde(default = "default_decal_opacity")]
+    pub opacity: Parameter,
+}
+
+impl From<Decal> for DeserializableDecal {
+    fn from(d: Decal) -> DeserializableDecal {
+        DeserializableDecal {
+            transform: d.transformation,
+            size: d.size,
+            color: d.color,
+            opacity: d.opacity,
+        }
+    }
+}
+
+impl From<DeserializableDecal> for Decal {
+    fn from(d: DeserializableDecal) -> Decal {
+        let transformation_matrix = d.transform.to_matrix();
+        Decal {
+            transformation: d.transform,
+            size: d.size,
+            color: d.color,
+            opacity: d.opacity,
+            inv_transformation_matrix: invert_or_zero(transformation_matrix),
+            projection_direction: transformation_matrix.transform_vector(-Vector3::unit_y()).normalize(),
+        }
+    }
+}
+
+/// A texture projected onto all surfaces within a box, e.g. for labels, posters or dirt
+///
+/// The box spans `-size` to `size` in decal space and the texture is projected along the negative Y axis, like a
+/// projector above a `Plane`: X maps to U and Z maps to V. Only surfaces facing the projector receive the decal.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "DeserializableDecal")]
+#[serde(into = "DeserializableDecal")]
+pub struct Decal {
+    pub transformation: Transformation,
+    /// Half extents of the projection box
+    pub size: Vector3<Float>,
+    pub color: Coloration,
+    /// Blend factor between the surface color (0) and the decal color (1)
+    pub opacity: Parameter,
+    inv_transformation_matrix: Matrix4<Float>,
+    projection_direction: Vector3<Float>,
+}
+
+impl Decal {
+    /// Composite the decal over `color`, the surface color at the hit point
+    pub fn apply(&self, hit: &Hit, color: Color) -> Color {
+        if hit.normal.dot(self.projection_direction) >= 0.0 {
+            return color;
+        }
+
+        let local = self.inv_transformation_matrix.transform_point(hit.point);
+        let (x, y, z) = (local.x / self.size.x, local.y / self.size.y, local.z / self.size.z);
+        if x.abs() > 1.0 || y.abs() > 1.0 || z.abs() > 1.0 {
+            return color;
+        }
+
+        let tex_coords = Vector2::new((x + 1.0) * 0.5, (z + 1.0) * 0.5);
+        let opacity = self.opacity.value(&tex_coords).clamp(0.0, 1.0);
+        color * (1.0 - opacity) + self.color.color(&tex_coords) * opacity
+    }
+}
+
+/// Removes everything on one side of a plane, for cutaway views into objects
+///
+/// Geometry on the side that `normal` points to is ignored by all rays, including shadow rays. With `cap` set, cuts
+/// through closed objects are closed with a flat surface facing the removed side, so that the objects look solid. The
+/// cap is shaded with the object's material; its texture coordinates are the world space position on the plane.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ClippingPlane {
+    /// Any point on the plane
+    pub point: Point3<Float>,
+    #[serde(deserialize_with = "deserialize_normalized")]
+    pub normal: Vector3<Float>,
+    #[serde(default)]
+    pub cap: bool,
+    /// Indices into `Scene::objects` of the objects that are cut, empty to cut all objects
+    #[serde(default)]
+    pub objects: Vec<usize>,
+}
+
+impl ClippingPlane {
+    pub fn applies_to(&self, object_index: usize) -> bool {
+        self.objects.is_empty() || self.objects.contains(&object_index)
+    }
+
+    /// Flat surface closing the cut where `ray` crosses the plane at `distance`
+    fn cap_hit(&self, ray: &Ray, distance: Float) -> Hit {
+        let point = ray.origin + ray.direction * distance;
+        let (tangent, bitangent) = orthonormal_basis(&self.normal);
+        let offset = point - self.point;
+        let tex_coords = Vector2::new(offset.dot(tangent), offset.dot(bitangent));
+        let mut hit = Hit::new(point, distance, self.normal, tex_coords, tangent, bitangent);
+        if let Some(differentials) = &ray.differentials {
+            hit.compute_differentials(differentials);
+        }
+        hit
+    }
+}
+
+/// The materials of a scene, given either as a list or as a map from names to materials
+///
+/// The order of a map is preserved so that numeric material indices keep working.
+#[derive(Clone)]
+struct MaterialTable {
+    materials: Vec<Material>,
+    /// One name per material, empty if the materials were given as a list
+    names: Vec<String>,
+}
+
+impl Serialize for MaterialTable {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.names.is_empty() {
+            self.materials.serialize(serializer)
+        } else {
+            let mut map = serializer.serialize_map(Some(self.materials.len()))?;
+            for (name, material) in self.names.iter().zip(self.materials.iter()) {
+                map.serialize_entry(name, material)?;
+            }
+            map.end()
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MaterialTable {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MaterialTable, D::Error> {
+        struct MaterialTableVisitor;
+
+        impl<'de> Visitor<'de> for MaterialTableVisitor {
+            type Value = MaterialTable;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a list of materials or a map from names to materials")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<MaterialTable, A::Error> {
+                let mut materials = Vec::new();
+                while let Some(material) = seq.next_element()? {
+                    materials.push(material);
+                }
+                Ok(MaterialTable { materials, names: Vec::new() })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<MaterialTable, A::Error> {
+                let mut materials = Vec::new();
+                let mut names: Vec<String> = Vec::new();
+                while let Some((name, material)) = map.next_entry::<String, Material>()? {
+                    if names.contains(&name) {
+                        return Err(de::Error::custom(format!("Duplicate material name \"{}\"", name)));
+                    }
+                    names.push(name);
+                    materials.push(material);
+                }
+                Ok(MaterialTable { materials, names })
+            }
+        }
+
+        deserializer.deserialize_any(MaterialTableVisitor)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeserializableScene {
+    pub camera: Camera,
+    pub aa_samples: usize,
+    pub clear_color: Color,
+    #[serde(default)]
+    pub background: Background,
+    #[serde(default)]
+    pub transparent_background: bool,
+    pub materials: MaterialTable,
+    pub objects: Vec<DeserializableNode>,
+    pub ambient_light_color: Color,
+    pub lights: Vec<Light>,
+    pub max_recursion_depth: u32,
+    #[serde(default)]
+    pub ambient_occlusion: Option<AmbientOcclusion>,
+    #[serde(default)]
+    pub fog: Option<Fog>,
+    #[serde(default)]
+    pub decals: Vec<Decal>,
+    #[serde(default)]
+    pub clipping_planes: Vec<ClippingPlane>,
+    #[serde(default)]
+    pub light_sampling: LightSampling,
+    #[serde(default)]
+    pub max_reflection_depth: Option<u32>,
+    #[serde(default)]
+    pub max_refraction_depth: Option<u32>,
+    #[serde(default)]
+    pub russian_roulette: Option<RussianRoulette>,
+    #[serde(default)]
+    pub time: Float,
+}
+
+impl From<Scene> for DeserializableScene {
+    fn from(s: Scene) -> DeserializableScene {
+        // Only emit names if every material has one, otherwise indices would shift
+        let mut names = vec![None; s.materials.len()];
+        for (name, &index) in s.material_names.iter() {
+            if let Some(slot) = names.get_mut(index) {
+                *slot = Some(name.clone());
+            }
+        }
+        let names = names.into_iter().collect::<Option<Vec<String>>>().unwrap_or_default();
+
+        DeserializableScene {
+            camera: s.camera,
+            aa_samples: s.aa_samples,
+            clear_color: s.clear_color,
+            background: s.background,
+            transparent_background: s.transparent_background,
+            objects: group_nodes(s.objects.into_iter().map(|object| {
+                let group = object.group;
+                let mut d = DeserializableObject::from(object);
+                if let MaterialReference::Index(index) = d.material {
+                    if let Some(name) = names.get(index) {
+                        d.material = MaterialReference::Name(name.clone());
+                    }
+                }
+                (d, group)
+            }).collect(), s.groups),
+            materials: MaterialTable { materials: s.materials, names },
+            ambient_light_color: s.ambient_light_color,
+            lights: s.lights,
+            max_recursion_depth: s.max_recursion_depth,
+            ambient_occlusion: s.ambient_occlusion,
+            fog: s.fog,
+            decals: s.decals,
+            clipping_planes: s.clipping_planes,
+            light_sampling: s.light_sampling,
+            max_reflection_depth: s.max_reflection_depth,
+            max_refraction_depth: s.max_refraction_depth,
+            russian_roulette: s.russian_roulette,
+            time: s.time,
+        }
+    }
+}
+
+impl TryFrom<DeserializableScene> for Scene {
+    type Error = RaytracerError;
+
+    fn try_from(d: DeserializableScene) -> Result<Scene, RaytracerError> {
+        let material_names: HashMap<String, usize> = d.materials.names.into_iter()
+            .enumerate()
+            .map(|(index, name)| (name, index))
+            .collect();
+        let material_count = d.materials.materials.len();
+
+        let resolve_material = |material: &MaterialReference| match material {
+            MaterialReference::Index(index) if *index < material_count => Ok(*index),
+            MaterialReference::Index(index) => {
+                Err(format!("Material index {} is out of range, the scene has {} materials", index, material_count))
+            }
+            MaterialReference::Name(name) => match material_names.get(name) {
+                Some(&index) => Ok(index),
+                None => Err(format!("Unknown material \"{}\"", name)),
+            },
+        };
+
+        let mut objects = Vec::new();
+        let mut groups = Vec::new();
+        for node in d.objects {
+            node.flatten(None, &resolve_material, &mut objects, &mut groups).map_err(RaytracerError::InvalidScene)?;
+        }
+
+        let mut scene = Scene {
+            camera: d.camera,
+            aa_samples: d.aa_samples,
+            clear_color: d.clear_color,
+            background: d.background,
+            transparent_background: d.transparent_background,
+            materials: d.materials.materials,
+            material_names,
+            objects,
+            groups,
+            ambient_light_color: d.ambient_light_color,
+            lights: d.lights,
+            max_recursion_depth: d.max_recursion_depth,
+            ambient_occlusion: d.ambient_occlusion,
+            fog: d.fog,
+            decals: d.decals,
+            clipping_planes: d.clipping_planes,
+            light_sampling: d.light_sampling,
+            max_reflection_depth: d.max_reflection_depth,
+            max_refraction_depth: d.max_refraction_depth,
+            russian_roulette: d.russian_roulette,
+            time: d.time,
+            acceleration: TopLevelBvh::default(),
+        };
+        scene.update_group_matrices();
+        Ok(scene)
+    }
+}
+
+/// Rebuild the hierarchy of the scene file from the flat object list and the objects' groups
+///
+/// Objects and groups keep their relative order, so loading the result yields the objects in the same order again.
+fn group_nodes(objects: Vec<(DeserializableObject, Option<usize>)>, groups: Vec<Group>) -> Vec<DeserializableNode> {
+    // Lowest index of any object in each group including nested ones, which determines where the group is placed
+    let mut first_objects = vec![usize::MAX; groups.len()];
+    for (index, (_, group)) in objects.iter().enumerate() {
+        let mut group = *group;
+        for _ in 0..groups.len() {
+            match group.filter(|&group| group < groups.len()) {
+                Some(current) => {
+                    first_objects[current] = first_objects[current].min(index);
+                    group = groups[current].parent;
+                }
+                None => break,
+            }
+        }
+    }
+
+    enum Child {
+        Object(usize),
+        Group(usize),
+    }
+
+    let mut objects: Vec<_> = objects.into_iter().map(Some).collect();
+    let mut groups: Vec<_> = groups.into_iter().map(Some).collect();
+
+    fn children_of(parent: Option<usize>, objects: &mut [Option<(DeserializableObject, Option<usize>)>], groups: &mut [Option<Group>], first_objects: &[usize]) -> Vec<DeserializableNode> {
+        let mut children: Vec<_> = objects.iter().enumerate()
+            .filter(|(_, object)| object.as_ref().is_some_and(|(_, group)| *group == parent))
+            .map(|(index, _)| (index, Child::Object(index)))
+            .chain(groups.iter().enumerate()
+                .filter(|(_, group)| group.as_ref().is_some_and(|group| group.parent == parent))
+                .map(|(index, _)| (first_objects[index], Child::Group(index))))
+            .collect();
+        // Stable, so that empty groups stay in their original order at the end
+        children.sort_by_key(|&(position, _)| position);
+
+        children.into_iter()
+            .filter_map(|(_, child)| match child {
+                Child::Object(index) => objects[index].take().map(|(object, _)| object.into()),
+                Child::Group(index) => groups[index].take().map(|group| DeserializableNode {
+                    name: group.name,
+                    shape: None,
+                    material: None,
+                    transform: group.transformation,
+                    animation: None,
+                    visibility: None,
+                    children: Some(children_of(Some(index), objects, groups, first_objects)),
+                }),
+            })
+            .collect()
+    }
+
+    children_of(None, &mut objects, &mut groups, &first_objects)
 }
 
 /// Holds all information about the scene
+///
+/// The objects aren't a public field like the rest: they are edited through methods like `objects_mut()` and
+/// `add_object()`, which mark the top-level BVH as outdated, so that a moved object can't silently be missed by rays.
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "DeserializableScene")]
+#[serde(into = "DeserializableScene")]
 pub struct Scene {
     pub camera: Camera,
     pub aa_samples: usize,
     /// Background color, assigned to pixels that are not covered by any object in the scene
     pub clear_color: Color,
+    pub background: Background,
+    /// Leave pixels that aren't covered by any object transparent in `Renderer::render_rgba()` instead of filling them
+    /// with the background, which is then only seen in reflections and refractions
+    pub transparent_background: bool,
+    /// Materials can be given as a list or as a map from names to materials in the scene file
     pub materials: Vec<Material>,
-    pub objects: Vec<Object>,
+    /// Indices into `materials` by name, empty if the materials were given as a list
+    pub material_names: HashMap<String, usize>,
+    /// Edited with `objects_mut()`, `object_mut()` and `add_object()`, which keep `acceleration` from going stale
+    pub(crate) objects: Vec<Object>,
+    /// Groups that objects and other groups can belong to, see `Object::group`
+    pub groups: Vec<Group>,
     pub ambient_light_color: Color,
     pub lights: Vec<Light>,
+    /// Maximum number of reflections and refractions along a path from the camera, in total
     pub max_recursion_depth: u32,
+    /// Additional limit for the number of reflections along a path, only `max_recursion_depth` applies if `None`
+    pub max_reflection_depth: Option<u32>,
+    /// Additional limit for the number of refractions along a path, only `max_recursion_depth` applies if `None`
+    pub max_refraction_depth: Option<u32>,
+    /// Paths are only terminated by the depth limits if this is `None`
+    pub russian_roulette: Option<RussianRoulette>,
+    /// Ambient occlusion is disabled if this is `None`
+    pub ambient_occlusion: Option<AmbientOcclusion>,
+    /// Fog is disabled if this is `None`
+    pub fog: Option<Fog>,
+    /// Composited over the material colors in order
+    pub decals: Vec<Decal>,
+    /// Cut away parts of the objects, e.g. to look inside mechanical assemblies
+    pub clipping_planes: Vec<ClippingPlane>,
+    /// Evaluating only a few randomly picked lights per shading point is faster in scenes with many lights
+    pub light_sampling: LightSampling,
+    /// Point in time (in seconds) that the scene represents, set by `at_time()`
+    pub time: Float,
+    /// Top-level BVH over the objects, only used while it is current; every change to the objects invalidates it
+    /// until the next `refit()`
+    pub(crate) acceleration: TopLevelBvh,
 }
 
 impl Scene {
-    /// Check ray intersections against all objects in the scene and return the closest hit
-    pub fn trace(&self, ray: &Ray) -> Option<(&Object, Hit)> {
+    /// Create an empty scene with a black background and all optional effects disabled
+    pub fn new(camera: Camera) -> Scene {
+        Scene {
+            camera,
+            aa_samples: 1,
+            clear_color: Color::black(),
+            background: Background::default(),
+            transparent_background: false,
+            materials: Vec::new(),
+            material_names: HashMap::new(),
+            objects: Vec::new(),
+            groups: Vec::new(),
+            ambient_light_color: Color::black(),
+            lights: Vec::new(),
+            max_recursion_depth: 4,
+            ambient_occlusion: None,
+            fog: None,
+            decals: Vec::new(),
+            clipping_planes: Vec::new(),
+            light_sampling: LightSampling::default(),
+            max_reflection_depth: None,
+            max_refraction_depth: None,
+            russian_roulette: None,
+            time: 0.0,
+            acceleration: TopLevelBvh::default(),
+        }
+    }
+
+    pub fn objects(&self) -> &[Object] {
+        &self.objects
+    }
+
+    /// All objects, for editing the scene between frames
+    ///
+    /// Like with `object_mut()`, rays are tested against all objects until the next `refit()`.
+    pub fn objects_mut(&mut self) -> &mut [Object] {
+        self.acceleration.invalidate();
+        &mut self.objects
+    }
+
+    /// Append an object to the scene and return its index
+    ///
+    /// Rays are tested against all objects until the next `refit()`, so that several objects can be added before
+    /// building the top-level BVH once.
+    pub fn add_object(&mut self, object: Object) -> usize {
+        self.acceleration.invalidate();
+        self.objects.push(object);
+        self.objects.len() - 1
+    }
+
+    /// Top-level BVH over the objects, see `refit()`
+    pub fn acceleration(&self) -> &TopLevelBvh {
+        &self.acceleration
+    }
+
+    /// Index of the first object with the given name
+    pub fn object_index(&self, name: &str) -> Option<usize> {
+        self.objects.iter().position(|object| object.name.as_deref() == Some(name))
+    }
+
+    /// First object with the given name
+    pub fn object(&self, name: &str) -> Option<&Object> {
+        self.object_index(name).map(|index| &self.objects[index])
+    }
+
+    /// First object with the given name, for editing the scene between frames
+    ///
+    /// Change its transformation with `Object::set_transformation()` to keep the cached matrices consistent. Rays are
+    /// tested against all objects until the next `refit()`.
+    pub fn object_mut(&mut self, name: &str) -> Option<&mut Object> {
+        self.acceleration.invalidate();
+        self.object_index(name).map(move |index| &mut self.objects[index])
+    }
+
+    /// Set the transformation of an object relative to its group and update its cached matrices
+    ///
+    /// Rays are tested against all objects until the next `refit()`, so that several objects can be moved before
+    /// updating the top-level BVH once.
+    pub fn set_object_transformation(&mut self, index: usize, transformation: Transformation) {
+        self.acceleration.invalidate();
+        self.objects[index].set_transformation(transformation);
+    }
+
+    /// Update the top-level BVH after objects moved, without rebuilding the acceleration structures of their meshes
+    ///
+    /// Only the bounds of the objects are updated if the list of objects is the same, otherwise the top-level BVH is
+    /// built again. `Renderer::new()` does this for the scene it renders.
+    pub fn refit(&mut self) {
+        self.acceleration.refit(&self.objects);
+    }
+
+    /// Index of the first light with the given name
+    pub fn light_index(&self, name: &str) -> Option<usize> {
+        self.lights.iter().position(|light| light.name() == Some(name))
+    }
+
+    /// First light with the given name
+    pub fn light(&self, name: &str) -> Option<&Light> {
+        self.light_index(name).map(|index| &self.lights[index])
+    }
+
+    /// First light with the given name, for editing the scene between frames
+    pub fn light_mut(&mut self, name: &str) -> Option<&mut Light> {
+        self.light_index(name).map(move |index| &mut self.lights[index])
+    }
+
+    /// Index of the material with the given name, see `material_names`
+    pub fn material_index(&self, name: &str) -> Option<usize> {
+        self.material_names.get(name).copied().filter(|&index| index < self.materials.len())
+    }
+
+    /// Material with the given name
+    pub fn material(&self, name: &str) -> Option<&Material> {
+        self.material_index(name).map(|index| &self.materials[index])
+    }
+
+    /// Material with the given name, for editing the scene between frames
+    pub fn material_mut(&mut self, name: &str) -> Option<&mut Material> {
+        // The material's visibility or opacity may change
+        self.acceleration.bump_generation();
+        self.material_index(name).map(move |index| &mut self.materials[index])
+    }
+
+    /// Index of the first group with the given name
+    pub fn group_index(&self, name: &str) -> Option<usize> {
+        self.groups.iter().position(|group| group.name.as_deref() == Some(name))
+    }
+
+    /// Object-to-world matrix of a group, composed of its transformation and those of all enclosing groups
+    pub fn group_matrix(&self, index: usize) -> Matrix4<Float> {
+        let mut matrix = Matrix4::identity();
+        let mut group = Some(index);
+        // Bounded, so that a cycle of parents can't hang
+        for _ in 0..self.groups.len() {
+            match group.and_then(|index| self.groups.get(index)) {
+                Some(current) => {
+                    matrix = current.transformation.to_matrix() * matrix;
+                    group = current.parent;
+                }
+                None => break,
+            }
+        }
+        matrix
+    }
+
+    /// Indices of all objects in a group, including those in nested groups
+    pub fn group_objects(&self, index: usize) -> Vec<usize> {
+        self.objects.iter()
+            .enumerate()
+            .filter(|(_, object)| {
+                let mut group = object.group;
+                for _ in 0..self.groups.len() {
+                    match group {
+                        Some(current) if current == index => return true,
+                        Some(current) => group = self.groups.get(current).and_then(|group| group.parent),
+                        None => break,
+                    }
+                }
+                false
+            })
+            .map(|(object_index, _)| object_index)
+            .collect()
+    }
+
+    /// Set the transformation of a group and move all objects in it accordingly
+    pub fn set_group_transformation(&mut self, index: usize, transformation: Transformation) {
+        self.groups[index].transformation = transformation;
+        self.update_group_matrices();
+    }
+
+    /// Compose the world matrices of all objects again after groups or the group membership of objects changed
+    pub fn update_group_matrices(&mut self) {
+        let group_matrices: Vec<_> = (0..self.groups.len()).map(|index| self.group_matrix(index)).collect();
+        for object in &mut self.objects {
+            let parent_matrix = object.group
+                .and_then(|group| group_matrices.get(group).copied())
+                .unwrap_or_else(Matrix4::identity);
+            object.set_parent_matrix(parent_matrix);
+        }
+        self.refit();
+    }
+
+    /// World space bounding box of all bounded objects, `None` if there are none
+    ///
+    /// Infinite planes are ignored, and animated objects are only included in their static transformation.
+    pub fn bounds(&self) -> Option<AABB> {
+        self.objects.iter()
+            .filter_map(Object::world_bounds)
+            .reduce(|bounding_box, object_bounding_box| bounding_box.union(&object_bounding_box))
+    }
+
+    /// Move the camera back along its viewing direction until the bounding box of the scene fits into the image
+    ///
+    /// The camera keeps its orientation and field of view and ends up looking at the center of the bounding box.
+    /// Returns `false` and leaves the camera unchanged if the scene has no bounded objects (see `bounds()`).
+    pub fn frame_all(&mut self) -> bool {
+        let bounding_box = match self.bounds() {
+            Some(bounding_box) => bounding_box,
+            None => return false,
+        };
+
+        // Fitting the bounding sphere of the box makes the result independent of the viewing direction
+        let center = bounding_box.min.midpoint(bounding_box.max);
+        let radius = bounding_box.min.distance(bounding_box.max) * 0.5;
+
+        let camera = &self.camera;
+        let half_fov_y = camera.fov.to_radians() / 2.0;
+        let aspect_ratio = camera.resolution.0 as Float / camera.resolution.1 as Float;
+        let half_fov_x = float::atan2(float::tan(half_fov_y) * aspect_ratio, 1.0);
+        let distance = radius / float::sin(half_fov_y.min(half_fov_x));
+
+        let direction = camera.direction.normalize();
+        self.camera.set_pose(CameraPose {
+            fov: camera.fov,
+            position: center - direction * distance,
+            direction,
+            up: camera.up,
+            roll: camera.roll,
+        }).is_ok()
+    }
+
+    /// Create a static copy of the scene with all animated objects and the camera at their state at `time` (in seconds)
+    pub fn at_time(&self, time: Float) -> Scene {
+        let mut scene = self.clone();
+        scene.time = time;
+
+        if let Some(pose) = scene.camera.animation.as_ref().and_then(|track| track.sample(time)) {
+            // A degenerate interpolated pose keeps the static one, like in `Camera::transformation_matrix_at()`
+            let _ = scene.camera.set_pose(pose);
+        }
+
+        for object in &mut scene.objects {
+            if let Some(transformation) = object.animation.as_ref().and_then(|track| track.sample(time)) {
+                object.set_transformation(transformation);
+            }
+        }
+        scene.refit();
+
+        scene
+    }
+
+    /// Build statistics of the acceleration structures of all meshes, together with the index of their object
+    pub fn build_stats(&self) -> Vec<(usize, &BuildStats)> {
         self.objects.iter()
-            .filter_map(|obj| obj.intersect(ray))
-            .min_by(|(_, hit1), (_, hit2)| hit1.cmp(hit2))
+            .enumerate()
+            .filter_map(|(index, object)| match &object.shape {
+                Shape::Mesh(mesh) => Some((index, mesh.build_stats())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Check the scene for problems that would make rendering panic or produce garbage
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let is_finite_vector = |v: &Vector3<Float>| v.x.is_finite() && v.y.is_finite() && v.z.is_finite();
+        let is_finite_point = |p: &Point3<Float>| p.x.is_finite() && p.y.is_finite() && p.z.is_finite();
+
+        let camera = &self.camera;
+        if camera.resolution.0 == 0 || camera.resolution.1 == 0 {
+            diagnostics.push(Diagnostic::new(Severity::Error, Location::Camera, DiagnosticKind::EmptyResolution));
+        }
+        if !(camera.fov > 0.0 && camera.fov < 180.0) {
+            diagnostics.push(Diagnostic::new(Severity::Error, Location::Camera, DiagnosticKind::InvalidFieldOfView(camera.fov)));
+        }
+        if !is_finite_point(&camera.position) || !is_finite_vector(&camera.direction) || !is_finite_vector(&camera.up) {
+            diagnostics.push(Diagnostic::new(Severity::Error, Location::Camera, DiagnosticKind::NonFiniteValue));
+        } else if camera.direction.cross(camera.up).magnitude2() < Float::EPSILON {
+            diagnostics.push(Diagnostic::new(Severity::Error, Location::Camera, DiagnosticKind::DegenerateCameraVectors));
+        }
+
+        for (index, object) in self.objects.iter().enumerate() {
+            let location = Location::Object(index);
+
+            if object.material_index >= self.materials.len() {
+                diagnostics.push(Diagnostic::new(Severity::Error, location, DiagnosticKind::MaterialIndexOutOfRange {
+                    index: object.material_index,
+                    material_count: self.materials.len(),
+                }));
+            }
+
+            let transformation = &object.transformation;
+            if !is_finite_vector(&transformation.translation) || !is_finite_vector(&transformation.rotation) || !transformation.scale.is_finite() {
+                diagnostics.push(Diagnostic::new(Severity::Error, location, DiagnosticKind::NonFiniteValue));
+            } else if transformation.to_matrix().invert().is_none() {
+                diagnostics.push(Diagnostic::new(Severity::Error, location, DiagnosticKind::NonInvertibleTransformation));
+            }
+
+            if let Shape::Mesh(mesh) = &object.shape {
+                let data = mesh.data();
+                if data.triangle_count() == 0 {
+                    diagnostics.push(Diagnostic::new(Severity::Warning, location, DiagnosticKind::EmptyMesh));
+                }
+                if !data.has_finite_positions() {
+                    diagnostics.push(Diagnostic::new(Severity::Error, location, DiagnosticKind::NonFiniteValue));
+                }
+            }
+        }
+
+        for (index, light) in self.lights.iter().enumerate() {
+            let location = Location::Light(index);
+
+            let (intensity, is_finite) = match light {
+                Light::Directional(light) => (light.intensity, is_finite_vector(&light.direction)),
+                Light::Point(light) => (light.intensity, is_finite_point(&light.point) && light.radius.is_finite()
+                    && light.projection.as_ref().is_none_or(|projection| is_finite_vector(&projection.direction) && projection.fov.is_finite())),
+                Light::Hemisphere(light) => (light.intensity, is_finite_vector(&light.up)),
+            };
+            if !is_finite {
+                diagnostics.push(Diagnostic::new(Severity::Error, location, DiagnosticKind::NonFiniteValue));
+            }
+            if intensity.is_nan() || intensity <= 0.0 {
+                diagnostics.push(Diagnostic::new(Severity::Warning, location, DiagnosticKind::NonPositiveLightIntensity(intensity)));
+            }
+        }
+
+        diagnostics
+    }
+
+    /// Like `validate()`, but fail with the diagnostics of severity `Error` if there are any, ignoring warnings
+    pub fn check(&self) -> Result<(), RaytracerError> {
+        let errors: Vec<_> = self.validate().into_iter().filter(Diagnostic::is_error).collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(RaytracerError::Validation(errors))
+        }
+    }
+
+    /// Deserialize a scene, loading all of its textures and meshes through `loader`
+    ///
+    /// Plain `Scene::deserialize()` uses the default loader (see `asset_loader::set_instance()`).
+    pub fn deserialize_with<'de, D>(deserializer: D, loader: Arc<dyn AssetLoader>) -> Result<Scene, D::Error>
+        where
+            D: Deserializer<'de>
+    {
+        asset_loader::with_loader(loader, || Scene::deserialize(deserializer))
+    }
+
+    /// Load all textures and meshes of the scene again through `loader`
+    ///
+    /// Used together with `DeferredLoader` to deserialize a scene before its assets are available and fill them in
+    /// once they have arrived. Stops at the first asset that fails to load.
+    pub fn resolve_assets(&mut self, loader: Arc<dyn AssetLoader>) -> Result<(), RaytracerError> {
+        asset_loader::with_loader(loader, || {
+            let textures = self.materials.iter_mut()
+                .flat_map(Material::textures_mut)
+                .chain(self.decals.iter_mut().flat_map(|decal| decal.color.texture_mut().into_iter().chain(decal.opacity.texture_mut())))
+                .chain(self.lights.iter_mut().filter_map(Light::projection_texture_mut));
+            for texture in textures {
+                texture.reload()?;
+            }
+
+            if let Background::Environment(environment) = &mut self.background {
+                environment.reload()?;
+            }
+
+            for obj in &mut self.objects {
+                match &mut obj.shape {
+                    Shape::Mesh(mesh) => {
+                        mesh.reload()?;
+                    }
+                    Shape::Heightfield(heightfield) => {
+                        heightfield.reload()?;
+                    }
+                    Shape::Volume(volume) => {
+                        volume.reload()?;
+                    }
+                    _ => {}
+                }
+            }
+            self.refit();
+
+            Ok(())
+        })
+    }
+
+    /// Composite all decals covering the hit point over the surface color
+    pub fn apply_decals(&self, hit: &Hit, color: Color) -> Color {
+        self.decals.iter().fold(color, |color, decal| decal.apply(hit, color))
+    }
+
+    /// Color seen by a ray that doesn't hit any object
+    pub fn background_color(&self, ray: &Ray) -> Color {
+        match &self.background {
+            Background::ClearColor => self.clear_color,
+            Background::Gradient { top, bottom } => {
+                let t = (ray.direction.y * 0.5 + 0.5).clamp(0.0, 1.0);
+                *bottom * (1.0 - t) + *top * t
+            }
+            Background::Horizon { zenith, horizon, ground } => {
+                let elevation = ray.direction.y / ray.direction.magnitude();
+                if elevation < 0.0 {
+                    *ground
+                } else {
+                    *horizon * (1.0 - elevation) + *zenith * elevation
+                }
+            }
+            Background::Environment(environment) => environment.radiance(&ray.direction),
+            Background::Sky(sky) => sky.radiance(&ray.direction, &self.lights),
+        }
+    }
+
+    /// Whether intersecting the object needs more than `Object::intersect()`, so it can't be traced as a packet
+    fn needs_filtered_intersection(&self, obj: &Object, index: usize) -> bool {
+        self.materials.get(obj.material_index).is_some_and(|material| material.opacity.is_some())
+            || self.clipping_planes.iter().any(|plane| plane.applies_to(index))
+    }
+
+    /// Section of `ray` that isn't removed by the clipping planes of the object with index `index`
+    ///
+    /// Returns the distances at which the ray enters and leaves the remaining space and the plane it enters through,
+    /// `None` if the ray lies completely in removed space.
+    fn clipped_range(&self, index: usize, ray: &Ray) -> Option<(Float, Float, Option<&ClippingPlane>)> {
+        let mut entry = 0.0;
+        let mut exit = Float::INFINITY;
+        let mut entry_plane = None;
+        for plane in self.clipping_planes.iter().filter(|plane| plane.applies_to(index)) {
+            let height = (ray.origin - plane.point).dot(plane.normal);
+            let slope = ray.direction.dot(plane.normal);
+            if slope == 0.0 {
+                if height > 0.0 {
+                    return None;
+                }
+                continue;
+            }
+
+            let distance = -height / slope;
+            if slope < 0.0 {
+                if distance > entry {
+                    entry = distance;
+                    entry_plane = Some(plane);
+                }
+            } else {
+                exit = exit.min(distance);
+            }
+        }
+
+        if entry > exit {
+            None
+        } else {
+            Some((entry, exit, entry_plane))
+        }
+    }
+
+    /// Intersect a single object, skipping hits on parts that are cut out by the material's opacity map or removed by
+    /// clipping planes
+    fn intersect_object<'a>(&self, obj: &'a Object, index: usize, ray: &Ray) -> Option<(&'a Object, Hit)> {
+        // Upper bound for the number of cut out surfaces a ray may pass through
+        const MAX_CUT_OUT_HITS: usize = 16;
+
+        if !self.needs_filtered_intersection(obj, index) {
+            return obj.intersect(ray);
+        }
+
+        let (entry, exit, entry_plane) = self.clipped_range(index, ray)?;
+        // The ray enters the remaining space inside the object exactly where the cap is
+        if let Some(plane) = entry_plane.filter(|plane| plane.cap) {
+            let inside = obj.intersect_interval(ray).iter()
+                .any(|interval| interval.entry_distance() < entry && interval.exit_distance() > entry);
+            if inside {
+                return Some((obj, plane.cap_hit(ray, entry)));
+            }
+        }
+
+        let material = self.materials.get(obj.material_index).filter(|material| material.opacity.is_some());
+
+        // Skip everything in front of the clipping planes at once
+        let mut current_ray = ray.clone();
+        current_ray.origin = ray.origin + ray.direction * entry;
+        for _ in 0..MAX_CUT_OUT_HITS {
+            let (_, hit) = obj.intersect(&current_ray)?;
+            if ray.origin.distance(hit.point) > exit {
+                return None;
+            }
+            if !material.is_some_and(|material| material.is_cut_out(&hit)) {
+                // Distance has to be relative to the original ray origin
+                let distance = ray.origin.distance(hit.point);
+                return Some((obj, Hit { distance, ..hit }));
+            }
+            current_ray.origin = hit.point + current_ray.direction * 1e-4;
+        }
+
+        None
+    }
+
+    /// Whether rays of type `ray_type` hit an object, which both its own visibility and that of its material have to
+    /// allow
+    pub fn is_visible(&self, obj: &Object, ray_type: RayType) -> bool {
+        obj.visibility.includes(ray_type)
+            && self.materials.get(obj.material_index).is_none_or(|material| material.visibility.includes(ray_type))
+    }
+
+    /// Check ray intersections against all objects that are visible to rays of type `ray_type` and return the closest
+    /// hit
+    pub fn trace(&self, ray: &Ray, ray_type: RayType) -> Option<(&Object, Hit)> {
+        self.closest_hit(ray, ray_type)
+            .map(|(index, hit)| (&self.objects[index], hit))
+    }
+
+    /// Whether rays of type `ray_type` stop at the object; volumes let shadow and ambient occlusion rays through and
+    /// only attenuate them, see `volume_transmittance()`
+    fn blocks(&self, obj: &Object, ray_type: RayType) -> bool {
+        let is_volume = matches!(obj.shape, Shape::Volume(_));
+        self.is_visible(obj, ray_type) && !(is_volume && matches!(ray_type, RayType::Shadow | RayType::Occlusion))
+    }
+
+    /// Fraction of light that passes through all volumes along `ray` up to its `max_distance`
+    pub fn volume_transmittance(&self, ray: &Ray) -> Float {
+        let transmittance = |index: usize| {
+            let obj = &self.objects[index];
+            match obj.volume_ray(ray) {
+                Some((volume, object_ray, _)) if self.is_visible(obj, RayType::Shadow) => volume.transmittance(&object_ray),
+                _ => 1.0,
+            }
+        };
+
+        if self.acceleration.is_current(&self.objects) {
+            self.acceleration.volumes().iter().map(|&index| transmittance(index)).product()
+        } else {
+            (0..self.objects.len()).map(transmittance).product()
+        }
+    }
+
+    /// Closest hit of `ray` together with the index of the object, through the top-level BVH if it is up to date
+    pub(crate) fn closest_hit(&self, ray: &Ray, ray_type: RayType) -> Option<(usize, Hit)> {
+        let intersect = |index: usize| {
+            let obj = &self.objects[index];
+            if self.blocks(obj, ray_type) {
+                self.intersect_object(obj, index, ray).map(|(_, hit)| hit)
+            } else {
+                None
+            }
+        };
+
+        if self.acceleration.is_current(&self.objects) {
+            self.acceleration.closest_hit(ray, intersect)
+        } else {
+            (0..self.objects.len())
+                .filter_map(|index| intersect(index).map(|hit| (index, hit)))
+                .min_by(|(_, hit1), (_, hit2)| hit1.cmp(hit2))
+        }
+    }
+
+    /// Like `trace()`, but only checks the object with index `index`
+    pub(crate) fn trace_object(&self, index: usize, ray: &Ray, ray_type: RayType) -> Option<(&Object, Hit)> {
+        let obj = self.objects.get(index).filter(|obj| self.blocks(obj, ray_type))?;
+        self.intersect_object(obj, index, ray)
+    }
+
+    /// Like `trace()`, but for up to `PACKET_SIZE` rays at once
+    ///
+    /// Gives the same results as tracing the rays one by one, but meshes traverse their acceleration structures only
+    /// once for the whole packet. Entries after the last ray are `None`.
+    pub fn trace_packet(&self, rays: &[Ray], ray_type: RayType) -> [Option<(&Object, Hit)>; PACKET_SIZE] {
+        let mut nearest_hits: [Option<(&Object, Hit)>; PACKET_SIZE] = Default::default();
+        let mut test = |index: usize| {
+            let obj = &self.objects[index];
+            if !self.is_visible(obj, ray_type) {
+                return;
+            }
+
+            let hits = if self.needs_filtered_intersection(obj, index) {
+                let mut hits: [Option<(&Object, Hit)>; PACKET_SIZE] = Default::default();
+                for (hit, ray) in hits.iter_mut().zip(rays) {
+                    *hit = self.intersect_object(obj, index, ray);
+                }
+                hits
+            } else {
+                obj.intersect_packet(rays)
+            };
+
+            for (nearest_hit, hit) in nearest_hits.iter_mut().zip(hits) {
+                if let Some((obj, hit)) = hit {
+                    if nearest_hit.as_ref().is_none_or(|(_, nearest)| hit < *nearest) {
+                        *nearest_hit = Some((obj, hit));
+                    }
+                }
+            }
+        };
+
+        if self.acceleration.is_current(&self.objects) {
+            self.acceleration.packet_candidates(rays).iter().for_each(|&index| test(index));
+        } else {
+            (0..self.objects.len()).for_each(test);
+        }
+        nearest_hits
+    }
+
+    /// Closest hit of each primary ray, traced in packets like `trace_packet()`
+    ///
+    /// A plain entry point for benchmarks and other code that only needs the hits; rays are tested against the objects
+    /// visible to camera rays, in world space.
+    pub fn trace_batch(&self, rays: &[Ray]) -> Vec<Option<Hit>> {
+        rays.chunks(PACKET_SIZE)
+            .flat_map(|packet| IntoIterator::into_iter(self.trace_packet(packet, RayType::Primary)).take(packet.len()))
+            .map(|hit| hit.map(|(_, hit)| hit))
+            .collect()
+    }
+
+    /// Like `trace()`, but only intersect the object that a similar ray hit before if `cache` knows one
+    ///
+    /// See `HitCache` for when the result may differ from `trace()`. The cache is emptied if anything that decides
+    /// which surfaces rays hit changed since it was filled.
+    pub fn trace_cached(&self, ray: &Ray, ray_type: RayType, cache: &mut HitCache) -> Option<(&Object, Hit)> {
+        cache.sync(self.hit_state());
+
+        let cached = match cache.get(ray, ray_type) {
+            Some(Some(index)) => self.trace_object(index, ray, ray_type).map(Some),
+            Some(None) => Some(None),
+            None => None,
+        };
+        cache.record_lookup(cached.is_some());
+
+        cached.unwrap_or_else(|| {
+            let result = self.closest_hit(ray, ray_type);
+            cache.insert(ray, ray_type, result.as_ref().map(|&(index, _)| index));
+            result.map(|(index, hit)| (&self.objects[index], hit))
+        })
+    }
+
+    /// Fingerprint of everything that decides which surfaces rays hit, for `trace_cached()`
+    ///
+    /// Besides the generation of the top-level BVH, which changes with the objects, this covers the public fields that
+    /// can be edited directly: the visibility and opacity of the materials and the clipping planes.
+    fn hit_state(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.acceleration.generation().hash(&mut hasher);
+        for material in &self.materials {
+            material.visibility.hash(&mut hasher);
+            material.alpha_cutoff.to_bits().hash(&mut hasher);
+            match &material.opacity {
+                None => 0u8.hash(&mut hasher),
+                Some(Parameter::Value(value)) => (1u8, value.to_bits()).hash(&mut hasher),
+                // Images are only shared, never modified, so a different texture has a different image
+                Some(Parameter::Texture(texture, channel)) => (2u8, Arc::as_ptr(&texture.img), *channel as u8).hash(&mut hasher),
+            }
+        }
+        for plane in &self.clipping_planes {
+            let ClippingPlane { point, normal, cap, objects } = plane;
+            [point.x, point.y, point.z, normal.x, normal.y, normal.z].map(Float::to_bits).hash(&mut hasher);
+            (cap, objects).hash(&mut hasher);
+        }
+        hasher.finish()
     }
 }
@@ -0,0 +1,49 @@
+
+use std::cell::RefCell;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::thread::LocalKey;
+
+/// Thread-local vector that scratch buffers of one kind are taken from and returned to
+pub(crate) type ScratchPool<T> = LocalKey<RefCell<Vec<T>>>;
+
+/// Vector borrowed from a `ScratchPool` and handed back empty when dropped
+///
+/// Hot loops like acceleration structure traversals run once per ray; taking their buffers from a pool reuses the
+/// allocation of the previous ray on the same thread instead of allocating a new one every time.
+pub(crate) struct ScratchVec<T: 'static> {
+    vec: Vec<T>,
+    pool: &'static ScratchPool<T>,
+}
+
+impl<T> ScratchVec<T> {
+    /// Take the vector of `pool`, or a new one if it is already taken further up the stack
+    pub fn take(pool: &'static ScratchPool<T>, capacity: usize) -> ScratchVec<T> {
+        let mut vec = pool.with(|pool| pool.take());
+        vec.reserve(capacity);
+        ScratchVec { vec, pool }
+    }
+}
+
+impl<T> Drop for ScratchVec<T> {
+    fn drop(&mut self) {
+        let mut vec = mem::take(&mut self.vec);
+        vec.clear();
+        // The pool is gone while the thread shuts down, the vector is simply freed then
+        let _ = self.pool.try_with(|pool| *pool.borrow_mut() = vec);
+    }
+}
+
+impl<T> Deref for ScratchVec<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.vec
+    }
+}
+
+impl<T> DerefMut for ScratchVec<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.vec
+    }
+}
@@ -0,0 +1,63 @@
+
+use crate::scene::Scene;
+
+/// Quality of a render, kept apart from the scene so that the same scene can be previewed quickly and rendered in
+/// full quality later
+///
+/// Passed to `Renderer::render_with_settings()`. Every knob either keeps the scene's own value or lowers it, so
+/// `RenderSettings::full()` renders exactly what `Renderer::render()` does.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RenderSettings {
+    /// Render at the camera's resolution divided by this and upscale the result, 1 for full resolution
+    pub resolution_divisor: usize,
+    /// Replaces `Scene::aa_samples` if set
+    pub aa_samples: Option<usize>,
+    /// Lowers `Scene::max_recursion_depth` if set
+    pub max_recursion_depth: Option<u32>,
+}
+
+impl RenderSettings {
+    /// The scene's own settings at full resolution
+    pub fn full() -> RenderSettings {
+        RenderSettings {
+            resolution_divisor: 1,
+            aa_samples: None,
+            max_recursion_depth: None,
+        }
+    }
+
+    /// Quick preview at `1 / resolution_divisor` of the resolution (e.g. 2 or 4), with a single sample per pixel and
+    /// at most two bounces
+    pub fn preview(resolution_divisor: usize) -> RenderSettings {
+        RenderSettings {
+            resolution_divisor,
+            aa_samples: Some(1),
+            max_recursion_depth: Some(2),
+        }
+    }
+
+    /// Resolution that is actually rendered for a camera resolution, at least one pixel in each direction
+    pub fn render_resolution(&self, resolution: (usize, usize)) -> (usize, usize) {
+        let divisor = self.resolution_divisor.max(1);
+        ((resolution.0 / divisor).max(1), (resolution.1 / divisor).max(1))
+    }
+
+    /// Copy of `scene` with these settings applied, rendering at the reduced resolution
+    pub fn apply(&self, scene: &Scene) -> Scene {
+        let mut scene = scene.clone();
+        scene.camera.resolution = self.render_resolution(scene.camera.resolution);
+        if let Some(aa_samples) = self.aa_samples {
+            scene.aa_samples = aa_samples;
+        }
+        if let Some(max_recursion_depth) = self.max_recursion_depth {
+            scene.max_recursion_depth = scene.max_recursion_depth.min(max_recursion_depth);
+        }
+        scene
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings::full()
+    }
+}
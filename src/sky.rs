@@ -0,0 +1,125 @@
+
+use serde::{Serialize, Deserialize};
+use cgmath::{Vector3, InnerSpace};
+
+use crate::color::Color;
+use crate::lights::Light;
+use crate::math_util::{deserialize_normalized, float, Float, consts};
+
+fn default_sun_direction() -> Vector3<Float> {
+    Vector3::new(0.0, 1.0, 1.0).normalize()
+}
+
+fn default_turbidity() -> Float {
+    3.0
+}
+
+fn default_intensity() -> Float {
+    1.0
+}
+
+fn default_ground_color() -> Color {
+    Color::new(0.2, 0.18, 0.15)
+}
+
+/// Scale from the model's luminance in kcd/m² to radiance, chosen so that a clear sky at noon is slightly brighter
+/// than a white surface lit by a directional light with an intensity of 1
+const LUMINANCE_SCALE: Float = 0.05;
+
+/// Clear daytime sky after the analytic model of Preetham et al. (1999)
+///
+/// Blue overhead, brighter and paler towards the horizon and around the sun. The sky itself doesn't include a sun
+/// disk and doesn't light the scene; pair it with a `DirectionalLight` and link the two with `sun_light` so that the
+/// sky always matches the direction of the shadows.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sky {
+    /// Direction towards the sun, used when `sun_light` doesn't refer to a directional light
+    #[serde(default = "default_sun_direction", deserialize_with = "deserialize_normalized")]
+    pub sun_direction: Vector3<Float>,
+    /// Index into `Scene::lights` of a directional light that shines from the sun
+    #[serde(default)]
+    pub sun_light: Option<usize>,
+    /// Haziness of the atmosphere, from about 2 (very clear) to 10 (hazy)
+    #[serde(default = "default_turbidity")]
+    pub turbidity: Float,
+    /// Factor applied to the radiance of the sky
+    #[serde(default = "default_intensity")]
+    pub intensity: Float,
+    /// Uniform color seen below the horizon
+    #[serde(default = "default_ground_color")]
+    pub ground_color: Color,
+}
+
+impl Sky {
+    /// Direction towards the sun, taken from the linked light if there is one
+    pub fn sun_direction(&self, lights: &[Light]) -> Vector3<Float> {
+        match self.sun_light.and_then(|index| lights.get(index)) {
+            Some(Light::Directional(light)) => -light.direction,
+            _ => self.sun_direction,
+        }
+    }
+
+    /// Radiance arriving from `direction`
+    pub fn radiance(&self, direction: &Vector3<Float>, lights: &[Light]) -> Color {
+        let direction = direction.normalize();
+        if direction.y < 0.0 {
+            return self.ground_color;
+        }
+
+        let sun = self.sun_direction(lights);
+        // The model only covers a sun above the horizon
+        let sun_theta = float::acos(sun.y.clamp(-1.0, 1.0)).min(consts::FRAC_PI_2 - 0.01);
+        let turbidity = self.turbidity.max(1.0);
+
+        // Near the horizon the model's brightening grows without bounds
+        let theta = float::acos(direction.y.clamp(0.0, 1.0)).min(consts::FRAC_PI_2 - 0.01);
+        let gamma = float::acos(direction.dot(sun.normalize()).clamp(-1.0, 1.0));
+
+        let chi = (4.0 / 9.0 - turbidity / 120.0) * (consts::PI - 2.0 * sun_theta);
+        let zenith_luminance = (4.0453 * turbidity - 4.9710) * float::tan(chi) - 0.2155 * turbidity + 2.4192;
+        let zenith_x = zenith_chromaticity(&ZENITH_X, turbidity, sun_theta);
+        let zenith_y = zenith_chromaticity(&ZENITH_Y, turbidity, sun_theta);
+
+        let relative = |coefficients: &[[Float; 2]; 5], zenith: Float| {
+            let coefficients = coefficients.map(|[slope, offset]| slope * turbidity + offset);
+            zenith * perez(&coefficients, theta, gamma) / perez(&coefficients, 0.0, sun_theta)
+        };
+        let luminance = relative(&PEREZ_LUMINANCE, zenith_luminance).max(0.0) * LUMINANCE_SCALE * self.intensity;
+        let x = relative(&PEREZ_X, zenith_x);
+        let y = relative(&PEREZ_Y, zenith_y).max(Float::EPSILON);
+
+        // xyY to XYZ to linear sRGB
+        let big_x = x / y * luminance;
+        let big_z = (1.0 - x - y) / y * luminance;
+        Color::new(
+            (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0),
+            (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0),
+            (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0),
+        )
+    }
+}
+
+/// Slope and offset with respect to the turbidity of the coefficients A to E of the Perez distributions
+const PEREZ_LUMINANCE: [[Float; 2]; 5] = [[0.1787, -1.4630], [-0.3554, 0.4275], [-0.0227, 5.3251], [0.1206, -2.5771], [-0.0670, 0.3703]];
+const PEREZ_X: [[Float; 2]; 5] = [[-0.0193, -0.2592], [-0.0665, 0.0008], [-0.0004, 0.2125], [-0.0641, -0.8989], [-0.0033, 0.0452]];
+const PEREZ_Y: [[Float; 2]; 5] = [[-0.0167, -0.2608], [-0.0950, 0.0092], [-0.0079, 0.2102], [-0.0441, -1.6537], [-0.0109, 0.0529]];
+
+/// Polynomials in turbidity (rows) and sun zenith angle (columns) for the chromaticity at the zenith
+const ZENITH_X: [[Float; 4]; 3] = [[0.0017, -0.0037, 0.0021, 0.0], [-0.0290, 0.0638, -0.0320, 0.0039], [0.1169, -0.2120, 0.0605, 0.2589]];
+const ZENITH_Y: [[Float; 4]; 3] = [[0.0028, -0.0061, 0.0032, 0.0], [-0.0421, 0.0897, -0.0415, 0.0052], [0.1535, -0.2676, 0.0667, 0.2669]];
+
+fn zenith_chromaticity(matrix: &[[Float; 4]; 3], turbidity: Float, sun_theta: Float) -> Float {
+    let turbidity_powers = [turbidity * turbidity, turbidity, 1.0];
+    let theta_powers = [float::powi(sun_theta, 3), float::powi(sun_theta, 2), sun_theta, 1.0];
+    matrix.iter()
+        .zip(&turbidity_powers)
+        .map(|(row, t)| t * row.iter().zip(&theta_powers).map(|(m, s)| m * s).sum::<Float>())
+        .sum()
+}
+
+/// Perez sky distribution for a direction at zenith angle `theta` and angle `gamma` from the sun
+fn perez(coefficients: &[Float; 5], theta: Float, gamma: Float) -> Float {
+    let [a, b, c, d, e] = *coefficients;
+    let cos_gamma = float::cos(gamma);
+    (1.0 + a * float::exp(b / float::cos(theta))) * (1.0 + c * float::exp(d * gamma) + e * cos_gamma * cos_gamma)
+}
@@ -0,0 +1,116 @@
+use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Purpose of a ray, used to break down `RenderStats`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RayType {
+    /// Ray from the camera
+    Primary,
+    /// Ray towards a light source, including those cast while ray marching fog
+    Shadow,
+    Reflection,
+    Refraction,
+    /// Ray cast to estimate ambient occlusion
+    Occlusion,
+}
+
+/// Counters collected while rendering
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    pub primary_rays: usize,
+    pub shadow_rays: usize,
+    pub reflection_rays: usize,
+    pub refraction_rays: usize,
+    pub occlusion_rays: usize,
+    /// Number of ray-triangle intersection tests performed by mesh acceleration structures
+    pub triangle_tests: usize,
+    /// Number of acceleration structure nodes visited
+    pub node_traversals: usize,
+}
+
+impl RenderStats {
+    pub fn total_rays(&self) -> usize {
+        self.primary_rays + self.shadow_rays + self.reflection_rays + self.refraction_rays + self.occlusion_rays
+    }
+}
+
+/// Thread-safe counterpart of `RenderStats` that all threads using a renderer add to
+#[derive(Default)]
+pub(crate) struct RenderCounters {
+    rays: [AtomicUsize; 5],
+    triangle_tests: AtomicUsize,
+    node_traversals: AtomicUsize,
+}
+
+impl RenderCounters {
+    pub fn record_ray(&self, ray_type: RayType, triangle_tests: usize, node_traversals: usize) {
+        self.record_rays(ray_type, 1, triangle_tests, node_traversals);
+    }
+
+    /// Record `count` rays that did the given work together
+    pub fn record_rays(&self, ray_type: RayType, count: usize, triangle_tests: usize, node_traversals: usize) {
+        self.rays[ray_type as usize].fetch_add(count, Ordering::Relaxed);
+        self.triangle_tests.fetch_add(triangle_tests, Ordering::Relaxed);
+        self.node_traversals.fetch_add(node_traversals, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> RenderStats {
+        let rays = |ray_type: RayType| self.rays[ray_type as usize].load(Ordering::Relaxed);
+        RenderStats {
+            primary_rays: rays(RayType::Primary),
+            shadow_rays: rays(RayType::Shadow),
+            reflection_rays: rays(RayType::Reflection),
+            refraction_rays: rays(RayType::Refraction),
+            occlusion_rays: rays(RayType::Occlusion),
+            triangle_tests: self.triangle_tests.load(Ordering::Relaxed),
+            node_traversals: self.node_traversals.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        for counter in self.rays.iter().chain([&self.triangle_tests, &self.node_traversals]) {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Information about the construction of a mesh acceleration structure
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BuildStats {
+    pub build_time: Duration,
+    /// Number of nodes, including leaves
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub max_depth: usize,
+    /// Number of triangle indices stored in all leaves; larger than the triangle count if triangles straddle splits
+    pub triangle_references: usize,
+    /// Memory used by the acceleration structure in bytes, excluding the mesh data
+    pub memory_usage: usize,
+    /// Memory used by the mesh data in bytes, not counting memory-mapped files
+    pub mesh_memory_usage: usize,
+    /// Time spent subdividing and displacing the mesh data before the build, zero for meshes without subdivision
+    pub subdivision_time: Duration,
+}
+
+/// Progress of building a K-D tree, see `Mesh::new_with_progress()`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BuildProgress {
+    pub triangle_count: usize,
+    /// Triangle references stored in the leaves built so far
+    pub triangles_processed: usize,
+    /// Triangle references in the nodes that still have to be built; triangles that straddle splits count more than once
+    pub triangles_pending: usize,
+    /// Number of nodes built so far, including leaves
+    pub nodes_created: usize,
+}
+
+impl BuildProgress {
+    /// Estimated fraction of the work that is done, from 0 to 1
+    ///
+    /// Reaches 1 once the tree is complete. It can go back slightly when splits duplicate triangles, since the total
+    /// number of triangle references isn't known in advance.
+    pub fn fraction(&self) -> f64 {
+        let total = self.triangles_processed + self.triangles_pending;
+        if total == 0 { 1.0 } else { self.triangles_processed as f64 / total as f64 }
+    }
+}
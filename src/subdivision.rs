@@ -0,0 +1,455 @@
+use std::array;
+use std::collections::HashMap;
+use std::mem;
+
+use serde::{Serialize, Deserialize};
+use cgmath::{Vector2, Vector3, InnerSpace, Zero};
+
+use crate::material::Texture;
+use crate::mesh::{MeshData, IndexedTriangle, CornerIndices, weld_values};
+use crate::ray::MAX_UV_CHANNELS;
+use crate::math_util::{float, Float};
+use crate::error::RaytracerError;
+
+/// How each triangle is split into four when a mesh is subdivided
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SubdivisionScheme {
+    /// Insert the midpoints of the edges, which keeps the surface exactly as it is
+    Midpoint,
+    /// Loop subdivision, which also moves the vertices to approach a smooth surface
+    ///
+    /// Only positions are smoothed, all other vertex attributes are interpolated linearly. Creases stay sharp, see
+    /// `Subdivision::crease_angle`. Vertex normals are recalculated as smooth normals of the new surface, also for meshes
+    /// that had none, except across edges where the vertex normals were split.
+    Loop,
+}
+
+/// Moves the vertices of a mesh along their normals by the values of a height map
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Displacement {
+    /// Grayscale height map, sampled in its UV channel; usually `linear`
+    pub texture: Texture,
+    /// Distance by which white moves the surface, relative to `midlevel`; negative values invert the heights
+    pub scale: Float,
+    /// Height that leaves the surface in place, e.g. 0.5 to displace both inwards and outwards
+    #[serde(default)]
+    pub midlevel: Float,
+}
+
+impl Displacement {
+    fn offset(&self, tex_coords: &Vector2<Float>) -> Float {
+        (self.texture.sample_bilinear(tex_coords).luminance() - self.midlevel) * self.scale
+    }
+}
+
+/// Refines a mesh after loading and before its acceleration structure is built, e.g. to add the detail of a terrain or
+/// brick wall to a low-poly base mesh
+///
+/// Each level quadruples the number of triangles.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Subdivision {
+    pub scheme: SubdivisionScheme,
+    pub levels: usize,
+    /// Loop subdivision keeps edges sharp where the faces meet at more than this angle in degrees
+    ///
+    /// Boundary edges, edges shared by more than two triangles and edges where the vertex normals are split, i.e. the
+    /// hard edges of the control mesh, are always creases. The normals are split along the edges sharper than this angle,
+    /// so that they are shaded as hard edges too.
+    #[serde(default)]
+    pub crease_angle: Option<Float>,
+    #[serde(default)]
+    pub displacement: Option<Displacement>,
+}
+
+impl Subdivision {
+    /// Smooth Loop subdivision without displacement, as used for control meshes
+    pub fn smooth(levels: usize) -> Subdivision {
+        Subdivision {
+            scheme: SubdivisionScheme::Loop,
+            levels,
+            crease_angle: None,
+            displacement: None,
+        }
+    }
+
+    /// Subdivide `data`, then displace it
+    pub fn apply(&self, data: &mut MeshData) {
+        data.subdivide(self.scheme, self.levels, self.crease_angle);
+        if let Some(displacement) = &self.displacement {
+            data.displace(displacement);
+        }
+    }
+
+    /// Load the displacement map again through the current asset loader
+    pub fn reload(&mut self) -> Result<(), RaytracerError> {
+        match &mut self.displacement {
+            Some(displacement) => displacement.texture.reload(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    if a < b { (a, b) } else { (b, a) }
+}
+
+/// Index of the value halfway along the edge from `a` to `b`, which is added the first time the edge is split
+fn midpoint<T: Copy>(values: &mut Vec<T>, midpoints: &mut HashMap<(usize, usize), usize>, a: usize, b: usize, interpolate: &impl Fn(T, T) -> T) -> usize {
+    *midpoints.entry(edge_key(a, b)).or_insert_with(|| {
+        values.push(interpolate(values[a], values[b]));
+        values.len() - 1
+    })
+}
+
+/// Split the corner indices of a triangle into those of its four sub-triangles, adding the midpoints to `values`
+///
+/// The sub-triangles keep the winding of the triangle, the last one is the one in the middle.
+fn split_triangle<T: Copy>(indices: CornerIndices, values: &mut Vec<T>, midpoints: &mut HashMap<(usize, usize), usize>, interpolate: &impl Fn(T, T) -> T) -> [CornerIndices; 4] {
+    let (a, b, c) = indices.get();
+    let ab = midpoint(values, midpoints, a, b, interpolate);
+    let bc = midpoint(values, midpoints, b, c, interpolate);
+    let ca = midpoint(values, midpoints, c, a, interpolate);
+    [(a, ab, ca), (ab, b, bc), (ca, bc, c), (ab, bc, ca)].map(CornerIndices::from)
+}
+
+fn average2(a: (Float, Float), b: (Float, Float)) -> (Float, Float) {
+    ((a.0 + b.0) * 0.5, (a.1 + b.1) * 0.5)
+}
+
+fn average3(a: (Float, Float, Float), b: (Float, Float, Float)) -> (Float, Float, Float) {
+    ((a.0 + b.0) * 0.5, (a.1 + b.1) * 0.5, (a.2 + b.2) * 0.5)
+}
+
+fn average_normal(a: (Float, Float, Float), b: (Float, Float, Float)) -> (Float, Float, Float) {
+    let sum = Vector3::from(a) + Vector3::from(b);
+    if sum.magnitude2() > 0.0 { sum.normalize().into() } else { a }
+}
+
+/// Map every position to the first one at exactly the same place
+///
+/// Meshes often duplicate positions along seams of their texture coordinates or normals. Treating the copies as one
+/// vertex keeps such seams from tearing open when positions are smoothed or displaced.
+fn canonical_positions(positions: &[(Float, Float, Float)]) -> Vec<usize> {
+    // Adding zero turns -0 into 0, which would have different bits otherwise
+    let key = |p: &(Float, Float, Float)| ((p.0 + 0.0).to_bits(), (p.1 + 0.0).to_bits(), (p.2 + 0.0).to_bits());
+    let mut first_index = HashMap::with_capacity(positions.len());
+    positions.iter().enumerate()
+        .map(|(index, position)| *first_index.entry(key(position)).or_insert(index))
+        .collect()
+}
+
+impl MeshData {
+    /// Split every triangle into four, `levels` times
+    ///
+    /// `crease_angle` only applies to Loop subdivision, see `Subdivision::crease_angle`.
+    pub fn subdivide(&mut self, scheme: SubdivisionScheme, levels: usize, crease_angle: Option<Float>) {
+        if levels == 0 {
+            return;
+        }
+
+        if scheme == SubdivisionScheme::Loop {
+            // Copies of the same normal, e.g. along texture seams, must not count as split normals
+            let (normals, normal_map) = weld_values(&self.vertex_normals, |n| [n.0, n.1, n.2], 1e-5);
+            self.vertex_normals = normals;
+            for triangle in &mut self.triangles {
+                triangle.normal_indices = triangle.normal_indices.map(|indices| indices.map(|index| normal_map[index]));
+            }
+
+            if let Some(angle) = crease_angle {
+                self.split_normals_at_creases(float::cos(angle.to_radians()));
+            }
+        }
+
+        for _ in 0..levels {
+            self.subdivide_once(scheme);
+        }
+
+        if scheme == SubdivisionScheme::Loop {
+            self.recalculate_normals(&canonical_positions(&self.vertex_positions));
+        }
+    }
+
+    fn subdivide_once(&mut self, scheme: SubdivisionScheme) {
+        // Loop subdivision computes the new positions from the old mesh
+        let smoothed = match scheme {
+            SubdivisionScheme::Midpoint => None,
+            SubdivisionScheme::Loop => Some(self.loop_positions()),
+        };
+        let old_position_count = self.vertex_positions.len();
+
+        let mut position_midpoints = HashMap::new();
+        let mut normal_midpoints = HashMap::new();
+        let mut tex_coords_midpoints = HashMap::new();
+        let mut color_midpoints = HashMap::new();
+        let mut extra_tex_coords_midpoints = vec![HashMap::new(); self.extra_tex_coords.len()];
+
+        let old_triangles = mem::take(&mut self.triangles);
+        let mut triangles = Vec::with_capacity(old_triangles.len() * 4);
+        for triangle in &old_triangles {
+            let positions = split_triangle(triangle.position_indices, &mut self.vertex_positions, &mut position_midpoints, &average3);
+            let normals = triangle.normal_indices
+                .map(|indices| split_triangle(indices, &mut self.vertex_normals, &mut normal_midpoints, &average_normal));
+            let tex_coords = triangle.tex_coords_indices
+                .map(|indices| split_triangle(indices, &mut self.vertex_tex_coords, &mut tex_coords_midpoints, &average2));
+            let colors = triangle.color_indices
+                .map(|indices| split_triangle(indices, &mut self.vertex_colors, &mut color_midpoints, &average3));
+            let mut extra_tex_coords = [None; MAX_UV_CHANNELS - 1];
+            for (index, split) in extra_tex_coords.iter_mut().enumerate().take(self.extra_tex_coords.len()) {
+                *split = triangle.extra_tex_coords_indices[index]
+                    .map(|indices| split_triangle(indices, &mut self.extra_tex_coords[index], &mut extra_tex_coords_midpoints[index], &average2));
+            }
+
+            triangles.extend((0..4).map(|i| IndexedTriangle {
+                position_indices: positions[i],
+                normal_indices: normals.map(|normals| normals[i]),
+                tex_coords_indices: tex_coords.map(|tex_coords| tex_coords[i]),
+                color_indices: colors.map(|colors| colors[i]),
+                extra_tex_coords_indices: array::from_fn(|index| extra_tex_coords[index].map(|split: [_; 4]| split[i])),
+            }));
+        }
+        self.triangles = triangles;
+
+        if let Some((canonical, vertex_points, edge_points)) = smoothed {
+            for (position, vertex) in self.vertex_positions[..old_position_count].iter_mut().zip(&canonical) {
+                if let Some(point) = vertex_points.get(vertex) {
+                    *position = (*point).into();
+                }
+            }
+            for (&(a, b), &index) in &position_midpoints {
+                self.vertex_positions[index] = edge_points[&edge_key(canonical[a], canonical[b])].into();
+            }
+        }
+    }
+
+    /// New positions for one step of Loop subdivision
+    ///
+    /// Returns the canonical index of every position (see `canonical_positions()`), the smoothed positions of the
+    /// existing vertices by canonical index and the positions of the new vertices by the edge they split.
+    #[allow(clippy::type_complexity)]
+    fn loop_positions(&self) -> (Vec<usize>, HashMap<usize, Vector3<Float>>, HashMap<(usize, usize), Vector3<Float>>) {
+        let canonical = canonical_positions(&self.vertex_positions);
+        let position = |index: usize| Vector3::from(self.vertex_positions[index]);
+
+        /// One of the triangles sharing an edge
+        struct EdgeFace {
+            /// Canonical index of the vertex opposite of the edge
+            opposite: usize,
+            /// Normal indices at the smaller and the larger canonical index of the edge
+            normals: Option<(usize, usize)>,
+        }
+
+        let mut edge_faces: HashMap<(usize, usize), Vec<EdgeFace>> = HashMap::new();
+        for triangle in &self.triangles {
+            let (a, b, c) = triangle.position_indices.get();
+            let corners = [canonical[a], canonical[b], canonical[c]];
+            let normals = triangle.normal_indices.map(CornerIndices::to_array);
+            for i in 0..3 {
+                let (p, q, r) = (corners[i], corners[(i + 1) % 3], corners[(i + 2) % 3]);
+                let normals = normals.map(|normals| {
+                    let (normal_p, normal_q) = (normals[i], normals[(i + 1) % 3]);
+                    if p < q { (normal_p, normal_q) } else { (normal_q, normal_p) }
+                });
+                edge_faces.entry(edge_key(p, q)).or_default().push(EdgeFace { opposite: r, normals });
+            }
+        }
+
+        let mut neighbours: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut crease_neighbours: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut edge_points = HashMap::with_capacity(edge_faces.len());
+        for (&(p, q), faces) in &edge_faces {
+            neighbours.entry(p).or_default().push(q);
+            neighbours.entry(q).or_default().push(p);
+
+            // Edges with split normals are creases, and so are boundary and non-manifold edges
+            let smooth_faces = match faces.as_slice() {
+                [f, g] if !matches!((f.normals, g.normals), (Some(f), Some(g)) if f != g) => Some((f, g)),
+                _ => None,
+            };
+
+            let edge_point = match smooth_faces {
+                Some((f, g)) => (position(p) + position(q)) * (3.0 / 8.0) + (position(f.opposite) + position(g.opposite)) * (1.0 / 8.0),
+                None => {
+                    crease_neighbours.entry(p).or_default().push(q);
+                    crease_neighbours.entry(q).or_default().push(p);
+                    (position(p) + position(q)) * 0.5
+                }
+            };
+            edge_points.insert((p, q), edge_point);
+        }
+
+        let vertex_points = neighbours.iter()
+            .map(|(&vertex, neighbours)| {
+                let point = position(vertex);
+                let new_point = match crease_neighbours.get(&vertex).map(Vec::as_slice) {
+                    // A single crease ends in the smooth surface (a dart)
+                    None | Some(&[_]) => {
+                        // Warren's simplified weights
+                        let n = neighbours.len() as Float;
+                        let beta = if neighbours.len() == 3 { 3.0 / 16.0 } else { 3.0 / (8.0 * n) };
+                        let neighbour_sum = neighbours.iter().fold(Vector3::zero(), |sum, &neighbour| sum + position(neighbour));
+                        point * (1.0 - n * beta) + neighbour_sum * beta
+                    }
+                    // Vertices on a crease only move along it
+                    Some(&[a, b]) => point * 0.75 + (position(a) + position(b)) * 0.125,
+                    // Corners where several creases meet stay in place
+                    Some(_) => point,
+                };
+                (vertex, new_point)
+            })
+            .collect();
+
+        (canonical, vertex_points, edge_points)
+    }
+
+    /// Give the triangles on either side of edges sharper than the crease angle their own normals, so that Loop
+    /// subdivision keeps these edges as creases
+    ///
+    /// Triangles keep sharing a normal at a position if they are connected through smooth edges around it. The new
+    /// normals are area weighted averages of the normals of these triangles.
+    fn split_normals_at_creases(&mut self, min_crease_cos: Float) {
+        let canonical = canonical_positions(&self.vertex_positions);
+        let face_normals: Vec<_> = self.triangles.iter()
+            .map(|triangle| {
+                let [p0, p1, p2] = triangle.position_indices.to_array().map(|index| *self.get_vertex_position(index));
+                (p1 - p0).cross(p2 - p0)
+            })
+            .collect();
+        let corner_normal = |corner: usize| self.triangles[corner / 3].normal_indices
+            .map(|normals| normals.to_array()[corner % 3]);
+
+        // Corners are numbered 3 * triangle + corner, grouped into the triangles around a position that share a normal
+        let mut groups: Vec<usize> = (0..self.triangles.len() * 3).collect();
+        fn find(groups: &mut [usize], mut corner: usize) -> usize {
+            while groups[corner] != corner {
+                groups[corner] = groups[groups[corner]];
+                corner = groups[corner];
+            }
+            corner
+        }
+
+        // The corners at the smaller and the larger canonical index of each edge, for every triangle sharing it
+        let mut edge_corners: HashMap<(usize, usize), Vec<[usize; 2]>> = HashMap::new();
+        for (triangle_index, triangle) in self.triangles.iter().enumerate() {
+            let vertices = triangle.position_indices.to_array().map(|index| canonical[index]);
+            for i in 0..3 {
+                let (p, q) = (vertices[i], vertices[(i + 1) % 3]);
+                let (corner_p, corner_q) = (triangle_index * 3 + i, triangle_index * 3 + (i + 1) % 3);
+                edge_corners.entry(edge_key(p, q)).or_default()
+                    .push(if p < q { [corner_p, corner_q] } else { [corner_q, corner_p] });
+            }
+        }
+
+        for corners in edge_corners.values() {
+            if let [f, g] = corners.as_slice() {
+                let split_normals = corner_normal(f[0]) != corner_normal(g[0]) || corner_normal(f[1]) != corner_normal(g[1]);
+                let cos = face_normals[f[0] / 3].normalize().dot(face_normals[g[0] / 3].normalize());
+                // Degenerate triangles have no normal and don't split anything
+                let too_sharp = cos < min_crease_cos;
+                if !split_normals && !too_sharp {
+                    for i in 0..2 {
+                        let (root_f, root_g) = (find(&mut groups, f[i]), find(&mut groups, g[i]));
+                        groups[root_f] = root_g;
+                    }
+                }
+            }
+        }
+
+        let mut group_normals: HashMap<usize, usize> = HashMap::new();
+        let mut normal_sums = Vec::new();
+        let mut triangle_normals = Vec::with_capacity(self.triangles.len());
+        for (triangle_index, face_normal) in face_normals.iter().enumerate() {
+            let [n0, n1, n2] = [0, 1, 2].map(|i| {
+                let group = find(&mut groups, triangle_index * 3 + i);
+                let index = *group_normals.entry(group).or_insert_with(|| {
+                    normal_sums.push(Vector3::zero());
+                    normal_sums.len() - 1
+                });
+                normal_sums[index] += *face_normal;
+                index
+            });
+            triangle_normals.push((n0, n1, n2));
+        }
+
+        self.vertex_normals = normal_sums.into_iter()
+            .map(|sum| if sum.magnitude2() > 0.0 { sum.normalize().into() } else { (0.0, 1.0, 0.0) })
+            .collect();
+        for (triangle, normals) in self.triangles.iter_mut().zip(triangle_normals) {
+            triangle.normal_indices = Some(normals.into());
+        }
+    }
+
+    /// Move every vertex along its normal by the height of `displacement` at its texture coordinates
+    ///
+    /// Vertices at the same position move together, with their heights and normals averaged, so the surface stays
+    /// closed along seams. Normals are taken from the vertex normals if there are any and from the triangles otherwise.
+    /// Vertex normals are recalculated afterwards as smooth normals of the displaced surface.
+    pub fn displace(&mut self, displacement: &Displacement) {
+        let canonical = canonical_positions(&self.vertex_positions);
+        let mut normal_sums = vec![Vector3::zero(); self.vertex_positions.len()];
+        let mut offset_sums = vec![(0.0, 0); self.vertex_positions.len()];
+
+        for triangle in &self.triangles {
+            let corners = triangle.position_indices.to_array();
+            let [p0, p1, p2] = corners.map(|index| *self.get_vertex_position(index));
+            // Not normalized, which weights the normals by the area of the triangles
+            let face_normal = (p1 - p0).cross(p2 - p0) * 0.5;
+            let normals = triangle.normal_indices.map(|indices| indices.to_array().map(|index| Vector3::from(self.vertex_normals[index]) * face_normal.magnitude()));
+            let tex_coords = self.get_triangle_tex_coords(triangle, displacement.texture.uv_channel)
+                .or_else(|| self.get_triangle_tex_coords(triangle, 0))
+                .unwrap_or([Vector2::zero(); 3]);
+
+            for corner in 0..3 {
+                let vertex = canonical[corners[corner]];
+                normal_sums[vertex] += normals.map_or(face_normal, |normals| normals[corner]);
+                offset_sums[vertex].0 += displacement.offset(&tex_coords[corner]);
+                offset_sums[vertex].1 += 1;
+            }
+        }
+
+        for (index, position) in self.vertex_positions.iter_mut().enumerate() {
+            let vertex = canonical[index];
+            let (offset_sum, count) = offset_sums[vertex];
+            if count > 0 && normal_sums[vertex].magnitude2() > 0.0 {
+                let moved = Vector3::from(*position) + normal_sums[vertex].normalize() * (offset_sum / count as Float);
+                *position = moved.into();
+            }
+        }
+
+        if !self.vertex_normals.is_empty() {
+            self.recalculate_normals(&canonical);
+        }
+    }
+
+    /// Replace the vertex normals with the area weighted average of the normals of the triangles at each position
+    ///
+    /// Triangles only share a new normal if they shared their old normal at that position, so split normals stay
+    /// split. Triangles without normals share one at each position.
+    fn recalculate_normals(&mut self, canonical: &[usize]) {
+        let mut normal_indices: HashMap<(usize, Option<usize>), usize> = HashMap::new();
+        let mut normal_sums = Vec::new();
+        let mut triangle_normals = Vec::with_capacity(self.triangles.len());
+        for triangle in &self.triangles {
+            let (a, b, c) = triangle.position_indices.get();
+            let [p0, p1, p2] = [a, b, c].map(|index| *self.get_vertex_position(index));
+            let face_normal = (p1 - p0).cross(p2 - p0);
+            let old_normals = triangle.normal_indices.map_or([None; 3], |indices| indices.to_array().map(Some));
+
+            let [n0, n1, n2] = [(a, old_normals[0]), (b, old_normals[1]), (c, old_normals[2])].map(|(position, old_normal)| {
+                let index = *normal_indices.entry((canonical[position], old_normal)).or_insert_with(|| {
+                    normal_sums.push(Vector3::zero());
+                    normal_sums.len() - 1
+                });
+                normal_sums[index] += face_normal;
+                index
+            });
+            triangle_normals.push((n0, n1, n2));
+        }
+
+        self.vertex_normals = normal_sums.into_iter()
+            .map(|sum| if sum.magnitude2() > 0.0 { sum.normalize().into() } else { (0.0, 1.0, 0.0) })
+            .collect();
+        for (triangle, normals) in self.triangles.iter_mut().zip(triangle_normals) {
+            triangle.normal_indices = Some(normals.into());
+        }
+    }
+}
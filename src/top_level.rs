@@ -0,0 +1,315 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use cgmath::{InnerSpace, EuclideanSpace};
+
+use crate::aabb::AABB;
+use crate::ray::{Hit, Ray};
+use crate::scene::{Object, Shape};
+use crate::scratch::ScratchVec;
+use crate::packet::RayPacket;
+use crate::math_util::Float;
+
+/// Largest number of objects in a leaf
+const MAX_LEAF_SIZE: usize = 2;
+
+/// Source of `TopLevelBvh::generation()`, shared by all scenes so that their generations never coincide
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+fn next_generation() -> u64 {
+    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+thread_local! {
+    /// Traversal stack reused by all rays of a thread
+    static STACK: RefCell<Vec<(usize, Float)>> = const { RefCell::new(Vec::new()) };
+    /// Candidate objects of the packet being traced on this thread
+    static CANDIDATES: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Node of the top-level BVH; the first child of an inner node directly follows it
+#[derive(Clone)]
+struct TopLevelNode {
+    bounding_box: AABB,
+    /// Index of the second child for inner nodes
+    second_child: usize,
+    /// Start of the objects of a leaf in `TopLevelBvh::object_indices`
+    start_index: usize,
+    /// Zero for inner nodes
+    object_count: usize,
+}
+
+/// Bounding volume hierarchy over the world space bounds of the objects of a scene
+///
+/// This is the top level of a two-level hierarchy: the acceleration structures of the meshes are built once in object
+/// space and don't change when their objects move. Moving objects only changes their bounds here, which `refit()`
+/// updates without rebuilding the tree. That is all an animated scene needs per frame, though the tree gets less
+/// efficient if the objects move far from where they were when it was built.
+#[derive(Clone)]
+pub struct TopLevelBvh {
+    nodes: Vec<TopLevelNode>,
+    /// Indices into `Scene::objects`, referenced by the leaves
+    object_indices: Vec<usize>,
+    /// Objects without bounds, e.g. infinite planes, which every ray is tested against
+    unbounded: Vec<usize>,
+    /// Whether each object is in the tree and has an animation; its bounds are those of the static transformation, so
+    /// rays at a point in time test it separately
+    animated: Vec<bool>,
+    /// Objects with a `Shape::Volume`, which shadow rays don't stop at but have to pass through
+    volumes: Vec<usize>,
+    /// Set while objects may have moved since the last refit
+    outdated: bool,
+    generation: u64,
+}
+
+impl Default for TopLevelBvh {
+    /// Empty tree, which is outdated as soon as there are objects
+    fn default() -> TopLevelBvh {
+        TopLevelBvh {
+            nodes: Vec::new(),
+            object_indices: Vec::new(),
+            unbounded: Vec::new(),
+            animated: Vec::new(),
+            volumes: Vec::new(),
+            outdated: false,
+            generation: next_generation(),
+        }
+    }
+}
+
+impl TopLevelBvh {
+    pub fn build(objects: &[Object]) -> TopLevelBvh {
+        let bounds: Vec<_> = objects.iter().map(Object::world_bounds).collect();
+
+        let mut bvh = TopLevelBvh {
+            nodes: Vec::new(),
+            object_indices: Vec::new(),
+            unbounded: bounds.iter().enumerate().filter(|(_, bounds)| bounds.is_none()).map(|(index, _)| index).collect(),
+            animated: animated_objects(objects, &bounds),
+            volumes: volume_objects(objects),
+            outdated: false,
+            generation: next_generation(),
+        };
+
+        let mut indices: Vec<_> = bounds.iter().enumerate().filter(|(_, bounds)| bounds.is_some()).map(|(index, _)| index).collect();
+        if !indices.is_empty() {
+            let bounding_boxes: Vec<_> = bounds.into_iter().map(|bounds| bounds.unwrap_or_else(AABB::empty)).collect();
+            bvh.build_node(&mut indices, 0, &bounding_boxes);
+        }
+        bvh.object_indices = indices;
+
+        bvh
+    }
+
+    /// Build the subtree for the objects in `indices` (which start at `offset` in `object_indices`)
+    fn build_node(&mut self, indices: &mut [usize], offset: usize, bounding_boxes: &[AABB]) {
+        let bounding_box = indices.iter().fold(AABB::empty(), |bounding_box, &index| bounding_box.union(&bounding_boxes[index]));
+        let node_index = self.nodes.len();
+        self.nodes.push(TopLevelNode {
+            bounding_box,
+            second_child: 0,
+            start_index: offset,
+            object_count: indices.len(),
+        });
+        if indices.len() <= MAX_LEAF_SIZE {
+            return;
+        }
+
+        // Split at the median object centroid along the axis of maximum extent
+        let centroid = |index: usize| bounding_boxes[index].min.midpoint(bounding_boxes[index].max);
+        let centroid_bounds = indices.iter()
+            .map(|&index| centroid(index))
+            .fold(AABB::empty(), |bounding_box, c| bounding_box.union(&AABB { min: c, max: c }));
+        let axis = centroid_bounds.maximum_extent();
+        let mid = indices.len() / 2;
+        indices.select_nth_unstable_by(mid, |&a, &b| centroid(a)[axis].total_cmp(&centroid(b)[axis]));
+
+        let (below, above) = indices.split_at_mut(mid);
+        self.build_node(below, offset, bounding_boxes);
+        let second_child = self.nodes.len();
+        self.build_node(above, offset + mid, bounding_boxes);
+
+        let node = &mut self.nodes[node_index];
+        node.second_child = second_child;
+        node.object_count = 0;
+    }
+
+    /// Update the bounds of all nodes after objects moved, keeping the structure of the tree
+    ///
+    /// Builds the tree again if objects were added or removed, or gained or lost their bounds (e.g. meshes loaded
+    /// after the scene).
+    pub fn refit(&mut self, objects: &[Object]) {
+        let bounds: Vec<_> = objects.iter().map(Object::world_bounds).collect();
+        let bounded_count = bounds.iter().filter(|bounds| bounds.is_some()).count();
+        let same_objects = objects.len() == self.animated.len()
+            && bounded_count == self.object_indices.len()
+            && self.object_indices.iter().all(|&index| bounds[index].is_some());
+        if !same_objects {
+            *self = TopLevelBvh::build(objects);
+            return;
+        }
+
+        self.animated = animated_objects(objects, &bounds);
+        self.volumes = volume_objects(objects);
+        // Children are stored after their parents
+        for node_index in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[node_index];
+            let bounding_box = if node.object_count > 0 {
+                self.object_indices[node.start_index..node.start_index + node.object_count].iter()
+                    .filter_map(|&index| bounds[index].as_ref())
+                    .fold(AABB::empty(), |bounding_box, object_bounds| bounding_box.union(object_bounds))
+            } else {
+                self.nodes[node_index + 1].bounding_box.union(&self.nodes[node.second_child].bounding_box)
+            };
+            self.nodes[node_index].bounding_box = bounding_box;
+        }
+        self.outdated = false;
+        self.generation = next_generation();
+    }
+
+    /// Mark the tree as outdated until the next `refit()`, e.g. because objects are about to move
+    pub fn invalidate(&mut self) {
+        self.outdated = true;
+        self.generation = next_generation();
+    }
+
+    /// Number that changes whenever the objects may have changed, unique among all trees
+    ///
+    /// Lets caches of intersection results, like `HitCache`, notice that they are stale.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Change the generation without touching the tree, for edits that change which surfaces rays hit but not the
+    /// bounds of the objects, e.g. replacing a material
+    pub(crate) fn bump_generation(&mut self) {
+        self.generation = next_generation();
+    }
+
+    /// Whether the tree matches the objects, otherwise rays have to be tested against all of them
+    pub fn is_current(&self, objects: &[Object]) -> bool {
+        !self.outdated && self.animated.len() == objects.len()
+    }
+
+    /// Indices of the objects with a `Shape::Volume`
+    pub(crate) fn volumes(&self) -> &[usize] {
+        &self.volumes
+    }
+
+    /// Closest hit along `ray` of the objects that `intersect` reports hits for, together with the object index
+    ///
+    /// Like testing all objects in order, the object with the lowest index wins if several are hit at the same
+    /// distance.
+    pub(crate) fn closest_hit(&self, ray: &Ray, mut intersect: impl FnMut(usize) -> Option<Hit>) -> Option<(usize, Hit)> {
+        let mut closest: Option<(usize, Hit)> = None;
+        let mut test = |index: usize, closest: &mut Option<(usize, Hit)>| {
+            if let Some(hit) = intersect(index) {
+                let is_closer = closest.as_ref()
+                    .is_none_or(|(closest_index, closest_hit)| hit < *closest_hit || (hit.distance == closest_hit.distance && index < *closest_index));
+                if is_closer {
+                    *closest = Some((index, hit));
+                }
+            }
+        };
+
+        let timed = ray.time.is_some();
+        for &index in &self.unbounded {
+            test(index, &mut closest);
+        }
+        if timed {
+            for index in (0..self.animated.len()).filter(|&index| self.animated[index]) {
+                test(index, &mut closest);
+            }
+        }
+
+        if self.nodes.is_empty() {
+            return closest;
+        }
+
+        // Box intersections are in units of the direction, hit distances in world units
+        let scale = ray.direction.magnitude();
+        let entry_distance = |node: &TopLevelNode| node.bounding_box.intersects_p(ray)
+            .map(|(t_min, _)| t_min.max(0.0) * scale)
+            .filter(|&distance| distance <= ray.max_distance);
+
+        let mut stack = ScratchVec::take(&STACK, 64);
+        if let Some(distance) = entry_distance(&self.nodes[0]) {
+            stack.push((0, distance));
+        }
+        while let Some((node_index, distance)) = stack.pop() {
+            if closest.as_ref().is_some_and(|(_, hit)| distance > hit.distance) {
+                continue;
+            }
+
+            let node = &self.nodes[node_index];
+            if node.object_count > 0 {
+                for &index in &self.object_indices[node.start_index..node.start_index + node.object_count] {
+                    if !(timed && self.animated[index]) {
+                        test(index, &mut closest);
+                    }
+                }
+                continue;
+            }
+
+            // Visit the nearer child first
+            let first = entry_distance(&self.nodes[node_index + 1]).map(|distance| (node_index + 1, distance));
+            let second = entry_distance(&self.nodes[node.second_child]).map(|distance| (node.second_child, distance));
+            match (first, second) {
+                (Some(first), Some(second)) => {
+                    let (near, far) = if first.1 <= second.1 { (first, second) } else { (second, first) };
+                    stack.push(far);
+                    stack.push(near);
+                }
+                (Some(child), None) | (None, Some(child)) => stack.push(child),
+                (None, None) => {}
+            }
+        }
+
+        closest
+    }
+
+    /// Indices of the objects that any of up to `PACKET_SIZE` rays may hit, in ascending order
+    pub(crate) fn packet_candidates(&self, rays: &[Ray]) -> ScratchVec<usize> {
+        let mut candidates = ScratchVec::take(&CANDIDATES, self.object_indices.len() + self.unbounded.len());
+        candidates.extend_from_slice(&self.unbounded);
+        if rays.iter().any(|ray| ray.time.is_some()) {
+            candidates.extend((0..self.animated.len()).filter(|&index| self.animated[index]));
+        }
+
+        if !self.nodes.is_empty() {
+            let packet = RayPacket::new(rays);
+            let mut stack = ScratchVec::take(&STACK, 64);
+            stack.push((0, 0.0));
+            while let Some((node_index, _)) = stack.pop() {
+                let node = &self.nodes[node_index];
+                if !packet.intersects_box(&node.bounding_box).contains(&true) {
+                    continue;
+                }
+
+                if node.object_count > 0 {
+                    candidates.extend_from_slice(&self.object_indices[node.start_index..node.start_index + node.object_count]);
+                } else {
+                    stack.push((node_index + 1, 0.0));
+                    stack.push((node.second_child, 0.0));
+                }
+            }
+        }
+
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+}
+
+fn animated_objects(objects: &[Object], bounds: &[Option<AABB>]) -> Vec<bool> {
+    objects.iter().zip(bounds)
+        .map(|(object, bounds)| object.animation.is_some() && bounds.is_some())
+        .collect()
+}
+
+fn volume_objects(objects: &[Object]) -> Vec<usize> {
+    objects.iter().enumerate()
+        .filter(|(_, object)| matches!(object.shape, Shape::Volume(_)))
+        .map(|(index, _)| index)
+        .collect()
+}
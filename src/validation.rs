@@ -0,0 +1,243 @@
+
+use cgmath::{Point3, Vector3, InnerSpace, EuclideanSpace};
+
+use crate::color::Color;
+use crate::hdr_image::HdrImage;
+use crate::lights::{Light, DirectionalLight};
+use crate::material::{Material, Coloration};
+use crate::primitives::{Plane, Sphere};
+use crate::ray::Ray;
+use crate::renderer::Renderer;
+use crate::scene::{Scene, Camera, Object, Shape, Transformation};
+use crate::math_util::{float, Float, consts};
+
+const RESOLUTION: (usize, usize) = (64, 64);
+
+/// Scenes whose rendered image can be computed analytically, for checking the renderer quantitatively
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReferenceScene {
+    /// A half-mirrored white sphere inside a uniform environment; the sphere has to be indistinguishable from the
+    /// background because it reflects exactly as much light as it receives
+    Furnace,
+    /// A white Lambertian unit sphere lit by a single directional light, so that each pixel equals the cosine between
+    /// the surface normal and the light direction
+    LambertSphere,
+    /// The camera inside a closed box of partially reflective walls lit only by ambient light; every pixel equals the
+    /// geometric series of the reflections up to the maximum recursion depth
+    MirrorBox,
+}
+
+/// Result of comparing a rendered image against the analytic solution
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Comparison {
+    /// Number of pixels that have an analytic solution
+    pub compared_pixels: usize,
+    /// Mean absolute difference over all compared pixels and channels
+    pub mean_error: Float,
+    /// Largest absolute difference of any compared pixel and channel
+    pub max_error: Float,
+}
+
+impl Comparison {
+    pub fn is_within(&self, tolerance: Float) -> bool {
+        self.compared_pixels > 0 && self.max_error <= tolerance
+    }
+}
+
+impl ReferenceScene {
+    pub fn all() -> [ReferenceScene; 3] {
+        [ReferenceScene::Furnace, ReferenceScene::LambertSphere, ReferenceScene::MirrorBox]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReferenceScene::Furnace => "furnace",
+            ReferenceScene::LambertSphere => "lambert_sphere",
+            ReferenceScene::MirrorBox => "mirror_box",
+        }
+    }
+
+    /// Build the scene to render
+    pub fn scene(&self) -> Scene {
+        match self {
+            ReferenceScene::Furnace => {
+                let environment = Color::new(0.5, 0.5, 0.5);
+                let camera = Camera::new(RESOLUTION, 60.0, Point3::new(0.0, 0.0, 3.0), -Vector3::unit_z(), Vector3::unit_y())
+                    .expect("Reference cameras have a valid orientation");
+                let material = Material::new(Coloration::Color(Color::white()), 1.0, 0.5, 0.0, 1.0);
+                let sphere = Object::new(Shape::Sphere(Sphere::default()), 0, identity());
+                reference_scene(camera, environment, vec![material], vec![sphere], environment, Vec::new(), 4)
+            }
+            ReferenceScene::LambertSphere => {
+                let camera = Camera::new(RESOLUTION, 60.0, Point3::new(0.0, 0.0, 3.0), -Vector3::unit_z(), Vector3::unit_y())
+                    .expect("Reference cameras have a valid orientation");
+                // An albedo of pi cancels the normalization of the Lambertian BRDF
+                let material = Material::new(Coloration::Color(Color::white()), consts::PI, 0.0, 0.0, 1.0);
+                let sphere = Object::new(Shape::Sphere(Sphere::default()), 0, identity());
+                let light = Light::Directional(DirectionalLight {
+                    direction: lambert_light_direction(),
+                    color: Color::white(),
+                    intensity: 1.0,
+                    name: None,
+                    group: None,
+                });
+                reference_scene(camera, Color::black(), vec![material], vec![sphere], Color::black(), vec![light], 0)
+            }
+            ReferenceScene::MirrorBox => {
+                let camera = Camera::new(RESOLUTION, 90.0, Point3::new(0.0, 0.0, 0.0), Vector3::new(0.3, -0.2, -1.0).normalize(), Vector3::unit_y())
+                    .expect("Reference cameras have a valid orientation");
+                let material = Material::new(Coloration::Color(Color::white()), 1.0, MIRROR_BOX_REFLECTIVITY, 0.0, 1.0);
+                // Planes face upwards, so rotate them to face the inside of the box
+                let walls = [
+                    (Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 0.0, 0.0)),
+                    (Vector3::new(0.0, 1.0, 0.0), Vector3::new(180.0, 0.0, 0.0)),
+                    (Vector3::new(-1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, -90.0)),
+                    (Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 90.0)),
+                    (Vector3::new(0.0, 0.0, -1.0), Vector3::new(90.0, 0.0, 0.0)),
+                    (Vector3::new(0.0, 0.0, 1.0), Vector3::new(-90.0, 0.0, 0.0)),
+                ];
+                let objects = walls.iter()
+                    .map(|&(translation, rotation)| Object::new(Shape::Plane(Plane::default()), 0, Transformation::new(translation, rotation, 1.0)))
+                    .collect();
+                reference_scene(camera, Color::black(), vec![material], objects, MIRROR_BOX_AMBIENT, Vec::new(), MIRROR_BOX_DEPTH)
+            }
+        }
+    }
+
+    /// Analytic color of a pixel, or `None` if the pixel is too close to a discontinuity to be compared reliably
+    /// because of the jittered anti-aliasing samples
+    pub fn expected_color(&self, x: usize, y: usize) -> Option<Color> {
+        match self {
+            ReferenceScene::Furnace => Some(Color::new(0.5, 0.5, 0.5)),
+            ReferenceScene::LambertSphere => {
+                let camera = self.scene().camera;
+                let camera_ray = |x: Float, y: Float| {
+                    Ray::from_screen_coordinates(x, y, camera.resolution.0, camera.resolution.1, camera.fov)
+                        .transform(&camera.transformation_matrix)
+                };
+
+                // Skip pixels whose samples may land on both the sphere and the background; samples stay within the
+                // pixel and the outline of the sphere is convex, so checking the corners is enough
+                let (x, y) = (x as Float, y as Float);
+                let corners = [(-0.5, -0.5), (0.5, -0.5), (-0.5, 0.5), (0.5, 0.5)];
+                let corner_hits = corners.iter()
+                    .filter(|(dx, dy)| intersect_unit_sphere(&camera_ray(x + dx, y + dy)).is_some())
+                    .count();
+
+                match intersect_unit_sphere(&camera_ray(x, y)) {
+                    Some(point) if corner_hits == corners.len() => {
+                        let normal = point.to_vec().normalize();
+                        let value = normal.dot(-lambert_light_direction()).max(0.0);
+                        Some(Color::new(value, value, value))
+                    }
+                    None if corner_hits == 0 => Some(Color::black()),
+                    _ => None,
+                }
+            }
+            ReferenceScene::MirrorBox => {
+                let value = MIRROR_BOX_AMBIENT.r * (1.0 - float::powi(MIRROR_BOX_REFLECTIVITY, MIRROR_BOX_DEPTH + 1));
+                Some(Color::new(value, value, value))
+            }
+        }
+    }
+
+    /// Compare a rendering of the full frame against the analytic solution
+    pub fn compare(&self, image: &HdrImage) -> Comparison {
+        let mut compared_pixels = 0;
+        let mut error_sum = 0.0;
+        let mut max_error: Float = 0.0;
+
+        for y in 0..image.height() {
+            for x in 0..image.width() {
+                if let Some(expected) = self.expected_color(x, y) {
+                    let actual = image.get_pixel(x, y);
+                    for &error in &[(actual.r - expected.r).abs(), (actual.g - expected.g).abs(), (actual.b - expected.b).abs()] {
+                        error_sum += error;
+                        max_error = max_error.max(error);
+                    }
+                    compared_pixels += 1;
+                }
+            }
+        }
+
+        Comparison {
+            compared_pixels,
+            mean_error: if compared_pixels > 0 { error_sum / (compared_pixels * 3) as Float } else { 0.0 },
+            max_error,
+        }
+    }
+
+    /// Render the scene and compare it against the analytic solution
+    pub fn render_and_compare(&self) -> Comparison {
+        let renderer = Renderer::new(self.scene());
+        let image = renderer.render_rect_hdr(0, 0, RESOLUTION.0, RESOLUTION.1);
+        self.compare(&image)
+    }
+}
+
+const MIRROR_BOX_REFLECTIVITY: Float = 0.5;
+const MIRROR_BOX_AMBIENT: Color = Color { r: 0.8, g: 0.8, b: 0.8 };
+const MIRROR_BOX_DEPTH: u32 = 5;
+
+fn lambert_light_direction() -> Vector3<Float> {
+    Vector3::new(-1.0, -1.0, -1.0).normalize()
+}
+
+fn identity() -> Transformation {
+    Transformation::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.0), 1.0)
+}
+
+/// Closest intersection of a ray with the unit sphere at the origin
+fn intersect_unit_sphere(ray: &Ray) -> Option<Point3<Float>> {
+    let origin = ray.origin.to_vec();
+    let b = origin.dot(ray.direction);
+    let c = origin.magnitude2() - 1.0;
+    let discriminant = b * b - c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let t = -b - discriminant.sqrt();
+    if t > 0.0 {
+        Some(ray.origin + ray.direction * t)
+    } else {
+        None
+    }
+}
+
+fn reference_scene(camera: Camera, clear_color: Color, materials: Vec<Material>, objects: Vec<Object>, ambient_light_color: Color, lights: Vec<Light>, max_recursion_depth: u32) -> Scene {
+    Scene {
+        aa_samples: 4,
+        clear_color,
+        materials,
+        objects,
+        ambient_light_color,
+        lights,
+        max_recursion_depth,
+        ..Scene::new(camera)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn furnace_matches_environment() {
+        let comparison = ReferenceScene::Furnace.render_and_compare();
+        assert!(comparison.is_within(1e-3), "{:?}", comparison);
+    }
+
+    #[test]
+    fn lambert_sphere_follows_cosine_law() {
+        // Pixels along the terminator average samples on both sides of it
+        let comparison = ReferenceScene::LambertSphere.render_and_compare();
+        assert!(comparison.is_within(0.02), "{:?}", comparison);
+        assert!(comparison.mean_error < 2e-3, "{:?}", comparison);
+    }
+
+    #[test]
+    fn mirror_box_sums_reflections() {
+        let comparison = ReferenceScene::MirrorBox.render_and_compare();
+        assert!(comparison.is_within(1e-3), "{:?}", comparison);
+    }
+}
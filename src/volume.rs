@@ -0,0 +1,446 @@
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize, Deserializer};
+use cgmath::{Point3, Vector2, Vector3};
+
+use crate::ray::{Ray, Hit, Interval};
+use crate::aabb::AABB;
+use crate::color::Color;
+use crate::asset_loader::{self, AssetLoader};
+use crate::math_util::{float, Float};
+use crate::error::RaytracerError;
+
+/// Upper bound for the number of samples along a ray, which keeps tiny step sizes from stalling the render
+const MAX_STEPS: usize = 4096;
+
+/// Optical depth beyond which the light that still passes through is negligible
+const MAX_OPTICAL_DEPTH: Float = 10.0;
+
+#[derive(Debug)]
+pub enum DensityGridError {
+    /// The NRRD header is malformed
+    InvalidHeader(String),
+    /// The file uses a feature that isn't supported, e.g. compressed data or a sample type other than 8 or 16 bit
+    /// unsigned integers and 32 or 64 bit floats
+    Unsupported(String),
+    /// The name of a raw file doesn't contain its resolution and sample type
+    UnknownLayout,
+    /// The data is shorter than the resolution says, or the resolution has more values than can be addressed
+    InvalidSize,
+}
+
+impl Display for DensityGridError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DensityGridError::InvalidHeader(msg) => write!(f, "Invalid NRRD header: {}", msg),
+            DensityGridError::Unsupported(msg) => write!(f, "Unsupported density grid: {}", msg),
+            DensityGridError::UnknownLayout => write!(f, "Raw density grids need a name like \"smoke_64x64x64_uint8.raw\""),
+            DensityGridError::InvalidSize => write!(f, "Data doesn't match the resolution"),
+        }
+    }
+}
+
+impl Error for DensityGridError {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum SampleType {
+    UInt8,
+    UInt16,
+    Float32,
+    Float64,
+}
+
+impl SampleType {
+    /// Sample type from its NRRD name or the suffix of a raw file name
+    fn from_name(name: &str) -> Option<SampleType> {
+        match name {
+            "uchar" | "unsigned char" | "uint8" | "uint8_t" => Some(SampleType::UInt8),
+            "ushort" | "unsigned short" | "unsigned short int" | "uint16" | "uint16_t" => Some(SampleType::UInt16),
+            "float" | "float32" => Some(SampleType::Float32),
+            "double" | "float64" => Some(SampleType::Float64),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            SampleType::UInt8 => 1,
+            SampleType::UInt16 => 2,
+            SampleType::Float32 => 4,
+            SampleType::Float64 => 8,
+        }
+    }
+
+    /// Decode the samples of a grid with the given resolution, normalizing integers to the range from 0 to 1
+    fn decode(self, data: &[u8], resolution: [usize; 3], big_endian: bool) -> Result<Vec<f32>, DensityGridError> {
+        // The resolution comes from the file, so the sizes may overflow
+        let length = resolution.iter()
+            .try_fold(self.size(), |length, &size| length.checked_mul(size))
+            .ok_or(DensityGridError::InvalidSize)?;
+        let data = data.get(..length).ok_or(DensityGridError::InvalidSize)?;
+        let samples = data.chunks_exact(self.size()).map(|bytes| match (self, big_endian) {
+            (SampleType::UInt8, _) => bytes[0] as f32 / 255.0,
+            (SampleType::UInt16, false) => u16::from_le_bytes(bytes.try_into().unwrap()) as f32 / 65535.0,
+            (SampleType::UInt16, true) => u16::from_be_bytes(bytes.try_into().unwrap()) as f32 / 65535.0,
+            (SampleType::Float32, false) => f32::from_le_bytes(bytes.try_into().unwrap()),
+            (SampleType::Float32, true) => f32::from_be_bytes(bytes.try_into().unwrap()),
+            (SampleType::Float64, false) => f64::from_le_bytes(bytes.try_into().unwrap()) as f32,
+            (SampleType::Float64, true) => f64::from_be_bytes(bytes.try_into().unwrap()) as f32,
+        });
+        Ok(samples.collect())
+    }
+}
+
+/// Values on a regular 3D grid, e.g. the density of smoke
+///
+/// The values are stored with X varying fastest, then Y, then Z. Files can be NRRD with raw encoding or headerless
+/// little endian raw data whose name contains the resolution and sample type like `smoke_64x64x64_uint8.raw`, the
+/// convention of many published volume data sets. Integer samples are normalized to the range from 0 to 1.
+#[derive(Clone, Debug)]
+pub struct DensityGrid {
+    resolution: [usize; 3],
+    values: Vec<f32>,
+}
+
+impl DensityGrid {
+    /// Panics if a dimension of the resolution is zero or the number of values doesn't match it
+    pub fn new(resolution: [usize; 3], values: Vec<f32>) -> DensityGrid {
+        assert!(resolution.iter().all(|&size| size > 0), "Density grids need at least one value in each dimension");
+        assert_eq!(values.len(), resolution.iter().product::<usize>(), "Number of values doesn't match the resolution");
+        DensityGrid { resolution, values }
+    }
+
+    /// Parse a NRRD file with three dimensions and raw encoding
+    pub fn parse_nrrd(bytes: &[u8]) -> Result<DensityGrid, DensityGridError> {
+        if !bytes.starts_with(b"NRRD000") {
+            return Err(DensityGridError::InvalidHeader("Missing magic".to_string()));
+        }
+
+        let mut resolution = None;
+        let mut sample_type = None;
+        let mut big_endian = false;
+        let mut lines = bytes.split(|&byte| byte == b'\n');
+        let mut offset = lines.next().map_or(0, |magic| magic.len() + 1);
+        // The header ends with an empty line
+        for line in lines {
+            offset += line.len() + 1;
+            let line = String::from_utf8_lossy(line);
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                break;
+            }
+            // Comments and key/value pairs don't affect the data
+            if line.starts_with('#') || line.contains(":=") {
+                continue;
+            }
+
+            let (field, description) = line.split_once(": ")
+                .ok_or_else(|| DensityGridError::InvalidHeader(format!("Invalid line \"{}\"", line)))?;
+            let description = description.trim();
+            match field {
+                "dimension" if description != "3" => {
+                    return Err(DensityGridError::Unsupported(format!("{} dimensions instead of 3", description)));
+                }
+                "sizes" => {
+                    let sizes = description.split_whitespace()
+                        .map(|size| size.parse::<usize>().ok().filter(|&size| size > 0))
+                        .collect::<Option<Vec<_>>>()
+                        .and_then(|sizes| <[usize; 3]>::try_from(sizes).ok())
+                        .ok_or_else(|| DensityGridError::InvalidHeader(format!("Invalid sizes \"{}\"", description)))?;
+                    resolution = Some(sizes);
+                }
+                "type" => {
+                    sample_type = Some(SampleType::from_name(description)
+                        .ok_or_else(|| DensityGridError::Unsupported(format!("Sample type \"{}\"", description)))?);
+                }
+                "encoding" if description != "raw" => {
+                    return Err(DensityGridError::Unsupported(format!("Encoding \"{}\"", description)));
+                }
+                "endian" => big_endian = description == "big",
+                "data file" | "datafile" => return Err(DensityGridError::Unsupported("Detached data file".to_string())),
+                "line skip" | "lineskip" | "byte skip" | "byteskip" if description != "0" => {
+                    return Err(DensityGridError::Unsupported("Skipping data".to_string()));
+                }
+                _ => {}
+            }
+        }
+
+        let resolution = resolution.ok_or_else(|| DensityGridError::InvalidHeader("Missing sizes".to_string()))?;
+        let sample_type = sample_type.ok_or_else(|| DensityGridError::InvalidHeader("Missing type".to_string()))?;
+        let data = bytes.get(offset..).unwrap_or(&[]);
+        let values = sample_type.decode(data, resolution, big_endian)?;
+        Ok(DensityGrid { resolution, values })
+    }
+
+    /// Parse headerless raw data, taking the resolution and sample type from `file_name`
+    pub fn parse_raw(bytes: &[u8], file_name: &str) -> Result<DensityGrid, DensityGridError> {
+        let stem = file_name.rsplit_once('.').map_or(file_name, |(stem, _)| stem);
+        let parse_resolution = |part: &str| {
+            let sizes = part.split('x')
+                .map(|size| size.parse::<usize>().ok().filter(|&size| size > 0))
+                .collect::<Option<Vec<_>>>()?;
+            <[usize; 3]>::try_from(sizes).ok()
+        };
+        let resolution = stem.split('_').find_map(parse_resolution).ok_or(DensityGridError::UnknownLayout)?;
+        let sample_type = stem.split('_').find_map(SampleType::from_name).ok_or(DensityGridError::UnknownLayout)?;
+
+        let values = sample_type.decode(bytes, resolution, false)?;
+        Ok(DensityGrid { resolution, values })
+    }
+
+    /// Number of values along X, Y and Z
+    pub fn resolution(&self) -> [usize; 3] {
+        self.resolution
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    pub fn value(&self, x: usize, y: usize, z: usize) -> Float {
+        let [size_x, size_y, _] = self.resolution;
+        self.values[(z * size_y + y) * size_x + x] as Float
+    }
+
+    /// Trilinearly interpolated value at grid coordinates, which are clamped to the grid
+    fn sample(&self, position: Vector3<Float>) -> Float {
+        let mut lower = [0; 3];
+        let mut upper = [0; 3];
+        let mut fraction = [0.0; 3];
+        for axis in 0..3 {
+            let max = (self.resolution[axis] - 1) as Float;
+            let coordinate = position[axis].max(0.0).min(max);
+            lower[axis] = coordinate.floor() as usize;
+            upper[axis] = (lower[axis] + 1).min(self.resolution[axis] - 1);
+            fraction[axis] = coordinate - lower[axis] as Float;
+        }
+
+        let lerp = |a: Float, b: Float, t: Float| a + (b - a) * t;
+        let along_x = |y: usize, z: usize| lerp(self.value(lower[0], y, z), self.value(upper[0], y, z), fraction[0]);
+        let along_y = |z: usize| lerp(along_x(lower[1], z), along_x(upper[1], z), fraction[1]);
+        lerp(along_y(lower[2]), along_y(upper[2]), fraction[2])
+    }
+}
+
+fn default_density() -> Float {
+    1.0
+}
+
+fn default_albedo() -> Color {
+    Color::white()
+}
+
+fn default_step_size() -> Float {
+    0.02
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeserializableVolume {
+    path: PathBuf,
+    #[serde(default = "default_density")]
+    density: Float,
+    #[serde(default = "default_albedo")]
+    albedo: Color,
+    #[serde(default = "default_step_size")]
+    step_size: Float,
+}
+
+impl From<Volume> for DeserializableVolume {
+    fn from(volume: Volume) -> DeserializableVolume {
+        DeserializableVolume {
+            path: volume.path,
+            density: volume.density,
+            albedo: volume.albedo,
+            step_size: volume.step_size,
+        }
+    }
+}
+
+/// Participating medium like smoke or clouds, whose density is read from a `DensityGrid`
+///
+/// The grid is stretched over the cube from (-1, -1, -1) to (1, 1, 1), with its first value at (-1, -1, -1). Rays
+/// that hit the cube march through it in steps of `step_size`: the medium absorbs part of the light from behind it
+/// and scatters the light of the scene's lights towards the viewer (single scattering with an isotropic phase
+/// function). Instead of blocking shadow rays like other shapes, volumes let the fraction of the light through that
+/// passes them, so they cast soft shadows onto themselves and the rest of the scene.
+///
+/// The material of the object is only used for its visibility flags.
+#[derive(Clone, Serialize)]
+#[serde(into = "DeserializableVolume")]
+pub struct Volume {
+    path: PathBuf,
+    /// Shared between clones
+    grid: Arc<DensityGrid>,
+    /// Extinction coefficient per object space unit at a grid value of 1, so scaling the object doesn't change how
+    /// opaque it is
+    pub density: Float,
+    /// Fraction of the extinguished light that is scattered rather than absorbed, per color channel
+    pub albedo: Color,
+    /// Distance between samples along a ray in object space units, the cube is 2 units wide
+    pub step_size: Float,
+}
+
+impl<'de> Deserialize<'de> for Volume {
+    fn deserialize<D>(deserializer: D) -> Result<Volume, D::Error>
+        where
+            D: Deserializer<'de>
+    {
+        let d = DeserializableVolume::deserialize(deserializer)?;
+        let volume = Self::load(d.path.clone()).map_err(|err| {
+            serde::de::Error::custom(format!("Unable to open density grid \"{}\": {}", d.path.display(), err))
+        })?;
+        Ok(Volume {
+            density: d.density,
+            albedo: d.albedo,
+            step_size: d.step_size,
+            ..volume
+        })
+    }
+}
+
+impl Volume {
+    /// Volume with the default density, albedo and step size for a grid that was loaded from `path`
+    pub fn new(path: PathBuf, grid: DensityGrid) -> Volume {
+        Volume {
+            path,
+            grid: Arc::new(grid),
+            density: default_density(),
+            albedo: default_albedo(),
+            step_size: default_step_size(),
+        }
+    }
+
+    pub fn load(path: PathBuf) -> Result<Volume, RaytracerError> {
+        let grid = asset_loader::load_density_grid(&path)?;
+        Ok(Volume::new(path, grid))
+    }
+
+    /// Like `load()`, but load the grid through `loader` instead of the current asset loader
+    pub fn load_with(path: PathBuf, loader: &dyn AssetLoader) -> Result<Volume, RaytracerError> {
+        let grid = loader.load_density_grid(&path).map_err(|err| RaytracerError::asset(&path, err))?;
+        Ok(Volume::new(path, grid))
+    }
+
+    /// Load the grid again from the same path through the current asset loader
+    pub fn reload(&mut self) -> Result<(), RaytracerError> {
+        self.grid = Arc::new(asset_loader::load_density_grid(&self.path)?);
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn grid(&self) -> &DensityGrid {
+        &self.grid
+    }
+
+    pub fn bounding_box(&self) -> AABB {
+        AABB::new(&Point3::new(-1.0, -1.0, -1.0), &Point3::new(1.0, 1.0, 1.0))
+    }
+
+    /// Extinction coefficient at a point in object space
+    pub fn extinction_at(&self, point: &Point3<Float>) -> Float {
+        let [size_x, size_y, size_z] = self.grid.resolution.map(|size| (size - 1) as Float);
+        let position = Vector3::new((point.x + 1.0) * 0.5 * size_x, (point.y + 1.0) * 0.5 * size_y, (point.z + 1.0) * 0.5 * size_z);
+        self.grid.sample(position) * self.density
+    }
+
+    /// Section of the ray inside the cube, limited to the part between its origin and `max_distance`
+    pub fn segment(&self, ray: &Ray) -> Option<(Float, Float)> {
+        let (t_min, t_max) = self.bounding_box().intersects_p(ray)?;
+        let (start, end) = (t_min.max(0.0), t_max.min(ray.max_distance));
+        if start < end { Some((start, end)) } else { None }
+    }
+
+    /// Distance and length of the samples along a section of a ray, `offset` between 0 and 1 shifts them within
+    /// their steps
+    pub fn steps(&self, start: Float, end: Float, offset: Float) -> impl Iterator<Item = (Float, Float)> {
+        let step_count = ((end - start) / self.step_size).ceil().clamp(1.0, MAX_STEPS as Float) as usize;
+        let step_length = (end - start) / step_count as Float;
+        (0..step_count).map(move |step| (start + (step as Float + offset) * step_length, step_length))
+    }
+
+    /// Fraction of light that passes through the volume along the ray up to its `max_distance`
+    pub fn transmittance(&self, ray: &Ray) -> Float {
+        let (start, end) = match self.segment(ray) {
+            Some(segment) => segment,
+            None => return 1.0,
+        };
+
+        let mut optical_depth = 0.0;
+        for (t, step_length) in self.steps(start, end, 0.5) {
+            optical_depth += self.extinction_at(&(ray.origin + ray.direction * t)) * step_length;
+            if optical_depth > MAX_OPTICAL_DEPTH {
+                return 0.0;
+            }
+        }
+        float::exp(-optical_depth)
+    }
+
+    /// Hit where the ray enters the cube, at its origin if it starts inside
+    pub fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (start, _) = self.segment(ray)?;
+        Some(self.box_hit(ray, start))
+    }
+
+    /// The section of the ray inside the cube, regardless of the density
+    pub fn intersect_interval(&self, ray: &Ray) -> Vec<Interval> {
+        let (t_min, t_max) = match self.bounding_box().intersects_p(ray) {
+            Some(distances) if distances.1 > 0.0 => distances,
+            _ => return Vec::new(),
+        };
+        vec![Interval {
+            entry: if t_min > 0.0 { Some(self.box_hit(ray, t_min)) } else { None },
+            exit: Some(self.box_hit(ray, t_max)),
+        }]
+    }
+
+    /// Hit on the face of the cube closest to the point at `distance`
+    fn box_hit(&self, ray: &Ray, distance: Float) -> Hit {
+        let point = ray.origin + ray.direction * distance;
+        let faces = [
+            ((point.x + 1.0).abs(), -Vector3::unit_x()),
+            ((point.x - 1.0).abs(), Vector3::unit_x()),
+            ((point.y + 1.0).abs(), -Vector3::unit_y()),
+            ((point.y - 1.0).abs(), Vector3::unit_y()),
+            ((point.z + 1.0).abs(), -Vector3::unit_z()),
+            ((point.z - 1.0).abs(), Vector3::unit_z()),
+        ];
+        let normal = faces.iter()
+            .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap())
+            .map(|&(_, normal)| normal)
+            .unwrap();
+
+        let tex_coords = Vector2::new((point.x + 1.0) * 0.5, (point.z + 1.0) * 0.5);
+        Hit::new(point, distance, normal, tex_coords, Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 2.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nrrd_with_overflowing_sizes_is_rejected() {
+        let header = b"NRRD0004\ntype: uint8\ndimension: 3\nsizes: 4294967296 4294967296 1\nencoding: raw\n\n\0\0\0\0";
+        assert!(matches!(DensityGrid::parse_nrrd(header), Err(DensityGridError::InvalidSize)));
+    }
+
+    #[test]
+    fn raw_with_overflowing_sizes_is_rejected() {
+        let name = format!("smoke_{}x{}x1_float64.raw", usize::MAX / 4, 4);
+        assert!(matches!(DensityGrid::parse_raw(&[0; 64], &name), Err(DensityGridError::InvalidSize)));
+    }
+
+    #[test]
+    fn nrrd_is_parsed() {
+        let mut bytes = b"NRRD0004\n# comment\ntype: uchar\ndimension: 3\nsizes: 2 1 1\nencoding: raw\n\n".to_vec();
+        bytes.extend_from_slice(&[0, 255]);
+        let grid = DensityGrid::parse_nrrd(&bytes).unwrap();
+        assert_eq!(grid.resolution(), [2, 1, 1]);
+        assert_eq!(grid.value(1, 0, 0), 1.0);
+    }
+}
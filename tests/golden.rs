@@ -0,0 +1,44 @@
+// The golden images are rendered in single precision; with `f64` the generated scenes draw different random numbers
+#![cfg(all(feature = "golden", not(feature = "f64")))]
+
+use std::path::PathBuf;
+
+use raytracer::GoldenScene;
+
+/// Renders are deterministic, so this only tolerates the slight noise of e.g. a reordered sum
+const MIN_SSIM: raytracer::Float = 0.99;
+
+fn golden_directory() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+fn check(scene: GoldenScene) {
+    if let Err(err) = scene.check(&golden_directory(), MIN_SSIM) {
+        panic!("{}", err);
+    }
+}
+
+#[test]
+fn furnace() {
+    check(GoldenScene::Furnace);
+}
+
+#[test]
+fn lambert_sphere() {
+    check(GoldenScene::LambertSphere);
+}
+
+#[test]
+fn mirror_box() {
+    check(GoldenScene::MirrorBox);
+}
+
+#[test]
+fn room() {
+    check(GoldenScene::Room);
+}
+
+#[test]
+fn city() {
+    check(GoldenScene::City);
+}